@@ -0,0 +1,448 @@
+use std::{ops::Range, sync::Arc};
+
+use cgmath::{EuclideanSpace, Point3, Quaternion, Rotation3};
+use wgpu::{naga::FastHashMap, util::DrawIndexedIndirectArgs};
+
+use crate::{
+    camera::Sphere,
+    game::chunk::ChunkPos,
+    mesh::{Material, Mesh, MeshBuilder, MeshSize, MeshVertex},
+    upload::Uploader,
+    Instance, InstanceRaw,
+};
+
+/// Free ranges of a buffer, in elements, handed out first fit
+struct RangeAllocator {
+    /// Sorted and never adjacent, adjacent ranges are merged
+    free: Vec<Range<u32>>,
+    capacity: u32,
+}
+
+impl RangeAllocator {
+    fn new(capacity: u32) -> Self {
+        Self {
+            free: std::iter::once(0..capacity).collect(),
+            capacity,
+        }
+    }
+
+    fn allocate(&mut self, len: u32) -> Option<Range<u32>> {
+        if len == 0 {
+            return Some(0..0);
+        }
+        let i = self
+            .free
+            .iter()
+            .position(|free| free.end - free.start >= len)?;
+        let start = self.free[i].start;
+        self.free[i].start += len;
+        if self.free[i].is_empty() {
+            self.free.remove(i);
+        }
+        Some(start..start + len)
+    }
+
+    fn free(&mut self, range: Range<u32>) {
+        if range.is_empty() {
+            return;
+        }
+        let i = self.free.partition_point(|free| free.start < range.start);
+        self.free.insert(i, range);
+        if i + 1 < self.free.len() && self.free[i].end == self.free[i + 1].start {
+            self.free[i].end = self.free[i + 1].end;
+            self.free.remove(i + 1);
+        }
+        if i > 0 && self.free[i - 1].end == self.free[i].start {
+            self.free[i - 1].end = self.free[i].end;
+            self.free.remove(i);
+        }
+    }
+
+    fn grow(&mut self, capacity: u32) {
+        self.free(self.capacity..capacity);
+        self.capacity = capacity;
+    }
+}
+
+/// A GPU buffer shared by many meshes, each getting a range of its elements
+struct ArenaBuffer {
+    label: &'static str,
+    buffer: wgpu::Buffer,
+    allocator: RangeAllocator,
+    /// Size of one element in bytes
+    stride: u64,
+    usage: wgpu::BufferUsages,
+}
+
+impl ArenaBuffer {
+    /// Elements every arena buffer starts out with room for
+    const INITIAL_CAPACITY: u32 = 1 << 12;
+
+    fn new(
+        label: &'static str,
+        stride: u64,
+        usage: wgpu::BufferUsages,
+        device: &wgpu::Device,
+    ) -> Self {
+        Self {
+            label,
+            buffer: Self::create_buffer(
+                label,
+                Self::INITIAL_CAPACITY as u64 * stride,
+                usage,
+                device,
+            ),
+            allocator: RangeAllocator::new(Self::INITIAL_CAPACITY),
+            stride,
+            usage,
+        }
+    }
+
+    fn create_buffer(
+        label: &str,
+        size: u64,
+        usage: wgpu::BufferUsages,
+        device: &wgpu::Device,
+    ) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some(label),
+            size,
+            // Copied from when the arena grows
+            usage: usage | wgpu::BufferUsages::COPY_DST | wgpu::BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Upload `data` into a free range, growing the buffer if there is none large enough
+    fn insert(
+        &mut self,
+        data: &[u8],
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) -> Range<u32> {
+        let len = (data.len() as u64 / self.stride) as u32;
+        let range = match self.allocator.allocate(len) {
+            Some(range) => range,
+            None => {
+                self.grow(
+                    (self.allocator.capacity + len).next_power_of_two(),
+                    device,
+                    uploader,
+                );
+                self.allocator.allocate(len).unwrap()
+            }
+        };
+        uploader.write_buffer(&self.buffer, range.start as u64 * self.stride, data, device);
+        range
+    }
+
+    /// Move everything into a larger buffer. The copy is recorded along with the uploads, so
+    /// writes made earlier in the frame are carried over.
+    fn grow(&mut self, capacity: u32, device: &wgpu::Device, uploader: &mut Uploader) {
+        let buffer = Self::create_buffer(
+            self.label,
+            capacity as u64 * self.stride,
+            self.usage,
+            device,
+        );
+        uploader.copy_buffer(
+            &self.buffer,
+            &buffer,
+            self.allocator.capacity as u64 * self.stride,
+            device,
+        );
+        self.buffer = buffer;
+        self.allocator.grow(capacity);
+    }
+
+    fn free(&mut self, range: Range<u32>) {
+        self.allocator.free(range);
+    }
+}
+
+/// Where the opaque mesh of a chunk is in the arena
+struct ArenaEntry {
+    vertices: Range<u32>,
+    indices: Range<u32>,
+    instance: Range<u32>,
+    bounding_sphere: Sphere,
+}
+
+/// The opaque meshes of every loaded chunk, packed into shared vertex, index and instance
+/// buffers.
+///
+/// With the right features, all visible chunks are drawn with a single indirect draw call.
+/// Otherwise each chunk gets its own draw call, though without changing buffers between them.
+pub struct ChunkArena {
+    vertices: ArenaBuffer,
+    indices: ArenaBuffer,
+    instances: ArenaBuffer,
+    entries: FastHashMap<ChunkPos, ArenaEntry>,
+    material: Arc<Material>,
+    /// Draws of the chunks passed to the last `prepare`
+    draws: Vec<DrawIndexedIndirectArgs>,
+    indirect_buffer: wgpu::Buffer,
+}
+
+impl ChunkArena {
+    /// The features needed to draw every chunk in one call
+    pub const MULTI_DRAW_FEATURES: wgpu::Features =
+        wgpu::Features::MULTI_DRAW_INDIRECT.union(wgpu::Features::INDIRECT_FIRST_INSTANCE);
+
+    pub fn new(material: Arc<Material>, device: &wgpu::Device) -> Self {
+        Self {
+            vertices: ArenaBuffer::new(
+                "Chunk Arena Vertex Buffer",
+                std::mem::size_of::<MeshVertex>() as u64,
+                wgpu::BufferUsages::VERTEX,
+                device,
+            ),
+            indices: ArenaBuffer::new(
+                "Chunk Arena Index Buffer",
+                std::mem::size_of::<u32>() as u64,
+                wgpu::BufferUsages::INDEX,
+                device,
+            ),
+            instances: ArenaBuffer::new(
+                "Chunk Arena Instance Buffer",
+                std::mem::size_of::<InstanceRaw>() as u64,
+                wgpu::BufferUsages::VERTEX,
+                device,
+            ),
+            entries: FastHashMap::default(),
+            material,
+            draws: vec![],
+            indirect_buffer: Self::create_indirect_buffer(1, device),
+        }
+    }
+
+    fn create_indirect_buffer(draws: usize, device: &wgpu::Device) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Chunk Arena Indirect Buffer"),
+            size: (draws * std::mem::size_of::<DrawIndexedIndirectArgs>()) as u64,
+            usage: wgpu::BufferUsages::INDIRECT | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Replace the mesh of the chunk at `pos`, which is placed at `position` in the world.
+    /// Chunks without any faces still get an entry, marking them as meshed.
+    pub fn insert(
+        &mut self,
+        pos: ChunkPos,
+        builder: &MeshBuilder,
+        position: Point3<f32>,
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) {
+        self.remove(pos);
+
+        let instance = Instance {
+            position,
+            rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+        };
+        let local_sphere = Mesh::bounding_sphere(builder.vertices());
+        let entry = ArenaEntry {
+            vertices: self.vertices.insert(
+                bytemuck::cast_slice(builder.vertices()),
+                device,
+                uploader,
+            ),
+            indices: self
+                .indices
+                .insert(bytemuck::cast_slice(builder.indices()), device, uploader),
+            instance: self.instances.insert(
+                bytemuck::bytes_of(&instance.to_raw()),
+                device,
+                uploader,
+            ),
+            bounding_sphere: Sphere {
+                center: local_sphere.center + position.to_vec(),
+                radius: local_sphere.radius,
+            },
+        };
+        self.entries.insert(pos, entry);
+    }
+
+    pub fn remove(&mut self, pos: ChunkPos) {
+        if let Some(entry) = self.entries.remove(&pos) {
+            self.vertices.free(entry.vertices);
+            self.indices.free(entry.indices);
+            self.instances.free(entry.instance);
+        }
+    }
+
+    /// Keep only the chunks for which `f` returns true, returning how many were dropped
+    pub fn retain(&mut self, mut f: impl FnMut(ChunkPos) -> bool) -> usize {
+        let dropped = self
+            .entries
+            .keys()
+            .copied()
+            .filter(|&pos| !f(pos))
+            .collect::<Vec<_>>();
+        for &pos in &dropped {
+            self.remove(pos);
+        }
+        dropped.len()
+    }
+
+    pub fn contains(&self, pos: ChunkPos) -> bool {
+        self.entries.contains_key(&pos)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn positions(&self) -> impl Iterator<Item = ChunkPos> + '_ {
+        self.entries.keys().copied()
+    }
+
+    pub fn bounding_sphere(&self, pos: ChunkPos) -> Option<Sphere> {
+        self.entries.get(&pos).map(|entry| entry.bounding_sphere)
+    }
+
+    /// The size of the mesh of the chunk at `pos`, counting the bytes of its ranges
+    pub fn mesh_size(&self, pos: ChunkPos) -> Option<MeshSize> {
+        let entry = self.entries.get(&pos)?;
+        let vertices = (entry.vertices.end - entry.vertices.start) as u64;
+        let indices = (entry.indices.end - entry.indices.start) as u64;
+        let instances = (entry.instance.end - entry.instance.start) as u64;
+        Some(MeshSize {
+            vertices,
+            indices,
+            bytes: vertices * self.vertices.stride
+                + indices * self.indices.stride
+                + instances * self.instances.stride,
+        })
+    }
+
+    /// Bytes of GPU memory taken up by the arena's buffers, free ranges included
+    pub fn allocated_bytes(&self) -> u64 {
+        self.vertices.buffer.size() + self.indices.buffer.size() + self.instances.buffer.size()
+    }
+
+    /// Set up the draws of `chunks` for the next `draw`, skipping chunks without faces
+    pub fn prepare(
+        &mut self,
+        chunks: impl IntoIterator<Item = ChunkPos>,
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) {
+        self.draws.clear();
+        for pos in chunks {
+            let Some(entry) = self.entries.get(&pos) else {
+                continue;
+            };
+            if entry.indices.is_empty() {
+                continue;
+            }
+            self.draws.push(DrawIndexedIndirectArgs {
+                index_count: entry.indices.end - entry.indices.start,
+                instance_count: 1,
+                first_index: entry.indices.start,
+                base_vertex: entry.vertices.start as i32,
+                first_instance: entry.instance.start,
+            });
+        }
+
+        let size = (self.draws.len() * std::mem::size_of::<DrawIndexedIndirectArgs>()) as u64;
+        if size > self.indirect_buffer.size() {
+            self.indirect_buffer =
+                Self::create_indirect_buffer(self.draws.len().next_power_of_two(), device);
+        }
+        let bytes = self
+            .draws
+            .iter()
+            .flat_map(|draw| draw.as_bytes().iter().copied())
+            .collect::<Vec<_>>();
+        uploader.write_buffer(&self.indirect_buffer, 0, &bytes, device);
+    }
+
+    /// Draw the chunks passed to the last `prepare`, returning the number of draw calls made
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+        multi_draw: bool,
+    ) -> usize {
+        if self.draws.is_empty() {
+            return 0;
+        }
+        render_pass.set_vertex_buffer(0, self.vertices.buffer.slice(..));
+        render_pass.set_vertex_buffer(1, self.instances.buffer.slice(..));
+        render_pass.set_index_buffer(self.indices.buffer.slice(..), wgpu::IndexFormat::Uint32);
+        render_pass.set_bind_group(0, &self.material.bind_group, &[]);
+        render_pass.set_bind_group(1, camera_bind_group, &[]);
+
+        if multi_draw {
+            render_pass.multi_draw_indexed_indirect(
+                &self.indirect_buffer,
+                0,
+                self.draws.len() as u32,
+            );
+            return 1;
+        }
+        for draw in &self.draws {
+            render_pass.draw_indexed(
+                draw.first_index..draw.first_index + draw.index_count,
+                draw.base_vertex,
+                draw.first_instance..draw.first_instance + 1,
+            );
+        }
+        self.draws.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_first_fit() {
+        let mut allocator = RangeAllocator::new(10);
+        assert_eq!(allocator.allocate(4), Some(0..4));
+        assert_eq!(allocator.allocate(0), Some(0..0));
+        assert_eq!(allocator.allocate(6), Some(4..10));
+        assert_eq!(allocator.allocate(1), None);
+        assert_eq!(allocator.free, []);
+    }
+
+    #[test]
+    fn reuses_freed_ranges() {
+        let mut allocator = RangeAllocator::new(10);
+        let a = allocator.allocate(3).unwrap();
+        allocator.allocate(3).unwrap();
+        allocator.free(a);
+        assert_eq!(allocator.free, [0..3, 6..10]);
+        assert_eq!(allocator.allocate(2), Some(0..2));
+        assert_eq!(allocator.allocate(4), Some(6..10));
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.free[0], 2..3);
+    }
+
+    #[test]
+    fn coalesces_adjacent_free_ranges() {
+        let mut allocator = RangeAllocator::new(9);
+        let [a, b, c] = [3, 3, 3].map(|len| allocator.allocate(len).unwrap());
+        allocator.free(a);
+        allocator.free(c);
+        assert_eq!(allocator.free, [0..3, 6..9]);
+        allocator.free(b);
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.free[0], 0..9);
+        assert_eq!(allocator.allocate(9), Some(0..9));
+    }
+
+    #[test]
+    fn grows_when_full() {
+        let mut allocator = RangeAllocator::new(4);
+        assert_eq!(allocator.allocate(3), Some(0..3));
+        assert_eq!(allocator.allocate(4), None);
+        allocator.grow(8);
+        assert_eq!(allocator.free.len(), 1);
+        assert_eq!(allocator.free[0], 3..8);
+        assert_eq!(allocator.allocate(4), Some(3..7));
+        assert_eq!(allocator.capacity, 8);
+    }
+}
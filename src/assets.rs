@@ -1,6 +1,14 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, path::Path, sync::Arc};
 
-use crate::mesh::Material;
+use cgmath::Point2;
+use image::{Rgba, RgbaImage};
+use wgpu::naga::FastHashMap;
+
+use crate::{
+    game::atlas::Atlas,
+    mesh::Material,
+    texture::{TextureFiltering, TextureOptions},
+};
 
 pub struct AssetManager {
     device: Arc<wgpu::Device>,
@@ -23,12 +31,131 @@ impl AssetManager {
         }
     }
 
-    pub fn load_material(&mut self, path: impl Into<String>) -> Option<Arc<Material>> {
-        let path = path.into();
-        let data = std::fs::read(&path).ok()?;
-        let material = Material::new(&data, &self.device, &self.queue, &self.layout);
+    /// Pack every texture in `dir` into an atlas
+    pub fn load_atlas(
+        &mut self,
+        dir: impl Into<String>,
+        cell_size: u32,
+        filtering: TextureFiltering,
+    ) -> anyhow::Result<Atlas> {
+        let dir = dir.into();
+        let mut builder = AtlasBuilder::new(cell_size);
+        builder.add_dir(&dir)?;
+        let (image, cells) = builder.build();
+
+        // Mipmaps stop at one pixel per cell, smaller levels would blend neighbouring cells
+        let options = TextureOptions {
+            filtering,
+            mip_levels: cell_size.ilog2() + 1,
+        };
+        let material = Material::from_image(
+            &image.into(),
+            options,
+            &self.device,
+            &self.queue,
+            &self.layout,
+        );
         let material = Arc::new(material);
-        self.materials.insert(path, material.clone());
-        Some(material)
+        self.materials.insert(dir, material.clone());
+        Ok(Atlas::new(material, cell_size as usize, cells))
+    }
+}
+
+/// Packs individual textures into a single atlas image.
+///
+/// Every texture is one cell high and a whole number of cells wide. The cells of wider
+/// textures, like animation frames or connected texture sets, are kept next to each other on
+/// the same row.
+pub struct AtlasBuilder {
+    cell_size: u32,
+    textures: Vec<(String, RgbaImage)>,
+}
+
+impl AtlasBuilder {
+    /// Width of the atlas in cells
+    const ROW_CELLS: u32 = 16;
+
+    /// A builder holding only the missing texture
+    pub fn new(cell_size: u32) -> Self {
+        let missing = RgbaImage::from_fn(cell_size, cell_size, |x, y| {
+            if (x < cell_size / 2) == (y < cell_size / 2) {
+                Rgba([255, 0, 255, 255])
+            } else {
+                Rgba([0, 0, 0, 255])
+            }
+        });
+        Self {
+            cell_size,
+            textures: vec![(Atlas::MISSING.to_string(), missing)],
+        }
+    }
+
+    /// Add every PNG file in `dir`, named after the file without its extension
+    pub fn add_dir(&mut self, dir: impl AsRef<Path>) -> anyhow::Result<()> {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "png") {
+                let name = path.file_stem().unwrap().to_string_lossy().into_owned();
+                let image = image::open(&path)?.to_rgba8();
+                self.add(name, image)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, name: impl Into<String>, image: RgbaImage) -> anyhow::Result<()> {
+        let name = name.into();
+        let (width, height) = image.dimensions();
+        anyhow::ensure!(
+            height == self.cell_size
+                && width > 0
+                && width % self.cell_size == 0
+                && width <= self.cell_size * Self::ROW_CELLS,
+            "texture {name} is {width}x{height}, but must be {} pixels high and at most {} cells wide",
+            self.cell_size,
+            Self::ROW_CELLS,
+        );
+        self.textures.push((name, image));
+        Ok(())
+    }
+
+    /// Lay out the textures row by row, returning the atlas image and the first cell of every
+    /// texture
+    pub fn build(mut self) -> (RgbaImage, FastHashMap<String, Point2<usize>>) {
+        // Widest first so rows are filled up, then by name so the layout doesn't depend on the
+        // order the files were found in
+        self.textures.sort_by(|(a_name, a), (b_name, b)| {
+            b.width().cmp(&a.width()).then_with(|| a_name.cmp(b_name))
+        });
+
+        let mut cells = FastHashMap::default();
+        let mut placed = vec![];
+        let (mut x, mut y) = (0, 0);
+        for (name, image) in self.textures {
+            let width = image.width() / self.cell_size;
+            if x + width > Self::ROW_CELLS {
+                x = 0;
+                y += 1;
+            }
+            cells.insert(name, Point2::new(x as usize, y as usize));
+            placed.push((x, y, image));
+            x += width;
+        }
+
+        let rows = if x == 0 { y } else { y + 1 };
+        let mut atlas = RgbaImage::new(
+            Self::ROW_CELLS * self.cell_size,
+            rows.max(1) * self.cell_size,
+        );
+        for (x, y, image) in placed {
+            image::imageops::replace(
+                &mut atlas,
+                &image,
+                (x * self.cell_size) as i64,
+                (y * self.cell_size) as i64,
+            );
+        }
+
+        (atlas, cells)
     }
 }
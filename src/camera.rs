@@ -22,9 +22,13 @@ pub struct Camera {
     pub position: Point3<f32>,
     pub yaw: Rad<f32>,
     pub pitch: Rad<f32>,
+    /// Vertical field of view, copied to the projection every frame
+    pub fovy: Rad<f32>,
 }
 
 impl Camera {
+    pub const DEFAULT_FOV: Deg<f32> = Deg(45.0);
+
     pub fn new(
         position: impl Into<Point3<f32>>,
         yaw: impl Into<Rad<f32>>,
@@ -34,6 +38,7 @@ impl Camera {
             position: position.into(),
             yaw: yaw.into(),
             pitch: pitch.into(),
+            fovy: Self::DEFAULT_FOV.into(),
         }
     }
 
@@ -121,6 +126,10 @@ impl Projection {
         self.aspect = width as f32 / height as f32;
     }
 
+    pub fn set_fovy(&mut self, fovy: impl Into<Rad<f32>>) {
+        self.fovy = fovy.into();
+    }
+
     pub fn calc_matrix(&self) -> Matrix4<f32> {
         OPENGL_TO_WGPU_MATRIX * perspective(self.fovy, self.aspect, self.znear, self.zfar)
     }
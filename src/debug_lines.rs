@@ -0,0 +1,175 @@
+use cgmath::{Point3, Vector3};
+
+use crate::{mesh::Vertex, texture::Texture, upload::Uploader};
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LineVertex {
+    pub position: [f32; 3],
+    pub color: [f32; 3],
+}
+
+impl Vertex for LineVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<LineVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 3]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+            ],
+        }
+    }
+}
+
+/// Draws colored lines in world space, e.g. chunk borders for the debug overlay.
+///
+/// Lines are collected from scratch every frame and drawn depth tested on top of the world.
+pub struct DebugLines {
+    pipeline: wgpu::RenderPipeline,
+    vertices: Vec<LineVertex>,
+    buffer: wgpu::Buffer,
+    /// Vertices written to `buffer` by the last `upload`
+    uploaded: u32,
+}
+
+impl DebugLines {
+    const INITIAL_CAPACITY: u64 = 1024;
+
+    pub fn new(
+        device: &wgpu::Device,
+        format: wgpu::TextureFormat,
+        sample_count: u32,
+        camera_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Debug Line Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("debug_lines.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Debug Line Pipeline Layout"),
+            bind_group_layouts: &[camera_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Debug Line Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[LineVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::REPLACE),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState {
+                topology: wgpu::PrimitiveTopology::LineList,
+                ..Default::default()
+            },
+            depth_stencil: Some(wgpu::DepthStencilState {
+                format: Texture::DEPTH_FORMAT,
+                depth_write_enabled: false,
+                depth_compare: wgpu::CompareFunction::LessEqual,
+                stencil: wgpu::StencilState::default(),
+                bias: wgpu::DepthBiasState::default(),
+            }),
+            multisample: wgpu::MultisampleState {
+                count: sample_count,
+                ..Default::default()
+            },
+            multiview: None,
+        });
+
+        Self {
+            pipeline,
+            vertices: vec![],
+            buffer: Self::create_buffer(device, Self::INITIAL_CAPACITY),
+            uploaded: 0,
+        }
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Debug Line Buffer"),
+            size: capacity * std::mem::size_of::<LineVertex>() as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    pub fn line(&mut self, from: Point3<f32>, to: Point3<f32>, color: [f32; 3]) {
+        self.vertices.extend([
+            LineVertex {
+                position: from.into(),
+                color,
+            },
+            LineVertex {
+                position: to.into(),
+                color,
+            },
+        ]);
+    }
+
+    /// The twelve edges of an axis-aligned box
+    pub fn cuboid(&mut self, min: Point3<f32>, size: Vector3<f32>, color: [f32; 3]) {
+        let corner = |x: bool, y: bool, z: bool| {
+            min + Vector3::new(
+                if x { size.x } else { 0.0 },
+                if y { size.y } else { 0.0 },
+                if z { size.z } else { 0.0 },
+            )
+        };
+        for a in [false, true] {
+            for b in [false, true] {
+                self.line(corner(false, a, b), corner(true, a, b), color);
+                self.line(corner(a, false, b), corner(a, true, b), color);
+                self.line(corner(a, b, false), corner(a, b, true), color);
+            }
+        }
+    }
+
+    /// Write this frame's lines to the GPU and start collecting the next frame's
+    pub fn upload(&mut self, device: &wgpu::Device, uploader: &mut Uploader) {
+        let capacity = self.buffer.size() / std::mem::size_of::<LineVertex>() as u64;
+        if self.vertices.len() as u64 > capacity {
+            self.buffer =
+                Self::create_buffer(device, (self.vertices.len() as u64).next_power_of_two());
+        }
+        uploader.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&self.vertices),
+            device,
+        );
+        self.uploaded = self.vertices.len() as u32;
+        self.vertices.clear();
+    }
+
+    pub fn draw<'a>(
+        &'a self,
+        render_pass: &mut wgpu::RenderPass<'a>,
+        camera_bind_group: &'a wgpu::BindGroup,
+    ) {
+        if self.uploaded == 0 {
+            return;
+        }
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, camera_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.draw(0..self.uploaded, 0..1);
+    }
+}
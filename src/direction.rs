@@ -0,0 +1,210 @@
+use std::ops::Neg;
+
+use cgmath::{One, Point2, Point3, Vector3, Zero};
+use serde::{Deserialize, Serialize};
+
+/// One of the six faces of a block, or the axis-aligned direction it faces.
+///
+/// The horizontal directions are in clockwise order as seen from above, and every direction has
+/// a compact index in `0..6` for storing faces in block metadata or lookup tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[repr(u8)]
+pub enum Direction {
+    /// +Z
+    North = 0,
+    /// -X
+    East = 1,
+    /// -Z
+    South = 2,
+    /// +X
+    West = 3,
+    /// +Y
+    Up = 4,
+    /// -Y
+    Down = 5,
+}
+
+impl Direction {
+    /// Every direction, ordered by index
+    pub const ALL: [Direction; 6] = [
+        Direction::North,
+        Direction::East,
+        Direction::South,
+        Direction::West,
+        Direction::Up,
+        Direction::Down,
+    ];
+
+    /// The directions along the ground, clockwise as seen from above
+    pub const HORIZONTAL: [Direction; 4] = [
+        Direction::North,
+        Direction::East,
+        Direction::South,
+        Direction::West,
+    ];
+
+    pub fn iter() -> impl Iterator<Item = Direction> {
+        Self::ALL.into_iter()
+    }
+
+    pub fn index(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Self> {
+        Self::ALL.get(index as usize).copied()
+    }
+
+    /// The direction a unit vector along one of the axes points in
+    pub fn from_normal(normal: Vector3<isize>) -> Option<Self> {
+        Self::iter().find(|dir| dir.normal() == normal)
+    }
+
+    pub fn is_horizontal(self) -> bool {
+        !matches!(self, Direction::Up | Direction::Down)
+    }
+
+    pub fn normal<T: Zero<Output = T> + One + Neg<Output = T> + Clone>(&self) -> Vector3<T> {
+        match self {
+            Direction::North => [T::zero(), T::zero(), T::one()],
+            Direction::East => [-T::one(), T::zero(), T::zero()],
+            Direction::South => [T::zero(), T::zero(), -T::one()],
+            Direction::West => [T::one(), T::zero(), T::zero()],
+            Direction::Up => [T::zero(), T::one(), T::zero()],
+            Direction::Down => [T::zero(), -T::one(), T::zero()],
+        }
+        .into()
+    }
+
+    pub fn opposite(self) -> Self {
+        match self {
+            Direction::North => Self::South,
+            Direction::East => Self::West,
+            Direction::South => Self::North,
+            Direction::West => Self::East,
+            Direction::Up => Self::Down,
+            Direction::Down => Self::Up,
+        }
+    }
+
+    /// The next horizontal direction clockwise as seen from above. Up and down stay the same.
+    pub fn rotate_cw(self) -> Self {
+        match self {
+            Direction::North => Direction::East,
+            Direction::East => Direction::South,
+            Direction::South => Direction::West,
+            Direction::West => Direction::North,
+            Direction::Up | Direction::Down => self,
+        }
+    }
+
+    /// The next horizontal direction counterclockwise as seen from above. Up and down stay the
+    /// same.
+    pub fn rotate_ccw(self) -> Self {
+        self.rotate_y(3)
+    }
+
+    /// Rotate clockwise around the vertical axis in quarter turns
+    pub fn rotate_y(self, quarter_turns: u8) -> Self {
+        (0..quarter_turns % 4).fold(self, |dir, _| dir.rotate_cw())
+    }
+
+    /// The coordinate of the blocks at this side of a chunk
+    pub fn chunk_limit(self) -> usize {
+        match self {
+            Direction::North => 15,
+            Direction::East => 0,
+            Direction::South => 0,
+            Direction::West => 15,
+            Direction::Up => 15,
+            Direction::Down => 0,
+        }
+    }
+
+    pub fn axle(self) -> Vector3Accessor {
+        match self {
+            Direction::North => Vector3Accessor::Z,
+            Direction::East => Vector3Accessor::X,
+            Direction::South => Vector3Accessor::Z,
+            Direction::West => Vector3Accessor::X,
+            Direction::Up => Vector3Accessor::Y,
+            Direction::Down => Vector3Accessor::Y,
+        }
+    }
+
+    pub fn on_plane<T: Zero + Neg<Output = T> + Clone>(self, coords: Point2<T>) -> Point3<T> {
+        match self {
+            Direction::North => [coords.x, coords.y, T::zero()],
+            Direction::East => [T::zero(), coords.y, coords.x],
+            Direction::South => [-coords.x, coords.y, T::zero()],
+            Direction::West => [T::zero(), coords.y, -coords.x],
+            Direction::Up => [-coords.x, T::zero(), coords.y],
+            Direction::Down => [coords.x, T::zero(), coords.y],
+        }
+        .into()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Vector3Accessor {
+    X,
+    Y,
+    Z,
+}
+
+impl Vector3Accessor {
+    pub fn of<T: D3Accessible>(self, vec: T) -> T::Element {
+        vec.get(self)
+    }
+
+    pub fn set<T: D3Accessible>(self, vec: &mut T, value: T::Element) {
+        vec.set(self, value);
+    }
+}
+
+pub trait D3Accessible {
+    type Element;
+
+    fn get(self, accessor: Vector3Accessor) -> Self::Element;
+    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element);
+}
+
+impl<T> D3Accessible for Vector3<T> {
+    type Element = T;
+
+    fn get(self, accessor: Vector3Accessor) -> Self::Element {
+        match accessor {
+            Vector3Accessor::X => self.x,
+            Vector3Accessor::Y => self.y,
+            Vector3Accessor::Z => self.z,
+        }
+    }
+
+    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element) {
+        match accessor {
+            Vector3Accessor::X => self.x = value,
+            Vector3Accessor::Y => self.y = value,
+            Vector3Accessor::Z => self.z = value,
+        }
+    }
+}
+
+impl<T> D3Accessible for Point3<T> {
+    type Element = T;
+
+    fn get(self, accessor: Vector3Accessor) -> Self::Element {
+        match accessor {
+            Vector3Accessor::X => self.x,
+            Vector3Accessor::Y => self.y,
+            Vector3Accessor::Z => self.z,
+        }
+    }
+
+    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element) {
+        match accessor {
+            Vector3Accessor::X => self.x = value,
+            Vector3Accessor::Y => self.y = value,
+            Vector3Accessor::Z => self.z = value,
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! How the window is shown: windowed, or fullscreen on one of the monitors.
+//!
+//! The settings are applied by the event loop whenever they change, so toggling fullscreen only
+//! has to change them.
+
+use serde::{Deserialize, Serialize};
+use winit::{
+    dpi::PhysicalSize,
+    monitor::{MonitorHandle, VideoMode},
+    window::{Fullscreen, Window},
+};
+
+/// The kind of fullscreen the window switches to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum FullscreenMode {
+    /// A borderless window covering the monitor, quick to switch in and out of
+    #[default]
+    Borderless,
+    /// Takes the monitor over, switching it to the chosen resolution
+    Exclusive,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WindowSettings {
+    pub fullscreen: bool,
+    pub fullscreen_mode: FullscreenMode,
+    /// Index of the monitor to go fullscreen on, in the order the system lists them. The
+    /// window's current monitor is used if this is not set or not connected.
+    pub monitor: Option<usize>,
+    /// Resolution of exclusive fullscreen, the monitor's highest if not set or not supported
+    pub resolution: Option<[u32; 2]>,
+    /// Size of the window when it is not fullscreen, as it was last left
+    pub windowed_size: Option<[u32; 2]>,
+}
+
+impl WindowSettings {
+    /// Switch `window` to the mode these settings describe
+    pub fn apply(&self, window: &Window) {
+        let fullscreen = self.fullscreen(window);
+        let windowed = fullscreen.is_none();
+        window.set_fullscreen(fullscreen);
+        if let Some([width, height]) = self.windowed_size.filter(|_| windowed) {
+            // The size arrives as a `Resized` event if it changes
+            let _ = window.request_inner_size(PhysicalSize::new(width, height));
+        }
+    }
+
+    fn fullscreen(&self, window: &Window) -> Option<Fullscreen> {
+        if !self.fullscreen {
+            return None;
+        }
+        let monitor = self.monitor(window);
+        match self.fullscreen_mode {
+            FullscreenMode::Borderless => Some(Fullscreen::Borderless(monitor)),
+            FullscreenMode::Exclusive => {
+                let mode = monitor
+                    .as_ref()
+                    .and_then(|monitor| self.video_mode(monitor));
+                match mode {
+                    Some(mode) => Some(Fullscreen::Exclusive(mode)),
+                    None => {
+                        log::warn!(
+                            "No video mode to go fullscreen with, using a borderless window"
+                        );
+                        Some(Fullscreen::Borderless(monitor))
+                    }
+                }
+            }
+        }
+    }
+
+    fn monitor(&self, window: &Window) -> Option<MonitorHandle> {
+        let chosen = self.monitor.and_then(|index| {
+            let monitor = window.available_monitors().nth(index);
+            if monitor.is_none() {
+                log::warn!("Monitor {index} is not connected, using the current one");
+            }
+            monitor
+        });
+        chosen.or_else(|| window.current_monitor())
+    }
+
+    /// The monitor's video mode at the chosen resolution with the highest refresh rate, or its
+    /// largest one
+    fn video_mode(&self, monitor: &MonitorHandle) -> Option<VideoMode> {
+        let best = |modes: &mut dyn Iterator<Item = VideoMode>| {
+            modes.max_by_key(|mode| {
+                let size = mode.size();
+                (
+                    size.width * size.height,
+                    mode.refresh_rate_millihertz(),
+                    mode.bit_depth(),
+                )
+            })
+        };
+        if let Some([width, height]) = self.resolution {
+            let size = PhysicalSize::new(width, height);
+            let mut matching = monitor.video_modes().filter(|mode| mode.size() == size);
+            if let Some(mode) = best(&mut matching) {
+                return Some(mode);
+            }
+            log::warn!(
+                "The monitor does not support {width}x{height}, using its highest resolution"
+            );
+        }
+        best(&mut monitor.video_modes())
+    }
+}
@@ -0,0 +1,278 @@
+use std::{
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use cgmath::{Point3, Vector3};
+use serde_json::json;
+
+use crate::{
+    assets::AtlasBuilder,
+    game::{
+        atlas::Atlas,
+        block::BlockRegistry,
+        chunk::{BlockPos, ChunkRelativeBlockPos},
+        world::World,
+    },
+    mesh::{MeshBuilder, MeshVertex},
+    meshifier::ChunkMeshifier,
+};
+
+/// Regions with more blocks than this are refused, as the file would be unreasonably large
+const MAX_BLOCKS: usize = 256 * 256 * 256;
+
+/// Where exports are written, one directory per export
+const EXPORT_DIR: &str = "exports";
+
+/// Geometry of one material, with positions relative to the region's lowest corner
+#[derive(Default)]
+struct Primitive {
+    vertices: Vec<MeshVertex>,
+    indices: Vec<u32>,
+}
+
+impl Primitive {
+    fn extend(&mut self, builder: &MeshBuilder, offset: Vector3<f32>) {
+        let first = self.vertices.len() as u32;
+        self.vertices
+            .extend(builder.vertices().iter().map(|vertex| MeshVertex {
+                position: (Vector3::from(vertex.position) + offset).into(),
+                ..*vertex
+            }));
+        self.indices
+            .extend(builder.indices().iter().map(|index| index + first));
+    }
+}
+
+/// Export the blocks between two corners, both included, as a glTF file that other programs can
+/// render. The faces are built the same way as chunk meshes, with the block textures in a copy
+/// of the atlas and ambient occlusion baked into vertex colors.
+///
+/// Blocks outside the region are left out, so faces on its border are exported as if it was
+/// surrounded by air. Returns the path of the written `.gltf` file.
+pub fn export_region(
+    world: &World,
+    corners: [BlockPos; 2],
+    atlas: &Atlas,
+    block_registry: &BlockRegistry,
+    enable_ao: bool,
+    smooth_lighting: bool,
+) -> anyhow::Result<PathBuf> {
+    let [a, b] = corners.map(Point3::from);
+    let min = Point3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+    let max = Point3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+    let size = max - min + Vector3::new(1, 1, 1);
+    let blocks = (size.x * size.y * size.z) as usize;
+    anyhow::ensure!(
+        blocks <= MAX_BLOCKS,
+        "region has {blocks} blocks, at most {MAX_BLOCKS} can be exported"
+    );
+
+    let mut region = World::new();
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                let pos = BlockPos::from(Point3::new(x, y, z));
+                let block = world
+                    .block(pos)
+                    .ok_or_else(|| anyhow::anyhow!("the region is not loaded at {pos:?}"))?;
+                if !block_registry.get(block.id).unwrap().invisible {
+                    region.place_block(*block, pos);
+                }
+            }
+        }
+    }
+
+    let mut meshifier = ChunkMeshifier::new();
+    meshifier.enable_ao = enable_ao;
+    meshifier.smooth_lighting = smooth_lighting;
+    let mut opaque = Primitive::default();
+    let mut translucent = Primitive::default();
+    for chunk in region.chunks.values() {
+        let origin = Point3::from(chunk.pos + ChunkRelativeBlockPos::from(Point3::new(0, 0, 0)));
+        let offset = (origin - min).cast().unwrap();
+        let (opaque_builder, translucent_builder) =
+            meshifier.build_builders(&region, chunk, atlas, block_registry);
+        opaque.extend(&opaque_builder, offset);
+        translucent.extend(&translucent_builder, offset);
+    }
+
+    let seconds = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let dir = Path::new(EXPORT_DIR).join(format!("region-{seconds}"));
+    std::fs::create_dir_all(&dir)?;
+
+    // Packed again from the same files, which gives the same layout as the atlas in use
+    let mut atlas_builder = AtlasBuilder::new(atlas.cell_size as u32);
+    atlas_builder.add_dir("assets/blocks")?;
+    atlas_builder.build().0.save(dir.join("atlas.png"))?;
+
+    let mut gltf = Gltf::default();
+    let mut primitives = vec![];
+    for (primitive, material) in [(&opaque, 0), (&translucent, 1)] {
+        if !primitive.indices.is_empty() {
+            primitives.push(gltf.primitive(primitive, material));
+        }
+    }
+    let document = json!({
+        "asset": { "version": "2.0", "generator": "voxels" },
+        "scene": 0,
+        "scenes": [{ "nodes": [0] }],
+        "nodes": [{ "mesh": 0, "name": "region" }],
+        "meshes": [{ "primitives": primitives }],
+        "materials": [
+            // Cut-out textures like leaves have fully transparent pixels, so the opaque
+            // geometry needs alpha testing too
+            {
+                "name": "opaque",
+                "pbrMetallicRoughness": Gltf::block_pbr(),
+                "alphaMode": "MASK",
+            },
+            {
+                "name": "translucent",
+                "pbrMetallicRoughness": Gltf::block_pbr(),
+                "alphaMode": "BLEND",
+            },
+        ],
+        "textures": [{ "source": 0, "sampler": 0 }],
+        "images": [{ "uri": "atlas.png" }],
+        // Nearest filtering, to keep the pixelated look
+        "samplers": [{ "magFilter": 9728, "minFilter": 9728 }],
+        "buffers": [{ "uri": "region.bin", "byteLength": gltf.buffer.len() }],
+        "bufferViews": gltf.buffer_views,
+        "accessors": gltf.accessors,
+    });
+
+    std::fs::write(dir.join("region.bin"), &gltf.buffer)?;
+    let path = dir.join("region.gltf");
+    std::fs::write(&path, serde_json::to_string_pretty(&document)?)?;
+    Ok(path)
+}
+
+/// The binary buffer of a glTF file and the views and accessors describing it
+#[derive(Default)]
+struct Gltf {
+    buffer: Vec<u8>,
+    buffer_views: Vec<serde_json::Value>,
+    accessors: Vec<serde_json::Value>,
+}
+
+impl Gltf {
+    const FLOAT: u32 = 5126;
+    const UNSIGNED_INT: u32 = 5125;
+    const ARRAY_BUFFER: u32 = 34962;
+    const ELEMENT_ARRAY_BUFFER: u32 = 34963;
+
+    /// Blocks take all their color from the atlas, and aren't shiny
+    fn block_pbr() -> serde_json::Value {
+        json!({
+            "baseColorTexture": { "index": 0 },
+            "metallicFactor": 0.0,
+            "roughnessFactor": 1.0,
+        })
+    }
+
+    fn primitive(&mut self, primitive: &Primitive, material: usize) -> serde_json::Value {
+        let vertices = &primitive.vertices;
+        let positions = vertices.iter().map(|v| v.position).collect::<Vec<_>>();
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for position in &positions {
+            for i in 0..3 {
+                min[i] = min[i].min(position[i]);
+                max[i] = max[i].max(position[i]);
+            }
+        }
+
+        let position = self.accessor(
+            bytemuck::cast_slice(&positions),
+            vertices.len(),
+            "VEC3",
+            Self::FLOAT,
+            Self::ARRAY_BUFFER,
+            // Required for positions
+            Some((min, max)),
+        );
+        let normals = vertices.iter().map(|v| v.normal).collect::<Vec<_>>();
+        let normal = self.accessor(
+            bytemuck::cast_slice(&normals),
+            vertices.len(),
+            "VEC3",
+            Self::FLOAT,
+            Self::ARRAY_BUFFER,
+            None,
+        );
+        let tex_coords = vertices.iter().map(|v| v.tex_coords).collect::<Vec<_>>();
+        let tex_coord = self.accessor(
+            bytemuck::cast_slice(&tex_coords),
+            vertices.len(),
+            "VEC2",
+            Self::FLOAT,
+            Self::ARRAY_BUFFER,
+            None,
+        );
+        // Darkened the same way the shader does it
+        let colors = vertices
+            .iter()
+            .map(|v| [1.0 - v.ambient_occlusion; 3])
+            .collect::<Vec<_>>();
+        let color = self.accessor(
+            bytemuck::cast_slice(&colors),
+            vertices.len(),
+            "VEC3",
+            Self::FLOAT,
+            Self::ARRAY_BUFFER,
+            None,
+        );
+        let indices = self.accessor(
+            bytemuck::cast_slice(&primitive.indices),
+            primitive.indices.len(),
+            "SCALAR",
+            Self::UNSIGNED_INT,
+            Self::ELEMENT_ARRAY_BUFFER,
+            None,
+        );
+
+        json!({
+            "attributes": {
+                "POSITION": position,
+                "NORMAL": normal,
+                "TEXCOORD_0": tex_coord,
+                "COLOR_0": color,
+            },
+            "indices": indices,
+            "material": material,
+        })
+    }
+
+    /// Append `data` to the buffer, returning the index of an accessor for it
+    fn accessor(
+        &mut self,
+        data: &[u8],
+        count: usize,
+        kind: &str,
+        component_type: u32,
+        target: u32,
+        bounds: Option<([f32; 3], [f32; 3])>,
+    ) -> usize {
+        self.buffer_views.push(json!({
+            "buffer": 0,
+            "byteOffset": self.buffer.len(),
+            "byteLength": data.len(),
+            "target": target,
+        }));
+        self.buffer.extend_from_slice(data);
+
+        let mut accessor = json!({
+            "bufferView": self.buffer_views.len() - 1,
+            "componentType": component_type,
+            "count": count,
+            "type": kind,
+        });
+        if let Some((min, max)) = bounds {
+            accessor["min"] = json!(min);
+            accessor["max"] = json!(max);
+        }
+        self.accessors.push(accessor);
+        self.accessors.len() - 1
+    }
+}
@@ -0,0 +1,60 @@
+use cgmath::Point3;
+
+/// Color of the sky, which fog fades distant geometry into
+pub const SKY_COLOR: [f32; 3] = [0.1, 0.2, 0.3];
+
+/// How geometry fades into the sky with distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Fog {
+    /// Distance at which fog starts
+    pub start: f32,
+    /// Distance at which geometry is completely hidden by fog
+    pub end: f32,
+    /// How quickly fog thickens below `height_base`, 0.0 for no height fog
+    pub height_density: f32,
+    /// Height below which fog gathers, filling valleys
+    pub height_base: f32,
+}
+
+impl Fog {
+    /// Fog that never covers anything
+    pub const NONE: Fog = Fog {
+        start: f32::MAX / 2.0,
+        end: f32::MAX,
+        height_density: 0.0,
+        height_base: 0.0,
+    };
+}
+
+/// Uniform holding the fog of the current frame
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct FogUniform {
+    color: [f32; 3],
+    start: f32,
+    camera_position: [f32; 3],
+    end: f32,
+    height_density: f32,
+    height_base: f32,
+    _padding: [f32; 2],
+}
+
+impl FogUniform {
+    pub fn new() -> Self {
+        let mut uniform: Self = bytemuck::Zeroable::zeroed();
+        uniform.update(Fog::NONE, Point3::new(0.0, 0.0, 0.0));
+        uniform
+    }
+
+    pub fn update(&mut self, fog: Fog, camera_position: Point3<f32>) {
+        *self = Self {
+            color: SKY_COLOR,
+            start: fog.start,
+            camera_position: camera_position.into(),
+            end: fog.end,
+            height_density: fog.height_density,
+            height_base: fog.height_base,
+            _padding: [0.0; 2],
+        };
+    }
+}
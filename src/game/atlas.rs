@@ -1,22 +1,46 @@
 use std::sync::Arc;
 
 use cgmath::Point2;
+use wgpu::naga::FastHashMap;
 
 use crate::{mesh::Material, texture::Texture};
 
 pub struct Atlas {
     pub material: Arc<Material>,
     pub cell_size: usize,
+    /// First cell of every texture, by name
+    cells: FastHashMap<String, Point2<usize>>,
 }
 
 impl Atlas {
-    pub fn new(material: Arc<Material>, cell_size: usize) -> Self {
+    /// Texture used in place of ones that don't exist
+    pub const MISSING: &'static str = "missing";
+
+    pub fn new(
+        material: Arc<Material>,
+        cell_size: usize,
+        cells: FastHashMap<String, Point2<usize>>,
+    ) -> Self {
         Self {
             material,
             cell_size,
+            cells,
         }
     }
 
+    pub fn contains(&self, name: &str) -> bool {
+        self.cells.contains_key(name)
+    }
+
+    /// The first cell of the texture called `name`, or of the missing texture if there is none
+    pub fn cell(&self, name: &str) -> Point2<usize> {
+        self.cells
+            .get(name)
+            .or_else(|| self.cells.get(Self::MISSING))
+            .copied()
+            .unwrap_or(Point2::new(0, 0))
+    }
+
     pub fn uv(&self, coords: impl Into<Point2<usize>>) -> [Point2<f32>; 4] {
         let w = self.material.diffuse_texture.texture.width() as usize;
         let h = self.material.diffuse_texture.texture.height() as usize;
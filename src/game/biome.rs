@@ -0,0 +1,60 @@
+use serde::{Deserialize, Serialize};
+
+use super::block::BlockId;
+
+pub const DIRT: BlockId = BlockId(1);
+pub const STONE: BlockId = BlockId(2);
+pub const SAND: BlockId = BlockId(12);
+
+/// The climate of an area of the world, deciding what its terrain looks like
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Biome {
+    Plains,
+    Forest,
+    Desert,
+    Mountains,
+}
+
+impl Biome {
+    /// Pick the biome for a climate, both values being noise in about `-1.0..=1.0`
+    pub fn from_climate(temperature: f64, humidity: f64) -> Self {
+        if temperature < -0.3 {
+            Biome::Mountains
+        } else if temperature > 0.4 && humidity < 0.0 {
+            Biome::Desert
+        } else if humidity > 0.2 {
+            Biome::Forest
+        } else {
+            Biome::Plains
+        }
+    }
+
+    /// The block covering the terrain, a few blocks deep
+    pub fn surface_block(self) -> BlockId {
+        match self {
+            Biome::Plains | Biome::Forest => DIRT,
+            Biome::Desert => SAND,
+            Biome::Mountains => STONE,
+        }
+    }
+
+    /// How far the terrain rises above and sinks below sea level
+    pub fn amplitude(self) -> f64 {
+        match self {
+            Biome::Plains => 6.0,
+            Biome::Forest => 8.0,
+            Biome::Desert => 4.0,
+            Biome::Mountains => 28.0,
+        }
+    }
+
+    /// Chance of a structure at each spot worldgen considers for one
+    pub fn structure_chance(self) -> f64 {
+        match self {
+            Biome::Plains => 0.1,
+            Biome::Forest => 0.8,
+            Biome::Desert => 0.0,
+            Biome::Mountains => 0.3,
+        }
+    }
+}
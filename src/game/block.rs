@@ -1,18 +1,27 @@
 use std::sync::Arc;
 
 use bevy_ecs::system::Resource;
-use cgmath::Point2;
+use cgmath::{InnerSpace, Point3, Vector3};
 use serde::{Deserialize, Serialize};
+use wgpu::naga::FastHashMap;
 
-use crate::mesh::Material;
+use crate::{direction::Direction, mesh::Material};
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
-pub struct BlockId(pub u8);
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+use super::{
+    biome,
+    chunk::BlockPos,
+    ore::{self, OreRule},
+    particles::ParticleKind,
+    signal, structure,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct BlockId(pub u16);
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockMetadata(pub u8);
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Block {
     pub id: BlockId,
     pub metadata: BlockMetadata,
@@ -30,33 +39,648 @@ impl Default for Block {
 pub trait BlockInfo {}
 
 pub struct BlockAttributes {
+    /// Stable, namespaced identifier like `voxels:dirt`. Unlike the numeric id, this is what
+    /// saves refer to blocks by.
+    pub name: &'static str,
     pub transparent: bool,
     pub invisible: bool,
-    pub uv_coords: Point2<usize>,
+    /// Drawn with alpha blending after all opaque geometry
+    pub translucent: bool,
+    /// Can be swum in, and is neither collided with nor targeted by raycasts
+    pub liquid: bool,
+    /// Name of the texture in the atlas, the file name in `assets/blocks` without `.png`
+    pub texture: &'static str,
+    /// Alternative textures, one of which is picked per block position instead of `texture`
+    pub texture_variants: Vec<&'static str>,
+    /// Radius in blocks of the light this block gives off, 0.0 if it doesn't emit light
+    pub light_emission: f32,
+    /// Cycle through the cells of `texture`, which has one cell per frame
+    pub animation: Option<TextureAnimation>,
+    /// Faces join up with neighbouring blocks of the same type. `texture` then has 16 cells,
+    /// indexed by which neighbours are the same block, see `ConnectedTexture`
+    pub connected_texture: bool,
+    /// Particles that drift down from the underside of this block
+    pub ambient_particle: Option<ParticleKind>,
+    /// Texture used instead of `texture` while the block carries a signal, see `signal`
+    pub powered_texture: Option<&'static str>,
+    /// The boxes the block is made of, which are drawn, collided with and targeted by raycasts
+    pub shape: BlockShape,
+    /// Generated in veins underground, see `ore`
+    pub ore: Option<OreRule>,
+}
+
+/// The geometry of a block. Blocks that don't fill their whole cell should be `transparent`, so
+/// the faces of their neighbours are built wherever the block doesn't cover them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockShape {
+    #[default]
+    Full,
+    /// The lower half of the cell
+    SlabBottom,
+    /// The upper half of the cell
+    SlabTop,
+    /// A bottom slab with a step on top, on the side of the horizontal direction in the low two
+    /// bits of the metadata, see `Direction::HORIZONTAL`
+    Stairs,
+}
+
+impl BlockShape {
+    const SLAB_BOTTOM: Aabb = Aabb::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 0.5, 1.0));
+    const SLAB_TOP: Aabb = Aabb::new(Point3::new(0.0, 0.5, 0.0), Point3::new(1.0, 1.0, 1.0));
+    /// Indexed like `Direction::HORIZONTAL`
+    const STAIRS: [[Aabb; 2]; 4] = [
+        [
+            Self::SLAB_BOTTOM,
+            Aabb::new(Point3::new(0.0, 0.5, 0.5), Point3::new(1.0, 1.0, 1.0)),
+        ],
+        [
+            Self::SLAB_BOTTOM,
+            Aabb::new(Point3::new(0.0, 0.5, 0.0), Point3::new(0.5, 1.0, 1.0)),
+        ],
+        [
+            Self::SLAB_BOTTOM,
+            Aabb::new(Point3::new(0.0, 0.5, 0.0), Point3::new(1.0, 1.0, 0.5)),
+        ],
+        [
+            Self::SLAB_BOTTOM,
+            Aabb::new(Point3::new(0.5, 0.5, 0.0), Point3::new(1.0, 1.0, 1.0)),
+        ],
+    ];
+
+    /// The boxes of a block of this shape with `metadata`, in block-local coordinates
+    pub fn boxes(self, metadata: BlockMetadata) -> &'static [Aabb] {
+        match self {
+            BlockShape::Full => &[Aabb::FULL],
+            BlockShape::SlabBottom => &[Self::SLAB_BOTTOM],
+            BlockShape::SlabTop => &[Self::SLAB_TOP],
+            BlockShape::Stairs => &Self::STAIRS[(metadata.0 & 0b11) as usize],
+        }
+    }
+
+    /// The metadata of a block of this shape placed by someone looking along `forward`. Stairs
+    /// step up away from them.
+    pub fn placement_metadata(self, forward: Vector3<f32>) -> BlockMetadata {
+        match self {
+            BlockShape::Stairs => {
+                let facing = Direction::HORIZONTAL
+                    .into_iter()
+                    .max_by(|a, b| {
+                        let a = forward.dot(a.normal());
+                        let b = forward.dot(b.normal());
+                        a.total_cmp(&b)
+                    })
+                    .unwrap();
+                BlockMetadata(facing.index())
+            }
+            _ => BlockMetadata(0),
+        }
+    }
+}
+
+/// An axis-aligned box in block-local coordinates, where a full block goes from 0.0 to 1.0
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+    pub min: Point3<f32>,
+    pub max: Point3<f32>,
+}
+
+impl Aabb {
+    pub const FULL: Aabb = Aabb::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, 1.0, 1.0));
+
+    pub const fn new(min: Point3<f32>, max: Point3<f32>) -> Self {
+        Self { min, max }
+    }
+
+    /// The box moved by `offset`, like from block-local to world coordinates
+    pub fn offset(self, offset: Vector3<f32>) -> Self {
+        Self::new(self.min + offset, self.max + offset)
+    }
+
+    /// Whether the box overlaps the box between `min` and `max`, touching doesn't count
+    pub fn overlaps(&self, min: Point3<f32>, max: Point3<f32>) -> bool {
+        (0..3).all(|i| self.min[i] < max[i] && min[i] < self.max[i])
+    }
+
+    /// Whether the side of the box facing `dir` lies against the opposite side of `other` and
+    /// is entirely covered by it
+    pub fn face_covered_by(&self, dir: Direction, other: &Aabb) -> bool {
+        let normal = dir.normal::<f32>();
+        let axis = (0..3).find(|&i| normal[i] != 0.0).unwrap();
+        let touching = if normal[axis] > 0.0 {
+            self.max[axis] == other.min[axis]
+        } else {
+            self.min[axis] == other.max[axis]
+        };
+        touching
+            && (0..3)
+                .filter(|&i| i != axis)
+                .all(|i| other.min[i] <= self.min[i] && self.max[i] <= other.max[i])
+    }
+
+    /// Where a ray from `origin` along `dir` enters the box, as the distance along the ray in
+    /// multiples of `dir`, and the face it enters through. Negative if `origin` is inside.
+    pub fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<(f32, Direction)> {
+        // The face entered through when moving towards positive and negative along each axis
+        let faces = [
+            (Direction::East, Direction::West),
+            (Direction::Down, Direction::Up),
+            (Direction::South, Direction::North),
+        ];
+        let mut enter = f32::NEG_INFINITY;
+        let mut exit = f32::INFINITY;
+        let mut face = None;
+        for (i, (positive, negative)) in faces.into_iter().enumerate() {
+            if dir[i] == 0.0 {
+                if origin[i] < self.min[i] || origin[i] > self.max[i] {
+                    return None;
+                }
+                continue;
+            }
+            let to_min = (self.min[i] - origin[i]) / dir[i];
+            let to_max = (self.max[i] - origin[i]) / dir[i];
+            let (near, far, near_face) = if dir[i] > 0.0 {
+                (to_min, to_max, positive)
+            } else {
+                (to_max, to_min, negative)
+            };
+            if near > enter {
+                enter = near;
+                face = Some(near_face);
+            }
+            exit = exit.min(far);
+        }
+        if enter > exit || exit < 0.0 {
+            return None;
+        }
+        face.map(|face| (enter, face))
+    }
+}
+
+/// A texture made of `frames` consecutive atlas cells in a row
+#[derive(Debug, Clone, Copy)]
+pub struct TextureAnimation {
+    pub frames: u32,
+    /// Seconds each frame is shown
+    pub frame_time: f32,
+}
+
+impl TextureAnimation {
+    /// Per-vertex data telling the shader how to animate a face, see `MeshVertex::animation`
+    pub fn vertex_data(&self, cell_width: f32) -> [f32; 3] {
+        [self.frames as f32, self.frame_time, cell_width]
+    }
+}
+
+/// Bits of the connected texture index, set when the neighbour on that side of a face, as seen
+/// on the texture, is the same block
+pub struct ConnectedTexture;
+
+impl ConnectedTexture {
+    pub const UP: usize = 1;
+    pub const RIGHT: usize = 2;
+    pub const DOWN: usize = 4;
+    pub const LEFT: usize = 8;
+}
+
+impl BlockAttributes {
+    /// The boxes `block` is made of, `block` being of this type
+    pub fn boxes(&self, block: Block) -> &'static [Aabb] {
+        self.shape.boxes(block.metadata)
+    }
+
+    /// The texture to use for the block at `pos`, chosen deterministically among the variants
+    pub fn texture_at(&self, pos: BlockPos) -> &'static str {
+        if self.texture_variants.is_empty() {
+            return self.texture;
+        }
+
+        let pos = Point3::from(pos);
+        // Cheap integer hash, only needs to look random, not be good
+        let mut hash = (pos.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ (pos.y as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+            ^ (pos.z as u64).wrapping_mul(0x1656_67B1_9E37_79F9);
+        hash ^= hash >> 31;
+        hash = hash.wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        hash ^= hash >> 29;
+
+        match (hash % (self.texture_variants.len() as u64 + 1)) as usize {
+            0 => self.texture,
+            i => self.texture_variants[i - 1],
+        }
+    }
+
+    /// The texture to use for `block` at `pos`, taking its signal state into account
+    pub fn block_texture(&self, block: Block, pos: BlockPos) -> &'static str {
+        match self.powered_texture {
+            Some(texture) if signal::is_powered(block) => texture,
+            _ => self.texture_at(pos),
+        }
+    }
+}
+
+/// Mapping between numeric block ids and block names
+#[derive(Debug, Clone, Default)]
+pub struct BlockNames {
+    names: FastHashMap<BlockId, &'static str>,
+    ids: FastHashMap<&'static str, BlockId>,
+}
+
+impl BlockNames {
+    pub fn name(&self, id: BlockId) -> Option<&'static str> {
+        self.names.get(&id).copied()
+    }
+
+    pub fn id(&self, name: &str) -> Option<BlockId> {
+        self.ids.get(name).copied()
+    }
 }
 
 #[derive(Resource)]
 pub struct BlockRegistry {
-    blocks: [Option<BlockAttributes>; 256],
+    /// Indexed by block id
+    blocks: Vec<Option<BlockAttributes>>,
+    names: BlockNames,
 }
 
 impl BlockRegistry {
     pub fn new() -> Self {
         Self {
-            blocks: [const { None }; _],
+            blocks: vec![],
+            names: BlockNames::default(),
         }
     }
 
+    /// The registry with every block built into the game
+    pub fn with_default_blocks() -> Self {
+        let mut block_registry = BlockRegistry::new();
+
+        let air_block_attr = BlockAttributes {
+            name: "voxels:air",
+            transparent: true,
+            invisible: true,
+            translucent: false,
+            liquid: false,
+            texture: "",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(0), air_block_attr);
+
+        let dirt_block_attr = BlockAttributes {
+            name: "voxels:dirt",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "dirt",
+            texture_variants: vec!["dirt_1"],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(1), dirt_block_attr);
+
+        let stone_block_attr = BlockAttributes {
+            name: "voxels:stone",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "stone",
+            texture_variants: vec!["stone_1", "stone_2"],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(2), stone_block_attr);
+
+        let neco_arc_block_attr = BlockAttributes {
+            name: "voxels:neco_arc",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "neco_arc",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(3), neco_arc_block_attr);
+
+        let blue_block_attr = BlockAttributes {
+            name: "voxels:blue",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "blue",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(4), blue_block_attr);
+
+        let lamp_block_attr = BlockAttributes {
+            name: "voxels:lamp",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "lamp",
+            texture_variants: vec![],
+            light_emission: 10.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: Some("lamp_on"),
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(5), lamp_block_attr);
+
+        let water_block_attr = BlockAttributes {
+            name: "voxels:water",
+            transparent: true,
+            invisible: false,
+            translucent: true,
+            liquid: true,
+            texture: "water",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: Some(TextureAnimation {
+                frames: 4,
+                frame_time: 0.25,
+            }),
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(6), water_block_attr);
+
+        let glass_block_attr = BlockAttributes {
+            name: "voxels:glass",
+            transparent: true,
+            invisible: false,
+            translucent: true,
+            liquid: false,
+            texture: "glass",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: true,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(BlockId(7), glass_block_attr);
+
+        let lever_block_attr = BlockAttributes {
+            name: "voxels:lever",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "lever",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: Some("lever_on"),
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(signal::LEVER, lever_block_attr);
+
+        let wire_block_attr = BlockAttributes {
+            name: "voxels:wire",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "wire",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: Some("wire_on"),
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(signal::WIRE, wire_block_attr);
+
+        let log_block_attr = BlockAttributes {
+            name: "voxels:log",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "log",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(structure::LOG, log_block_attr);
+
+        let leaves_block_attr = BlockAttributes {
+            name: "voxels:leaves",
+            transparent: true,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "leaf",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: Some(ParticleKind::Leaf),
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(structure::LEAVES, leaves_block_attr);
+
+        let sand_block_attr = BlockAttributes {
+            name: "voxels:sand",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "sand",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: None,
+        };
+        block_registry.register(biome::SAND, sand_block_attr);
+
+        let coal_ore_block_attr = BlockAttributes {
+            name: "voxels:coal_ore",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "coal_ore",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: Some(OreRule {
+                vein_size: 12,
+                frequency: 4.0,
+                heights: -128..16,
+            }),
+        };
+        block_registry.register(ore::COAL_ORE, coal_ore_block_attr);
+
+        let iron_ore_block_attr = BlockAttributes {
+            name: "voxels:iron_ore",
+            transparent: false,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "iron_ore",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Full,
+            ore: Some(OreRule {
+                vein_size: 6,
+                frequency: 1.5,
+                heights: -256..-16,
+            }),
+        };
+        block_registry.register(ore::IRON_ORE, iron_ore_block_attr);
+
+        let stone_slab_block_attr = BlockAttributes {
+            name: "voxels:stone_slab",
+            transparent: true,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "stone",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::SlabBottom,
+            ore: None,
+        };
+        block_registry.register(BlockId(15), stone_slab_block_attr);
+
+        let stone_slab_top_block_attr = BlockAttributes {
+            name: "voxels:stone_slab_top",
+            transparent: true,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "stone",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::SlabTop,
+            ore: None,
+        };
+        block_registry.register(BlockId(16), stone_slab_top_block_attr);
+
+        let stone_stairs_block_attr = BlockAttributes {
+            name: "voxels:stone_stairs",
+            transparent: true,
+            invisible: false,
+            translucent: false,
+            liquid: false,
+            texture: "stone",
+            texture_variants: vec![],
+            light_emission: 0.0,
+            animation: None,
+            connected_texture: false,
+            ambient_particle: None,
+            powered_texture: None,
+            shape: BlockShape::Stairs,
+            ore: None,
+        };
+        block_registry.register(BlockId(17), stone_stairs_block_attr);
+
+        block_registry
+    }
+
+    /// Register a block, failing if either its id or its name is already taken
     pub fn register(&mut self, id: BlockId, info: BlockAttributes) -> Option<()> {
-        if self.blocks[id.0 as usize].is_some() {
+        if self.get(id).is_some() || self.names.ids.contains_key(info.name) {
             None
         } else {
-            self.blocks[id.0 as usize] = Some(info);
+            self.names.names.insert(id, info.name);
+            self.names.ids.insert(info.name, id);
+            let index = id.0 as usize;
+            if index >= self.blocks.len() {
+                self.blocks.resize_with(index + 1, || None);
+            }
+            self.blocks[index] = Some(info);
             Some(())
         }
     }
 
+    pub fn names(&self) -> &BlockNames {
+        &self.names
+    }
+
     pub fn get(&self, id: BlockId) -> Option<&BlockAttributes> {
-        self.blocks[id.0 as usize].as_ref()
+        self.blocks.get(id.0 as usize)?.as_ref()
+    }
+
+    /// Every registered block
+    pub fn iter(&self) -> impl Iterator<Item = (BlockId, &BlockAttributes)> {
+        self.blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(id, attr)| Some((BlockId(id as u16), attr.as_ref()?)))
     }
 }
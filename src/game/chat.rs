@@ -0,0 +1,110 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use bevy_ecs::system::Resource;
+use winit::keyboard::KeyCode;
+
+pub struct ChatMessage {
+    pub text: String,
+    pub received: Instant,
+}
+
+/// Messages received from the server, oldest first
+#[derive(Default, Resource)]
+pub struct ChatLog {
+    pub messages: VecDeque<ChatMessage>,
+}
+
+impl ChatLog {
+    /// Older messages are dropped once the log grows past this
+    pub const MAX_MESSAGES: usize = 100;
+    /// How many of the latest messages are shown on screen
+    pub const VISIBLE_MESSAGES: usize = 10;
+    /// How long a message is shown before it starts fading out
+    const SHOW_TIME: Duration = Duration::from_secs(10);
+    const FADE_TIME: Duration = Duration::from_secs(2);
+
+    pub fn push(&mut self, text: String) {
+        println!("[chat] {text}");
+        self.messages.push_back(ChatMessage {
+            text,
+            received: Instant::now(),
+        });
+        while self.messages.len() > Self::MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+    }
+
+    /// The latest messages to show on screen, oldest first, with their opacity. Messages fade
+    /// out a while after they arrive, unless the player is `typing`.
+    pub fn visible(&self, now: Instant, typing: bool) -> impl Iterator<Item = (&str, f32)> {
+        let skip = self.messages.len().saturating_sub(Self::VISIBLE_MESSAGES);
+        self.messages.iter().skip(skip).filter_map(move |message| {
+            let opacity = if typing {
+                1.0
+            } else {
+                let age = now.saturating_duration_since(message.received);
+                let fading = age.saturating_sub(Self::SHOW_TIME);
+                1.0 - fading.as_secs_f32() / Self::FADE_TIME.as_secs_f32()
+            };
+            (opacity > 0.0).then_some((message.text.as_str(), opacity))
+        })
+    }
+}
+
+/// What the player did in the chat input
+pub enum ChatInputEvent {
+    None,
+    /// Send this line, the input is closed
+    Send(String),
+    Close,
+}
+
+/// A chat line being typed. There is no in-game UI yet, so it is shown in the window title.
+///
+/// Enter sends the line, and Backspace on an empty line closes the input without sending
+/// anything.
+#[derive(Debug, Default)]
+pub struct ChatInput {
+    pub text: String,
+}
+
+impl ChatInput {
+    /// Opens the chat input. Not rebindable, like the key binding menu's toggle.
+    pub const OPEN_KEY: KeyCode = KeyCode::Enter;
+    /// Opens the chat input with a `/` typed, to start a command
+    pub const COMMAND_KEY: KeyCode = KeyCode::Slash;
+    /// Longest line that can be typed
+    pub const MAX_LENGTH: usize = 256;
+
+    pub fn command() -> Self {
+        Self {
+            text: "/".to_string(),
+        }
+    }
+
+    /// Handle a key press, with `text` being what it typed, if anything
+    pub fn key_pressed(&mut self, key: KeyCode, text: Option<&str>) -> ChatInputEvent {
+        match key {
+            KeyCode::Enter | KeyCode::NumpadEnter => {
+                return ChatInputEvent::Send(std::mem::take(&mut self.text));
+            }
+            KeyCode::Backspace => {
+                if self.text.pop().is_none() {
+                    return ChatInputEvent::Close;
+                }
+            }
+            _ => {
+                let typed = text.unwrap_or_default().chars().filter(|c| !c.is_control());
+                for c in typed {
+                    if self.text.chars().count() < Self::MAX_LENGTH {
+                        self.text.push(c);
+                    }
+                }
+            }
+        }
+        ChatInputEvent::None
+    }
+}
@@ -8,9 +8,9 @@ use rusqlite::{types::{FromSql, ToSqlOutput}, ToSql};
 use serde::{Deserialize, Serialize};
 use wgpu::naga::FastHashMap;
 
-use crate::mesh::{D3Accessible, Vector3Accessor};
+use crate::direction::{D3Accessible, Vector3Accessor};
 
-use super::block::{Block, BlockMetadata};
+use super::block::{Block, BlockId, BlockMetadata, BlockNames};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ChunkPos(Point3<isize>);
@@ -187,6 +187,12 @@ impl D3Accessible for ChunkRelativeBlockPos {
     }
 }
 
+/// Identifies the blocks of a chunk, see [`Chunk::content_hash`]
+pub type ChunkHash = [u8; 32];
+
+/// Version of a chunk on the server, which goes up whenever one of its blocks changes
+pub type ChunkRevision = u64;
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Chunk {
     pub dirty: AtomicBool,
@@ -265,13 +271,212 @@ impl Chunk {
         self.dirty.store(dirty, Ordering::Relaxed);
     }
 
-    pub fn serialize(&self) -> Vec<u8> {
-        // serde_json::to_vec_pretty(self).unwrap()
-        postcard::to_allocvec(self).unwrap()
+    /// SHA-256 of the chunk's blocks, telling whether two copies of a chunk have the same blocks
+    pub fn content_hash(&self) -> ChunkHash {
+        let blocks = postcard::to_allocvec(&self.blocks).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &blocks)
+            .as_ref()
+            .try_into()
+            .unwrap()
+    }
+
+    /// First byte of chunks saved with a block palette and 8 bit block ids. Chunks saved before
+    /// palettes were added start with the dirty flag instead, which is always 0 or 1.
+    const PALETTE_FORMAT: u8 = 2;
+    /// First byte of chunks saved with a block palette and 16 bit block ids
+    const WIDE_ID_FORMAT: u8 = 3;
+
+    /// Serialize for saving, storing block names alongside the blocks so that the chunk stays
+    /// valid even if block ids change
+    pub fn serialize(&self, names: &BlockNames) -> Vec<u8> {
+        let mut palette = vec![];
+        let mut palette_indices = FastHashMap::default();
+        let blocks = self
+            .block_iter()
+            .map(|(_, &block)| {
+                let index = *palette_indices.entry(block.id).or_insert_with(|| {
+                    let name = names
+                        .name(block.id)
+                        .unwrap_or_else(|| panic!("Block id {:?} has no name", block.id));
+                    palette.push(name.to_string());
+                    BlockId(palette.len() as u16 - 1)
+                });
+                Block { id: index, ..block }
+            })
+            .collect();
+
+        let saved = SavedChunk {
+            pos: self.pos,
+            palette,
+            blocks,
+        };
+        let mut data = vec![Self::WIDE_ID_FORMAT];
+        data.extend(postcard::to_allocvec(&saved).unwrap());
+        data
+    }
+
+    /// Deserialize a saved chunk, remapping the saved block names to the current ids.
+    /// Blocks that no longer exist are replaced with air.
+    pub fn deserialize(data: &[u8], names: &BlockNames) -> Self {
+        let saved: SavedChunk = match data.first() {
+            Some(&Self::WIDE_ID_FORMAT) => postcard::from_bytes(&data[1..]).unwrap(),
+            Some(&Self::PALETTE_FORMAT) => {
+                let saved: SavedChunk<NarrowBlock> = postcard::from_bytes(&data[1..]).unwrap();
+                SavedChunk {
+                    pos: saved.pos,
+                    palette: saved.palette,
+                    blocks: saved.blocks.into_iter().map(NarrowBlock::widen).collect(),
+                }
+            }
+            _ => {
+                // Saved before palettes, ids are used as they are
+                let legacy: LegacyChunk = postcard::from_bytes(data).unwrap();
+                let mut chunk = Chunk::new(legacy.pos);
+                let blocks = legacy.blocks.iter().flatten().flatten();
+                for ((_, block), legacy_block) in chunk.block_iter_mut().zip(blocks) {
+                    *block = legacy_block.widen();
+                }
+                return chunk;
+            }
+        };
+
+        let ids = saved
+            .palette
+            .iter()
+            .map(|name| {
+                names.id(name).unwrap_or_else(|| {
+                    log::warn!(
+                        "Unknown block {name} in chunk {:?}, replacing it with air",
+                        saved.pos
+                    );
+                    BlockId(0)
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut chunk = Chunk::new(saved.pos);
+        for ((_, block), saved_block) in chunk.block_iter_mut().zip(saved.blocks) {
+            *block = Block {
+                id: ids[saved_block.id.0 as usize],
+                ..saved_block
+            };
+        }
+        chunk
+    }
+}
+
+/// How a chunk is stored on disk, with block ids being indices into `palette`
+#[derive(Serialize, Deserialize)]
+struct SavedChunk<B = Block> {
+    pos: ChunkPos,
+    /// Names of the blocks in this chunk
+    palette: Vec<String>,
+    blocks: Vec<B>,
+}
+
+/// A block as saved while block ids were 8 bits
+#[derive(Clone, Copy, Deserialize)]
+struct NarrowBlock {
+    id: u8,
+    metadata: u8,
+}
+
+impl NarrowBlock {
+    fn widen(self) -> Block {
+        Block {
+            id: BlockId(self.id as u16),
+            metadata: BlockMetadata(self.metadata),
+        }
+    }
+}
+
+/// A chunk as saved before block palettes were added
+#[derive(Deserialize)]
+struct LegacyChunk {
+    _dirty: bool,
+    pos: ChunkPos,
+    blocks: [[[NarrowBlock; 16]; 16]; 16],
+}
+
+/// A chunk at reduced resolution, used to render terrain far away
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LodChunk {
+    pub pos: ChunkPos,
+    /// Each block of this chunk covers `2^level` blocks along every axis
+    pub level: u8,
+    blocks: Vec<Block>,
+}
+
+impl LodChunk {
+    pub const MAX_LEVEL: u8 = 2;
+
+    /// Downsample a chunk. Each cell becomes the most common non-air block in it, or air if at
+    /// least half of the cell is air.
+    pub fn from_chunk(chunk: &Chunk, level: u8) -> Self {
+        let level = level.min(Self::MAX_LEVEL);
+        let scale = 1 << level;
+        let size = Chunk::SIZE / scale;
+
+        let mut blocks = Vec::with_capacity(size * size * size);
+        let mut counts: Vec<(Block, usize)> = vec![];
+
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    counts.clear();
+                    let mut air = 0;
+
+                    for dx in 0..scale {
+                        for dy in 0..scale {
+                            for dz in 0..scale {
+                                let block = chunk.blocks[x * scale + dx][y * scale + dy]
+                                    [z * scale + dz];
+                                if block.id.0 == 0 {
+                                    air += 1;
+                                } else if let Some((_, count)) =
+                                    counts.iter_mut().find(|(b, _)| b.id == block.id)
+                                {
+                                    *count += 1;
+                                } else {
+                                    counts.push((block, 1));
+                                }
+                            }
+                        }
+                    }
+
+                    let block = if air * 2 >= scale * scale * scale {
+                        Block::default()
+                    } else {
+                        counts
+                            .iter()
+                            .max_by_key(|(_, count)| *count)
+                            .map(|(block, _)| *block)
+                            .unwrap_or_default()
+                    };
+                    blocks.push(block);
+                }
+            }
+        }
+
+        Self {
+            pos: chunk.pos,
+            level,
+            blocks,
+        }
+    }
+
+    /// How many blocks each cell spans along every axis
+    pub fn scale(&self) -> usize {
+        1 << self.level
+    }
+
+    /// Number of cells along every axis
+    pub fn size(&self) -> usize {
+        Chunk::SIZE / self.scale()
     }
 
-    pub fn deserialize(data: &[u8]) -> Self {
-        // serde_json::from_slice(data).unwrap()
-        postcard::from_bytes(data).unwrap()
+    pub fn block(&self, pos: Point3<usize>) -> &Block {
+        let size = self.size();
+        &self.blocks[(pos.x * size + pos.y) * size + pos.z]
     }
 }
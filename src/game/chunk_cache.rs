@@ -0,0 +1,210 @@
+//! Chunks received from servers, kept on disk so that rejoining a server only downloads the
+//! chunks that changed in the meantime.
+//!
+//! Every server gets a directory of its own, with a file for each chunk named after its
+//! position, its revision and the hash of its blocks. Chunks are requested along with the hash
+//! and revision of the cached copy, and the server answers
+//! [`MessageToClient::ChunksUnchanged`] for the ones that still match, or a
+//! [`MessageToClient::ChunkDelta`] with the blocks that changed since. Only requested chunks are
+//! cached, not streamed ones.
+//!
+//! [`MessageToClient::ChunksUnchanged`]: crate::server::message::MessageToClient::ChunksUnchanged
+//! [`MessageToClient::ChunkDelta`]: crate::server::message::MessageToClient::ChunkDelta
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use cgmath::Point3;
+use wgpu::naga::FastHashMap;
+
+use super::{
+    block::Block,
+    chunk::{BlockPos, Chunk, ChunkHash, ChunkPos, ChunkRevision},
+};
+use crate::server::message::CachedChunk;
+
+/// A file name for what is kept per server, from the server's address
+pub fn server_file_name(server: &str) -> String {
+    server
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone)]
+pub struct ChunkCache {
+    dir: PathBuf,
+    /// The hash and revision of every cached chunk, shared with the task receiving chunks
+    chunks: Arc<Mutex<FastHashMap<ChunkPos, CachedChunk>>>,
+}
+
+impl ChunkCache {
+    pub const DIR: &'static str = "./cache/chunks";
+
+    /// Open the cache of the chunks of `server` in `root`, creating it if there is none yet
+    pub fn open(root: impl AsRef<Path>, server: &str) -> anyhow::Result<Self> {
+        let dir = root.as_ref().join(server_file_name(server));
+        std::fs::create_dir_all(&dir)?;
+
+        let mut chunks = FastHashMap::default();
+        for entry in std::fs::read_dir(&dir)? {
+            let path = entry?.path();
+            match Self::parse_file_name(&path) {
+                Some((pos, cached)) => {
+                    chunks.insert(pos, cached);
+                }
+                // Files cached before revisions were part of the name can't be used anymore
+                None => {
+                    log::warn!(
+                        "Removing unexpected file from the chunk cache: {}",
+                        path.display()
+                    );
+                    if let Err(e) = std::fs::remove_file(&path) {
+                        log::warn!("Could not remove {}: {e}", path.display());
+                    }
+                }
+            }
+        }
+
+        Ok(Self {
+            dir,
+            chunks: Arc::new(Mutex::new(chunks)),
+        })
+    }
+
+    /// The hash and revision of the cached copy of the chunk at `pos`, if there is one
+    pub fn cached(&self, pos: ChunkPos) -> Option<CachedChunk> {
+        self.chunks.lock().unwrap().get(&pos).copied()
+    }
+
+    pub fn load(&self, pos: ChunkPos) -> anyhow::Result<Chunk> {
+        let cached = self
+            .cached(pos)
+            .ok_or_else(|| anyhow::anyhow!("chunk {pos:?} is not cached"))?;
+        let chunk: Chunk = postcard::from_bytes(&std::fs::read(self.path(pos, &cached))?)?;
+        anyhow::ensure!(
+            chunk.pos == pos && chunk.content_hash() == cached.hash,
+            "the cached copy of chunk {pos:?} is corrupt"
+        );
+        chunk.set_dirty(true);
+        Ok(chunk)
+    }
+
+    /// Cache `chunk` at `revision`, replacing the copy cached before
+    pub fn store(&self, chunk: &Chunk, revision: ChunkRevision) -> anyhow::Result<()> {
+        let cached = CachedChunk {
+            hash: chunk.content_hash(),
+            revision,
+        };
+        if self.cached(chunk.pos) == Some(cached) {
+            return Ok(());
+        }
+        std::fs::write(self.path(chunk.pos, &cached), postcard::to_allocvec(chunk)?)?;
+        let old = self.chunks.lock().unwrap().insert(chunk.pos, cached);
+        if let Some(old) = old {
+            std::fs::remove_file(self.path(chunk.pos, &old))?;
+        }
+        Ok(())
+    }
+
+    /// Cache every chunk in `chunks`, warning about the ones that could not be
+    pub fn store_all(&self, chunks: &[(Chunk, ChunkRevision)]) {
+        for (chunk, revision) in chunks {
+            if let Err(e) = self.store(chunk, *revision) {
+                log::warn!("Could not cache chunk {:?}: {e:#}", chunk.pos);
+            }
+        }
+    }
+
+    /// Apply the blocks changed in the chunk at `pos` since its cached copy, caching the result
+    /// as `revision`. Fails if the result doesn't have the content `hash` of the server's copy.
+    pub fn update(
+        &self,
+        pos: ChunkPos,
+        revision: ChunkRevision,
+        hash: ChunkHash,
+        changes: &[(BlockPos, Block)],
+    ) -> anyhow::Result<Chunk> {
+        let mut chunk = self.load(pos)?;
+        for &(block_pos, block) in changes {
+            anyhow::ensure!(
+                block_pos.chunk_pos() == pos,
+                "change at {block_pos:?} is outside of chunk {pos:?}"
+            );
+            *chunk.block_mut(block_pos.rel_pos()) = block;
+        }
+        anyhow::ensure!(
+            chunk.content_hash() == hash,
+            "the changes don't add up to the server's copy"
+        );
+        self.store(&chunk, revision)?;
+        Ok(chunk)
+    }
+
+    /// Load the cached chunks at `positions`, along with the positions of the ones that could
+    /// not be loaded. Those are removed from the cache, as they are of no use anymore.
+    pub fn load_all(&self, positions: Vec<ChunkPos>) -> (Vec<Chunk>, Vec<ChunkPos>) {
+        let mut chunks = vec![];
+        let mut failed = vec![];
+        for pos in positions {
+            match self.load(pos) {
+                Ok(chunk) => chunks.push(chunk),
+                Err(e) => {
+                    log::warn!("Could not load cached chunk: {e:#}");
+                    self.remove(pos);
+                    failed.push(pos);
+                }
+            }
+        }
+        (chunks, failed)
+    }
+
+    /// Drop the cached copy of the chunk at `pos`, e.g. because it could not be read
+    pub fn remove(&self, pos: ChunkPos) {
+        let Some(cached) = self.chunks.lock().unwrap().remove(&pos) else {
+            return;
+        };
+        if let Err(e) = std::fs::remove_file(self.path(pos, &cached)) {
+            log::warn!("Could not remove chunk {pos:?} from the cache: {e}");
+        }
+    }
+
+    fn path(&self, pos: ChunkPos, cached: &CachedChunk) -> PathBuf {
+        let p = Point3::from(pos);
+        let hash = cached
+            .hash
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        self.dir.join(format!(
+            "{}.{}.{}.{}.{hash}.chunk",
+            p.x, p.y, p.z, cached.revision
+        ))
+    }
+
+    /// The position, revision and hash of the chunk cached in the file at `path`
+    fn parse_file_name(path: &Path) -> Option<(ChunkPos, CachedChunk)> {
+        let name = path.file_name()?.to_str()?.strip_suffix(".chunk")?;
+        let mut parts = name.split('.');
+        let mut coordinate = || parts.next()?.parse::<isize>().ok();
+        let pos = Point3::new(coordinate()?, coordinate()?, coordinate()?);
+        let revision = parts.next()?.parse::<ChunkRevision>().ok()?;
+        let hex = parts.next()?;
+        if parts.next().is_some() || hex.len() != 64 {
+            return None;
+        }
+        let mut hash = ChunkHash::default();
+        for (i, byte) in hash.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+        }
+        Some((pos.into(), CachedChunk { hash, revision }))
+    }
+}
@@ -0,0 +1,168 @@
+use std::{fmt::Display, time::Duration};
+
+use cgmath::Point3;
+
+use crate::{game::chunk::ChunkPos, mesh::MeshSize};
+
+/// Snapshot of how far behind the client is on loading the world
+#[derive(Debug, Clone, Copy)]
+pub struct LoadingDiagnostics {
+    /// Chunks requested from the server but not yet received
+    pub chunks_in_flight: usize,
+    /// How long the oldest chunk request has been outstanding
+    pub oldest_request: Option<Duration>,
+    /// Loaded chunks whose mesh has yet to be (re)built
+    pub mesh_jobs: usize,
+    /// How many of `mesh_jobs` may currently be done per frame
+    pub meshes_per_frame: usize,
+    /// Chunk transactions the server has not finished responding to
+    pub pending_transactions: usize,
+    /// Messages waiting to be sent to the server
+    pub queued_messages: usize,
+}
+
+impl LoadingDiagnostics {
+    /// Requests outstanding for longer than this are most likely caused by server lag
+    pub const STALL_THRESHOLD: Duration = Duration::from_secs(5);
+
+    pub fn is_stalled(&self) -> bool {
+        self.oldest_request
+            .is_some_and(|age| age > Self::STALL_THRESHOLD)
+    }
+}
+
+impl Display for LoadingDiagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "chunks in flight: {}, mesh jobs: {} ({}/frame), transactions: {}, queued messages: {}",
+            self.chunks_in_flight,
+            self.mesh_jobs,
+            self.meshes_per_frame,
+            self.pending_transactions,
+            self.queued_messages
+        )?;
+        if let Some(age) = self.oldest_request {
+            write!(f, ", oldest request: {:.1}s", age.as_secs_f32())?;
+        }
+        if self.is_stalled() {
+            write!(f, " [WAITING ON SERVER]")?;
+        }
+        Ok(())
+    }
+}
+
+/// Sizes of the client's render caches, which should always match the set of loaded chunks
+#[derive(Debug, Clone, Copy)]
+pub struct RenderStats {
+    pub loaded_chunks: usize,
+    /// Chunks with an opaque mesh object
+    pub chunk_objects: usize,
+    /// Chunks with a translucent mesh object
+    pub translucent_chunk_objects: usize,
+    /// Chunks whose meshes are cached by the meshifier
+    pub cached_meshes: usize,
+    /// Entries for chunks that were no longer loaded, removed by reconciliation since startup
+    pub stale_entries_removed: usize,
+    /// Chunks skipped last frame because terrain hides them from the camera
+    pub occluded_chunks: usize,
+}
+
+impl RenderStats {
+    pub fn is_consistent(&self) -> bool {
+        self.chunk_objects <= self.loaded_chunks
+            && self.translucent_chunk_objects <= self.loaded_chunks
+            && self.cached_meshes <= self.loaded_chunks
+    }
+}
+
+impl Display for RenderStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "loaded chunks: {}, chunk objects: {}/{}, cached meshes: {}, stale entries removed: {}, occluded: {}",
+            self.loaded_chunks,
+            self.chunk_objects,
+            self.translucent_chunk_objects,
+            self.cached_meshes,
+            self.stale_entries_removed,
+            self.occluded_chunks
+        )
+    }
+}
+
+/// Draw calls made for the last frame
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DrawStats {
+    pub draw_calls: usize,
+    /// Objects skipped because they were outside the view frustum
+    pub culled: usize,
+}
+
+impl Display for DrawStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "draw calls: {}, culled: {}", self.draw_calls, self.culled)
+    }
+}
+
+/// How much geometry the meshes of the loaded chunks have and how much GPU memory they take
+/// up, shown by the debug overlay so that meshing getting less efficient is noticed
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MeshStats {
+    /// Chunks with an opaque or translucent mesh
+    pub chunks: usize,
+    /// Opaque meshes, whose bytes are the parts of the chunk arena they use
+    pub opaque: MeshSize,
+    pub translucent: MeshSize,
+    /// Bytes of the chunk arena's buffers, including the free parts
+    pub arena_allocated: u64,
+    /// The chunk with the most vertices, and how many it has
+    pub largest: Option<(ChunkPos, u64)>,
+}
+
+impl MeshStats {
+    /// Count the meshes of the chunk at `pos`
+    pub fn add_chunk(
+        &mut self,
+        pos: ChunkPos,
+        opaque: Option<MeshSize>,
+        translucent: Option<MeshSize>,
+    ) {
+        let opaque = opaque.unwrap_or_default();
+        let translucent = translucent.unwrap_or_default();
+        self.chunks += 1;
+        self.opaque += opaque;
+        self.translucent += translucent;
+        let vertices = opaque.vertices + translucent.vertices;
+        if self.largest.is_none_or(|(_, largest)| vertices > largest) {
+            self.largest = Some((pos, vertices));
+        }
+    }
+}
+
+impl Display for MeshStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let vertices = self.opaque.vertices + self.translucent.vertices;
+        write!(
+            f,
+            "meshes: {} chunks, {} vertices ({} per chunk), {} indices, opaque {:.1}/{:.1} MiB, translucent {:.1} MiB",
+            self.chunks,
+            vertices,
+            vertices / self.chunks.max(1) as u64,
+            self.opaque.indices + self.translucent.indices,
+            mib(self.opaque.bytes),
+            mib(self.arena_allocated),
+            mib(self.translucent.bytes)
+        )?;
+        if let Some((pos, vertices)) = self.largest {
+            let pos = Point3::from(pos);
+            write!(
+                f,
+                ", largest: {}, {}, {} ({vertices} vertices)",
+                pos.x, pos.y, pos.z
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+use std::{fmt::Display, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+
+/// Identifies one of the separate worlds of a save, each with its own world generation and
+/// chunk storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DimensionId(pub u16);
+
+impl DimensionId {
+    pub const OVERWORLD: DimensionId = DimensionId(0);
+}
+
+impl Display for DimensionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if *self == Self::OVERWORLD {
+            write!(f, "overworld")
+        } else {
+            write!(f, "dim{}", self.0)
+        }
+    }
+}
+
+impl FromStr for DimensionId {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "overworld" {
+            return Ok(Self::OVERWORLD);
+        }
+        s.strip_prefix("dim")
+            .unwrap_or(s)
+            .parse()
+            .map(DimensionId)
+            .map_err(|_| format!("Invalid dimension `{s}`"))
+    }
+}
+
+/// Saved metadata of a dimension, needed to keep generating it the same way
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DimensionInfo {
+    pub seed: u32,
+}
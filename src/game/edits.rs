@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+
+use bevy_ecs::{
+    event::EventReader,
+    system::{Res, ResMut, Resource},
+};
+
+use crate::{
+    input::{Action, ActionEvent, ActionState},
+    server::message::MessageToServer,
+};
+
+use super::{block::Block, chunk::BlockPos, world::World, MessageQueue};
+
+/// Block edits made locally that the server has not confirmed yet.
+///
+/// Edits are applied to the local world right away. Block changes from the server are
+/// authoritative, except where a local edit is still on its way to the server: the server will
+/// apply that edit after the change it just sent, so the local block is kept until then.
+#[derive(Debug, Default, Resource)]
+pub struct EditJournal {
+    next_edit: u32,
+    pending: VecDeque<PendingEdit>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingEdit {
+    edit: u32,
+    pos: BlockPos,
+}
+
+impl EditJournal {
+    /// Record a local edit, returning the number to send to the server along with it
+    pub fn record(&mut self, pos: BlockPos) -> u32 {
+        let edit = self.next_edit;
+        self.next_edit = self.next_edit.wrapping_add(1);
+        self.pending.push_back(PendingEdit { edit, pos });
+        edit
+    }
+
+    /// Apply block changes from the server. `acknowledged` is the last of our edits the server
+    /// had applied when it sent the changes.
+    pub fn reconcile(
+        &mut self,
+        changes: Vec<(BlockPos, Block)>,
+        acknowledged: Option<u32>,
+        world: &mut World,
+    ) {
+        if let Some(acknowledged) = acknowledged {
+            // Edits are acknowledged in order, so everything up to this one is done
+            if let Some(index) = self.pending.iter().position(|e| e.edit == acknowledged) {
+                self.pending.drain(..=index);
+            }
+        }
+
+        for (pos, block) in changes {
+            if self.pending.iter().any(|e| e.pos == pos) {
+                continue;
+            }
+            world.place_block(block, pos);
+        }
+    }
+}
+
+/// A block the player changed, and what it was before
+#[derive(Debug, Clone, Copy)]
+pub struct BlockEdit {
+    pub pos: BlockPos,
+    pub old: Block,
+    pub new: Block,
+}
+
+impl BlockEdit {
+    fn inverse(self) -> Self {
+        Self {
+            pos: self.pos,
+            old: self.new,
+            new: self.old,
+        }
+    }
+}
+
+/// The player's own block edits, to be undone and redone with the `Undo` and `Redo` actions. Only the
+/// last `capacity` edits are kept.
+#[derive(Debug, Resource)]
+pub struct EditHistory {
+    undo: VecDeque<BlockEdit>,
+    redo: Vec<BlockEdit>,
+    capacity: usize,
+}
+
+impl EditHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            undo: VecDeque::new(),
+            redo: vec![],
+            capacity,
+        }
+    }
+
+    /// Record a new edit, which can't be followed by the edits undone before it anymore
+    pub fn push(&mut self, edit: BlockEdit) {
+        self.redo.clear();
+        self.push_undo(edit);
+    }
+
+    fn push_undo(&mut self, edit: BlockEdit) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.undo.len() == self.capacity {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+    }
+
+    /// The edit that undoes the last one still in place in `world`. Edits whose block has
+    /// been changed since, e.g. by another player, are dropped.
+    fn undo(&mut self, world: &World) -> Option<BlockEdit> {
+        while let Some(edit) = self.undo.pop_back() {
+            if world.block(edit.pos) == Some(&edit.new) {
+                self.redo.push(edit);
+                return Some(edit.inverse());
+            }
+        }
+        None
+    }
+
+    /// The edit that redoes the last one undone, if its block is still the way it was left
+    fn redo(&mut self, world: &World) -> Option<BlockEdit> {
+        while let Some(edit) = self.redo.pop() {
+            if world.block(edit.pos) == Some(&edit.old) {
+                self.push_undo(edit);
+                return Some(edit);
+            }
+        }
+        None
+    }
+}
+
+/// Undo and redo the player's block edits, sending them to the server like any other edit
+pub fn undo_system(
+    mut action_events: EventReader<ActionEvent>,
+    mut world: ResMut<World>,
+    transport: Res<MessageQueue>,
+    mut journal: ResMut<EditJournal>,
+    mut history: ResMut<EditHistory>,
+) {
+    let action = action_events
+        .read()
+        .filter(|event| event.state == ActionState::Pressed)
+        .map(|event| event.action)
+        .find(|&action| action == Action::Undo || action == Action::Redo);
+    let edit = match action {
+        Some(Action::Undo) => history.undo(&world),
+        Some(Action::Redo) => history.redo(&world),
+        _ => None,
+    };
+    let Some(edit) = edit else {
+        return;
+    };
+
+    world.place_block(edit.new, edit.pos);
+    transport
+        .0
+        .send_blocking(MessageToServer::ReplaceBlock {
+            pos: edit.pos,
+            new_block: edit.new,
+            edit: journal.record(edit.pos),
+        })
+        .unwrap();
+}
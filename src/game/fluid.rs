@@ -0,0 +1,89 @@
+//! Water flowing out of its sources.
+//!
+//! The level of a water block is kept in its metadata: 0 for a source, and the number of blocks
+//! the water has flowed sideways for flowing water, up to [`MAX_LEVEL`]. Water that falls from
+//! above is [`FALLING`] and spreads like a source when it lands. Flowing water that is no longer
+//! fed drains away, one block per update. Only the server simulates fluids, clients just draw the
+//! levels they are sent.
+
+use cgmath::Point3;
+
+use crate::direction::Direction;
+
+use super::{
+    block::{Block, BlockId, BlockMetadata},
+    chunk::BlockPos,
+    world::World,
+};
+
+pub const WATER: BlockId = BlockId(6);
+
+pub const SOURCE: u8 = 0;
+/// How far water flows sideways from where it is fed
+pub const MAX_LEVEL: u8 = 7;
+/// Water fed from above
+pub const FALLING: u8 = MAX_LEVEL + 1;
+
+/// Ticks between each step water flows
+pub const FLOW_DELAY: u64 = 5;
+
+/// Height of the water's surface in a block, from 0 to 1
+pub fn surface_height(block: Block) -> f32 {
+    match block.metadata.0 {
+        SOURCE | FALLING => 0.875,
+        level => 0.875 * f32::from(MAX_LEVEL + 1 - level) / f32::from(MAX_LEVEL + 1),
+    }
+}
+
+fn water(level: u8) -> Block {
+    Block {
+        id: WATER,
+        metadata: BlockMetadata(level),
+    }
+}
+
+/// The level the water at `pos` would have, given the water around it
+fn fed_level(world: &World, pos: BlockPos) -> Option<u8> {
+    let offset =
+        |pos: BlockPos, dir: Direction| -> BlockPos { (Point3::from(pos) + dir.normal()).into() };
+
+    if world
+        .block(offset(pos, Direction::Up))
+        .is_some_and(|block| block.id == WATER)
+    {
+        return Some(FALLING);
+    }
+
+    Direction::iter()
+        .filter(|dir| dir.is_horizontal())
+        .filter_map(|dir| {
+            let neighbour_pos = offset(pos, dir);
+            let neighbour = world.block(neighbour_pos)?;
+            if neighbour.id != WATER {
+                return None;
+            }
+            let level = match neighbour.metadata.0 {
+                FALLING => SOURCE,
+                level => level,
+            };
+            // Flowing water pours down rather than spreading sideways while it can
+            let below = world.block(offset(neighbour_pos, Direction::Down))?;
+            if level != SOURCE && (below.id == BlockId(0) || below.id == WATER) {
+                return None;
+            }
+            Some(level + 1)
+        })
+        .min()
+        .filter(|&level| level <= MAX_LEVEL)
+}
+
+/// The block at `pos` after water has flowed into, out of or within it, if that changed it
+pub fn update(world: &World, pos: BlockPos) -> Option<Block> {
+    let block = *world.block(pos)?;
+    let new = match block.id {
+        WATER if block.metadata.0 == SOURCE => return None,
+        WATER | BlockId(0) => fed_level(world, pos).map_or(Block::default(), water),
+        _ => return None,
+    };
+    (new != block).then_some(new)
+}
@@ -0,0 +1,60 @@
+use std::time::Duration;
+
+use bevy_ecs::system::Resource;
+
+/// How long frames take, smoothed so that a single slow frame doesn't cause an overreaction
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct FrameTiming {
+    pub last: Duration,
+    pub average: Duration,
+}
+
+impl FrameTiming {
+    /// How much of the average each new frame makes up
+    const SMOOTHING: f32 = 0.1;
+
+    pub fn record(&mut self, frame_time: Duration) {
+        self.last = frame_time;
+        self.average = self.average.mul_f32(1.0 - Self::SMOOTHING)
+            + frame_time.mul_f32(Self::SMOOTHING);
+    }
+}
+
+impl Default for FrameTiming {
+    fn default() -> Self {
+        let frame_time = Duration::from_secs_f32(1.0 / 60.0);
+        Self {
+            last: frame_time,
+            average: frame_time,
+        }
+    }
+}
+
+/// How many chunk meshes may be built in a frame. Halved whenever frames take longer than the
+/// target, and raised by one each frame they don't, so a burst of arriving chunks is spread out
+/// over several frames instead of stalling one.
+#[derive(Debug, Clone, Copy)]
+pub struct MeshingBudget {
+    limit: usize,
+}
+
+impl MeshingBudget {
+    pub fn new(max: usize) -> Self {
+        Self { limit: max.max(1) }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Adjust the budget to how long frames have been taking. At least one mesh is always built
+    /// per frame, so chunks keep loading however slow frames are.
+    pub fn update(&mut self, timing: &FrameTiming, max: usize, target: Duration) {
+        self.limit = if timing.average > target {
+            self.limit / 2
+        } else {
+            self.limit + 1
+        }
+        .clamp(1, max.max(1));
+    }
+}
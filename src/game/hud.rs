@@ -0,0 +1,103 @@
+use std::{fmt::Display, time::Duration};
+
+use cgmath::{InnerSpace, Point3, Vector3};
+
+use crate::{camera::Camera, direction::Direction, locale::Locale, server::metrics::ServerMetrics};
+
+use super::{
+    biome::Biome,
+    chunk::{BlockPos, ChunkPos},
+    diagnostics::MeshStats,
+};
+
+/// Which way the player is facing and which block they are standing in, for finding one's way
+/// around large worlds
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Compass {
+    /// The horizontal direction closest to where the camera is looking
+    pub facing: Direction,
+    pub position: BlockPos,
+}
+
+impl Compass {
+    pub fn new(camera: &Camera) -> Self {
+        let (sin_yaw, cos_yaw) = camera.yaw.0.sin_cos();
+        let forward = Vector3::new(cos_yaw, 0.0, sin_yaw);
+        let facing = Direction::HORIZONTAL
+            .into_iter()
+            .max_by(|a, b| {
+                let a = forward.dot(a.normal::<f32>());
+                let b = forward.dot(b.normal::<f32>());
+                a.total_cmp(&b)
+            })
+            .unwrap();
+
+        Self {
+            facing,
+            position: BlockPos::from_point(camera.position),
+        }
+    }
+
+    /// The compass as one line of text, with the direction letter in the UI language
+    pub fn text(&self, locale: &Locale) -> String {
+        let (letter, axis) = match self.facing {
+            Direction::North => ("compass.north", "+Z"),
+            Direction::East => ("compass.east", "-X"),
+            Direction::South => ("compass.south", "-Z"),
+            Direction::West => ("compass.west", "+X"),
+            Direction::Up | Direction::Down => unreachable!(),
+        };
+        let pos = Point3::from(self.position);
+        format!(
+            "{} ({axis}) {}, {}, {}",
+            locale.get(letter),
+            pos.x,
+            pos.y,
+            pos.z
+        )
+    }
+}
+
+/// Where the player is and how much of the world is loaded, shown by the debug overlay along
+/// with the chunk borders
+#[derive(Debug, Clone)]
+pub struct DebugOverlay {
+    pub compass: Compass,
+    pub chunk: ChunkPos,
+    pub biome: Biome,
+    pub loaded_chunks: usize,
+    /// Loaded chunks whose mesh needs to be rebuilt
+    pub dirty_chunks: usize,
+    pub meshes: MeshStats,
+    /// How long messages take to get to the server and back, as the connection estimates it
+    pub rtt: Duration,
+    /// `None` until the server has answered
+    pub server: Option<ServerMetrics>,
+}
+
+impl Display for DebugOverlay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let block = Point3::from(self.compass.position);
+        let chunk = Point3::from(self.chunk);
+        write!(
+            f,
+            "block: {}, {}, {}, chunk: {}, {}, {}, facing: {:?}, biome: {:?}, chunks: {} loaded, {} dirty",
+            block.x,
+            block.y,
+            block.z,
+            chunk.x,
+            chunk.y,
+            chunk.z,
+            self.compass.facing,
+            self.biome,
+            self.loaded_chunks,
+            self.dirty_chunks
+        )?;
+        write!(f, " | {}", self.meshes)?;
+        write!(f, " | ping: {} ms", self.rtt.as_millis())?;
+        if let Some(server) = &self.server {
+            write!(f, " | server {server}")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,46 @@
+//! The tokens the client logs in to its accounts with, one per server.
+//!
+//! They are kept apart from the settings, so that sharing those doesn't hand out the accounts.
+//! Each server only ever gets its own token, so it can't log in as the player anywhere else.
+
+use std::path::{Path, PathBuf};
+
+use uuid::Uuid;
+
+use super::chunk_cache::server_file_name;
+
+pub const DIR: &str = "./identities";
+
+/// Where the token for the server at `server` is kept
+pub fn path(server: &str) -> PathBuf {
+    Path::new(DIR).join(format!("{}.txt", server_file_name(server)))
+}
+
+/// The stored token, or `None` if this client has never logged in or the file is invalid
+pub fn load_token(path: impl AsRef<Path>) -> Option<Uuid> {
+    let path = path.as_ref();
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+        Err(e) => {
+            log::warn!("Could not read {}: {e}", path.display());
+            return None;
+        }
+    };
+    match contents.trim().parse() {
+        Ok(token) => Some(token),
+        Err(e) => {
+            log::warn!("Invalid identity token in {}: {e}", path.display());
+            None
+        }
+    }
+}
+
+pub fn save_token(path: impl AsRef<Path>, token: Uuid) -> anyhow::Result<()> {
+    let path = path.as_ref();
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, format!("{token}\n"))?;
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use std::{collections::BTreeMap, fmt::Display};
+
+use bevy_ecs::component::Component;
+use serde::{Deserialize, Serialize};
+
+/// A number of one kind of item, identified by its namespaced name (e.g. `voxels:stone`)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ItemStack {
+    pub item: String,
+    pub count: u32,
+}
+
+impl Display for ItemStack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} x{}", self.item, self.count)
+    }
+}
+
+/// The items a player or other entity carries. Only tracked by the server, clients get a copy
+/// when it changes.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, Component)]
+pub struct Inventory {
+    items: BTreeMap<String, u32>,
+}
+
+impl Inventory {
+    pub fn add(&mut self, stack: &ItemStack) {
+        *self.items.entry(stack.item.clone()).or_default() += stack.count;
+    }
+
+    /// Remove the items in `stack`, returning `false` without removing anything if there aren't
+    /// enough of them
+    pub fn remove(&mut self, stack: &ItemStack) -> bool {
+        let Some(count) = self.items.get_mut(&stack.item) else {
+            return stack.count == 0;
+        };
+        if *count < stack.count {
+            return false;
+        }
+        *count -= stack.count;
+        if *count == 0 {
+            self.items.remove(&stack.item);
+        }
+        true
+    }
+
+    pub fn stacks(&self) -> Vec<ItemStack> {
+        self.items
+            .iter()
+            .map(|(item, &count)| ItemStack {
+                item: item.clone(),
+                count,
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,98 @@
+//! Holding the player in place after joining or respawning, until the terrain around them has
+//! loaded and been meshed, so they don't fall into the void in the meantime.
+
+use std::time::{Duration, Instant};
+
+use cgmath::{InnerSpace, Point3, Vector3};
+
+use super::chunk::{BlockPos, Chunk, ChunkPos};
+
+/// How far around the player the terrain has to be ready, in blocks
+const RADIUS: f32 = 8.0;
+/// Give up waiting after this long, in case some chunk never arrives
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+/// The player being held at `position` until the chunks around it are ready
+#[derive(Debug, Clone, Copy)]
+pub struct Loading {
+    pub position: Point3<f32>,
+    started: Instant,
+}
+
+/// How many of the chunks the player waits for are at each step of loading
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadingProgress {
+    pub total: usize,
+    /// Requested from the server, or already received
+    pub requested: usize,
+    pub received: usize,
+    /// Received and meshed
+    pub ready: usize,
+}
+
+impl LoadingProgress {
+    pub fn is_done(&self) -> bool {
+        self.ready == self.total
+    }
+}
+
+impl Loading {
+    pub fn new(position: Point3<f32>) -> Self {
+        Self {
+            position,
+            started: Instant::now(),
+        }
+    }
+
+    /// The chunks within `RADIUS` of the player, leaving out those too far away to be loaded at
+    /// `loading_distance`
+    pub fn chunks(&self, loading_distance: isize) -> Vec<ChunkPos> {
+        let center = Point3::from(BlockPos::from_point(self.position).chunk_pos());
+        let max_distance = (loading_distance * Chunk::SIZE as isize) as f32;
+        let size = Chunk::SIZE as f32;
+        let mut chunks = vec![];
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let pos = ChunkPos::from(center + Vector3::new(x, y, z));
+                    let min = Point3::from(pos).cast::<f32>().unwrap() * size;
+                    // The point of the chunk closest to the player
+                    let closest = Point3::new(
+                        self.position.x.clamp(min.x, min.x + size),
+                        self.position.y.clamp(min.y, min.y + size),
+                        self.position.z.clamp(min.z, min.z + size),
+                    );
+                    let in_radius = (closest - self.position).magnitude() <= RADIUS;
+                    let loadable = (pos.center() - self.position).magnitude() <= max_distance;
+                    if in_radius && loadable {
+                        chunks.push(pos);
+                    }
+                }
+            }
+        }
+        chunks
+    }
+
+    /// Count the chunks waited for at each step, given which step each of them has reached
+    pub fn progress(
+        &self,
+        loading_distance: isize,
+        requested: impl Fn(ChunkPos) -> bool,
+        received: impl Fn(ChunkPos) -> bool,
+        ready: impl Fn(ChunkPos) -> bool,
+    ) -> LoadingProgress {
+        let chunks = self.chunks(loading_distance);
+        let count = |f: &dyn Fn(ChunkPos) -> bool| chunks.iter().filter(|&&pos| f(pos)).count();
+        LoadingProgress {
+            total: chunks.len(),
+            requested: count(&|pos| requested(pos) || received(pos)),
+            received: count(&received),
+            ready: count(&ready),
+        }
+    }
+
+    /// Waited long enough to let the player go even if not everything has loaded
+    pub fn timed_out(&self) -> bool {
+        self.started.elapsed() >= MAX_WAIT
+    }
+}
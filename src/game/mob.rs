@@ -0,0 +1,47 @@
+use bevy_ecs::component::Component;
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    direction::Direction,
+    mesh::{Mesh, MeshBuilder},
+};
+
+use super::{atlas::Atlas, physics::Collider};
+
+/// A creature wandering around on its own. Only the server simulates mobs, clients draw them
+/// where they are told.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Component)]
+pub struct Mob;
+
+impl Mob {
+    pub const EXTENTS: Vector3<f32> = Vector3::new(0.8, 0.8, 0.8);
+
+    pub fn collider() -> Collider {
+        Collider {
+            enabled: true,
+            gravity: true,
+            extents: Self::EXTENTS,
+            in_liquid: false,
+            step_height: 0.0,
+            step_offset: 0.0,
+            sneaking: false,
+            on_ground: false,
+        }
+    }
+
+    /// A placeholder cube standing on the origin
+    pub fn mesh(atlas: &Atlas, device: &wgpu::Device) -> Mesh {
+        let uv = atlas.uv(atlas.cell("blue"));
+        let mut builder = MeshBuilder::new();
+        for dir in Direction::ALL {
+            builder.add_face_scaled(
+                Vector3::new(0.0, Self::EXTENTS.y / 2.0, 0.0),
+                dir,
+                uv,
+                Self::EXTENTS.y,
+            );
+        }
+        builder.build(atlas.material.clone(), device)
+    }
+}
@@ -1,59 +1,122 @@
 use std::{
     collections::HashMap,
     io::Write,
-    sync::{atomic::Ordering, Arc},
-    time::Duration,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
 use async_std::channel::{Receiver, Sender};
 use bevy_ecs::{
     component::Component,
     entity::Entity,
-    schedule::{Schedule, ScheduleLabel},
+    event::{event_update_system, Events, ManualEventReader},
+    query::With,
+    schedule::{IntoSystemConfigs, Schedule, ScheduleLabel},
     system::{Res, ResMut, Resource},
+    world::Mut,
+};
+use cgmath::{
+    ElementWise, EuclideanSpace, InnerSpace, Point2, Point3, Quaternion, Rad, Rotation3, Vector2,
+    Vector3, Zero,
 };
-use cgmath::{EuclideanSpace, InnerSpace, Point3, Quaternion, Rotation3, Vector2, Vector3};
 use futures::{pin_mut, TryStreamExt};
-use quinn::{Endpoint, TransportConfig};
+use quinn::Endpoint;
+use uuid::Uuid;
 use wgpu::{
     naga::{FastHashMap, FastHashSet},
     RenderPass,
 };
 use winit::{
+    dpi::PhysicalSize,
     event::{ElementState, KeyEvent, MouseButton},
-    keyboard::{KeyCode, PhysicalKey},
+    keyboard::PhysicalKey,
 };
 
 use crate::{
+    arena::ChunkArena,
     assets::AssetManager,
     camera::Camera,
-    input::Input,
-    mesh::{Direction, DrawModel, Mesh, MeshBuilder, MeshVertex},
+    debug_lines::DebugLines,
+    display::WindowSettings,
+    fog::Fog,
+    export,
+    input::{self, Action, ActionEvent, ActionState, Input},
+    light::Lights,
+    locale::Locale,
+    direction::Direction,
+    mesh::{DrawModel, Mesh, MeshBuilder, MeshVertex},
     meshifier::ChunkMeshifier,
+    occlusion::{self, ChunkVisibility},
     object::Object,
+    settings::Settings,
+    upload::Uploader,
     server::{
-        connection::{RemoteTransport, Respond, SkipServerVerification, Transaction, Transport},
-        message::{MessageToClient, MessageToServer},
+        connection::{Disconnect, RemoteTransport, Respond, Transaction, Transport},
+        message::{MessageToClient, MessageToServer, Orientation, PROTOCOL_VERSION},
+        metrics::ServerMetrics,
     },
-    Instance,
+    Instance, RenderMode,
 };
 
 use self::{
     atlas::Atlas,
-    block::{BlockAttributes, BlockId, BlockRegistry},
-    chunk::{BlockPos, Chunk, ChunkPos},
-    physics::Collider,
-    player::PlayerController,
+    diagnostics::{LoadingDiagnostics, MeshStats, RenderStats},
+    frame_timing::{FrameTiming, MeshingBudget},
+    edits::{EditHistory, EditJournal},
+    hud::{Compass, DebugOverlay},
+    block::{Aabb, BlockRegistry},
+    inventory::ItemStack,
+    loading::{Loading, LoadingProgress},
+    mob::Mob,
+    npc::Npc,
+    chat::{ChatInput, ChatInputEvent, ChatLog},
+    chunk::{BlockPos, Chunk, ChunkPos, LodChunk},
+    chunk_cache::ChunkCache,
+    particles::Particles,
+    physics::{Collider, Interpolation},
+    player::{PlayerController, PositionSync},
+    rebind::RebindMenu,
+    spectator::Spectator,
+    shadow::BlobShadow,
+    trade::{TradeInput, TradeMenu},
     world::World,
+    worldgen::Worldgen,
 };
 
 pub mod atlas;
+pub mod biome;
 pub mod block;
+pub mod chat;
 pub mod chunk;
-mod physics;
-mod player;
+mod chunk_cache;
+pub mod diagnostics;
+pub mod dimension;
+mod edits;
+pub mod fluid;
+pub mod frame_timing;
+mod identity;
+mod particles;
+pub mod physics;
+pub mod hud;
+pub mod inventory;
+pub mod loading;
+pub mod mob;
+pub mod npc;
+pub mod ore;
+pub mod player;
+mod rebind;
+mod shadow;
+pub mod signal;
+mod spectator;
+pub mod structure;
+mod trade;
 pub mod world;
-mod worldgen;
+pub mod worldgen;
+pub mod worldgen_snapshot;
 
 #[derive(Clone, Copy, PartialEq, Component)]
 pub struct Position(pub Point3<f32>);
@@ -65,69 +128,233 @@ pub struct Game {
     chunk_meshifier: ChunkMeshifier,
     ecs_world: bevy_ecs::world::World,
     block_select_object: Object,
+    /// The boxes of the targeted block, `None` when no block is within reach
+    block_select_boxes: Option<&'static [Aabb]>,
+    /// The outline meshes built so far, one for each shape that has been targeted
+    block_select_meshes: Vec<(&'static [Aabb], Arc<Mesh>)>,
     show_select_object: bool,
-    chunk_objects: FastHashMap<ChunkPos, Object>,
+    shadow_objects: FastHashMap<Entity, Object>,
+    npc_objects: FastHashMap<Entity, Object>,
+    /// The entities of the mobs the server has told about
+    mobs: FastHashMap<Uuid, Entity>,
+    mob_objects: FastHashMap<Entity, Object>,
+    /// Last known positions and orientations of the other players on the server
+    other_players: FastHashMap<Uuid, (Point3<f32>, Orientation)>,
+    particle_object: Option<Object>,
+    /// Opaque meshes of the loaded chunks
+    chunk_arena: ChunkArena,
+    translucent_chunk_objects: FastHashMap<ChunkPos, Object>,
+    /// Render cache entries removed by `reconcile_chunk_objects` since startup
+    stale_render_entries: usize,
+    /// Chunks left out of the last frame by occlusion culling
+    occluded_chunks: usize,
+    meshing_budget: MeshingBudget,
     chunk_loading_distance: isize,
+    /// Whether the server streams the chunks around the player instead of them being requested
+    chunk_streaming: bool,
+    /// Downsampled chunks rendered beyond `chunk_loading_distance`
+    lod_chunks: FastHashMap<ChunkPos, LodChunk>,
+    lod_chunk_objects: FastHashMap<ChunkPos, Object>,
+    translucent_lod_chunk_objects: FastHashMap<ChunkPos, Object>,
+    /// LOD chunks requested from the server, and the level they were requested at
+    loading_lod_chunks: FastHashMap<ChunkPos, u8>,
     server_connection: Transport,
     load_chunk_tx: Sender<Transaction<MessageToClient>>,
-    chunk_loaded_rx: Receiver<Vec<Chunk>>,
-    loading_chunks: FastHashSet<ChunkPos>,
+    chunk_loaded_rx: Receiver<LoadedChunks>,
+    /// Chunks requested from the server, and when they were requested
+    loading_chunks: FastHashMap<ChunkPos, Instant>,
+    chunk_cache: Option<ChunkCache>,
+    pending_chunk_transactions: Arc<AtomicUsize>,
+    pub show_loading_diagnostics: bool,
+    pub show_debug_overlay: bool,
+    /// What the server last said about how it is doing, requested while the debug overlay is
+    /// shown
+    server_metrics: Option<ServerMetrics>,
+    metrics_requested: Option<Instant>,
+    /// Time in seconds not yet simulated by a physics step, less than a step
+    physics_time: f32,
+    /// The player fell out of the world and is waiting for the server to respawn them
+    respawn_requested: bool,
+    /// Set while the player is held in place until the terrain around them is ready
+    loading: Option<Loading>,
+    /// Reads the action events for the toggles handled outside of the ECS schedule
+    action_reader: ManualEventReader<ActionEvent>,
+    rebind_menu: Option<RebindMenu>,
+    trade_menu: Option<TradeMenu>,
+    chat_input: Option<ChatInput>,
+    /// The player's inventory, as last sent by the server
+    inventory: Vec<ItemStack>,
+    /// First corner of the region to export, marked but still waiting for the opposite one
+    export_corner: Option<BlockPos>,
     msg_queue_rx: Receiver<MessageToServer>,
+    /// Why the connection to the server ended, once it has
+    disconnect: Option<Disconnect>,
     msg_from_server_rx: Receiver<(MessageToClient, Respond<MessageToServer>)>,
 }
 
+/// Print why connecting to the server failed and exit, as there is nothing to play without it
+fn connection_failed(locale: &Locale, reason: &dyn std::fmt::Display) -> ! {
+    eprintln!("{}", locale.format("connection.failed", &[("reason", reason)]));
+    std::process::exit(1);
+}
+
+/// Connect to the server at `address` and log in, returning the connection and where the
+/// player spawns. Exits if that fails.
+///
+/// Players log in with the identity token saved for the server, if there is one. If the server
+/// doesn't accept it, e.g. because its world was reset, they connect again and log in by name.
+async fn connect(
+    settings: &Settings,
+    address: SocketAddr,
+    locale: &Locale,
+) -> (Transport, Point3<f32>) {
+    let token_path = identity::path(&address.to_string());
+    let mut token = identity::load_token(&token_path);
+    loop {
+        let transport = open_connection(settings, address, locale).await;
+        let response = match transport
+            .transact::<_, MessageToClient>(&MessageToServer::Connect {
+                name: settings.player_name.clone(),
+                token,
+            })
+            .await
+        {
+            Ok(mut transaction) => transaction.single().await,
+            Err(e) => Err(e),
+        };
+        match response.unwrap_or_else(|e| connection_failed(locale, &e)) {
+            MessageToClient::LoggedIn {
+                player,
+                name,
+                token,
+                spawn,
+            } => {
+                println!("Logged in as {name} ({player})");
+                if let Err(e) = identity::save_token(&token_path, token) {
+                    log::warn!("Could not save identity token: {e:#}");
+                }
+                return (transport, Point3::from_vec(spawn));
+            }
+            MessageToClient::LoginRejected(reason) if token.is_some() => {
+                log::warn!("Identity token rejected, logging in by name instead: {reason}");
+                token = None;
+            }
+            MessageToClient::LoginRejected(reason) => {
+                eprintln!(
+                    "{}",
+                    locale.format("connection.login_rejected", &[("reason", &reason)])
+                );
+                std::process::exit(1);
+            }
+            msg => connection_failed(
+                locale,
+                &format!("unexpected response to logging in: {}", msg.name()),
+            ),
+        }
+    }
+}
+
+/// Open a connection to the server at `address` and check that it speaks the same protocol.
+/// Exits if that fails.
+async fn open_connection(settings: &Settings, address: SocketAddr, locale: &Locale) -> Transport {
+    let client = quinn::Endpoint::client("[::]:0".parse().unwrap())
+        .unwrap_or_else(|e| connection_failed(locale, &e));
+    let client_config = settings
+        .server_trust
+        .client_config()
+        .unwrap_or_else(|e| connection_failed(locale, &format!("{e:#}")));
+    let mut client_config = quinn::ClientConfig::new(Arc::new(client_config));
+    client_config.transport_config(Arc::new(settings.keep_alive.transport_config()));
+    println!("Connecting...");
+    let connection = client
+        .connect_with(client_config, address, &settings.server_name)
+        .unwrap_or_else(|e| connection_failed(locale, &e))
+        .await
+        .unwrap_or_else(|e| connection_failed(locale, &e));
+    println!("Connected!");
+    let transport = Transport::Remote(RemoteTransport::new(connection));
+
+    let response = match transport
+        .transact::<_, MessageToClient>(&MessageToServer::Version(PROTOCOL_VERSION))
+        .await
+    {
+        Ok(mut transaction) => transaction.single().await,
+        Err(e) => Err(e),
+    };
+    let response = response.unwrap_or_else(|e| connection_failed(locale, &e));
+    match response {
+        MessageToClient::Ok => {}
+        MessageToClient::IncompatibleVersion { server } => {
+            eprintln!(
+                "{}",
+                locale.format(
+                    "connection.incompatible_version",
+                    &[("server", &server), ("client", &PROTOCOL_VERSION)]
+                )
+            );
+            std::process::exit(1);
+        }
+        msg => connection_failed(
+            locale,
+            &format!(
+                "unexpected response to the protocol version: {}",
+                msg.name()
+            ),
+        ),
+    }
+
+    transport
+}
+
+/// Chunk data received in response to a chunk request
+enum LoadedChunks {
+    Full(Vec<Chunk>),
+    Lod(Vec<LodChunk>),
+    /// Chunks the server said were cached or sent the changes to, but whose cached copy could
+    /// not be read or updated, to be requested again
+    Uncached(Vec<ChunkPos>),
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ScheduleLabel)]
 pub enum ScheduleStage {
     Update,
+    /// Runs in fixed steps of `physics::STEP` after `Update`, as many as fit in the frame
+    Physics,
 }
 
 #[derive(Clone, Copy, PartialEq, Resource)]
 pub struct DeltaTime(pub f32);
 
+/// Factor UI elements should be scaled by to appear the same size on any display
+#[derive(Clone, Copy, PartialEq, Resource)]
+pub struct UiScale(pub f32);
+
 #[derive(Clone, Resource)]
 pub struct MessageQueue(Sender<MessageToServer>);
 
 impl Game {
-    pub async fn new(asset_manager: &mut AssetManager, device: &wgpu::Device) -> Self {
-        let material = asset_manager.load_material("assets/atlas.png").unwrap();
-        let atlas = Atlas::new(material.clone(), 16);
-
-        let mut block_registry = BlockRegistry::new();
-
-        let air_block_attr = BlockAttributes {
-            transparent: true,
-            invisible: true,
-            uv_coords: [0, 0].into(),
-        };
-        block_registry.register(BlockId(0), air_block_attr);
-
-        let dirt_block_attr = BlockAttributes {
-            transparent: false,
-            invisible: false,
-            uv_coords: [0, 0].into(),
-        };
-        block_registry.register(BlockId(1), dirt_block_attr);
-
-        let stone_block_attr = BlockAttributes {
-            transparent: false,
-            invisible: false,
-            uv_coords: [1, 0].into(),
-        };
-        block_registry.register(BlockId(2), stone_block_attr);
+    pub async fn new(
+        asset_manager: &mut AssetManager,
+        device: &wgpu::Device,
+        address: SocketAddr,
+    ) -> Self {
+        let settings = Settings::load(Settings::PATH);
 
-        let neco_arc_block_attr = BlockAttributes {
-            transparent: false,
-            invisible: false,
-            uv_coords: [2, 0].into(),
-        };
-        block_registry.register(BlockId(3), neco_arc_block_attr);
+        let atlas = asset_manager
+            .load_atlas("assets/blocks", 16, settings.texture_filtering)
+            .unwrap();
 
-        let blue_block_attr = BlockAttributes {
-            transparent: false,
-            invisible: false,
-            uv_coords: [3, 0].into(),
-        };
-        block_registry.register(BlockId(4), blue_block_attr);
+        let block_registry = BlockRegistry::with_default_blocks();
+        for (id, attr) in block_registry.iter() {
+            let textures = std::iter::once(&attr.texture)
+                .chain(&attr.texture_variants)
+                .chain(&attr.powered_texture);
+            for texture in textures {
+                if !attr.invisible && !atlas.contains(texture) {
+                    log::warn!("Block {id:?} uses missing texture {texture:?}");
+                }
+            }
+        }
 
         let mut world = World::new();
 
@@ -141,7 +368,9 @@ impl Game {
 
         let camera = Camera::new([0.0, 0.0, 0.0], cgmath::Deg(0.0), cgmath::Deg(0.0));
 
-        let input = Input::new();
+        let mut input = Input::new();
+        input.mouse_settings = settings.mouse;
+        input.input_map = settings.keys.clone();
 
         let mut ecs_world = bevy_ecs::world::World::new();
         ecs_world.insert_resource(world);
@@ -149,10 +378,41 @@ impl Game {
         ecs_world.insert_resource(camera);
         ecs_world.insert_resource(input);
         ecs_world.insert_resource(DeltaTime(1.0 / 60.0));
+        ecs_world.insert_resource(FrameTiming::default());
+        ecs_world.insert_resource(UiScale(1.0));
+        ecs_world.insert_resource(Lights::default());
+        ecs_world.insert_resource(Locale::load(&settings.language));
+        ecs_world.insert_resource(settings.clone());
+        ecs_world.insert_resource(Particles::default());
+        ecs_world.insert_resource(ChatLog::default());
+        ecs_world.insert_resource(EditJournal::default());
+        ecs_world.insert_resource(PositionSync::default());
+        ecs_world.insert_resource(EditHistory::new(settings.edit_history_size));
+        ecs_world.insert_resource(Spectator::default());
+        ecs_world.init_resource::<Events<ActionEvent>>();
 
         let mut schedule = Schedule::new(ScheduleStage::Update);
+        schedule.add_systems(
+            (event_update_system::<ActionEvent>, input::action_event_system)
+                .chain()
+                .before(player::update_system),
+        );
         schedule.add_systems(player::update_system);
-        schedule.add_systems(physics::physics_system);
+        schedule.add_systems(edits::undo_system.after(player::update_system));
+        schedule.add_systems(
+            spectator::update_system
+                .after(player::update_system)
+                .after(input::action_event_system),
+        );
+        schedule.add_systems(player::held_item_light_system.after(player::update_system));
+        schedule.add_systems(player::fov_system.after(player::update_system));
+        schedule.add_systems(player::position_sync_system.after(player::update_system));
+        schedule.add_systems(particles::particle_system);
+        schedule.add_systems(
+            particles::ambient_particle_system
+                .after(particles::particle_system)
+                .after(player::update_system),
+        );
         schedule.add_systems({
             #[derive(Resource)]
             struct T(f32, f32);
@@ -169,80 +429,32 @@ impl Game {
         });
         ecs_world.add_schedule(schedule);
 
+        let mut physics_schedule = Schedule::new(ScheduleStage::Physics);
+        physics_schedule
+            .add_systems((physics::store_previous_system, physics::physics_system).chain());
+        ecs_world.add_schedule(physics_schedule);
+
+        // Put where the server says once logged in
         ecs_world.spawn((
-            Position([0.0, 20.0, 0.0].into()),
+            Position(Point3::new(0.0, 0.0, 0.0)),
             Velocity([0.0, 0.0, 0.0].into()),
             Collider {
                 enabled: false,
                 gravity: false,
                 extents: [0.5, 1.8, 0.5].into(),
+                in_liquid: false,
+                step_height: 1.0,
+                step_offset: 0.0,
+                sneaking: false,
+                on_ground: false,
             },
+            BlobShadow { radius: 0.4 },
+            Interpolation::new(Point3::new(0.0, 0.0, 0.0)),
             PlayerController::new(),
         ));
 
         let block_select_object = Object::new(
-            {
-                let mut builder = MeshBuilder::new();
-                let line_width = 0.01;
-                let dist = -line_width / 2.0 + 0.001;
-                let far = 0.5 + dist + line_width / 2.0;
-                let close = 0.5 + dist - line_width / 2.0;
-                let vertices = [
-                    // Top line
-                    [-far, far],
-                    [far, far],
-                    [close, close],
-                    [-close, close],
-                    // Right line
-                    [close, close],
-                    [far, far],
-                    [far, -far],
-                    [close, -close],
-                    // Bottom line
-                    [-close, -close],
-                    [close, -close],
-                    [far, -far],
-                    [-far, -far],
-                    // Left line
-                    [-far, far],
-                    [-close, close],
-                    [-close, -close],
-                    [-far, -far],
-                ];
-                let indices = [
-                    // Top line
-                    0, 3, 1, 1, 3, 2, // Right line
-                    4, 7, 5, 5, 7, 6, // Bottom line
-                    8, 11, 9, 9, 11, 10, // Left line
-                    12, 15, 13, 13, 15, 14,
-                ];
-                let dirs = [
-                    Direction::North,
-                    Direction::South,
-                    Direction::East,
-                    Direction::West,
-                    Direction::Up,
-                    Direction::Down,
-                ];
-
-                for dir in dirs {
-                    let vertices = vertices
-                        .into_iter()
-                        .map(|v| MeshVertex {
-                            position: (dir.on_plane(v.into())
-                                + dir.normal() * far
-                                + Vector3::new(0.5, 0.5, 0.5))
-                            .into(),
-                            tex_coords: [0.0, 0.0],
-                            ambient_occlusion: 1.0,
-                            normal: dir.normal().into(),
-                        })
-                        .collect::<Vec<_>>();
-                    builder.add_vert_indices(&vertices, &indices);
-                }
-
-                builder.build(material, device).into()
-            },
+            block_select_mesh(&[Aabb::FULL], &atlas, device).into(),
             Instance {
                 position: [0.0, 0.0, 0.0].into(),
                 rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
@@ -250,52 +462,105 @@ impl Game {
             device,
         );
 
-        let client = quinn::Endpoint::client("[::]:0".parse().unwrap()).unwrap();
-        let client_config = rustls::ClientConfig::builder()
-            .with_safe_defaults()
-            .with_custom_certificate_verifier(Arc::new(SkipServerVerification))
-            .with_no_client_auth();
-        let mut tc = TransportConfig::default();
-        tc.keep_alive_interval(Some(Duration::from_secs_f32(5.0).try_into().unwrap()));
-        let mut client_config = quinn::ClientConfig::new(Arc::new(client_config));
-        client_config.transport_config(Arc::new(tc));
-        println!("Connecting...");
-        let connection = client
-            .connect_with(client_config, "[::1]:1234".parse().unwrap(), "localhost")
-            .unwrap()
-            .await
-            .unwrap();
-        println!("Connected!");
+        let mut chunk_meshifier = ChunkMeshifier::new();
+        chunk_meshifier.enable_ao = settings.ambient_occlusion;
+        chunk_meshifier.smooth_lighting = settings.smooth_lighting;
+
+        let locale = ecs_world.resource::<Locale>();
+        let (transport, spawn_position) = connect(&settings, address, locale).await;
 
         let (load_chunk_tx, load_chunk_rx) =
             async_std::channel::unbounded::<Transaction<MessageToClient>>();
-        let (chunk_loaded_tx, chunk_loaded_rx) = async_std::channel::unbounded::<Vec<Chunk>>();
-
+        let (chunk_loaded_tx, chunk_loaded_rx) = async_std::channel::unbounded::<LoadedChunks>();
+        let pending_chunk_transactions = Arc::new(AtomicUsize::new(0));
+
+        let chunk_cache = settings
+            .chunk_cache
+            .then(|| ChunkCache::open(ChunkCache::DIR, &address.to_string()))
+            .and_then(|cache| {
+                cache
+                    .map_err(|e| log::warn!("Could not open the chunk cache: {e:#}"))
+                    .ok()
+            });
+
+        let pending = pending_chunk_transactions.clone();
+        let cache = chunk_cache.clone();
         async_std::task::spawn(async move {
             loop {
                 let mut transaction = load_chunk_rx.recv().await.unwrap();
                 let tx = chunk_loaded_tx.clone();
+                let pending = pending.clone();
+                let cache = cache.clone();
                 async_std::task::spawn(async move {
                     let stream = transaction.stream();
                     pin_mut!(stream);
                     while let Ok(Some(msg)) = stream.try_next().await {
                         let chunks = match msg {
-                            MessageToClient::Chunk(chunk) => vec![chunk],
-                            MessageToClient::Chunks(chunks) => chunks,
-                            _ => unreachable!(),
+                            MessageToClient::Chunk(chunk) => LoadedChunks::Full(vec![chunk]),
+                            MessageToClient::Chunks(chunks) => {
+                                if let Some(cache) = &cache {
+                                    cache.store_all(&chunks);
+                                }
+                                LoadedChunks::Full(
+                                    chunks.into_iter().map(|(chunk, _)| chunk).collect(),
+                                )
+                            }
+                            MessageToClient::ChunksUnchanged(positions) => {
+                                let Some(cache) = &cache else {
+                                    log::warn!("Chunks were said to be cached without a cache");
+                                    break;
+                                };
+                                let (chunks, uncached) = cache.load_all(positions);
+                                if !uncached.is_empty() {
+                                    tx.send(LoadedChunks::Uncached(uncached)).await.unwrap();
+                                }
+                                LoadedChunks::Full(chunks)
+                            }
+                            MessageToClient::ChunkDelta {
+                                pos,
+                                revision,
+                                hash,
+                                changes,
+                            } => {
+                                let Some(cache) = &cache else {
+                                    log::warn!("Chunk changes were sent without a cache");
+                                    break;
+                                };
+                                match cache.update(pos, revision, hash, &changes) {
+                                    Ok(chunk) => LoadedChunks::Full(vec![chunk]),
+                                    Err(e) => {
+                                        log::warn!("Could not update cached chunk: {e:#}");
+                                        cache.remove(pos);
+                                        LoadedChunks::Uncached(vec![pos])
+                                    }
+                                }
+                            }
+                            MessageToClient::LodChunks(chunks) => LoadedChunks::Lod(chunks),
+                            msg => {
+                                log::warn!("Unexpected response to loading chunks: {}", msg.name());
+                                break;
+                            }
                         };
                         tx.send(chunks).await.unwrap();
                     }
+                    pending.fetch_sub(1, Ordering::Relaxed);
                 });
             }
         });
 
         let (msg_from_server_tx, msg_from_server_rx) = async_std::channel::unbounded();
-        let transport = Transport::Remote(RemoteTransport { connection });
         let mut tp = transport.clone();
         async_std::task::spawn(async move {
             loop {
-                let (msg, respond) = tp.accept_transact::<MessageToClient, MessageToServer>().await.unwrap();
+                let (msg, respond) =
+                    match tp.accept_transact::<MessageToClient, MessageToServer>().await {
+                        Ok(x) => x,
+                        Err(e) => {
+                            log::warn!("Stopped receiving messages from the server: {e}");
+                            tp.close(&e.to_string());
+                            break;
+                        }
+                    };
 
                 msg_from_server_tx.send((msg, respond)).await.unwrap();
             }
@@ -304,28 +569,83 @@ impl Game {
         let (msg_queue_tx, msg_queue_rx) = async_std::channel::unbounded();
         ecs_world.insert_resource(MessageQueue(msg_queue_tx));
 
+        let (mut pos, mut interpolation) = ecs_world
+            .query_filtered::<(&mut Position, &mut Interpolation), With<PlayerController>>()
+            .single_mut(&mut ecs_world);
+        pos.0 = spawn_position;
+        interpolation.reset(spawn_position);
+
+        if settings.chunk_streaming {
+            transport.send(&MessageToServer::StreamChunks {
+                distance: settings.chunk_loading_distance.max(0) as u32,
+            });
+        }
+
+        let chunk_arena = ChunkArena::new(atlas.material.clone(), device);
+
         Self {
             atlas,
-            chunk_meshifier: ChunkMeshifier::new(),
+            chunk_meshifier,
             ecs_world,
             block_select_object,
+            block_select_boxes: None,
+            block_select_meshes: vec![],
             show_select_object: true,
-            chunk_objects: FastHashMap::default(),
-            chunk_loading_distance: 5,
+            shadow_objects: FastHashMap::default(),
+            npc_objects: FastHashMap::default(),
+            mobs: FastHashMap::default(),
+            mob_objects: FastHashMap::default(),
+            other_players: FastHashMap::default(),
+            particle_object: None,
+            chunk_arena,
+            translucent_chunk_objects: FastHashMap::default(),
+            stale_render_entries: 0,
+            occluded_chunks: 0,
+            meshing_budget: MeshingBudget::new(settings.meshes_per_frame),
+            chunk_loading_distance: settings.chunk_loading_distance,
+            chunk_streaming: settings.chunk_streaming,
+            lod_chunks: FastHashMap::default(),
+            lod_chunk_objects: FastHashMap::default(),
+            translucent_lod_chunk_objects: FastHashMap::default(),
+            loading_lod_chunks: FastHashMap::default(),
             server_connection: transport,
             load_chunk_tx,
             chunk_loaded_rx,
-            loading_chunks: FastHashSet::default(),
+            loading_chunks: FastHashMap::default(),
+            chunk_cache,
+            pending_chunk_transactions,
+            show_loading_diagnostics: true,
+            show_debug_overlay: false,
+            server_metrics: None,
+            metrics_requested: None,
+            physics_time: 0.0,
+            respawn_requested: false,
+            loading: Some(Loading::new(spawn_position)),
+            action_reader: ManualEventReader::default(),
+            rebind_menu: None,
+            trade_menu: None,
+            chat_input: None,
+            inventory: vec![],
+            export_corner: None,
             msg_queue_rx,
+            disconnect: None,
             msg_from_server_rx,
         }
     }
 
     pub async fn update(&mut self, dt: Duration) {
         std::io::stdout().flush().unwrap();
+        // A long hitch, e.g. from dragging the window, is played as a shorter frame
+        let dt = dt.min(Duration::from_secs_f32(physics::MAX_FRAME_TIME));
         self.ecs_world.resource_mut::<DeltaTime>().0 = dt.as_secs_f32();
+        self.ecs_world.resource_mut::<FrameTiming>().record(dt);
+        self.ecs_world
+            .resource_mut::<Input>()
+            .begin_frame(dt.as_secs_f32());
         self.ecs_world.run_schedule(ScheduleStage::Update);
+        self.step_physics(dt.as_secs_f32());
         self.ecs_world.resource_mut::<Input>().end_frame();
+        self.apply_toggles();
 
         let player_pos = self
             .ecs_world
@@ -333,50 +653,204 @@ impl Game {
             .single(&self.ecs_world)
             .0
              .0;
-        let world = &mut self.ecs_world.resource_mut::<World>();
-
+        // Chunks are loaded around wherever the world is seen from
+        let view_pos = self
+            .ecs_world
+            .resource::<Spectator>()
+            .view_position(self.ecs_world.resource::<Camera>(), player_pos);
+        let mut teleport = None;
+        let mut respawned = false;
+        // Collected separately, as `World` is borrowed from the ECS world
+        let mut chat = vec![];
+        let mut block_changes = vec![];
+        let mut streamed_chunks = vec![];
+        let mut unstreamed_chunks = vec![];
         while let Ok((msg, respond)) = self.msg_from_server_rx.try_recv() {
             match msg {
                 MessageToClient::Ok => todo!(),
                 MessageToClient::EntitiesPositionUpdate { entity, new_position } => todo!(),
-                MessageToClient::Chunk(_) => todo!(),
-                MessageToClient::Chunks(_) => todo!(),
-                MessageToClient::BlockPlaced { pos, new_block } => {
-                    world.place_block(new_block, pos);
+                MessageToClient::Chunk(chunk) => streamed_chunks.push(chunk),
+                MessageToClient::Chunks(chunks) => {
+                    streamed_chunks.extend(chunks.into_iter().map(|(chunk, _)| chunk));
+                }
+                MessageToClient::UnloadChunks(chunks) => unstreamed_chunks.extend(chunks),
+                MessageToClient::LodChunks(_) => {
+                    log::warn!("LOD chunks were sent without being requested");
+                }
+                MessageToClient::ChunksUnchanged(_) => {
+                    log::warn!("Chunks were said to be unchanged without being requested");
+                }
+                MessageToClient::ChunkDelta { pos, .. } => {
+                    log::warn!("Changes to chunk {pos:?} were sent without being requested");
+                }
+                MessageToClient::BlocksPlaced { changes, acknowledged_edit } => {
+                    block_changes.push((changes, acknowledged_edit));
                 },
+                MessageToClient::Teleport { position } => {
+                    teleport = Some(Point3::from_vec(position));
+                }
+                MessageToClient::Respawn { position } => {
+                    teleport = Some(Point3::from_vec(position));
+                    respawned = true;
+                }
+                MessageToClient::Chat(text) => chat.push(text),
+                MessageToClient::PlayerMoved {
+                    player,
+                    position,
+                    orientation,
+                } => {
+                    self.other_players
+                        .insert(player, (Point3::from_vec(position), orientation));
+                }
+                MessageToClient::NpcSpawned { npc, name, position } => {
+                    self.ecs_world.spawn((
+                        Npc { id: npc, name },
+                        Position(Point3::from_vec(position)),
+                        BlobShadow { radius: 0.5 },
+                    ));
+                }
+                MessageToClient::Mobs { moved, removed } => {
+                    for (id, position) in moved {
+                        let position = Position(Point3::from_vec(position));
+                        match self.mobs.get(&id) {
+                            Some(&entity) => {
+                                self.ecs_world.entity_mut(entity).insert(position);
+                            }
+                            None => {
+                                let entity = self
+                                    .ecs_world
+                                    .spawn((Mob, position, BlobShadow { radius: 0.4 }))
+                                    .id();
+                                self.mobs.insert(id, entity);
+                            }
+                        }
+                    }
+                    for id in removed {
+                        if let Some(entity) = self.mobs.remove(&id) {
+                            self.ecs_world.despawn(entity);
+                        }
+                    }
+                }
+                MessageToClient::TradeOffers { npc, offers } => {
+                    let name = self
+                        .ecs_world
+                        .query::<&Npc>()
+                        .iter(&self.ecs_world)
+                        .find(|candidate| candidate.id == npc)
+                        .map_or_else(|| "?".to_string(), |npc| npc.name.clone());
+                    self.trade_menu = Some(TradeMenu::open(npc, &name, offers, self.locale()));
+                    self.print_inventory();
+                }
+                MessageToClient::WorldSeed(seed) => {
+                    self.ecs_world.resource_mut::<World>().worldgen = Worldgen::with_seed(seed);
+                }
+                MessageToClient::Inventory(stacks) => {
+                    self.inventory = stacks;
+                    if self.trade_menu.is_some() {
+                        self.print_inventory();
+                    }
+                }
+                MessageToClient::Metrics(metrics) => {
+                    self.server_metrics = Some(metrics);
+                }
+                MessageToClient::IncompatibleVersion { .. }
+                | MessageToClient::LoggedIn { .. }
+                | MessageToClient::LoginRejected(_) => {
+                    unreachable!("only sent in response to connecting")
+                }
             }
         }
+        if self.disconnect.is_none() {
+            self.disconnect = self.server_connection.disconnect();
+            let message = self.disconnect.as_ref().and_then(|d| self.disconnect_message(d));
+            if let Some(text) = message {
+                println!("{text}");
+                chat.push(text);
+            }
+        }
+        let mut chat_log = self.ecs_world.resource_mut::<ChatLog>();
+        for text in chat {
+            chat_log.push(text);
+        }
+        self.ecs_world
+            .resource_scope(|ecs_world, mut edits: Mut<EditJournal>| {
+                let mut world = ecs_world.resource_mut::<World>();
+                for (changes, acknowledged_edit) in block_changes {
+                    edits.reconcile(changes, acknowledged_edit, &mut world);
+                }
+            });
+
+        if let Some(position) = teleport {
+            self.move_player(position);
+        }
+        if respawned {
+            let mut camera = self.ecs_world.resource_mut::<Camera>();
+            camera.yaw = Rad(0.0);
+            camera.pitch = Rad(0.0);
+            self.respawn_requested = false;
+            self.loading = teleport.map(Loading::new);
+        } else if player_pos.y < Self::VOID_DEPTH && !self.respawn_requested {
+            self.server_connection.send(&MessageToServer::Respawn);
+            self.respawn_requested = true;
+        }
+        if let Some(loading) = self.loading {
+            let done = self.loading_progress().is_some_and(|progress| progress.is_done());
+            if done || loading.timed_out() {
+                self.loading = None;
+            } else {
+                self.move_player(loading.position);
+            }
+        }
+
+        let forward = self.ecs_world.resource::<Camera>().forward();
+        let world = &mut self.ecs_world.resource_mut::<World>();
 
         while let Ok(x) = self.msg_queue_rx.try_recv() {
-            self.server_connection.transact::<_, ()>(&x).await.unwrap();
+            self.server_connection.send(&x);
         }
 
-        let mut chunks_to_destroy = vec![];
+        if self.show_debug_overlay
+            && self
+                .metrics_requested
+                .is_none_or(|requested| requested.elapsed() >= Self::METRICS_INTERVAL)
+        {
+            self.server_connection.send(&MessageToServer::GetMetrics);
+            self.metrics_requested = Some(Instant::now());
+        }
+
+        for chunk in streamed_chunks {
+            world.chunks.insert(chunk.pos, chunk);
+        }
+
+        let mut chunks_to_destroy = unstreamed_chunks;
 
         let allowed_distance = Chunk::SIZE as f32
             * Chunk::SIZE as f32
             * self.chunk_loading_distance as f32
             * self.chunk_loading_distance as f32;
 
-        for chunk in world.chunks.values() {
-            let pos = chunk.pos.center();
-            let dist2 = (pos - player_pos).magnitude2();
+        // Streamed chunks are unloaded when the server says so
+        if !self.chunk_streaming {
+            for chunk in world.chunks.values() {
+                let pos = chunk.pos.center();
+                let dist2 = (pos - view_pos).magnitude2();
 
-            if dist2 > allowed_distance {
-                chunks_to_destroy.push(chunk.pos);
+                if dist2 > allowed_distance {
+                    chunks_to_destroy.push(chunk.pos);
+                }
             }
-        }
 
-        if !chunks_to_destroy.is_empty() {
-            self.server_connection
-                .transact::<_, ()>(&MessageToServer::UnloadChunks(chunks_to_destroy.clone()))
-                .await
-                .unwrap();
+            for batch in chunks_to_destroy.chunks(MessageToServer::MAX_CHUNKS) {
+                self.server_connection
+                    .send(&MessageToServer::UnloadChunks(batch.to_vec()));
+            }
         }
 
         for chunk_pos in chunks_to_destroy {
             world.delete_chunk(chunk_pos);
-            self.chunk_objects.remove(&chunk_pos);
+            self.chunk_arena.remove(chunk_pos);
+            self.translucent_chunk_objects.remove(&chunk_pos);
+            self.chunk_meshifier.remove(chunk_pos);
             for dir in Direction::ALL {
                 if let Some(chunk) = world.chunk(chunk_pos + dir.normal()) {
                     chunk.set_dirty(true);
@@ -384,91 +858,316 @@ impl Game {
             }
         }
 
-        let player_chunk_pos = BlockPos::from_point(player_pos).chunk_pos();
+        let view_chunk_pos = BlockPos::from_point(view_pos).chunk_pos();
 
         let mut chunks_to_load = vec![];
-        for x in -self.chunk_loading_distance..=self.chunk_loading_distance {
-            for y in -self.chunk_loading_distance..=self.chunk_loading_distance {
-                for z in -self.chunk_loading_distance..=self.chunk_loading_distance {
-                    let chunk_pos =
-                        ChunkPos::from(Point3::from(player_chunk_pos) + Vector3::from([x, y, z]));
-                    if world.chunk(chunk_pos).is_some() || self.loading_chunks.contains(&chunk_pos)
-                    {
-                        continue;
-                    }
-                    let center = chunk_pos.center();
-
-                    let dist2 = (center - player_pos).magnitude2();
-
-                    if dist2 <= allowed_distance {
-                        // world.generate_chunk(chunk_pos);
-                        chunks_to_load.push(chunk_pos);
+        // Streamed chunks arrive without asking
+        if !self.chunk_streaming {
+            for x in -self.chunk_loading_distance..=self.chunk_loading_distance {
+                for y in -self.chunk_loading_distance..=self.chunk_loading_distance {
+                    for z in -self.chunk_loading_distance..=self.chunk_loading_distance {
+                        let chunk_pos = ChunkPos::from(
+                            Point3::from(view_chunk_pos) + Vector3::from([x, y, z]),
+                        );
+                        if world.chunk(chunk_pos).is_some()
+                            || self.loading_chunks.contains_key(&chunk_pos)
+                        {
+                            continue;
+                        }
+                        let center = chunk_pos.center();
+
+                        let dist2 = (center - view_pos).magnitude2();
+
+                        if dist2 <= allowed_distance {
+                            // world.generate_chunk(chunk_pos);
+                            chunks_to_load.push(chunk_pos);
+                        }
                     }
                 }
             }
         }
 
         chunks_to_load.sort_by(|a, b| {
-            let dista = (a.center() - player_pos).magnitude2();
-            let distb = (b.center() - player_pos).magnitude2();
-            dista.partial_cmp(&distb).unwrap()
+            let a = Self::load_priority(a.center() - view_pos, forward);
+            let b = Self::load_priority(b.center() - view_pos, forward);
+            a.partial_cmp(&b).unwrap()
         });
+        // The rest are asked for in the next frames
+        chunks_to_load.truncate(MessageToServer::MAX_CHUNKS);
 
         if !chunks_to_load.is_empty() {
-            self.loading_chunks.extend(&chunks_to_load);
+            let now = Instant::now();
+            self.loading_chunks
+                .extend(chunks_to_load.iter().map(|&pos| (pos, now)));
             let chunk_load = self
                 .server_connection
                 .transact::<MessageToServer, MessageToClient>(&MessageToServer::GetChunks(
-                    chunks_to_load,
+                    chunks_to_load
+                        .iter()
+                        .map(|&pos| (pos, self.chunk_cache.as_ref().and_then(|c| c.cached(pos))))
+                        .collect(),
                 ))
-                .await
-                .unwrap();
-            self.load_chunk_tx.send_blocking(chunk_load).unwrap();
+                .await;
+            match chunk_load {
+                Ok(chunk_load) => {
+                    self.pending_chunk_transactions
+                        .fetch_add(1, Ordering::Relaxed);
+                    self.load_chunk_tx.send_blocking(chunk_load).unwrap();
+                }
+                Err(e) => {
+                    // Requested again on a later frame
+                    log::warn!("Could not request chunks: {e}");
+                    for pos in &chunks_to_load {
+                        self.loading_chunks.remove(pos);
+                    }
+                }
+            }
         }
 
+        self.update_lod_chunks(view_pos).await;
+
+        let world = &mut self.ecs_world.resource_mut::<World>();
+
         while let Ok(chunks) = self.chunk_loaded_rx.try_recv() {
-            for chunk in chunks {
-                self.loading_chunks.remove(&chunk.pos);
-                world.chunks.insert(chunk.pos, chunk);
+            match chunks {
+                LoadedChunks::Full(chunks) => {
+                    for chunk in chunks {
+                        self.loading_chunks.remove(&chunk.pos);
+                        world.chunks.insert(chunk.pos, chunk);
+                    }
+                }
+                LoadedChunks::Uncached(positions) => {
+                    for pos in positions {
+                        self.loading_chunks.remove(&pos);
+                    }
+                }
+                LoadedChunks::Lod(chunks) => {
+                    for chunk in chunks {
+                        // Ignore responses that have been superseded by another request
+                        if self.loading_lod_chunks.get(&chunk.pos) == Some(&chunk.level) {
+                            self.loading_lod_chunks.remove(&chunk.pos);
+                            self.lod_chunk_objects.remove(&chunk.pos);
+                            self.translucent_lod_chunk_objects.remove(&chunk.pos);
+                            self.lod_chunks.insert(chunk.pos, chunk);
+                        }
+                    }
+                }
             }
         }
 
+        let reach = self
+            .ecs_world
+            .query::<&PlayerController>()
+            .iter(&self.ecs_world)
+            .next()
+            .map_or(player::DEFAULT_REACH, |pc| pc.reach);
         let camera = self.ecs_world.resource::<Camera>();
         let world = self.ecs_world.resource::<World>();
         let block_registry = self.ecs_world.resource::<BlockRegistry>();
-        let pos = if let Some(hitinfo) =
-            world.raycast(camera.position, camera.forward(), 5.0, block_registry)
-        {
-            Point3::from(hitinfo.position).cast().unwrap()
+        let hit = world.raycast(camera.position, camera.forward(), reach, block_registry);
+        self.block_select_boxes = hit.map(|hitinfo| {
+            let attr = block_registry.get(hitinfo.block.id).unwrap();
+            attr.boxes(hitinfo.block)
+        });
+        if let Some(hitinfo) = hit {
+            let pos = Point3::from(hitinfo.position).cast().unwrap();
+            self.block_select_object
+                .edit_instance(|instance| instance.position = pos);
+        }
+    }
+
+    /// When a chunk at `offset` from the player should be loaded, lower values first. Chunks are
+    /// loaded nearest first, but those behind the player count as up to twice as far away as
+    /// those straight ahead, so the terrain being looked at streams in first.
+    fn load_priority(offset: Vector3<f32>, forward: Vector3<f32>) -> f32 {
+        let distance = offset.magnitude();
+        if distance == 0.0 {
+            return 0.0;
+        }
+        // 0 straight ahead, 1 straight behind
+        let away = (1.0 - offset.dot(forward) / distance) / 2.0;
+        distance * (1.0 + away)
+    }
+
+    /// How often the server metrics are requested while the debug overlay is shown
+    const METRICS_INTERVAL: Duration = Duration::from_secs(1);
+
+    /// Falling below this kills the player
+    const VOID_DEPTH: f32 = -256.0;
+
+    /// How far LOD chunks are rendered, as a multiple of `chunk_loading_distance`
+    const LOD_DISTANCE_FACTOR: isize = 2;
+
+    /// The detail level a chunk this far from the player should have, where 0 means full detail.
+    /// `None` if the chunk is too far away to be rendered.
+    fn detail_level(&self, distance: f32) -> Option<u8> {
+        let full_detail = (self.chunk_loading_distance * Chunk::SIZE as isize) as f32;
+        if distance <= full_detail {
+            Some(0)
+        } else if distance <= full_detail * 1.5 {
+            Some(1)
+        } else if distance <= full_detail * Self::LOD_DISTANCE_FACTOR as f32 {
+            Some(2)
         } else {
-            [0.0, 0.0, 0.0].into()
-        };
-        self.block_select_object
-            .edit_instance(|instance| instance.position = pos);
+            None
+        }
+    }
+
+    /// Request downsampled chunks for the area beyond the full detail chunks, and drop the ones
+    /// that are no longer needed
+    async fn update_lod_chunks(&mut self, player_pos: Point3<f32>) {
+        let mut lod_chunks = std::mem::take(&mut self.lod_chunks);
+        lod_chunks.retain(|pos, chunk| {
+            let level = self.detail_level((pos.center() - player_pos).magnitude());
+            let keep = level == Some(chunk.level);
+            if !keep {
+                self.lod_chunk_objects.remove(pos);
+                self.translucent_lod_chunk_objects.remove(pos);
+            }
+            keep
+        });
+        self.lod_chunks = lod_chunks;
+
+        let player_chunk_pos = BlockPos::from_point(player_pos).chunk_pos();
+        let lod_distance = self.chunk_loading_distance * Self::LOD_DISTANCE_FACTOR;
+
+        let mut chunks_to_load = vec![vec![]; LodChunk::MAX_LEVEL as usize + 1];
+        for x in -lod_distance..=lod_distance {
+            for y in -lod_distance..=lod_distance {
+                for z in -lod_distance..=lod_distance {
+                    let chunk_pos =
+                        ChunkPos::from(Point3::from(player_chunk_pos) + Vector3::from([x, y, z]));
+                    let Some(level) =
+                        self.detail_level((chunk_pos.center() - player_pos).magnitude())
+                    else {
+                        continue;
+                    };
+                    if level == 0
+                        || self.lod_chunks.get(&chunk_pos).map(|c| c.level) == Some(level)
+                        || self.loading_lod_chunks.get(&chunk_pos) == Some(&level)
+                    {
+                        continue;
+                    }
+                    chunks_to_load[level as usize].push(chunk_pos);
+                }
+            }
+        }
+
+        for (level, mut chunks) in chunks_to_load.into_iter().enumerate() {
+            if chunks.is_empty() {
+                continue;
+            }
+            chunks.truncate(MessageToServer::MAX_CHUNKS);
+            let level = level as u8;
+            self.loading_lod_chunks
+                .extend(chunks.iter().map(|&pos| (pos, level)));
+            let chunk_load = match self
+                .server_connection
+                .transact::<MessageToServer, MessageToClient>(&MessageToServer::GetChunksLod {
+                    chunks: chunks.clone(),
+                    level,
+                })
+                .await
+            {
+                Ok(chunk_load) => chunk_load,
+                Err(e) => {
+                    log::warn!("Could not request LOD chunks: {e}");
+                    for pos in &chunks {
+                        self.loading_lod_chunks.remove(pos);
+                    }
+                    continue;
+                }
+            };
+            self.pending_chunk_transactions
+                .fetch_add(1, Ordering::Relaxed);
+            self.load_chunk_tx.send_blocking(chunk_load).unwrap();
+        }
     }
 
     pub fn keyboard_input(&mut self, event: KeyEvent) {
-        if let KeyEvent {
-            physical_key: PhysicalKey::Code(KeyCode::KeyF),
-            state: ElementState::Pressed,
-            repeat: false,
-            ..
-        } = &event
+        // Typing in the chat repeats held keys, unlike the menus
+        if let Some(input) = &mut self.chat_input
+            && let KeyEvent {
+                physical_key: PhysicalKey::Code(key),
+                state: ElementState::Pressed,
+                ..
+            } = &event
         {
-            self.chunk_meshifier.enable_ao = !self.chunk_meshifier.enable_ao;
-            for chunk in self.ecs_world.resource_mut::<World>().chunks.values() {
-                chunk.dirty.store(true, Ordering::Relaxed);
+            match input.key_pressed(*key, event.text.as_deref()) {
+                ChatInputEvent::None => {}
+                ChatInputEvent::Send(text) => {
+                    if !text.trim().is_empty() {
+                        let msg = MessageToServer::Chat(text);
+                        self.ecs_world.resource::<MessageQueue>().0.send_blocking(msg).unwrap();
+                    }
+                    self.chat_input = None;
+                }
+                ChatInputEvent::Close => self.chat_input = None,
             }
+            return;
         }
 
         if let KeyEvent {
-            physical_key: PhysicalKey::Code(KeyCode::KeyX),
+            physical_key: PhysicalKey::Code(key),
             state: ElementState::Pressed,
             repeat: false,
             ..
         } = &event
         {
-            self.show_select_object = !self.show_select_object;
+            if *key == RebindMenu::TOGGLE_KEY {
+                self.rebind_menu = match self.rebind_menu.take() {
+                    Some(_) => {
+                        println!("{}", self.locale().get("rebind.closed"));
+                        None
+                    }
+                    None => Some(RebindMenu::open(
+                        &self.ecs_world.resource::<Input>().input_map,
+                        self.locale(),
+                    )),
+                };
+                return;
+            }
+
+            if let Some(menu) = &mut self.trade_menu {
+                let locale = self.ecs_world.resource::<Locale>();
+                match menu.key_pressed(*key, locale) {
+                    TradeInput::None => {}
+                    TradeInput::Confirm(offer) => {
+                        let msg = MessageToServer::Trade {
+                            npc: menu.npc,
+                            offer,
+                        };
+                        self.ecs_world.resource::<MessageQueue>().0.send_blocking(msg).unwrap();
+                    }
+                    TradeInput::Close => {
+                        println!("{}", locale.get("trade.closed"));
+                        self.trade_menu = None;
+                    }
+                }
+                return;
+            }
+
+            if self.rebind_menu.is_none() && *key == ChatInput::OPEN_KEY {
+                self.chat_input = Some(ChatInput::default());
+                return;
+            }
+            if self.rebind_menu.is_none() && *key == ChatInput::COMMAND_KEY {
+                self.chat_input = Some(ChatInput::command());
+                return;
+            }
+
+            // Key presses go to the menu while it is open, releases still have to reach `Input`
+            // so that no keys get stuck
+            if let Some(menu) = &mut self.rebind_menu {
+                let mut input_map = self.ecs_world.resource::<Input>().input_map.clone();
+                if menu.key_pressed(*key, &mut input_map, self.ecs_world.resource::<Locale>()) {
+                    let keys = input_map.clone();
+                    self.ecs_world.resource_mut::<Input>().input_map = input_map;
+                    self.ecs_world.resource_mut::<Settings>().keys = keys;
+                    self.save_settings();
+                }
+                return;
+            }
         }
 
         if let KeyEvent {
@@ -480,6 +1179,9 @@ impl Game {
             && text == "-"
         {
             self.chunk_loading_distance = 1.max(self.chunk_loading_distance - 1);
+            self.ecs_world
+                .resource_mut::<Settings>()
+                .chunk_loading_distance = self.chunk_loading_distance;
         }
 
         if let KeyEvent {
@@ -491,6 +1193,9 @@ impl Game {
             && text == "+"
         {
             self.chunk_loading_distance += 1;
+            self.ecs_world
+                .resource_mut::<Settings>()
+                .chunk_loading_distance = self.chunk_loading_distance;
         }
 
         self.ecs_world
@@ -498,6 +1203,232 @@ impl Game {
             .process_key_event(event);
     }
 
+    /// Run as many physics steps as fit in the time since the last frame, carrying the rest over
+    /// to the next frame
+    fn step_physics(&mut self, dt: f32) {
+        self.physics_time += dt;
+        self.ecs_world.resource_mut::<DeltaTime>().0 = physics::STEP;
+        while self.physics_time >= physics::STEP {
+            self.ecs_world.run_schedule(ScheduleStage::Physics);
+            self.physics_time -= physics::STEP;
+        }
+        self.ecs_world.resource_mut::<DeltaTime>().0 = dt;
+        physics::interpolate(&mut self.ecs_world, self.physics_time / physics::STEP);
+    }
+
+    /// Put the player at `position` at a standstill, without moving there smoothly
+    fn move_player(&mut self, position: Point3<f32>) {
+        let (mut pos, mut vel, mut interpolation) = self
+            .ecs_world
+            .query_filtered::<(&mut Position, &mut Velocity, &mut Interpolation), With<PlayerController>>()
+            .single_mut(&mut self.ecs_world);
+        pos.0 = position;
+        vel.0 = Vector3::zero();
+        interpolation.reset(position);
+    }
+
+    /// Apply the settings toggled by this frame's action events
+    fn apply_toggles(&mut self) {
+        let events = self.ecs_world.resource::<Events<ActionEvent>>();
+        let pressed = self
+            .action_reader
+            .read(events)
+            .filter(|event| event.state == ActionState::Pressed)
+            .map(|event| event.action)
+            .collect::<Vec<_>>();
+
+        if pressed.contains(&Action::ToggleAmbientOcclusion) {
+            self.chunk_meshifier.enable_ao = !self.chunk_meshifier.enable_ao;
+            self.ecs_world.resource_mut::<Settings>().ambient_occlusion =
+                self.chunk_meshifier.enable_ao;
+            for chunk in self.ecs_world.resource_mut::<World>().chunks.values() {
+                chunk.dirty.store(true, Ordering::Relaxed);
+            }
+        }
+
+        if pressed.contains(&Action::ToggleSmoothLighting) {
+            self.chunk_meshifier.smooth_lighting = !self.chunk_meshifier.smooth_lighting;
+            let enabled = self.chunk_meshifier.smooth_lighting;
+            self.ecs_world.resource_mut::<Settings>().smooth_lighting = enabled;
+            for chunk in self.ecs_world.resource_mut::<World>().chunks.values() {
+                chunk.dirty.store(true, Ordering::Relaxed);
+            }
+            self.print_toggle("settings.smooth_lighting", enabled);
+        }
+
+        if pressed.contains(&Action::ToggleSelectOutline) {
+            self.show_select_object = !self.show_select_object;
+        }
+
+        if pressed.contains(&Action::ToggleRawMouse) {
+            let mut input = self.ecs_world.resource_mut::<Input>();
+            input.mouse_settings.raw_input = !input.mouse_settings.raw_input;
+            let mouse_settings = input.mouse_settings;
+            self.ecs_world.resource_mut::<Settings>().mouse = mouse_settings;
+            self.print_toggle("settings.raw_mouse", mouse_settings.raw_input);
+        }
+
+        if pressed.contains(&Action::ToggleHeldItemLight) {
+            let mut settings = self.ecs_world.resource_mut::<Settings>();
+            settings.held_item_light = !settings.held_item_light;
+            let enabled = settings.held_item_light;
+            self.print_toggle("settings.held_item_light", enabled);
+        }
+
+        if pressed.contains(&Action::ToggleAmbientParticles) {
+            let mut settings = self.ecs_world.resource_mut::<Settings>();
+            settings.ambient_particles = !settings.ambient_particles;
+            let enabled = settings.ambient_particles;
+            self.print_toggle("settings.ambient_particles", enabled);
+        }
+
+        if pressed.contains(&Action::ToggleCompass) {
+            let mut settings = self.ecs_world.resource_mut::<Settings>();
+            settings.show_compass = !settings.show_compass;
+        }
+
+        if pressed.contains(&Action::ToggleDiagnostics) {
+            self.show_loading_diagnostics = !self.show_loading_diagnostics;
+        }
+
+        if pressed.contains(&Action::ToggleDebugOverlay) {
+            self.show_debug_overlay = !self.show_debug_overlay;
+        }
+
+        if pressed.contains(&Action::CycleRenderMode) {
+            let mut settings = self.ecs_world.resource_mut::<Settings>();
+            settings.render_mode = settings.render_mode.next();
+            let mode = settings.render_mode;
+            let mode = format!("{mode:?}");
+            println!(
+                "{}",
+                self.locale().format("settings.render_mode", &[("mode", &mode)])
+            );
+        }
+
+        if pressed.contains(&Action::CycleLanguage) {
+            self.cycle_language();
+        }
+
+        if pressed.contains(&Action::ToggleFullscreen) {
+            let mut settings = self.ecs_world.resource_mut::<Settings>();
+            settings.window.fullscreen = !settings.window.fullscreen;
+            let fullscreen = settings.window.fullscreen;
+            self.print_toggle("settings.fullscreen", fullscreen);
+            self.save_settings();
+        }
+
+        if pressed.contains(&Action::Interact) {
+            self.interact();
+        }
+
+        if pressed.contains(&Action::ExportRegion) {
+            self.mark_export_corner();
+        }
+    }
+
+    /// Mark the block being looked at as a corner of the region to export, exporting the
+    /// region once both corners are marked
+    fn mark_export_corner(&mut self) {
+        let reach = self
+            .ecs_world
+            .query::<&PlayerController>()
+            .iter(&self.ecs_world)
+            .next()
+            .map_or(player::DEFAULT_REACH, |pc| pc.reach);
+        let camera = self.ecs_world.resource::<Camera>();
+        let world = self.ecs_world.resource::<World>();
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        let Some(hit) = world.raycast(camera.position, camera.forward(), reach, block_registry)
+        else {
+            println!("{}", self.locale().get("export.no_target"));
+            return;
+        };
+
+        let Some(first) = self.export_corner.take() else {
+            self.export_corner = Some(hit.position);
+            let pos = format!("{:?}", Point3::from(hit.position));
+            println!("{}", self.locale().format("export.corner", &[("pos", &pos)]));
+            return;
+        };
+
+        let result = export::export_region(
+            world,
+            [first, hit.position],
+            &self.atlas,
+            block_registry,
+            self.chunk_meshifier.enable_ao,
+            self.chunk_meshifier.smooth_lighting,
+        );
+        let locale = self.locale();
+        match result {
+            Ok(path) => {
+                let path = path.display();
+                println!("{}", locale.format("export.done", &[("path", &path)]));
+            }
+            Err(e) => {
+                log::warn!("Could not export region: {e:#}");
+                println!("{}", locale.format("export.failed", &[("error", &e)]));
+            }
+        }
+    }
+
+    /// Trade with the NPC the player is looking at, or flip the lever they are looking at
+    fn interact(&mut self) {
+        let camera = self.ecs_world.resource::<Camera>();
+        let (origin, forward) = (camera.position, camera.forward());
+        let world = self.ecs_world.resource::<World>();
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        let block_hit = world.raycast(origin, forward, Npc::REACH, block_registry);
+        // NPCs behind a wall can't be reached
+        let reach = block_hit.as_ref().map_or(Npc::REACH, |hit| hit.distance);
+        let lever = block_hit
+            .filter(|hit| hit.block.id == signal::LEVER)
+            .map(|hit| hit.position);
+
+        let target = self
+            .ecs_world
+            .query::<(&Npc, &Position)>()
+            .iter(&self.ecs_world)
+            .filter_map(|(npc, pos)| {
+                let center = pos.0 + Vector3::unit_y() * (Npc::HEIGHT / 2.0);
+                let along = (center - origin).dot(forward);
+                let closest = origin + forward * along;
+                // Roughly the NPC's column, a bit wider to make it easier to hit
+                let hit = (0.0..=reach).contains(&along)
+                    && (closest.x - center.x).hypot(closest.z - center.z) <= 0.75
+                    && (closest.y - center.y).abs() <= Npc::HEIGHT / 2.0;
+                hit.then_some((npc.id, along))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(id, _)| id);
+
+        let msg = match (target, lever) {
+            (Some(npc), _) => MessageToServer::OpenTrade { npc },
+            (None, Some(pos)) => MessageToServer::UseBlock { pos },
+            (None, None) => return,
+        };
+        self.ecs_world
+            .resource::<MessageQueue>()
+            .0
+            .send_blocking(msg)
+            .unwrap();
+    }
+
+    fn print_inventory(&self) {
+        let locale = self.locale();
+        let items = if self.inventory.is_empty() {
+            locale.get("trade.empty").to_string()
+        } else {
+            self.inventory
+                .iter()
+                .map(ItemStack::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        println!("{}", locale.format("trade.inventory", &[("items", &items)]));
+    }
+
     pub fn mouse_input(&mut self, delta: Vector2<f32>) {
         std::io::stdout().flush().unwrap();
         self.ecs_world
@@ -511,40 +1442,656 @@ impl Game {
             .process_mouse_input(button, state);
     }
 
+    /// Rebuild everything living on the graphics device after it was replaced by `device`, with
+    /// `asset_manager` loading onto the new one. Chunk and entity meshes are dropped and get
+    /// built again over the next frames, from the chunk data the game still has.
+    pub fn recreate_gpu_resources(
+        &mut self,
+        asset_manager: &mut AssetManager,
+        device: &wgpu::Device,
+    ) {
+        let filtering = self.ecs_world.resource::<Settings>().texture_filtering;
+        self.atlas = asset_manager
+            .load_atlas("assets/blocks", 16, filtering)
+            .unwrap();
+        self.chunk_arena = ChunkArena::new(self.atlas.material.clone(), device);
+        self.chunk_meshifier.retain(|_| false);
+        self.translucent_chunk_objects.clear();
+        self.lod_chunk_objects.clear();
+        self.translucent_lod_chunk_objects.clear();
+        self.block_select_meshes.clear();
+        self.block_select_object = Object::new(
+            block_select_mesh(&[Aabb::FULL], &self.atlas, device).into(),
+            Instance {
+                position: [0.0, 0.0, 0.0].into(),
+                rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+            },
+            device,
+        );
+        self.shadow_objects.clear();
+        self.npc_objects.clear();
+        self.mob_objects.clear();
+        self.particle_object = None;
+    }
+
+    pub fn set_ui_scale(&mut self, scale: f32) {
+        self.ecs_world.resource_mut::<UiScale>().0 = scale;
+    }
+
+    /// Tell the server the player is leaving, so it can save and clean up right away instead of
+    /// waiting for the connection to time out
+    pub async fn disconnect(&self) {
+        let result = async {
+            self.server_connection
+                .transact::<_, MessageToClient>(&MessageToServer::Disconnect)
+                .await?
+                .single()
+                .await
+        }
+        .await;
+        if let Err(e) = result {
+            log::warn!("Could not disconnect cleanly: {e:#}");
+        }
+        self.server_connection.close("disconnected");
+    }
+
+    pub fn save_settings(&self) {
+        if let Err(e) = self.ecs_world.resource::<Settings>().save(Settings::PATH) {
+            log::warn!("Could not save settings: {e}");
+        }
+    }
+
+    pub fn settings(&self) -> &Settings {
+        self.ecs_world.resource::<Settings>()
+    }
+
+    pub fn window_settings(&self) -> &WindowSettings {
+        &self.ecs_world.resource::<Settings>().window
+    }
+
+    /// Remember the size of the window outside of fullscreen, to restore it on the next launch
+    pub fn set_windowed_size(&mut self, size: PhysicalSize<u32>) {
+        self.ecs_world
+            .resource_mut::<Settings>()
+            .window
+            .windowed_size = Some([size.width, size.height]);
+    }
+
+    pub fn render_mode(&self) -> RenderMode {
+        self.ecs_world.resource::<Settings>().render_mode
+    }
+
+    pub fn locale(&self) -> &Locale {
+        self.ecs_world.resource::<Locale>()
+    }
+
+    /// What to tell the player about the connection to the server ending, nothing if they left
+    fn disconnect_message(&self, disconnect: &Disconnect) -> Option<String> {
+        let locale = self.locale();
+        match disconnect {
+            Disconnect::TimedOut => Some(locale.get("connection.lost_timed_out").to_string()),
+            Disconnect::Closed(reason) => {
+                Some(locale.format("connection.lost_closed", &[("reason", reason)]))
+            }
+            Disconnect::ClosedLocally => None,
+            Disconnect::Failed(reason) => {
+                Some(locale.format("connection.lost", &[("reason", reason)]))
+            }
+        }
+    }
+
+    fn print_toggle(&self, key: &str, enabled: bool) {
+        let locale = self.locale();
+        println!("{}", locale.format(key, &[("value", &locale.toggle(enabled))]));
+    }
+
+    /// Switch the UI to the language after the current one, saving the choice
+    fn cycle_language(&mut self) {
+        let languages = Locale::available();
+        let current = languages
+            .iter()
+            .position(|language| language == self.locale().language());
+        let next = match current {
+            Some(i) => &languages[(i + 1) % languages.len()],
+            None => Locale::FALLBACK,
+        };
+
+        let locale = Locale::load(next);
+        println!("{}", locale.format("settings.language", &[("language", &locale.get("name"))]));
+        self.ecs_world.resource_mut::<Settings>().language = next.to_string();
+        self.ecs_world.insert_resource(locale);
+        self.save_settings();
+    }
+
     pub fn camera(&self) -> &Camera {
         self.ecs_world.resource::<Camera>()
     }
 
+    /// The compass to show, if enabled
+    pub fn compass(&self) -> Option<Compass> {
+        self.ecs_world
+            .resource::<Settings>()
+            .show_compass
+            .then(|| Compass::new(self.camera()))
+    }
+
+    /// The chat line being typed, if the chat input is open
+    pub fn chat_draft(&self) -> Option<&str> {
+        self.chat_input.as_ref().map(|input| input.text.as_str())
+    }
+
+    /// The latest chat messages to show on screen, oldest first, with their opacity
+    pub fn visible_chat(&self, now: Instant) -> impl Iterator<Item = (&str, f32)> {
+        self.ecs_world
+            .resource::<ChatLog>()
+            .visible(now, self.chat_input.is_some())
+    }
+
+    /// The debug overlay to show, if enabled
+    pub fn debug_overlay(&self) -> Option<DebugOverlay> {
+        if !self.show_debug_overlay {
+            return None;
+        }
+        let world = self.ecs_world.resource::<World>();
+        let compass = Compass::new(self.camera());
+        Some(DebugOverlay {
+            compass,
+            chunk: compass.position.chunk_pos(),
+            biome: world.biome(compass.position),
+            loaded_chunks: world.chunks.len(),
+            dirty_chunks: world.chunks.values().filter(|c| c.get_dirty()).count(),
+            meshes: self.mesh_stats(),
+            rtt: self.server_connection.rtt(),
+            server: self.server_metrics.clone(),
+        })
+    }
+
+    /// Add the borders of the chunks around the player to `lines`, if the debug overlay is shown
+    pub fn draw_debug_lines(&self, lines: &mut DebugLines) {
+        let Some(overlay) = self.debug_overlay() else {
+            return;
+        };
+        let size = Chunk::SIZE as f32;
+        let center = Point3::from(overlay.chunk);
+        for x in -1..=1 {
+            for y in -1..=1 {
+                for z in -1..=1 {
+                    let min = (center + Vector3::new(x, y, z)).cast::<f32>().unwrap() * size;
+                    let color = if (x, y, z) == (0, 0, 0) {
+                        [1.0, 1.0, 0.0]
+                    } else {
+                        [0.4, 0.4, 0.4]
+                    };
+                    lines.cuboid(min, Vector3::new(size, size, size), color);
+                }
+            }
+        }
+    }
+
+    pub fn loading_diagnostics(&self) -> LoadingDiagnostics {
+        let world = self.ecs_world.resource::<World>();
+        let now = Instant::now();
+
+        LoadingDiagnostics {
+            chunks_in_flight: self.loading_chunks.len(),
+            oldest_request: self
+                .loading_chunks
+                .values()
+                .map(|&requested| now - requested)
+                .max(),
+            mesh_jobs: world
+                .chunks
+                .values()
+                .filter(|chunk| chunk.get_dirty() || !self.chunk_arena.contains(chunk.pos))
+                .count(),
+            meshes_per_frame: self.meshing_budget.limit(),
+            pending_transactions: self.pending_chunk_transactions.load(Ordering::Relaxed),
+            queued_messages: self.msg_queue_rx.len(),
+        }
+    }
+
+    /// How far the terrain the player is waiting for has loaded, `None` once they can move
+    pub fn loading_progress(&self) -> Option<LoadingProgress> {
+        let loading = self.loading?;
+        let world = self.ecs_world.resource::<World>();
+        Some(loading.progress(
+            self.chunk_loading_distance,
+            |pos| self.loading_chunks.contains_key(&pos),
+            |pos| world.chunk(pos).is_some(),
+            |pos| {
+                world.chunk(pos).is_some_and(|chunk| !chunk.get_dirty())
+                    && self.chunk_arena.contains(pos)
+            },
+        ))
+    }
+
+    pub fn render_stats(&self) -> RenderStats {
+        RenderStats {
+            loaded_chunks: self.ecs_world.resource::<World>().chunks.len(),
+            chunk_objects: self.chunk_arena.len(),
+            translucent_chunk_objects: self.translucent_chunk_objects.len(),
+            cached_meshes: self.chunk_meshifier.cached_chunks(),
+            stale_entries_removed: self.stale_render_entries,
+            occluded_chunks: self.occluded_chunks,
+        }
+    }
+
+    /// The sizes of the loaded chunks' meshes, added up
+    pub fn mesh_stats(&self) -> MeshStats {
+        let mut stats = MeshStats {
+            arena_allocated: self.chunk_arena.allocated_bytes(),
+            ..Default::default()
+        };
+        let chunks = self
+            .chunk_arena
+            .positions()
+            .chain(self.translucent_chunk_objects.keys().copied())
+            .collect::<FastHashSet<_>>();
+        for pos in chunks {
+            stats.add_chunk(
+                pos,
+                self.chunk_arena.mesh_size(pos),
+                self.translucent_chunk_objects
+                    .get(&pos)
+                    .map(|object| object.mesh.size()),
+            );
+        }
+        stats
+    }
+
+    /// Drop chunk objects and cached meshes of chunks that are no longer loaded.
+    /// Unloading normally removes these right away, so anything found here was missed.
+    fn reconcile_chunk_objects(&mut self) {
+        let world = self.ecs_world.resource::<World>();
+        let is_loaded = |pos: &ChunkPos| world.chunks.contains_key(pos);
+
+        let before = self.translucent_chunk_objects.len();
+        self.translucent_chunk_objects.retain(|pos, _| is_loaded(pos));
+        let mut removed = before - self.translucent_chunk_objects.len();
+        removed += self.chunk_arena.retain(|pos| is_loaded(&pos));
+        removed += self.chunk_meshifier.retain(|pos| is_loaded(&pos));
+
+        if removed > 0 {
+            log::warn!("Removed {removed} render cache entries for chunks that are not loaded");
+            self.stale_render_entries += removed;
+        }
+    }
+
+    pub fn lights(&self) -> &Lights {
+        self.ecs_world.resource::<Lights>()
+    }
+
+    /// The fog to render, ending where the furthest chunks end so they fade in instead of
+    /// popping in
+    pub fn fog(&self) -> Fog {
+        let settings = self.ecs_world.resource::<Settings>();
+        if !settings.fog {
+            return Fog::NONE;
+        }
+        let end = (self.chunk_loading_distance
+            * Self::LOD_DISTANCE_FACTOR
+            * Chunk::SIZE as isize) as f32;
+        Fog {
+            start: end * 0.6,
+            end,
+            height_density: if settings.height_fog { 0.05 } else { 0.0 },
+            height_base: 0.0,
+        }
+    }
+
+    /// Move each entity's blob shadow onto the ground below it, dropping the shadows of entities
+    /// that are gone or too far above the ground
+    fn update_shadow_objects(&mut self, device: &wgpu::Device) {
+        let shadows = self
+            .ecs_world
+            .query::<(Entity, &Position, Option<&Interpolation>, &BlobShadow)>()
+            .iter(&self.ecs_world)
+            .map(|(entity, pos, interpolation, shadow)| {
+                let pos = interpolation.map_or(pos.0, |interpolation| interpolation.position);
+                (entity, pos, *shadow)
+            })
+            .collect::<Vec<_>>();
+        let world = self.ecs_world.resource::<World>();
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+
+        let mut visible = FastHashMap::default();
+        for (entity, pos, shadow) in shadows {
+            let Some(ground) = BlobShadow::ground_position(pos, world, block_registry) else {
+                continue;
+            };
+            let mut object = self.shadow_objects.remove(&entity).unwrap_or_else(|| {
+                Object::new(
+                    shadow.mesh(&self.atlas, device).into(),
+                    Instance {
+                        position: ground,
+                        rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+                    },
+                    device,
+                )
+            });
+            object.edit_instance(|instance| instance.position = ground);
+            visible.insert(entity, object);
+        }
+        self.shadow_objects = visible;
+    }
+
     pub fn get_objects_to_render(
         &mut self,
         device: &wgpu::Device,
-    ) -> impl Iterator<Item = &mut Object> {
-        let world = self.ecs_world.resource::<World>();
-        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        uploader: &mut Uploader,
+    ) -> ObjectsToRender<'_> {
+        self.reconcile_chunk_objects();
+        self.update_shadow_objects(device);
 
-        for chunk in world.chunks.values() {
-            if chunk.get_dirty() || !self.chunk_objects.contains_key(&chunk.pos) {
-                let mesh =
-                    self.chunk_meshifier
-                        .meshify(world, chunk, &self.atlas, block_registry, device);
-                let object = Object::new(
-                    mesh,
+        // NPCs never move, so their objects only have to be created once
+        let npcs = self
+            .ecs_world
+            .query_filtered::<(Entity, &Position), With<Npc>>()
+            .iter(&self.ecs_world)
+            .map(|(entity, pos)| (entity, pos.0))
+            .collect::<Vec<_>>();
+        for (entity, position) in npcs {
+            self.npc_objects.entry(entity).or_insert_with(|| {
+                Object::new(
+                    Npc::mesh(&self.atlas, device).into(),
                     Instance {
-                        position: Point3::from(chunk.pos).cast::<f32>().unwrap() * 16.0,
+                        position,
                         rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
                     },
                     device,
-                );
-                self.chunk_objects.insert(chunk.pos, object);
+                )
+            });
+        }
+
+        // Mobs move, so their objects follow them every frame
+        let mobs = self
+            .ecs_world
+            .query_filtered::<(Entity, &Position), With<Mob>>()
+            .iter(&self.ecs_world)
+            .map(|(entity, pos)| (entity, pos.0))
+            .collect::<FastHashMap<_, _>>();
+        self.mob_objects.retain(|entity, _| mobs.contains_key(entity));
+        for (entity, position) in mobs {
+            self.mob_objects
+                .entry(entity)
+                .or_insert_with(|| {
+                    Object::new(
+                        Mob::mesh(&self.atlas, device).into(),
+                        Instance {
+                            position,
+                            rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+                        },
+                        device,
+                    )
+                })
+                .edit_instance(|instance| instance.position = position);
+        }
+
+        // Particles move every frame, so their mesh is rebuilt from scratch
+        self.particle_object = self
+            .ecs_world
+            .resource::<Particles>()
+            .mesh(&self.atlas, device)
+            .map(|mesh| {
+                Object::new(
+                    mesh.into(),
+                    Instance {
+                        position: [0.0, 0.0, 0.0].into(),
+                        rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+                    },
+                    device,
+                )
+            });
+
+        let settings = self.ecs_world.resource::<Settings>();
+        self.meshing_budget.update(
+            self.ecs_world.resource::<FrameTiming>(),
+            settings.meshes_per_frame,
+            Duration::from_secs_f32(settings.target_frame_ms / 1000.0),
+        );
+
+        let world = self.ecs_world.resource::<World>();
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        let camera_pos = self.ecs_world.resource::<Camera>().position;
+
+        // Nearest first, the rest stay dirty or unmeshed and wait for the next frames
+        let mut to_mesh = world
+            .chunks
+            .values()
+            .filter(|chunk| chunk.get_dirty() || !self.chunk_arena.contains(chunk.pos))
+            .collect::<Vec<_>>();
+        to_mesh.sort_by(|a, b| {
+            let dista = (a.pos.center() - camera_pos).magnitude2();
+            let distb = (b.pos.center() - camera_pos).magnitude2();
+            dista.partial_cmp(&distb).unwrap()
+        });
+        to_mesh.truncate(self.meshing_budget.limit());
+        let mut lod_budget = self.meshing_budget.limit() - to_mesh.len();
+
+        for chunk in to_mesh {
+            // Drop the old object first so the meshifier can reuse its buffers
+            self.translucent_chunk_objects.remove(&chunk.pos);
+            let meshes = self.chunk_meshifier.meshify(
+                world,
+                chunk,
+                &self.atlas,
+                block_registry,
+                &mut self.chunk_arena,
+                device,
+                uploader,
+            );
+            let instance = Instance {
+                position: Point3::from(chunk.pos).cast::<f32>().unwrap() * 16.0,
+                rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+            };
+            self.translucent_chunk_objects
+                .insert(chunk.pos, Object::new(meshes.translucent, instance, device));
+        }
+
+        for lod in self.lod_chunks.values() {
+            if self.lod_chunk_objects.contains_key(&lod.pos) {
+                continue;
             }
+            if lod_budget == 0 {
+                break;
+            }
+            lod_budget -= 1;
+            let meshes =
+                self.chunk_meshifier
+                    .meshify_lod(lod, &self.atlas, block_registry, device);
+            let instance = Instance {
+                position: Point3::from(lod.pos).cast::<f32>().unwrap() * 16.0,
+                rotation: Quaternion::from_angle_z(cgmath::Deg(0.0)),
+            };
+            self.lod_chunk_objects
+                .insert(lod.pos, Object::new(meshes.opaque, instance, device));
+            self.translucent_lod_chunk_objects
+                .insert(lod.pos, Object::new(meshes.translucent, instance, device));
+        }
+
+        // A chunk may be both loaded and have a LOD version for a short while when moving around,
+        // in which case the fully detailed chunk is preferred
+        let is_lod_visible = |pos: &ChunkPos| !world.chunks.contains_key(pos);
+
+        // LOD chunks are beyond the loaded area, where there is no visibility to walk through, so
+        // they are always drawn
+        let camera_chunk = BlockPos::from_point(self.ecs_world.resource::<Camera>().position)
+            .chunk_pos();
+        let visible = (self.ecs_world.resource::<Settings>().occlusion_culling
+            && world.chunks.contains_key(&camera_chunk))
+        .then(|| {
+            occlusion::visible_chunks(camera_chunk, |pos| {
+                world.chunks.contains_key(&pos).then(|| {
+                    self.chunk_meshifier
+                        .visibility(pos)
+                        .unwrap_or(ChunkVisibility::ALL)
+                })
+            })
+        });
+        let is_chunk_visible =
+            |pos: &ChunkPos| visible.as_ref().is_none_or(|visible| visible.contains(pos));
+        let (chunks, occluded) = self
+            .chunk_arena
+            .positions()
+            .partition::<Vec<_>, _>(|pos| is_chunk_visible(pos));
+        self.occluded_chunks = occluded.len();
+
+        let mut opaque = self
+            .lod_chunk_objects
+            .iter_mut()
+            .filter(|(pos, _)| is_lod_visible(pos))
+            .map(|(_, obj)| obj)
+            .collect::<Vec<_>>();
+
+        opaque.extend(self.npc_objects.values_mut());
+        opaque.extend(self.mob_objects.values_mut());
+
+        if let Some(boxes) = self.block_select_boxes.filter(|_| self.show_select_object) {
+            let mesh = match self.block_select_meshes.iter().find(|(b, _)| *b == boxes) {
+                Some((_, mesh)) => mesh.clone(),
+                None => {
+                    let mesh = Arc::new(block_select_mesh(boxes, &self.atlas, device));
+                    self.block_select_meshes.push((boxes, mesh.clone()));
+                    mesh
+                }
+            };
+            self.block_select_object.mesh = mesh;
+            opaque.push(&mut self.block_select_object);
+        }
+
+        let translucent = self
+            .translucent_chunk_objects
+            .iter_mut()
+            .filter(|(pos, _)| is_chunk_visible(pos))
+            .map(|(_, obj)| obj)
+            .chain(
+                self.translucent_lod_chunk_objects
+                    .iter_mut()
+                    .filter(|(pos, _)| is_lod_visible(pos))
+                    .map(|(_, obj)| obj),
+            )
+            .chain(self.shadow_objects.values_mut())
+            .chain(self.particle_object.as_mut())
+            .collect();
+
+        ObjectsToRender {
+            chunk_arena: &mut self.chunk_arena,
+            chunks,
+            opaque,
+            translucent,
         }
+    }
+}
 
-        let mut extra = vec![];
+pub struct ObjectsToRender<'a> {
+    pub chunk_arena: &'a mut ChunkArena,
+    /// Chunks in `chunk_arena` that occlusion culling didn't rule out
+    pub chunks: Vec<ChunkPos>,
+    pub opaque: Vec<&'a mut Object>,
+    /// Objects that must be drawn with blending, after everything in `opaque`
+    pub translucent: Vec<&'a mut Object>,
+}
 
-        if self.show_select_object {
-            extra.push(&mut self.block_select_object);
+/// The outline drawn around the targeted block, hugging each of its `boxes`
+fn block_select_mesh(boxes: &[Aabb], atlas: &Atlas, device: &wgpu::Device) -> Mesh {
+    let line_width = 0.01;
+    // Pushed out a little so the faces of the block don't hide the outline
+    let margin = Vector3::new(0.001, 0.001, 0.001);
+
+    // The corners of the four lines on a face, and whether each is on the inner edge
+    let corners = [
+        // Top line
+        (-1.0, 1.0, false),
+        (1.0, 1.0, false),
+        (1.0, 1.0, true),
+        (-1.0, 1.0, true),
+        // Right line
+        (1.0, 1.0, true),
+        (1.0, 1.0, false),
+        (1.0, -1.0, false),
+        (1.0, -1.0, true),
+        // Bottom line
+        (-1.0, -1.0, true),
+        (1.0, -1.0, true),
+        (1.0, -1.0, false),
+        (-1.0, -1.0, false),
+        // Left line
+        (-1.0, 1.0, false),
+        (-1.0, 1.0, true),
+        (-1.0, -1.0, true),
+        (-1.0, -1.0, false),
+    ];
+    let indices = [
+        // Top line
+        0, 3, 1, 1, 3, 2, // Right line
+        4, 7, 5, 5, 7, 6, // Bottom line
+        8, 11, 9, 9, 11, 10, // Left line
+        12, 15, 13, 13, 15, 14,
+    ];
+
+    let mut builder = MeshBuilder::new();
+    for shape in boxes {
+        let center = shape.min.midpoint(shape.max);
+        let outer = (shape.max - shape.min) / 2.0 + margin;
+        let inner = outer - Vector3::new(line_width, line_width, line_width);
+        for dir in Direction::iter() {
+            let vertices = corners
+                .into_iter()
+                .map(|(x, y, is_inner)| {
+                    let extents = if is_inner { inner } else { outer };
+                    let on_face = dir.on_plane(Point2::new(x, y)).to_vec();
+                    let position = center
+                        + on_face.mul_element_wise(extents)
+                        + dir.normal().mul_element_wise(outer);
+                    MeshVertex {
+                        position: position.into(),
+                        tex_coords: [0.0, 0.0],
+                        ambient_occlusion: 1.0,
+                        normal: dir.normal().into(),
+                        animation: [0.0; 3],
+                    }
+                })
+                .collect::<Vec<_>>();
+            builder.add_vert_indices(&vertices, &indices);
         }
+    }
+
+    builder.build(atlas.material.clone(), device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_priority_prefers_ahead() {
+        let forward = Vector3::unit_z();
+        let ahead = Game::load_priority(Vector3::new(0.0, 0.0, 3.0), forward);
+        let behind = Game::load_priority(Vector3::new(0.0, 0.0, -3.0), forward);
+        let side = Game::load_priority(Vector3::new(3.0, 0.0, 0.0), forward);
+        assert_eq!(ahead, 3.0);
+        assert_eq!(behind, 6.0);
+        assert!(ahead < side && side < behind);
+    }
 
-        self.chunk_objects.values_mut().chain(extra)
+    #[test]
+    fn load_priority_of_own_chunk_is_zero() {
+        assert_eq!(Game::load_priority(Vector3::zero(), Vector3::unit_x()), 0.0);
+    }
+
+    #[test]
+    fn load_priority_prefers_nearer() {
+        let forward = Vector3::unit_x();
+        for dir in [
+            Vector3::unit_x(),
+            -Vector3::unit_x(),
+            Vector3::unit_y(),
+            -Vector3::unit_z(),
+            Vector3::new(1.0, 1.0, -1.0).normalize(),
+        ] {
+            let near = Game::load_priority(dir * 2.0, forward);
+            let far = Game::load_priority(dir * 5.0, forward);
+            assert!(near < far, "{dir:?}: {near} is not before {far}");
+        }
     }
 }
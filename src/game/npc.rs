@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use bevy_ecs::component::Component;
+use cgmath::Vector3;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    direction::Direction,
+    mesh::{Mesh, MeshBuilder},
+};
+
+use super::{atlas::Atlas, inventory::ItemStack};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NpcId(pub u32);
+
+/// One item-for-item exchange offered by an NPC
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Trade {
+    /// What the player gives
+    pub cost: ItemStack,
+    /// What the player gets in return
+    pub reward: ItemStack,
+}
+
+/// A stationary NPC placed in the world by the server, as defined in [`NpcDefinition::PATH`]
+#[derive(Debug, Clone, Deserialize)]
+pub struct NpcDefinition {
+    pub name: String,
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub trades: Vec<Trade>,
+}
+
+impl NpcDefinition {
+    pub const PATH: &'static str = "assets/npcs.toml";
+
+    /// Load every NPC from `path`, or none if the file is missing or invalid
+    pub fn load_all(path: impl AsRef<Path>) -> Vec<Self> {
+        #[derive(Deserialize)]
+        struct File {
+            #[serde(default)]
+            npc: Vec<NpcDefinition>,
+        }
+
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                log::warn!("Could not read {}: {e}", path.display());
+                return vec![];
+            }
+        };
+        match toml::from_str::<File>(&contents) {
+            Ok(file) => file.npc,
+            Err(e) => {
+                log::warn!("Invalid NPCs in {}: {e}", path.display());
+                vec![]
+            }
+        }
+    }
+}
+
+/// The trades an NPC offers, only known by the server
+#[derive(Debug, Clone, Component)]
+pub struct Trades(pub Vec<Trade>);
+
+/// A stationary NPC
+#[derive(Debug, Clone, Component)]
+pub struct Npc {
+    pub id: NpcId,
+    pub name: String,
+}
+
+impl Npc {
+    /// How far away the player can be to trade
+    pub const REACH: f32 = 5.0;
+    pub const HEIGHT: f32 = 2.0;
+
+    /// A column two blocks tall, standing on the origin
+    pub fn mesh(atlas: &Atlas, device: &wgpu::Device) -> Mesh {
+        let uv = atlas.uv(atlas.cell("neco_arc"));
+        let mut builder = MeshBuilder::new();
+        for y in [0.5, 1.5] {
+            for dir in Direction::ALL {
+                builder.add_face(Vector3::new(0.0, y, 0.0), dir, uv);
+            }
+        }
+        builder.build(atlas.material.clone(), device)
+    }
+}
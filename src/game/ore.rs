@@ -0,0 +1,74 @@
+use std::ops::Range;
+
+use cgmath::{Point3, Vector3};
+use rand::Rng;
+
+use super::{
+    block::BlockId,
+    chunk::{Chunk, ChunkPos, ChunkRelativeBlockPos},
+};
+
+pub const STONE: BlockId = BlockId(2);
+pub const COAL_ORE: BlockId = BlockId(13);
+pub const IRON_ORE: BlockId = BlockId(14);
+
+/// Where and how often worldgen places veins of an ore, declared by the ore's block
+#[derive(Debug, Clone)]
+pub struct OreRule {
+    /// Most blocks in a vein
+    pub vein_size: usize,
+    /// Average number of veins per chunk within `heights`
+    pub frequency: f32,
+    /// Heights veins can start at
+    pub heights: Range<isize>,
+}
+
+impl OreRule {
+    /// Place the veins of `ore` in a chunk, replacing only stone. Veins are cut off at the
+    /// chunk border.
+    pub fn place_veins(&self, ore: BlockId, chunk: &mut Chunk, rng: &mut impl Rng) {
+        // The fractional part is the chance of one more vein
+        let mut veins = self.frequency.trunc() as usize;
+        if rng.gen::<f32>() < self.frequency.fract() {
+            veins += 1;
+        }
+
+        for _ in 0..veins {
+            let mut pos = Point3::new(
+                rng.gen_range(0..Chunk::SIZE as isize),
+                rng.gen_range(0..Chunk::SIZE as isize),
+                rng.gen_range(0..Chunk::SIZE as isize),
+            );
+            // Drawn before checking the height, so every vein takes the same numbers from
+            // `rng` whether it is placed or not
+            let size = rng.gen_range(1..=self.vein_size);
+            if !self.heights.contains(&global_y(chunk.pos, pos)) {
+                continue;
+            }
+
+            for _ in 0..size {
+                if (0..Chunk::SIZE as isize).contains(&pos.x)
+                    && (0..Chunk::SIZE as isize).contains(&pos.y)
+                    && (0..Chunk::SIZE as isize).contains(&pos.z)
+                {
+                    let block = chunk.block_mut(pos.cast().unwrap().into());
+                    if block.id == STONE {
+                        block.id = ore;
+                    }
+                }
+                // Wander to a neighbour, so the vein stays in one clump
+                let axis = rng.gen_range(0..3);
+                let step = if rng.gen_bool(0.5) { 1 } else { -1 };
+                pos += match axis {
+                    0 => Vector3::new(step, 0, 0),
+                    1 => Vector3::new(0, step, 0),
+                    _ => Vector3::new(0, 0, step),
+                };
+            }
+        }
+    }
+}
+
+fn global_y(chunk: ChunkPos, pos: Point3<isize>) -> isize {
+    Point3::from(chunk + ChunkRelativeBlockPos::from(Point3::new(0, 0, 0))).y + pos.y
+}
@@ -0,0 +1,187 @@
+use bevy_ecs::system::{Res, ResMut, Resource};
+use cgmath::{EuclideanSpace, Point3, Vector3};
+use rand::Rng;
+
+use crate::{
+    camera::Camera,
+    direction::Direction,
+    mesh::{Mesh, MeshBuilder},
+    settings::Settings,
+};
+
+use super::{atlas::Atlas, block::BlockRegistry, chunk::BlockPos, world::World, DeltaTime};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParticleKind {
+    /// Slowly drifting motes, spawned in enclosed spaces
+    Dust,
+    /// Falls and sways from side to side
+    Leaf,
+}
+
+impl ParticleKind {
+    fn texture(self) -> &'static str {
+        match self {
+            ParticleKind::Dust => "dust",
+            ParticleKind::Leaf => "leaf",
+        }
+    }
+
+    fn size(self) -> f32 {
+        match self {
+            ParticleKind::Dust => 0.04,
+            ParticleKind::Leaf => 0.12,
+        }
+    }
+
+    fn lifetime(self) -> f32 {
+        match self {
+            ParticleKind::Dust => 8.0,
+            ParticleKind::Leaf => 6.0,
+        }
+    }
+
+    fn initial_velocity(self, rng: &mut impl Rng) -> Vector3<f32> {
+        match self {
+            ParticleKind::Dust => Vector3::new(
+                rng.gen_range(-0.1..0.1),
+                rng.gen_range(-0.05..0.05),
+                rng.gen_range(-0.1..0.1),
+            ),
+            ParticleKind::Leaf => Vector3::new(0.0, -0.6, 0.0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Particle {
+    pub kind: ParticleKind,
+    pub position: Point3<f32>,
+    velocity: Vector3<f32>,
+    age: f32,
+}
+
+#[derive(Debug, Default, Resource)]
+pub struct Particles {
+    pub particles: Vec<Particle>,
+    /// Fractional spawn attempts carried over between frames
+    spawn_accumulator: f32,
+}
+
+impl Particles {
+    pub const MAX_PARTICLES: usize = 64;
+
+    pub fn spawn(&mut self, kind: ParticleKind, position: Point3<f32>, rng: &mut impl Rng) {
+        if self.particles.len() >= Self::MAX_PARTICLES {
+            return;
+        }
+        self.particles.push(Particle {
+            kind,
+            position,
+            velocity: kind.initial_velocity(rng),
+            age: 0.0,
+        });
+    }
+
+    /// Build a mesh with a small cube for every particle, in world coordinates.
+    /// Returns `None` if there are no particles.
+    pub fn mesh(&self, atlas: &Atlas, device: &wgpu::Device) -> Option<Mesh> {
+        if self.particles.is_empty() {
+            return None;
+        }
+        let mut builder = MeshBuilder::new();
+        for particle in &self.particles {
+            let uv = atlas.uv(atlas.cell(particle.kind.texture()));
+            for dir in Direction::ALL {
+                builder.add_face_scaled(
+                    particle.position.to_vec(),
+                    dir,
+                    uv,
+                    particle.kind.size(),
+                );
+            }
+        }
+        Some(builder.build(atlas.material.clone(), device))
+    }
+}
+
+pub fn particle_system(mut particles: ResMut<Particles>, dt: Res<DeltaTime>) {
+    let dt = dt.0;
+    particles.particles.retain_mut(|particle| {
+        particle.age += dt;
+        let mut velocity = particle.velocity;
+        if particle.kind == ParticleKind::Leaf {
+            velocity.x += (particle.age * 2.0).sin() * 0.4;
+            velocity.z += (particle.age * 1.3).cos() * 0.4;
+        }
+        particle.position += velocity * dt;
+        particle.age < particle.kind.lifetime()
+    });
+}
+
+/// How many blocks around the camera ambient particles are spawned in
+const SPAWN_RADIUS: f32 = 8.0;
+/// Random blocks checked per second for whether they should spawn a particle
+const SPAWN_ATTEMPTS_PER_SECOND: f32 = 30.0;
+/// How far above the camera a roof is searched for when deciding if it is underground
+const UNDERGROUND_CHECK_HEIGHT: isize = 24;
+
+/// Spawn dust when the camera is underground, and leaves below blocks that shed them
+pub fn ambient_particle_system(
+    camera: Res<Camera>,
+    world: Res<World>,
+    block_registry: Res<BlockRegistry>,
+    settings: Res<Settings>,
+    dt: Res<DeltaTime>,
+    mut particles: ResMut<Particles>,
+) {
+    if !settings.ambient_particles {
+        particles.particles.clear();
+        return;
+    }
+
+    let is_open = |pos: BlockPos| {
+        world
+            .block(pos)
+            .and_then(|block| block_registry.get(block.id))
+            .is_some_and(|attr| attr.invisible)
+    };
+
+    let camera_block = BlockPos::from_point(camera.position);
+    let underground = (1..=UNDERGROUND_CHECK_HEIGHT).any(|dy| {
+        let pos = (Point3::from(camera_block) + Vector3::new(0, dy, 0)).into();
+        world
+            .block(pos)
+            .and_then(|block| block_registry.get(block.id))
+            .is_some_and(|attr| !attr.transparent)
+    });
+
+    particles.spawn_accumulator += SPAWN_ATTEMPTS_PER_SECOND * dt.0;
+    let mut rng = rand::thread_rng();
+    while particles.spawn_accumulator >= 1.0 {
+        particles.spawn_accumulator -= 1.0;
+
+        let position = camera.position
+            + Vector3::new(
+                rng.gen_range(-SPAWN_RADIUS..SPAWN_RADIUS),
+                rng.gen_range(-SPAWN_RADIUS..SPAWN_RADIUS),
+                rng.gen_range(-SPAWN_RADIUS..SPAWN_RADIUS),
+            );
+        let block_pos = BlockPos::from_point(position);
+        if !is_open(block_pos) {
+            continue;
+        }
+
+        let above = (Point3::from(block_pos) + Vector3::unit_y()).into();
+        let shed = world
+            .block(above)
+            .and_then(|block| block_registry.get(block.id))
+            .and_then(|attr| attr.ambient_particle);
+
+        if let Some(kind) = shed {
+            particles.spawn(kind, position, &mut rng);
+        } else if underground {
+            particles.spawn(ParticleKind::Dust, position, &mut rng);
+        }
+    }
+}
@@ -2,26 +2,286 @@ use bevy_ecs::{
     component::Component,
     system::{Query, Res},
 };
-use cgmath::{EuclideanSpace, InnerSpace, MetricSpace, Point3, Vector3, Zero};
+use cgmath::{InnerSpace, Point3, Vector3};
 
-use super::{block::BlockRegistry, chunk::BlockPos, world::World, DeltaTime, Position, Velocity};
+use super::{
+    block::{Aabb, BlockRegistry},
+    chunk::BlockPos,
+    world::World,
+    DeltaTime, Position, Velocity,
+};
 
 #[derive(Clone, Copy, PartialEq, Component)]
 pub struct Collider {
     pub enabled: bool,
     pub gravity: bool,
     pub extents: Vector3<f32>,
+    /// Whether the collider overlapped a liquid block during the last physics step
+    pub in_liquid: bool,
+    /// Ledges up to this high are stepped up onto instead of blocking movement, 0.0 to disable
+    pub step_height: f32,
+    /// How far below `Position` the collider should be drawn, to smooth out step-ups
+    pub step_offset: f32,
+    /// Keep from walking off the edge of the blocks the collider is standing on
+    pub sneaking: bool,
+    /// Whether the collider landed on a block during the last physics step
+    pub on_ground: bool,
+}
+
+/// Where an entity is drawn: between its positions before and after the latest physics step,
+/// so its movement looks smooth when frames and physics steps don't line up
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Interpolation {
+    previous: Point3<f32>,
+    pub position: Point3<f32>,
+}
+
+impl Interpolation {
+    pub fn new(position: Point3<f32>) -> Self {
+        Self {
+            previous: position,
+            position,
+        }
+    }
+
+    /// Jump straight to `position` instead of moving there over a step, e.g. when teleported
+    pub fn reset(&mut self, position: Point3<f32>) {
+        *self = Self::new(position);
+    }
 }
 
+/// Length of a client physics step in seconds. Physics advances in steps of this length however
+/// long frames take, so a slow frame can't move anything further in one go.
+pub const STEP: f32 = 1.0 / 60.0;
+/// Longest frame simulated in full, longer hitches slow the game down instead of running a
+/// burst of steps
+pub const MAX_FRAME_TIME: f32 = 0.25;
+
 const GRAVITY: Vector3<f32> = Vector3::new(0.0, -30.0, 0.0);
+/// How much of the normal gravity applies while in a liquid
+const LIQUID_GRAVITY_SCALE: f32 = 0.2;
+/// Fraction of the velocity lost per second while in a liquid
+const LIQUID_DRAG: f32 = 2.0;
+/// Upwards speed given by swimming
+pub const SWIM_SPEED: f32 = 3.0;
+/// How fast `Collider::step_offset` returns to zero, in blocks per second
+const STEP_SMOOTHING_SPEED: f32 = 6.0;
+
+/// Whether the box between `min` and `max` overlaps any solid block
+pub fn overlaps_solid(
+    min: Point3<f32>,
+    max: Point3<f32>,
+    world: &World,
+    block_registry: &BlockRegistry,
+) -> bool {
+    let min_block_pos = Point3::from(BlockPos::from_point(min));
+    let max_block_pos = Point3::from(BlockPos::from_point(max));
+
+    for x in min_block_pos.x..=max_block_pos.x {
+        for y in min_block_pos.y..=max_block_pos.y {
+            for z in min_block_pos.z..=max_block_pos.z {
+                let Some(block) = world.block(Point3::new(x, y, z).into()) else {
+                    continue;
+                };
+                let attrs = block_registry.get(block.id).unwrap();
+                if attrs.invisible || attrs.liquid {
+                    continue;
+                }
+                let offset = Vector3::new(x, y, z).cast().unwrap();
+                if attrs
+                    .boxes(*block)
+                    .iter()
+                    .any(|shape| shape.offset(offset).overlaps(min, max))
+                {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
+/// Whether there is a solid block right below the bottom of a collider at `pos`
+pub fn is_supported(
+    pos: Point3<f32>,
+    extents: Vector3<f32>,
+    world: &World,
+    block_registry: &BlockRegistry,
+) -> bool {
+    let half_extents = Vector3::new(extents.x / 2.0 - 0.001, 0.0, extents.z / 2.0 - 0.001);
+    overlaps_solid(
+        pos - half_extents - Vector3::new(0.0, 0.05, 0.0),
+        pos + half_extents - Vector3::new(0.0, 0.001, 0.0),
+        world,
+        block_registry,
+    )
+}
+
+/// Remember where entities were before a physics step, to interpolate from
+pub fn store_previous_system(mut query: Query<(&Position, &mut Interpolation)>) {
+    for (pos, mut interpolation) in &mut query {
+        interpolation.previous = pos.0;
+    }
+}
+
+/// Place interpolated entities `alpha` of the way from before the latest physics step to after it
+pub fn interpolate(world: &mut bevy_ecs::world::World, alpha: f32) {
+    for (pos, mut interpolation) in world
+        .query::<(&Position, &mut Interpolation)>()
+        .iter_mut(world)
+    {
+        interpolation.position = interpolation.previous + (pos.0 - interpolation.previous) * alpha;
+    }
+}
+
+/// How much smaller than their extents colliders are swept, so that resting against a block or
+/// sliding along the floor doesn't count as hitting it because of rounding errors
+const SKIN: f32 = 0.001;
+/// Most blocks a collider can hit and slide along in a single step
+const MAX_SLIDES: usize = 4;
+
+/// The first block a moving box runs into
+#[derive(Debug, Clone, Copy)]
+struct Hit {
+    /// Fraction of the motion covered before the hit
+    time: f32,
+    /// The axis along which the box hit the block
+    axis: usize,
+    /// Top of the block's box that was hit
+    top: f32,
+}
+
+/// When the box between `min` and `max` moving by `motion` first touches `shape`, and along
+/// which axis. Shapes the box already overlaps at the start are ignored, so it can move out of
+/// them.
+fn sweep_shape(
+    min: Point3<f32>,
+    max: Point3<f32>,
+    motion: Vector3<f32>,
+    shape: Aabb,
+) -> Option<(f32, usize)> {
+    let mut entry = (f32::NEG_INFINITY, 0.0, None);
+    let mut exit = f32::INFINITY;
+    for axis in 0..3 {
+        let d = motion[axis];
+        if d == 0.0 {
+            if max[axis] <= shape.min[axis] || min[axis] >= shape.max[axis] {
+                return None;
+            }
+            continue;
+        }
+        let (near, far) = if d > 0.0 {
+            (shape.min[axis] - max[axis], shape.max[axis] - min[axis])
+        } else {
+            (min[axis] - shape.max[axis], max[axis] - shape.min[axis])
+        };
+        if near / d.abs() > entry.0 {
+            entry = (near / d.abs(), near, Some(axis));
+        }
+        exit = exit.min(far / d.abs());
+    }
+    let (time, near, axis) = entry;
+    let axis = axis?;
+    if near < -SKIN || time > 1.0 || time >= exit {
+        return None;
+    }
+    Some((time.max(0.0), axis))
+}
+
+/// The first solid block a collider at `pos` runs into when moving by `motion`
+fn sweep(
+    pos: Point3<f32>,
+    extents: Vector3<f32>,
+    motion: Vector3<f32>,
+    world: &World,
+    block_registry: &BlockRegistry,
+) -> Option<Hit> {
+    let half_extents = Vector3::new(extents.x / 2.0, 0.0, extents.z / 2.0);
+    let skin = Vector3::new(SKIN, SKIN, SKIN);
+    let min = pos - half_extents + skin;
+    let max = pos + half_extents + Vector3::unit_y() * extents.y - skin;
+
+    // Every block the box passes through on the way
+    let end_min = min + motion;
+    let end_max = max + motion;
+    let area_min = Point3::new(
+        min.x.min(end_min.x),
+        min.y.min(end_min.y),
+        min.z.min(end_min.z),
+    );
+    let area_max = Point3::new(
+        max.x.max(end_max.x),
+        max.y.max(end_max.y),
+        max.z.max(end_max.z),
+    );
+    let area_min = Point3::from(BlockPos::from_point(area_min));
+    let area_max = Point3::from(BlockPos::from_point(area_max));
+
+    let mut first: Option<Hit> = None;
+    for x in area_min.x..=area_max.x {
+        for y in area_min.y..=area_max.y {
+            for z in area_min.z..=area_max.z {
+                let Some(block) = world.block(Point3::new(x, y, z).into()) else {
+                    continue;
+                };
+                let attrs = block_registry.get(block.id).unwrap();
+                if attrs.invisible || attrs.liquid {
+                    continue;
+                }
+                let offset = Vector3::new(x, y, z).cast().unwrap();
+                for shape in attrs.boxes(*block) {
+                    let shape = shape.offset(offset);
+                    let Some((time, axis)) = sweep_shape(min, max, motion, shape) else {
+                        continue;
+                    };
+                    if first.is_none_or(|first| time < first.time) {
+                        first = Some(Hit {
+                            time,
+                            axis,
+                            top: shape.max.y,
+                        });
+                    }
+                }
+            }
+        }
+    }
+    first
+}
+
+/// Whether the box between `min` and `max` overlaps any liquid block
+fn overlaps_liquid(
+    min: Point3<f32>,
+    max: Point3<f32>,
+    world: &World,
+    block_registry: &BlockRegistry,
+) -> bool {
+    let min_block_pos = Point3::from(BlockPos::from_point(min));
+    let max_block_pos = Point3::from(BlockPos::from_point(max));
+    for x in min_block_pos.x..=max_block_pos.x {
+        for y in min_block_pos.y..=max_block_pos.y {
+            for z in min_block_pos.z..=max_block_pos.z {
+                let Some(block) = world.block(Point3::new(x, y, z).into()) else {
+                    continue;
+                };
+                if block_registry.get(block.id).unwrap().liquid {
+                    return true;
+                }
+            }
+        }
+    }
+    false
+}
 
 pub fn physics_system(
-    mut query: Query<(&mut Position, &mut Velocity, &Collider)>,
+    mut query: Query<(&mut Position, &mut Velocity, &mut Collider)>,
     dt: Res<DeltaTime>,
     world: Res<World>,
     block_registry: Res<BlockRegistry>,
 ) {
-    for (mut pos, mut vel, col) in &mut query {
+    for (mut pos, mut vel, mut col) in &mut query {
+        col.step_offset = (col.step_offset + STEP_SMOOTHING_SPEED * dt.0).min(0.0);
+
         let d = (vel.0 * dt.0).magnitude();
 
         let c = if d > 0.4 {
@@ -31,111 +291,90 @@ pub fn physics_system(
             1
         };
 
+        let step = dt.0 / c as f32;
         for _ in 0..c {
             let prev_pos = pos.0;
-            pos.0 += vel.0 * (dt.0 / c as f32);
+            // Gravity is applied before moving, so something resting on a block keeps being
+            // pushed into it and stays on the ground every step
             if col.gravity {
-                vel.0 += GRAVITY * dt.0;
+                if col.in_liquid {
+                    vel.0 += GRAVITY * LIQUID_GRAVITY_SCALE * step;
+                } else {
+                    vel.0 += GRAVITY * step;
+                }
+            }
+            if col.in_liquid {
+                vel.0 *= (1.0 - LIQUID_DRAG * step).max(0.0);
             }
+            let mut motion = vel.0 * step;
+            col.on_ground = false;
 
             if !col.enabled {
+                pos.0 += motion;
+                col.in_liquid = false;
                 continue;
             }
 
-            let min = Point3::new(
-                pos.0.x - col.extents.x / 2.0,
-                pos.0.y,
-                pos.0.z - col.extents.z / 2.0,
-            );
-            let max = Point3::new(
-                pos.0.x + col.extents.x / 2.0,
-                pos.0.y + col.extents.y,
-                pos.0.z + col.extents.z / 2.0,
-            );
-            let min_block_pos = Point3::from(BlockPos::from_point(min));
-            let max_block_pos = Point3::from(BlockPos::from_point(max));
-
-            let mut collisions = vec![];
-
-            for block_pos_x in min_block_pos.x..=max_block_pos.x {
-                for block_pos_y in min_block_pos.y..=max_block_pos.y {
-                    for block_pos_z in min_block_pos.z..=max_block_pos.z {
-                        let block_pos =
-                            BlockPos::from(Point3::new(block_pos_x, block_pos_y, block_pos_z));
-
-                        let chunk_pos = block_pos.chunk_pos();
-                        let rel_pos = block_pos.rel_pos();
-
-                        let Some(chunk) = world.chunk(chunk_pos) else {
-                            continue;
-                        };
-                        let block = chunk.block(rel_pos);
-                        let attrs = block_registry.get(block.id).unwrap();
-                        if attrs.invisible {
-                            continue;
-                        }
-
-                        collisions.push((
-                            block_pos,
-                            (Point3::from(block_pos).cast::<f32>().unwrap()
-                                + Vector3::new(0.5, 0.5, 0.5))
-                            .distance2(min.midpoint(max)),
-                        ));
+            // Move up to the first block in the way, then slide along it with what is left of
+            // the motion, so nothing is skipped over however fast the collider moves
+            for _ in 0..MAX_SLIDES {
+                let Some(hit) = sweep(pos.0, col.extents, motion, &world, &block_registry) else {
+                    pos.0 += motion;
+                    break;
+                };
+
+                if hit.axis != 1 && col.step_height > 0.0 && vel.0.y <= 0.0 {
+                    // Step up onto the block if it's low enough and there is room on top of it
+                    let rise = hit.top - pos.0.y;
+                    let half_extents = Vector3::new(col.extents.x / 2.0, 0.0, col.extents.z / 2.0);
+                    let stepped_min = Point3::new(pos.0.x, pos.0.y + rise, pos.0.z) - half_extents;
+                    let stepped_max = stepped_min + col.extents;
+                    let skin = Vector3::new(SKIN, SKIN, SKIN);
+                    if rise > 0.0
+                        && rise <= col.step_height
+                        && !overlaps_solid(
+                            stepped_min + skin,
+                            stepped_max - skin,
+                            &world,
+                            &block_registry,
+                        )
+                    {
+                        pos.0.y += rise;
+                        col.step_offset -= rise;
+                        continue;
                     }
                 }
-            }
-
-            collisions.sort_by(|(_, adist), (_, bdist)| adist.partial_cmp(bdist).unwrap());
-
-            for (collision, _) in collisions {
-                let block_min_extended = Point3::from(collision).cast::<f32>().unwrap()
-                    - Vector3::new(col.extents.x / 2.0, col.extents.y, col.extents.z / 2.0);
-                let block_max_extended = Point3::from(collision).cast::<f32>().unwrap()
-                    + Vector3::new(col.extents.x / 2.0 + 1.0, 1.0, col.extents.z / 2.0 + 1.0);
 
-                let overlap = Vector3::new(
-                    (block_max_extended.x - pos.0.x).min(pos.0.x - block_min_extended.x),
-                    (block_max_extended.y - pos.0.y).min(pos.0.y - block_min_extended.y),
-                    (block_max_extended.z - pos.0.z).min(pos.0.z - block_min_extended.z),
-                );
-
-                if overlap.x <= 0.0 || overlap.y <= 0.0 || overlap.z <= 0.0 {
-                    continue;
+                if hit.axis == 1 && motion.y < 0.0 {
+                    col.on_ground = true;
                 }
+                pos.0 += motion * hit.time;
+                motion *= 1.0 - hit.time;
+                motion[hit.axis] = 0.0;
+                vel.0[hit.axis] = 0.0;
+            }
 
-                let dir = pos.0 - prev_pos;
-                if dir.is_zero() {
-                    continue;
+            // Undo the horizontal movement along each axis that would leave nothing to stand on
+            if col.sneaking && is_supported(prev_pos, col.extents, &world, &block_registry) {
+                let moved_x = Point3::new(pos.0.x, prev_pos.y, prev_pos.z);
+                if !is_supported(moved_x, col.extents, &world, &block_registry) {
+                    pos.0.x = prev_pos.x;
+                    vel.0.x = 0.0;
                 }
-                let dir = dir.normalize();
-
-                let scaled_dir =
-                    Vector3::new(dir.x / overlap.x, dir.y / overlap.y, dir.z / overlap.z);
-
-                // println!("{overlap:?}");
-                // println!("{dir:?}");
-                // println!("{scaled_dir:?}");
-
-                let diff = pos.0 - block_min_extended.midpoint(block_max_extended);
-                // println!("{diff:?}");
-
-                if scaled_dir.x.abs() >= scaled_dir.y.abs().max(scaled_dir.z.abs()) {
-                    pos.0.x -= overlap.x * scaled_dir.x.signum();
-                    if diff.x.signum() != vel.0.x.signum() {
-                        vel.0.x = 0.0;
-                    }
-                } else if scaled_dir.y.abs() >= scaled_dir.x.abs().max(scaled_dir.z.abs()) {
-                    pos.0.y -= overlap.y * scaled_dir.y.signum();
-                    if diff.y.signum() != vel.0.y.signum() {
-                        vel.0.y = 0.0;
-                    }
-                } else if scaled_dir.z.abs() >= scaled_dir.x.abs().max(scaled_dir.y.abs()) {
-                    pos.0.z -= overlap.z * scaled_dir.z.signum();
-                    if diff.z.signum() != vel.0.z.signum() {
-                        vel.0.z = 0.0;
-                    }
+                let moved_z = Point3::new(pos.0.x, prev_pos.y, pos.0.z);
+                if !is_supported(moved_z, col.extents, &world, &block_registry) {
+                    pos.0.z = prev_pos.z;
+                    vel.0.z = 0.0;
                 }
             }
+
+            let half_extents = Vector3::new(col.extents.x / 2.0, 0.0, col.extents.z / 2.0);
+            col.in_liquid = overlaps_liquid(
+                pos.0 - half_extents,
+                pos.0 + half_extents + Vector3::unit_y() * col.extents.y,
+                &world,
+                &block_registry,
+            );
         }
     }
 }
@@ -1,11 +1,13 @@
-use std::f32::consts::FRAC_PI_2;
+use std::f32::consts::{FRAC_PI_2, PI};
 
 use bevy_ecs::{
     component::Component,
     entity::Entity,
-    system::{Commands, Query, Res, ResMut},
+    event::EventReader,
+    query::With,
+    system::{Commands, Query, Res, ResMut, Resource},
 };
-use cgmath::{InnerSpace, Point3, Rad, Vector3, Zero};
+use cgmath::{Deg, EuclideanSpace, InnerSpace, Point3, Rad, Vector3, Zero};
 use winit::{event::MouseButton, keyboard::KeyCode};
 
 use crate::{
@@ -14,39 +16,137 @@ use crate::{
         block::{Block, BlockId, BlockMetadata},
         physics,
     },
-    input::Input,
-    server::{connection::Transport, message::MessageToServer},
+    input::{Action, ActionEvent, ActionState, Input},
+    light::{Lights, PointLight},
+    settings::Settings,
+    server::{
+        connection::Transport,
+        message::{MessageToServer, Orientation},
+    },
 };
 
 use super::{
-    block::BlockRegistry, physics::Collider, world::World, DeltaTime, MessageQueue, Position,
-    Velocity,
+    block::BlockRegistry,
+    edits::{BlockEdit, EditHistory, EditJournal},
+    physics::{Collider, Interpolation},
+    spectator::Spectator,
+    world::World,
+    DeltaTime, MessageQueue, Position, Velocity,
 };
 
 #[derive(Clone, Component)]
 pub struct PlayerController {
+    /// Walking speed
     speed: f32,
-    sensitivity: f32,
     mine_cooldown: f32,
     place_cooldown: f32,
     place_block_id: BlockId,
+    /// How far away blocks can be mined and placed, and are outlined
+    pub reach: f32,
     noclip: bool,
     fly_trigger_cooldown: f32,
+    sprinting: bool,
+    sprint_trigger_cooldown: f32,
+    crouching: bool,
+    /// Whether the player stood on the ground after the latest physics step, for animations
+    /// and sounds to follow
+    on_ground: bool,
+    /// Progress through the walk cycle, in radians
+    bob_phase: f32,
+    /// How strongly the view bobs, eased towards how fast the player walks
+    bob_amount: f32,
+    /// Downwards speed during the last frame, to tell how hard the player landed
+    fall_speed: f32,
+    /// Extra camera height after landing, springing back to zero
+    landing_offset: f32,
+    landing_velocity: f32,
 }
 
+/// The reach of new players, which servers also allow by default
+pub const DEFAULT_REACH: f32 = 5.0;
 const SAFE_FRAC_PI_2: f32 = FRAC_PI_2 - 0.0001;
+const SPRINT_SPEED_FACTOR: f32 = 1.5;
+const CROUCH_SPEED_FACTOR: f32 = 0.3;
+const STANDING_HEIGHT: f32 = 1.8;
+const CROUCHING_HEIGHT: f32 = 1.5;
+/// Height of the camera above the top of the collider
+const EYE_OFFSET: f32 = -0.2;
+/// Distance walked per step, each step being half a walk cycle
+const STRIDE: f32 = 1.2;
+/// How far the camera bobs up and down, and half as far sideways
+const BOB_HEIGHT: f32 = 0.06;
+/// How quickly the bobbing fades in and out when starting and stopping
+const BOB_EASING: f32 = 8.0;
+/// Stiffness of the spring pulling the camera back after landing. Being critically damped, it
+/// settles this many radians per second without overshooting.
+const LANDING_SPRING: f32 = 14.0;
+/// Landings slower than this don't move the camera
+const LANDING_MIN_SPEED: f32 = 4.0;
+/// How much of the landing speed is turned into a downwards kick of the camera
+const LANDING_KICK: f32 = 0.15;
+/// How many times a second the player's position is sent to the server while moving
+const POSITION_SYNC_RATE: f32 = 20.0;
 
 impl PlayerController {
     pub fn new() -> Self {
         Self {
             speed: 4.0,
-            sensitivity: 1.0,
             mine_cooldown: 0.0,
             place_cooldown: 0.0,
             place_block_id: BlockId(1),
+            reach: DEFAULT_REACH,
             noclip: true,
             fly_trigger_cooldown: 0.0,
+            sprinting: false,
+            sprint_trigger_cooldown: 0.0,
+            crouching: false,
+            on_ground: false,
+            bob_phase: 0.0,
+            bob_amount: 0.0,
+            fall_speed: 0.0,
+            landing_offset: 0.0,
+            landing_velocity: 0.0,
+        }
+    }
+
+    /// Offset of the camera from the player's eyes, bobbing while walking and dipping on landing
+    fn camera_motion(
+        &mut self,
+        velocity: Vector3<f32>,
+        right: Vector3<f32>,
+        dt: f32,
+        settings: &Settings,
+    ) -> Vector3<f32> {
+        let mut offset = Vector3::zero();
+
+        if settings.camera_smoothing {
+            if self.on_ground && self.fall_speed >= LANDING_MIN_SPEED {
+                self.landing_velocity -= self.fall_speed * LANDING_KICK;
+            }
+            // Critically damped spring, integrated per frame
+            let acceleration = -LANDING_SPRING * LANDING_SPRING * self.landing_offset
+                - 2.0 * LANDING_SPRING * self.landing_velocity;
+            self.landing_velocity += acceleration * dt;
+            self.landing_offset += self.landing_velocity * dt;
+            offset.y += self.landing_offset;
+        } else {
+            self.landing_offset = 0.0;
+            self.landing_velocity = 0.0;
         }
+        self.fall_speed = (-velocity.y).max(0.0);
+
+        let speed = Vector3::new(velocity.x, 0.0, velocity.z).magnitude();
+        let target = if settings.view_bobbing && self.on_ground && !self.noclip {
+            (speed / self.speed).min(1.0)
+        } else {
+            0.0
+        };
+        self.bob_amount += (target - self.bob_amount) * (BOB_EASING * dt).min(1.0);
+        self.bob_phase = (self.bob_phase + speed * dt * PI / STRIDE) % (2.0 * PI);
+        offset.y += self.bob_phase.sin().abs() * BOB_HEIGHT * self.bob_amount;
+        offset += right * self.bob_phase.cos() * BOB_HEIGHT / 2.0 * self.bob_amount;
+
+        offset
     }
 }
 
@@ -54,18 +154,30 @@ pub fn update_system(
     mut query: Query<(
         &mut PlayerController,
         &Position,
+        &Interpolation,
         &mut Velocity,
         &mut Collider,
     )>,
     dt: Res<DeltaTime>,
     input: Res<Input>,
+    mut action_events: EventReader<ActionEvent>,
     mut camera: ResMut<Camera>,
     mut world: ResMut<World>,
     mut transport: ResMut<MessageQueue>,
+    mut edits: ResMut<EditJournal>,
+    mut history: ResMut<EditHistory>,
     block_registry: Res<BlockRegistry>,
+    settings: Res<Settings>,
+    spectator: Res<Spectator>,
     mut commands: Commands,
 ) {
-    for (mut pc, pos, mut vel, mut col) in &mut query {
+    let pressed = action_events
+        .read()
+        .filter(|event| event.state == ActionState::Pressed)
+        .map(|event| event.action)
+        .collect::<Vec<_>>();
+
+    for (mut pc, pos, interpolation, mut vel, mut col) in &mut query {
         let mut v = Vector3::zero();
 
         fn as_f32(b: bool) -> f32 {
@@ -76,10 +188,10 @@ pub fn update_system(
             }
         }
 
-        let amount_forward = as_f32(input.is_pressed(KeyCode::KeyW));
-        let amount_backward = as_f32(input.is_pressed(KeyCode::KeyS));
-        let amount_left = as_f32(input.is_pressed(KeyCode::KeyA));
-        let amount_right = as_f32(input.is_pressed(KeyCode::KeyD));
+        let amount_forward = as_f32(input.is_action_pressed(Action::MoveForward));
+        let amount_backward = as_f32(input.is_action_pressed(Action::MoveBackward));
+        let amount_left = as_f32(input.is_action_pressed(Action::MoveLeft));
+        let amount_right = as_f32(input.is_action_pressed(Action::MoveRight));
 
         let delta = input.mouse_delta();
         let rotate_horizontal = delta.x;
@@ -91,8 +203,8 @@ pub fn update_system(
         v += forward * (amount_forward - amount_backward);
         v += right * (amount_right - amount_left);
 
-        camera.yaw += Rad(rotate_horizontal) * pc.sensitivity * 0.001;
-        camera.pitch += Rad(-rotate_vertical) * pc.sensitivity * 0.001;
+        camera.yaw += Rad(rotate_horizontal) * settings.mouse_sensitivity * 0.001;
+        camera.pitch += Rad(-rotate_vertical) * settings.mouse_sensitivity * 0.001;
 
         if camera.pitch < -Rad(SAFE_FRAC_PI_2) {
             camera.pitch = -Rad(SAFE_FRAC_PI_2);
@@ -100,17 +212,72 @@ pub fn update_system(
             camera.pitch = Rad(SAFE_FRAC_PI_2);
         }
 
+        // The body stays where it is while spectating, even when the chunks around it are
+        // unloaded as the camera flies off, and the camera moves on its own
+        if spectator.is_active() {
+            vel.0 = Vector3::zero();
+            continue;
+        }
+
+        // Crouching only applies on foot, and standing up needs room above the player
+        let wants_crouch = !pc.noclip && input.is_action_pressed(Action::Descend);
+        if wants_crouch != pc.crouching {
+            let half_extents = Vector3::new(col.extents.x / 2.0, 0.0, col.extents.z / 2.0);
+            let epsilon = Vector3::new(0.001, 0.001, 0.001);
+            let blocked = !wants_crouch
+                && physics::overlaps_solid(
+                    pos.0 - half_extents + epsilon,
+                    pos.0 + half_extents + Vector3::unit_y() * STANDING_HEIGHT - epsilon,
+                    &world,
+                    &block_registry,
+                );
+            if !blocked {
+                pc.crouching = wants_crouch;
+                col.extents.y = if wants_crouch {
+                    CROUCHING_HEIGHT
+                } else {
+                    STANDING_HEIGHT
+                };
+            }
+        }
+        col.sneaking = pc.crouching;
+
+        if pressed.contains(&Action::MoveForward) {
+            if pc.sprint_trigger_cooldown > 0.0 {
+                pc.sprinting = true;
+            } else {
+                pc.sprint_trigger_cooldown = 0.25;
+            }
+        }
+        if input.is_action_pressed(Action::Sprint) {
+            pc.sprinting = true;
+        }
+        if amount_forward == 0.0 || pc.crouching {
+            pc.sprinting = false;
+        }
+
         if !v.is_zero() {
             v = v.normalize() * pc.speed;
+            if pc.sprinting {
+                v *= SPRINT_SPEED_FACTOR;
+            } else if pc.crouching {
+                v *= CROUCH_SPEED_FACTOR;
+            }
+            if col.in_liquid {
+                v *= 0.5;
+            }
         }
 
-        camera.position = pos.0 + Vector3::unit_y() * 1.6;
+        pc.on_ground = col.on_ground;
+        camera.position = interpolation.position
+            + Vector3::unit_y() * (col.extents.y + EYE_OFFSET + col.step_offset)
+            + pc.camera_motion(vel.0, right, dt.0, &settings);
 
         vel.0.x = v.x;
         vel.0.z = v.z;
 
-        if input.is_just_pressed(KeyCode::Space) {
-            if !pc.noclip {
+        if pressed.contains(&Action::Jump) {
+            if col.on_ground && !col.in_liquid {
                 vel.0.y = physics::jump_height_to_vel(1.2);
             }
 
@@ -127,13 +294,20 @@ pub fn update_system(
             || input.is_mouse_pressed(MouseButton::Left) && pc.mine_cooldown <= 0.0
         {
             if let Some(hitinfo) =
-                world.raycast(camera.position, camera.forward(), 5.0, &block_registry)
+                world.raycast(camera.position, camera.forward(), pc.reach, &block_registry)
             {
                 let pos = hitinfo.position;
                 let block = Block {
                     id: BlockId(0),
                     metadata: BlockMetadata(0),
                 };
+                if let Some(&old) = world.block(pos) {
+                    history.push(BlockEdit {
+                        pos,
+                        old,
+                        new: block,
+                    });
+                }
                 world.place_block(block, pos);
                 pc.mine_cooldown = 0.25;
 
@@ -142,6 +316,7 @@ pub fn update_system(
                     .send_blocking(MessageToServer::ReplaceBlock {
                         pos,
                         new_block: block,
+                        edit: edits.record(pos),
                     })
                     .unwrap();
             }
@@ -151,15 +326,25 @@ pub fn update_system(
             || input.is_mouse_pressed(MouseButton::Right) && pc.place_cooldown <= 0.0
         {
             if let Some(hitinfo) =
-                world.raycast(camera.position, camera.forward(), 10000.0, &block_registry)
+                world.raycast(camera.position, camera.forward(), pc.reach, &block_registry)
             {
-                let pos = (Point3::from(hitinfo.position)
-                    + hitinfo.normal.cast::<isize>().unwrap())
-                .into();
+                let pos = hitinfo.adjacent();
+                let metadata = block_registry
+                    .get(pc.place_block_id)
+                    .map_or(BlockMetadata(0), |attr| {
+                        attr.shape.placement_metadata(camera.forward())
+                    });
                 let block = Block {
                     id: pc.place_block_id,
-                    metadata: BlockMetadata(0),
+                    metadata,
                 };
+                if let Some(&old) = world.block(pos) {
+                    history.push(BlockEdit {
+                        pos,
+                        old,
+                        new: block,
+                    });
+                }
                 world.place_block(block, pos);
                 pc.place_cooldown = 0.25;
 
@@ -168,6 +353,7 @@ pub fn update_system(
                     .send_blocking(MessageToServer::ReplaceBlock {
                         pos,
                         new_block: block,
+                        edit: edits.record(pos),
                     })
                     .unwrap();
             }
@@ -185,26 +371,151 @@ pub fn update_system(
         if input.is_just_pressed(KeyCode::Digit4) {
             pc.place_block_id.0 = 4;
         }
+        if input.is_just_pressed(KeyCode::Digit5) {
+            pc.place_block_id.0 = 5;
+        }
+        if input.is_just_pressed(KeyCode::Digit6) {
+            pc.place_block_id.0 = 6;
+        }
+        if input.is_just_pressed(KeyCode::Digit7) {
+            pc.place_block_id.0 = 7;
+        }
+        if input.is_just_pressed(KeyCode::Digit8) {
+            pc.place_block_id.0 = 8;
+        }
+        if input.is_just_pressed(KeyCode::Digit9) {
+            pc.place_block_id.0 = 9;
+        }
 
         pc.mine_cooldown -= dt.0;
         pc.place_cooldown -= dt.0;
         pc.fly_trigger_cooldown -= dt.0;
+        pc.sprint_trigger_cooldown -= dt.0;
 
-        if input.is_just_pressed(KeyCode::KeyC) {
+        if pressed.contains(&Action::ToggleNoclip) {
             pc.noclip = !pc.noclip;
             col.enabled = !pc.noclip;
             col.gravity = !pc.noclip;
         }
 
+        if col.in_liquid && !pc.noclip && input.is_action_pressed(Action::Jump) {
+            vel.0.y = vel.0.y.max(physics::SWIM_SPEED);
+        }
+
         if pc.noclip {
             vel.0.y = 0.0;
 
-            if input.is_pressed(KeyCode::Space) {
+            if input.is_action_pressed(Action::Jump) {
                 vel.0.y += pc.speed;
             }
-            if input.is_pressed(KeyCode::ShiftLeft) {
+            if input.is_action_pressed(Action::Descend) {
                 vel.0.y -= pc.speed;
             }
         }
     }
 }
+
+/// Eases the camera's field of view towards the one in the settings, narrowed while zooming
+/// and widened when moving faster than walking speed
+pub fn fov_system(
+    query: Query<(&PlayerController, &Velocity)>,
+    dt: Res<DeltaTime>,
+    input: Res<Input>,
+    settings: Res<Settings>,
+    mut camera: ResMut<Camera>,
+) {
+    /// How much narrower the view gets while zooming
+    const ZOOM: f32 = 4.0;
+    /// Widening at twice walking speed and above
+    const MAX_SPEED_KICK: Deg<f32> = Deg(10.0);
+    /// How quickly the field of view follows its target, per second
+    const RATE: f32 = 12.0;
+
+    let Ok((pc, vel)) = query.get_single() else {
+        return;
+    };
+
+    let mut target = Deg(settings.fov.clamp(10.0, 150.0));
+    let speed = Vector3::new(vel.0.x, 0.0, vel.0.z).magnitude();
+    let speed_factor = (speed / pc.speed - 1.0).clamp(0.0, 1.0);
+    target += MAX_SPEED_KICK * speed_factor;
+    if input.is_action_pressed(Action::Zoom) {
+        target /= ZOOM;
+    }
+
+    let t = 1.0 - (-RATE * dt.0).exp();
+    let fovy = camera.fovy;
+    camera.fovy = fovy + (Rad::from(target) - fovy) * t;
+}
+
+/// Makes the player glow when holding a block that emits light
+pub fn held_item_light_system(
+    query: Query<(&PlayerController, &Position)>,
+    settings: Res<Settings>,
+    block_registry: Res<BlockRegistry>,
+    mut lights: ResMut<Lights>,
+) {
+    lights.lights.clear();
+
+    if !settings.held_item_light {
+        return;
+    }
+
+    for (pc, pos) in &query {
+        let Some(attr) = block_registry.get(pc.place_block_id) else {
+            continue;
+        };
+        if attr.light_emission <= 0.0 {
+            continue;
+        }
+
+        lights.lights.push(PointLight {
+            position: pos.0 + Vector3::unit_y() * 1.2,
+            color: Vector3::new(1.0, 0.85, 0.6),
+            radius: attr.light_emission,
+        });
+    }
+}
+
+/// The player's position and orientation as last sent to the server
+#[derive(Debug, Default, Resource)]
+pub struct PositionSync {
+    last_sent: Option<(Point3<f32>, Orientation)>,
+    /// Seconds since the last update was sent
+    elapsed: f32,
+}
+
+/// Tell the server where the player is and which way they are looking, at most
+/// [`POSITION_SYNC_RATE`] times a second and only when either changed
+pub fn position_sync_system(
+    query: Query<&Position, With<PlayerController>>,
+    camera: Res<Camera>,
+    dt: Res<DeltaTime>,
+    transport: Res<MessageQueue>,
+    mut sync: ResMut<PositionSync>,
+) {
+    sync.elapsed += dt.0;
+    if sync.elapsed < 1.0 / POSITION_SYNC_RATE {
+        return;
+    }
+    let Ok(pos) = query.get_single() else {
+        return;
+    };
+    let orientation = Orientation {
+        yaw: camera.yaw.0,
+        pitch: camera.pitch.0,
+    };
+    if sync.last_sent == Some((pos.0, orientation)) {
+        return;
+    }
+
+    sync.elapsed = 0.0;
+    sync.last_sent = Some((pos.0, orientation));
+    transport
+        .0
+        .send_blocking(MessageToServer::UpdatePlayerPosition {
+            new_position: pos.0.to_vec(),
+            orientation,
+        })
+        .unwrap();
+}
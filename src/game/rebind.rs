@@ -0,0 +1,125 @@
+use winit::keyboard::KeyCode;
+
+use crate::{
+    input::{Action, InputMap},
+    locale::Locale,
+};
+
+/// Options menu for changing key bindings, driven entirely by the keyboard.
+///
+/// Up and down select an action, Enter binds the next key pressed to it, and Backspace resets
+/// it to its default key. There is no in-game UI yet, so the menu is printed to stdout.
+pub struct RebindMenu {
+    selected: usize,
+    /// Waiting for the key to bind the selected action to
+    capturing: bool,
+}
+
+impl RebindMenu {
+    /// Opens and closes the menu. Not rebindable, so that it can't be lost.
+    pub const TOGGLE_KEY: KeyCode = KeyCode::F1;
+
+    pub fn open(input_map: &InputMap, locale: &Locale) -> Self {
+        println!("{}", locale.get("rebind.title"));
+        for action in Action::ALL {
+            println!(
+                "  {}: {:?}",
+                Self::action_name(action, locale),
+                input_map.key(action)
+            );
+        }
+        let menu = Self {
+            selected: 0,
+            capturing: false,
+        };
+        menu.print_selected(input_map, locale);
+        menu
+    }
+
+    fn action_name(action: Action, locale: &Locale) -> String {
+        locale.get(&format!("action.{action:?}")).to_string()
+    }
+
+    fn selected_action(&self) -> Action {
+        Action::ALL[self.selected]
+    }
+
+    fn print_selected(&self, input_map: &InputMap, locale: &Locale) {
+        let action = self.selected_action();
+        println!(
+            "> {}: {:?}",
+            Self::action_name(action, locale),
+            input_map.key(action)
+        );
+    }
+
+    fn warn_conflicts(key: KeyCode, conflicts: &[Action], locale: &Locale) {
+        if !conflicts.is_empty() {
+            let actions = conflicts
+                .iter()
+                .map(|&action| Self::action_name(action, locale))
+                .collect::<Vec<_>>()
+                .join(", ");
+            println!(
+                "{}",
+                locale.format(
+                    "rebind.conflict",
+                    &[("key", &format!("{key:?}")), ("actions", &actions)]
+                )
+            );
+        }
+    }
+
+    /// Handle a key pressed while the menu is open, returning whether any binding changed
+    pub fn key_pressed(&mut self, key: KeyCode, input_map: &mut InputMap, locale: &Locale) -> bool {
+        let action = self.selected_action();
+        let name = Self::action_name(action, locale);
+
+        if self.capturing {
+            self.capturing = false;
+            let conflicts = input_map.rebind(action, key);
+            println!(
+                "{}",
+                locale.format(
+                    "rebind.bound",
+                    &[("action", &name), ("key", &format!("{key:?}"))]
+                )
+            );
+            Self::warn_conflicts(key, &conflicts, locale);
+            return true;
+        }
+
+        match key {
+            KeyCode::ArrowUp => {
+                self.selected = (self.selected + Action::ALL.len() - 1) % Action::ALL.len();
+                self.print_selected(input_map, locale);
+            }
+            KeyCode::ArrowDown => {
+                self.selected = (self.selected + 1) % Action::ALL.len();
+                self.print_selected(input_map, locale);
+            }
+            KeyCode::Enter => {
+                self.capturing = true;
+                println!(
+                    "{}",
+                    locale.format("rebind.press_key", &[("action", &name)])
+                );
+            }
+            KeyCode::Backspace => {
+                let conflicts = input_map.reset(action);
+                let key = input_map.key(action);
+                println!(
+                    "{}",
+                    locale.format(
+                        "rebind.reset",
+                        &[("action", &name), ("key", &format!("{key:?}"))]
+                    )
+                );
+                Self::warn_conflicts(key, &conflicts, locale);
+                return true;
+            }
+            _ => {}
+        }
+        false
+    }
+}
@@ -0,0 +1,56 @@
+use bevy_ecs::component::Component;
+use cgmath::{Point3, Vector3};
+
+use crate::{
+    direction::Direction,
+    mesh::{Mesh, MeshBuilder},
+};
+
+use super::{atlas::Atlas, block::BlockRegistry, world::World};
+
+/// Draw a blob shadow on the ground below this entity
+#[derive(Clone, Copy, Component)]
+pub struct BlobShadow {
+    pub radius: f32,
+}
+
+impl BlobShadow {
+    /// How far below the entity the ground is searched for
+    pub const MAX_DISTANCE: f32 = 4.0;
+    /// Lift the shadow slightly above the ground to avoid z-fighting
+    const GROUND_OFFSET: f32 = 0.005;
+
+    /// Where the shadow of an entity standing at `pos` should be drawn, if there is ground below it
+    pub fn ground_position(
+        pos: Point3<f32>,
+        world: &World,
+        block_registry: &BlockRegistry,
+    ) -> Option<Point3<f32>> {
+        // Start slightly above the feet so standing on a block still finds it
+        let origin = pos + Vector3::new(0.0, 0.1, 0.0);
+        let hit = world.raycast(
+            origin,
+            -Vector3::unit_y(),
+            Self::MAX_DISTANCE,
+            block_registry,
+        )?;
+        // Only project onto top faces; hitting a side means we started inside a block
+        if hit.face != Direction::Up {
+            return None;
+        }
+        Some(Point3::new(pos.x, hit.point.y + Self::GROUND_OFFSET, pos.z))
+    }
+
+    /// A flat quad centered on the origin, textured with the shadow blob
+    pub fn mesh(&self, atlas: &Atlas, device: &wgpu::Device) -> Mesh {
+        let size = self.radius * 2.0;
+        let mut builder = MeshBuilder::new();
+        builder.add_face_scaled(
+            Vector3::new(0.0, -size / 2.0, 0.0),
+            Direction::Up,
+            atlas.uv(atlas.cell("shadow")),
+            size,
+        );
+        builder.build(atlas.material.clone(), device)
+    }
+}
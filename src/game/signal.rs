@@ -0,0 +1,68 @@
+//! On/off signals sent from levers through wires to lamps.
+//!
+//! The signal state is kept in the block metadata: whether a lever is switched on, the signal
+//! strength of a wire, and whether a lamp is lit. Only the server evaluates signals, clients
+//! just draw the state they are sent.
+
+use cgmath::Point3;
+
+use crate::direction::Direction;
+
+use super::{
+    block::{Block, BlockId, BlockMetadata},
+    chunk::BlockPos,
+    world::World,
+};
+
+pub const LAMP: BlockId = BlockId(5);
+pub const LEVER: BlockId = BlockId(8);
+pub const WIRE: BlockId = BlockId(9);
+
+/// Strength of the signal given off by a lever. Every wire it passes through weakens it by one,
+/// so a lever can power a lamp at most this many wires away.
+pub const MAX_STRENGTH: u8 = 15;
+
+/// Whether a lever, wire or lamp is switched on
+pub fn is_powered(block: Block) -> bool {
+    block.metadata.0 > 0
+}
+
+/// The signal strength `block` gives to its neighbours
+pub fn output(block: Block) -> u8 {
+    match block.id {
+        LEVER if is_powered(block) => MAX_STRENGTH,
+        WIRE => block.metadata.0,
+        _ => 0,
+    }
+}
+
+/// The lever with its state flipped
+pub fn toggle_lever(lever: Block) -> Block {
+    Block {
+        id: lever.id,
+        metadata: BlockMetadata(u8::from(!is_powered(lever))),
+    }
+}
+
+pub fn neighbours(pos: BlockPos) -> impl Iterator<Item = BlockPos> {
+    Direction::iter().map(move |dir| (Point3::from(pos) + dir.normal()).into())
+}
+
+/// The block at `pos` with its signal state recomputed from its neighbours, if that changed it
+pub fn update(world: &World, pos: BlockPos) -> Option<Block> {
+    let block = *world.block(pos)?;
+    let input = neighbours(pos)
+        .filter_map(|pos| world.block(pos))
+        .map(|&block| output(block))
+        .max()
+        .unwrap_or(0);
+    let metadata = match block.id {
+        WIRE => input.saturating_sub(1),
+        LAMP => u8::from(input > 0),
+        _ => return None,
+    };
+    (metadata != block.metadata.0).then_some(Block {
+        id: block.id,
+        metadata: BlockMetadata(metadata),
+    })
+}
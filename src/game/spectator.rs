@@ -0,0 +1,108 @@
+use bevy_ecs::{
+    event::EventReader,
+    system::{Res, ResMut, Resource},
+};
+use cgmath::{InnerSpace, Point3, Vector3, Zero};
+
+use crate::{
+    camera::Camera,
+    input::{Action, ActionEvent, ActionState, Input},
+};
+
+use super::DeltaTime;
+
+/// A free camera that flies around on its own, leaving the player's body where it was
+#[derive(Debug, Default, Resource)]
+pub enum Spectator {
+    #[default]
+    Off,
+    Flying(Point3<f32>),
+    /// Spectating has ended and the camera is easing back to the player's eyes
+    Returning {
+        /// Where the camera was when spectating ended
+        from: Point3<f32>,
+        /// From 0 to 1
+        progress: f32,
+    },
+}
+
+impl Spectator {
+    pub fn is_active(&self) -> bool {
+        matches!(self, Spectator::Flying(_))
+    }
+
+    /// Where the world is seen from, the camera while spectating and `player` otherwise
+    pub fn view_position(&self, camera: &Camera, player: Point3<f32>) -> Point3<f32> {
+        if self.is_active() {
+            camera.position
+        } else {
+            player
+        }
+    }
+}
+
+const SPEED: f32 = 10.0;
+const FAST_SPEED_FACTOR: f32 = 3.0;
+/// Seconds the camera takes to fly back to the player
+const RETURN_TIME: f32 = 0.4;
+
+/// Toggle spectating, and move the camera while spectating or returning from it. Runs after the
+/// player's update, which still turns the camera but leaves its position alone while spectating.
+pub fn update_system(
+    mut spectator: ResMut<Spectator>,
+    mut camera: ResMut<Camera>,
+    mut action_events: EventReader<ActionEvent>,
+    input: Res<Input>,
+    dt: Res<DeltaTime>,
+) {
+    let toggled = action_events
+        .read()
+        .filter(|event| event.state == ActionState::Pressed)
+        .any(|event| event.action == Action::ToggleSpectator);
+    if toggled {
+        *spectator = if spectator.is_active() {
+            Spectator::Returning {
+                from: camera.position,
+                progress: 0.0,
+            }
+        } else {
+            Spectator::Flying(camera.position)
+        };
+    }
+
+    match &mut *spectator {
+        Spectator::Off => {}
+        Spectator::Flying(position) => {
+            let as_f32 = |action| {
+                if input.is_action_pressed(action) {
+                    1.0
+                } else {
+                    0.0
+                }
+            };
+            let forward = camera.forward();
+            let right = forward.cross(Vector3::unit_y()).normalize();
+            let mut v = forward * (as_f32(Action::MoveForward) - as_f32(Action::MoveBackward))
+                + right * (as_f32(Action::MoveRight) - as_f32(Action::MoveLeft))
+                + Vector3::unit_y() * (as_f32(Action::Jump) - as_f32(Action::Descend));
+            if !v.is_zero() {
+                v = v.normalize() * SPEED;
+                if input.is_action_pressed(Action::Sprint) {
+                    v *= FAST_SPEED_FACTOR;
+                }
+            }
+            *position += v * dt.0;
+            camera.position = *position;
+        }
+        Spectator::Returning { from, progress } => {
+            // The player's update has put the camera at their eyes, ease from where it was to
+            // there
+            *progress = (*progress + dt.0 / RETURN_TIME).min(1.0);
+            let t = *progress * *progress * (3.0 - 2.0 * *progress);
+            camera.position = *from + (camera.position - *from) * t;
+            if *progress >= 1.0 {
+                *spectator = Spectator::Off;
+            }
+        }
+    }
+}
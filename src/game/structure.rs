@@ -0,0 +1,90 @@
+use cgmath::{Point3, Vector3};
+use rand::Rng;
+
+use super::{
+    biome::Biome,
+    block::{Block, BlockId, BlockMetadata},
+    chunk::BlockPos,
+};
+
+pub const STONE: BlockId = BlockId(2);
+pub const LOG: BlockId = BlockId(10);
+pub const LEAVES: BlockId = BlockId(11);
+
+/// Something placed on top of the terrain by worldgen, possibly reaching into neighbouring
+/// chunks
+#[derive(Debug, Clone, Copy)]
+pub enum Structure {
+    Tree { trunk_height: isize },
+    Boulder { radius: isize },
+}
+
+impl Structure {
+    /// How far structures reach from their base horizontally, at most
+    pub const MAX_REACH: isize = 2;
+
+    pub fn random(biome: Biome, rng: &mut impl Rng) -> Self {
+        // Trees don't grow on bare rock
+        if biome != Biome::Mountains && rng.gen_bool(0.8) {
+            Structure::Tree {
+                trunk_height: rng.gen_range(4..=6),
+            }
+        } else {
+            Structure::Boulder {
+                radius: rng.gen_range(1..=Self::MAX_REACH),
+            }
+        }
+    }
+
+    /// Every block of the structure when standing on `base`, the first block above the ground
+    pub fn blocks(self, base: BlockPos) -> Vec<(BlockPos, Block)> {
+        let base = Point3::from(base);
+        let block = |id| Block {
+            id,
+            metadata: BlockMetadata(0),
+        };
+        let mut blocks = vec![];
+
+        match self {
+            Structure::Tree { trunk_height } => {
+                for y in 0..trunk_height {
+                    blocks.push(((base + Vector3::new(0, y, 0)).into(), block(LOG)));
+                }
+                // A rounded blob of leaves around the top of the trunk. Structures only fill
+                // air, so these leave the trunk alone.
+                let top = base + Vector3::new(0, trunk_height, 0);
+                for x in -2..=2isize {
+                    for y in -2..=1isize {
+                        for z in -2..=2isize {
+                            let radius = if y <= -1 { 2 } else { 1 };
+                            if x.abs() > radius || z.abs() > radius {
+                                continue;
+                            }
+                            // Cut off the corners
+                            if x.abs() == radius && z.abs() == radius && y != -1 {
+                                continue;
+                            }
+                            let pos = top + Vector3::new(x, y, z);
+                            blocks.push((pos.into(), block(LEAVES)));
+                        }
+                    }
+                }
+            }
+            Structure::Boulder { radius } => {
+                // Sunk halfway into the ground
+                for x in -radius..=radius {
+                    for y in -radius..=radius {
+                        for z in -radius..=radius {
+                            if x * x + y * y + z * z <= radius * radius {
+                                let pos = base + Vector3::new(x, y, z);
+                                blocks.push((pos.into(), block(STONE)));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        blocks
+    }
+}
@@ -0,0 +1,74 @@
+use winit::keyboard::KeyCode;
+
+use crate::locale::Locale;
+
+use super::npc::{NpcId, Trade};
+
+/// What the player did in the trade menu
+pub enum TradeInput {
+    None,
+    /// Trade the offer at this index
+    Confirm(usize),
+    Close,
+}
+
+/// The offers of the NPC the player is trading with, driven by the keyboard like
+/// [`RebindMenu`](super::rebind::RebindMenu) and printed to stdout.
+///
+/// Up and down select an offer, Enter asks the server to make the trade, and Backspace closes
+/// the menu.
+pub struct TradeMenu {
+    pub npc: NpcId,
+    offers: Vec<Trade>,
+    selected: usize,
+}
+
+impl TradeMenu {
+    pub fn open(npc: NpcId, name: &str, offers: Vec<Trade>, locale: &Locale) -> Self {
+        println!("{}", locale.format("trade.title", &[("npc", &name)]));
+        for offer in &offers {
+            println!("  {}", Self::offer_text(offer, locale));
+        }
+        let menu = Self {
+            npc,
+            offers,
+            selected: 0,
+        };
+        menu.print_selected(locale);
+        menu
+    }
+
+    fn offer_text(offer: &Trade, locale: &Locale) -> String {
+        locale.format(
+            "trade.offer",
+            &[
+                ("cost", &offer.cost.to_string()),
+                ("reward", &offer.reward.to_string()),
+            ],
+        )
+    }
+
+    fn print_selected(&self, locale: &Locale) {
+        if let Some(offer) = self.offers.get(self.selected) {
+            println!("> {}", Self::offer_text(offer, locale));
+        }
+    }
+
+    pub fn key_pressed(&mut self, key: KeyCode, locale: &Locale) -> TradeInput {
+        let count = self.offers.len().max(1);
+        match key {
+            KeyCode::ArrowUp => {
+                self.selected = (self.selected + count - 1) % count;
+                self.print_selected(locale);
+            }
+            KeyCode::ArrowDown => {
+                self.selected = (self.selected + 1) % count;
+                self.print_selected(locale);
+            }
+            KeyCode::Enter if !self.offers.is_empty() => return TradeInput::Confirm(self.selected),
+            KeyCode::Backspace => return TradeInput::Close,
+            _ => {}
+        }
+        TradeInput::None
+    }
+}
@@ -1,9 +1,11 @@
-use crate::mesh::Direction;
+use crate::direction::Direction;
 
 use super::{
+    biome::Biome,
     block::{Block, BlockId, BlockMetadata, BlockRegistry},
-    chunk::{BlockPos, Chunk, ChunkPos},
-    worldgen::Worldgen,
+    chunk::{BlockPos, Chunk, ChunkPos, ChunkRelativeBlockPos},
+    dimension::DimensionId,
+    worldgen::{self, Worldgen},
 };
 use bevy_ecs::system::Resource;
 use cgmath::{EuclideanSpace, Point3, Vector3};
@@ -14,13 +16,24 @@ use wgpu::naga::FastHashMap;
 pub struct World {
     pub chunks: FastHashMap<ChunkPos, Chunk>,
     pub worldgen: Worldgen,
+    pub dimension: DimensionId,
+    /// Blocks of structures reaching into chunks that weren't loaded when the structure was
+    /// generated, placed once those chunks are generated or loaded. The server saves them with
+    /// the world, see [`pending_blocks`](Self::pending_blocks).
+    pending_blocks: FastHashMap<ChunkPos, Vec<(BlockPos, Block)>>,
 }
 
 impl World {
     pub fn new() -> Self {
+        Self::with_worldgen(DimensionId::OVERWORLD, Worldgen::new())
+    }
+
+    pub fn with_worldgen(dimension: DimensionId, worldgen: Worldgen) -> Self {
         Self {
             chunks: FastHashMap::default(),
-            worldgen: Worldgen::new(),
+            worldgen,
+            dimension,
+            pending_blocks: FastHashMap::default(),
         }
     }
 
@@ -32,9 +45,48 @@ impl World {
         self.chunks.get_mut(&pos)
     }
 
-    pub fn generate_chunk(&mut self, pos: ChunkPos) {
-        // println!("Generating chunk {pos:?}");
-        let chunk = self.worldgen.generate_chunk(pos);
+    /// The biome `pos` is in, whether or not its chunk is loaded
+    pub fn biome(&self, pos: BlockPos) -> Biome {
+        let pos = Point3::from(pos);
+        self.worldgen.biome(pos.x, pos.z)
+    }
+
+    /// The block at `pos`, or `None` if its chunk isn't loaded
+    pub fn block(&self, pos: BlockPos) -> Option<&Block> {
+        self.chunk(pos.chunk_pos())
+            .map(|chunk| chunk.block(pos.rel_pos()))
+    }
+
+    /// Generate the chunk at `pos` along with its structures, and add it. Returns the blocks
+    /// that its structures changed in other loaded chunks.
+    pub fn generate_chunk(&mut self, pos: ChunkPos) -> Vec<(BlockPos, Block)> {
+        let (chunk, overhang) = self.worldgen.generate(pos);
+        self.insert_generated_chunk(chunk, overhang)
+    }
+
+    /// Add a chunk generated ahead of time with [`Worldgen::generate`], placing the blocks of its
+    /// structures that reach into other chunks like [`generate_chunk`](Self::generate_chunk)
+    pub fn insert_generated_chunk(
+        &mut self,
+        chunk: Chunk,
+        overhang: Vec<(BlockPos, Block)>,
+    ) -> Vec<(BlockPos, Block)> {
+        let mut changes = vec![];
+        for (block_pos, block) in overhang {
+            match self.chunks.get_mut(&block_pos.chunk_pos()) {
+                Some(other) => {
+                    if worldgen::place_structure_block(other, block_pos, block) {
+                        other.set_dirty(true);
+                        changes.push((block_pos, block));
+                    }
+                }
+                None => self
+                    .pending_blocks
+                    .entry(block_pos.chunk_pos())
+                    .or_default()
+                    .push((block_pos, block)),
+            }
+        }
 
         for dir in Direction::ALL {
             if let Some(chunk) = self.chunk(chunk.pos + dir.normal()) {
@@ -42,7 +94,38 @@ impl World {
             }
         }
 
+        // Nobody has seen the chunk without its structure blocks
+        self.insert_chunk(chunk);
+        changes
+    }
+
+    /// Add a generated or loaded chunk, placing the structure blocks waiting for it. Returns the
+    /// positions of the blocks that were placed.
+    pub fn insert_chunk(&mut self, mut chunk: Chunk) -> Vec<BlockPos> {
+        let mut placed = vec![];
+        for (block_pos, block) in self.pending_blocks.remove(&chunk.pos).unwrap_or_default() {
+            if worldgen::place_structure_block(&mut chunk, block_pos, block) {
+                placed.push(block_pos);
+            }
+        }
         self.chunks.insert(chunk.pos, chunk);
+        placed
+    }
+
+    /// Every structure block waiting for its chunk to be generated or loaded
+    pub fn pending_blocks(&self) -> Vec<(BlockPos, Block)> {
+        self.pending_blocks.values().flatten().copied().collect()
+    }
+
+    /// Add structure blocks to place once their chunks are generated or loaded, like the ones
+    /// saved from [`pending_blocks`](Self::pending_blocks)
+    pub fn add_pending_blocks(&mut self, blocks: Vec<(BlockPos, Block)>) {
+        for (block_pos, block) in blocks {
+            self.pending_blocks
+                .entry(block_pos.chunk_pos())
+                .or_default()
+                .push((block_pos, block));
+        }
     }
 
     pub fn create_empty_chunk(&mut self, pos: ChunkPos) {
@@ -144,9 +227,39 @@ impl World {
         loop {
             let chunk = self.chunks.get(&chunk_pos);
 
+            // `t` is relative to where the ray entered the current chunk
+            let travelled = range - remaining_range;
+            let hit = |position, face: Direction, block, t: f32| HitInfo {
+                position,
+                face,
+                block,
+                distance: travelled + t,
+                point: origin + dir * (travelled + t),
+            };
+
             let mut block_hits = vec![];
 
             if let Some(chunk) = chunk {
+                // Where the ray hits the shape of the block at `pos`, if it does
+                let hit_block = |pos: ChunkRelativeBlockPos| {
+                    let block = *chunk.block(pos);
+                    let attr = block_registry.get(block.id).unwrap();
+                    if attr.invisible || attr.liquid {
+                        return None;
+                    }
+                    let block_origin = rel_origin - Point3::from(pos).cast().unwrap().to_vec();
+                    attr.boxes(block)
+                        .iter()
+                        .filter_map(|shape| shape.raycast(block_origin, dir))
+                        .filter(|&(t, _)| t >= -0.001 && t <= remaining_range)
+                        .min_by(|a, b| a.0.total_cmp(&b.0))
+                        .map(|(t, face)| (hit(chunk_pos + pos, face, block, t), t))
+                };
+
+                // The block the ray starts in, which isn't entered through any of the planes
+                let start = BlockPos::from_point(fix_range_inside(rel_origin)).rel_pos();
+                block_hits.extend(hit_block(start));
+
                 // X
                 for x in 0..=Chunk::SIZE {
                     let t = (x as f32 - rel_origin.x) / dir.x;
@@ -157,16 +270,7 @@ impl World {
 
                     if in_range(p) {
                         let pos = BlockPos::from_point(fix_range_inside(p)).rel_pos();
-                        let block = chunk.block(pos);
-                        let attr = block_registry.get(block.id).unwrap();
-                        if !attr.invisible {
-                            let position = chunk_pos + pos;
-                            let normal = Vector3::unit_x() * -dir.x.signum();
-
-                            let hitinfo = HitInfo { position, normal };
-
-                            block_hits.push((hitinfo, t));
-                        }
+                        block_hits.extend(hit_block(pos));
                     }
                 }
 
@@ -180,16 +284,7 @@ impl World {
 
                     if in_range(p) {
                         let pos = BlockPos::from_point(fix_range_inside(p)).rel_pos();
-                        let block = chunk.block(pos);
-                        let attr = block_registry.get(block.id).unwrap();
-                        if !attr.invisible {
-                            let position = chunk_pos + pos;
-                            let normal = Vector3::unit_y() * -dir.y.signum();
-
-                            let hitinfo = HitInfo { position, normal };
-
-                            block_hits.push((hitinfo, t));
-                        }
+                        block_hits.extend(hit_block(pos));
                     }
                 }
 
@@ -203,16 +298,7 @@ impl World {
 
                     if in_range(p) {
                         let pos = BlockPos::from_point(fix_range_inside(p)).rel_pos();
-                        let block = chunk.block(pos);
-                        let attr = block_registry.get(block.id).unwrap();
-                        if !attr.invisible {
-                            let position = chunk_pos + pos;
-                            let normal = Vector3::unit_z() * -dir.z.signum();
-
-                            let hitinfo = HitInfo { position, normal };
-
-                            block_hits.push((hitinfo, t));
-                        }
+                        block_hits.extend(hit_block(pos));
                     }
                 }
 
@@ -273,8 +359,22 @@ impl World {
 
 #[derive(Debug, Clone, Copy)]
 pub struct HitInfo {
+    /// The block that was hit
     pub position: BlockPos,
-    pub normal: Vector3<f32>,
+    /// The face of the block that was hit
+    pub face: Direction,
+    pub block: Block,
+    /// Distance along the ray to the hit, in the same unit as the range
+    pub distance: f32,
+    /// Where the ray hit the block, in world space
+    pub point: Point3<f32>,
+}
+
+impl HitInfo {
+    /// The position next to the hit face, where a block placed against it would go
+    pub fn adjacent(&self) -> BlockPos {
+        (Point3::from(self.position) + self.face.normal()).into()
+    }
 }
 
 pub fn to_block_pos(pos: Point3<f32>) -> Point3<isize> {
@@ -1,26 +1,205 @@
 use cgmath::{Point3, Vector2};
 use noise::{BasicMulti, NoiseFn, OpenSimplex, Perlin, Simplex};
-use rand::{thread_rng, Rng};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
 
-use super::{block::{Block, BlockId, BlockMetadata}, chunk::{Chunk, ChunkPos, ChunkRelativeBlockPos}};
+use super::{
+    biome::Biome,
+    block::{Block, BlockId, BlockRegistry},
+    chunk::{BlockPos, Chunk, ChunkPos, ChunkRelativeBlockPos},
+    ore::OreRule,
+    structure::Structure,
+};
 
 type Noise = impl NoiseFn<f64, 2>;
 
+/// Climate noise is sampled this many blocks apart, so that biomes span hundreds of blocks
+const CLIMATE_SCALE: f64 = 256.0;
+/// Distance between the points whose biomes are averaged for the terrain amplitude, so that
+/// the terrain changes gradually at biome borders
+const BLEND_DISTANCE: isize = 8;
+/// Spots considered for a structure per chunk column, each one getting one with the chance of
+/// its biome
+const STRUCTURE_CANDIDATES: usize = 5;
+/// Cave noise is sampled this many blocks apart, which sets how long and winding caves are
+const CAVE_SCALE: f64 = 32.0;
+
+/// How caves are carved out of the terrain
+#[derive(Debug, Clone, Copy)]
+pub struct CaveSettings {
+    /// Roughly how wide and common caves are, 0 for no caves. Values above 0.3 make the
+    /// underground mostly hollow.
+    pub density: f64,
+    /// Caves stay at least this many blocks below the surface, so they don't open up into
+    /// holes in the ground
+    pub min_depth: isize,
+}
+
+impl Default for CaveSettings {
+    fn default() -> Self {
+        Self {
+            density: 0.08,
+            min_depth: 6,
+        }
+    }
+}
+
 pub struct Worldgen {
+    seed: u32,
     elevation_noise: Noise,
     noise_offset: Vector2<f64>,
+    temperature_noise: OpenSimplex,
+    humidity_noise: OpenSimplex,
+    /// Caves are carved where both of these are close to zero, which happens along winding
+    /// lines through the terrain
+    cave_noise: [OpenSimplex; 2],
+    caves: CaveSettings,
+    /// Ores placed in order, later ones replacing only the stone left by earlier ones
+    ores: Vec<(BlockId, OreRule)>,
 }
 
 impl Worldgen {
     pub fn new() -> Self {
-        let base_elevation = OpenSimplex::new(thread_rng().gen());
+        Self::with_seed(thread_rng().gen())
+    }
+
+    pub fn with_seed(seed: u32) -> Self {
+        let base_elevation = OpenSimplex::new(seed);
         let noise = base_elevation;
+        let mut rng = StdRng::seed_from_u64(seed as u64);
         Self {
+            seed,
             elevation_noise: noise,
-            noise_offset: [thread_rng().gen_range(-1000.0..=1000.0), thread_rng().gen_range(-1000.0..=1000.0)].into()
+            noise_offset: [rng.gen_range(-1000.0..=1000.0), rng.gen_range(-1000.0..=1000.0)].into(),
+            temperature_noise: OpenSimplex::new(seed.wrapping_add(1)),
+            humidity_noise: OpenSimplex::new(seed.wrapping_add(2)),
+            cave_noise: [
+                OpenSimplex::new(seed.wrapping_add(3)),
+                OpenSimplex::new(seed.wrapping_add(4)),
+            ],
+            caves: CaveSettings::default(),
+            ores: vec![],
+        }
+    }
+
+    /// Generate the ores of every block in `block_registry` with an ore rule
+    pub fn with_ores(mut self, block_registry: &BlockRegistry) -> Self {
+        self.ores = block_registry
+            .iter()
+            .filter_map(|(id, attr)| Some((id, attr.ore.clone()?)))
+            .collect();
+        self
+    }
+
+    pub fn with_caves(mut self, caves: CaveSettings) -> Self {
+        self.caves = caves;
+        self
+    }
+
+    pub fn seed(&self) -> u32 {
+        self.seed
+    }
+
+    /// The biome of the column at `x`, `z`
+    pub fn biome(&self, x: isize, z: isize) -> Biome {
+        let point = [x as f64 / CLIMATE_SCALE, z as f64 / CLIMATE_SCALE];
+        Biome::from_climate(
+            self.temperature_noise.get(point),
+            self.humidity_noise.get(point),
+        )
+    }
+
+    /// The amplitude of the biomes around a column, averaged to blend biome borders
+    fn amplitude(&self, x: isize, z: isize) -> f64 {
+        let mut total = 0.0;
+        for dx in -1..=1 {
+            for dz in -1..=1 {
+                total += self
+                    .biome(x + dx * BLEND_DISTANCE, z + dz * BLEND_DISTANCE)
+                    .amplitude();
+            }
+        }
+        total / 9.0
+    }
+
+    /// Height of the terrain surface at a column, blocks at or below it are solid
+    fn surface_height(&self, x: isize, z: isize) -> f64 {
+        self.elevation_noise
+            .get([x as f64 / 16.0 + self.noise_offset.x, z as f64 / 16.0 + self.noise_offset.y])
+            * self.amplitude(x, z)
+    }
+
+    /// Height of the highest terrain block of a column, the sea surface where the terrain is
+    /// underwater. Caves never reach the surface, so this is solid ground or water.
+    pub fn ground_height(&self, x: isize, z: isize) -> isize {
+        (self.surface_height(x, z).floor() as isize).max(-1)
+    }
+
+    /// Whether the block at `x`, `y`, `z` is carved out by a cave, given the height of the
+    /// surface above it
+    fn is_cave(&self, x: isize, y: isize, z: isize, surface_height: f64) -> bool {
+        if self.caves.density <= 0.0 || y as f64 > surface_height - self.caves.min_depth as f64 {
+            return false;
         }
+        let point = [
+            x as f64 / CAVE_SCALE,
+            y as f64 / CAVE_SCALE,
+            z as f64 / CAVE_SCALE,
+        ];
+        let [a, b] = self.cave_noise.each_ref().map(|noise| noise.get(point));
+        a * a + b * b < self.caves.density * self.caves.density
     }
 
+    /// The blocks of every structure whose base is in chunk `pos`. Some of them may be in
+    /// neighbouring chunks.
+    pub fn structure_blocks(&self, pos: ChunkPos) -> Vec<(BlockPos, Block)> {
+        let origin = Point3::from(pos + ChunkRelativeBlockPos::from(Point3::new(0, 0, 0)));
+        // Seeded by column, so each column places the same structures whichever chunk of it
+        // is generated, and each chunk keeps the ones based inside of it
+        let column_seed = (self.seed as u64)
+            ^ (origin.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ (origin.z as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F);
+        let mut rng = StdRng::seed_from_u64(column_seed);
+
+        let mut blocks = vec![];
+        for _ in 0..STRUCTURE_CANDIDATES {
+            let x = origin.x + rng.gen_range(0..Chunk::SIZE as isize);
+            let z = origin.z + rng.gen_range(0..Chunk::SIZE as isize);
+            let biome = self.biome(x, z);
+            if !rng.gen_bool(biome.structure_chance()) {
+                continue;
+            }
+            let structure = Structure::random(biome, &mut rng);
+
+            let base = BlockPos::from(Point3::new(
+                x,
+                self.surface_height(x, z).floor() as isize + 1,
+                z,
+            ));
+            // Nothing grows underwater
+            if Point3::from(base).y < 1 || base.chunk_pos() != pos {
+                continue;
+            }
+            blocks.extend(structure.blocks(base));
+        }
+        blocks
+    }
+
+    /// Generate a chunk along with the structures based in it. The blocks of those structures
+    /// that are in other chunks are returned separately.
+    pub fn generate(&self, pos: ChunkPos) -> (Chunk, Vec<(BlockPos, Block)>) {
+        let mut chunk = self.generate_chunk(pos);
+        let mut overhang = vec![];
+        for (block_pos, block) in self.structure_blocks(pos) {
+            if block_pos.chunk_pos() == pos {
+                place_structure_block(&mut chunk, block_pos, block);
+            } else {
+                overhang.push((block_pos, block));
+            }
+        }
+        (chunk, overhang)
+    }
+
+    /// Generate the terrain of a chunk, without any structures
     pub fn generate_chunk(&self, pos: ChunkPos) -> Chunk {
         let offset = Point3::from(pos + ChunkRelativeBlockPos::from(Point3::new(0, 0, 0)));
 
@@ -30,10 +209,8 @@ impl Worldgen {
             for z in 0..Chunk::SIZE as isize {
                 let global_x = offset.x + x;
                 let global_z = offset.z + z;
-                let value = self
-                    .elevation_noise
-                    .get([global_x as f64 / 16.0 + self.noise_offset.x, global_z as f64 / 16.0 + self.noise_offset.y])
-                    * 8.0;
+                let value = self.surface_height(global_x, global_z);
+                let surface_block = self.biome(global_x, global_z).surface_block();
 
                 let max_height = value;
 
@@ -43,12 +220,14 @@ impl Worldgen {
                     let global_y = offset.y + y;
                     let id = if global_y as f64 > max_height {
                         if (global_y as f64) < 0.0 {
-                            4
+                            6
                         } else {
                             0
                         }
+                    } else if self.is_cave(global_x, global_y, global_z, value) {
+                        0
                     } else if global_y as f64 > dirt_height {
-                        1
+                        surface_block.0
                     } else {
                         2
                     };
@@ -65,6 +244,32 @@ impl Worldgen {
             }
         }
 
+        self.place_ores(&mut chunk);
         chunk
     }
+
+    fn place_ores(&self, chunk: &mut Chunk) {
+        let origin = Point3::from(chunk.pos + ChunkRelativeBlockPos::from(Point3::new(0, 0, 0)));
+        for (id, rule) in &self.ores {
+            // Each ore gets its own numbers, so registering a new ore doesn't move the
+            // veins of the others
+            let chunk_seed = (self.seed as u64)
+                ^ (origin.x as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+                ^ (origin.y as u64).wrapping_mul(0x1656_67B1_9E37_79F9)
+                ^ (origin.z as u64).wrapping_mul(0xC2B2_AE3D_27D4_EB4F)
+                ^ (id.0 as u64).wrapping_mul(0xD6E8_FEB8_6659_FD93);
+            rule.place_veins(*id, chunk, &mut StdRng::seed_from_u64(chunk_seed));
+        }
+    }
+}
+
+/// Place a block of a structure, returning whether it was placed. Structures only fill air, so
+/// they never cut into terrain or into what players have built.
+pub fn place_structure_block(chunk: &mut Chunk, pos: BlockPos, block: Block) -> bool {
+    let existing = chunk.block_mut(pos.rel_pos());
+    if existing.id != BlockId(0) {
+        return false;
+    }
+    *existing = block;
+    true
 }
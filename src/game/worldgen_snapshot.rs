@@ -0,0 +1,153 @@
+//! Hashes of chunks generated from fixed seeds, to check that changes to world generation
+//! don't change the terrain of existing worlds by accident.
+//!
+//! Chunks that were never saved are generated again whenever they load, so generating them
+//! differently changes worlds that players have already explored, and leaves seams where the
+//! new terrain meets saved chunks. A test compares freshly generated chunks against the
+//! snapshot in [`PATH`], and `--update-worldgen-snapshot` replaces it when the terrain is meant
+//! to change.
+
+use std::path::Path;
+
+use cgmath::Point3;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    block::BlockRegistry,
+    chunk::{Chunk, ChunkPos},
+    worldgen::{CaveSettings, Worldgen},
+};
+
+pub const PATH: &str = "./assets/worldgen_snapshot.toml";
+
+/// Seeds the snapshot is taken with, including 0 as an edge case for the noise offsets
+const SEEDS: [u32; 3] = [0, 1, 1_234_567];
+/// Chunks around the origin from below the surface up into the sky, so caves, ores, structures
+/// and the surface are all covered
+const CHUNK_RADIUS: isize = 1;
+const CHUNK_HEIGHTS: [isize; 5] = [-3, -2, -1, 0, 1];
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkHash {
+    pub seed: u32,
+    pub pos: [isize; 3],
+    /// FNV-1a hash of the names and metadata of the chunk's blocks, as hex
+    pub hash: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub chunks: Vec<ChunkHash>,
+}
+
+impl Snapshot {
+    /// Generate every chunk of the snapshot with the current world generation
+    pub fn take(block_registry: &BlockRegistry) -> Self {
+        let mut chunks = vec![];
+        for seed in SEEDS {
+            let worldgen = Worldgen::with_seed(seed)
+                .with_caves(CaveSettings::default())
+                .with_ores(block_registry);
+            for x in -CHUNK_RADIUS..=CHUNK_RADIUS {
+                for z in -CHUNK_RADIUS..=CHUNK_RADIUS {
+                    for y in CHUNK_HEIGHTS {
+                        let pos = ChunkPos::from(Point3::new(x, y, z));
+                        let (chunk, _) = worldgen.generate(pos);
+                        chunks.push(ChunkHash {
+                            seed,
+                            pos: [x, y, z],
+                            hash: format!("{:016x}", hash_chunk(&chunk, block_registry)),
+                        });
+                    }
+                }
+            }
+        }
+        Self { chunks }
+    }
+
+    #[cfg(test)]
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+
+    /// Describe every chunk that differs between `self` and the `expected` snapshot
+    #[cfg(test)]
+    pub fn differences(&self, expected: &Snapshot) -> Vec<String> {
+        let mut differences = vec![];
+        for chunk in &expected.chunks {
+            let actual = self
+                .chunks
+                .iter()
+                .find(|actual| actual.seed == chunk.seed && actual.pos == chunk.pos);
+            match actual {
+                Some(actual) if actual.hash == chunk.hash => {}
+                Some(actual) => differences.push(format!(
+                    "seed {} chunk {:?}: expected {}, generated {}",
+                    chunk.seed, chunk.pos, chunk.hash, actual.hash
+                )),
+                None => differences.push(format!(
+                    "seed {} chunk {:?}: no longer part of the snapshot",
+                    chunk.seed, chunk.pos
+                )),
+            }
+        }
+        for chunk in &self.chunks {
+            if !expected
+                .chunks
+                .iter()
+                .any(|expected| expected.seed == chunk.seed && expected.pos == chunk.pos)
+            {
+                differences.push(format!(
+                    "seed {} chunk {:?}: missing from the snapshot",
+                    chunk.seed, chunk.pos
+                ));
+            }
+        }
+        differences
+    }
+}
+
+/// Hash a chunk by block names rather than ids, so renumbering blocks doesn't count as a change,
+/// just like it doesn't change saves
+fn hash_chunk(chunk: &Chunk, block_registry: &BlockRegistry) -> u64 {
+    const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+
+    let names = block_registry.names();
+    let mut hash = OFFSET;
+    let mut write = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(PRIME);
+        }
+    };
+    for x in 0..Chunk::SIZE {
+        for y in 0..Chunk::SIZE {
+            for z in 0..Chunk::SIZE {
+                let block = chunk.block(Point3::new(x, y, z).into());
+                write(names.name(block.id).unwrap_or("?").as_bytes());
+                write(&[0, block.metadata.0]);
+            }
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// If this fails on purpose, run with `--update-worldgen-snapshot` to accept the new terrain
+    #[test]
+    fn generation_matches_snapshot() {
+        let expected = Snapshot::load(PATH).unwrap();
+        let differences =
+            Snapshot::take(&BlockRegistry::with_default_blocks()).differences(&expected);
+        assert!(differences.is_empty(), "{}", differences.join("\n"));
+    }
+}
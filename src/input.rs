@@ -1,18 +1,216 @@
-use bevy_ecs::system::Resource;
-use cgmath::{Vector2, Zero};
+use std::collections::BTreeMap;
+
+use bevy_ecs::{
+    event::{Event, EventWriter},
+    system::{Res, ResMut, Resource},
+};
+use cgmath::{InnerSpace, Vector2, Zero};
+use serde::{Deserialize, Serialize};
 use wgpu::naga::FastHashSet;
 use winit::{
     event::{ElementState, KeyEvent, MouseButton},
     keyboard::{KeyCode, PhysicalKey},
 };
 
+use crate::game::DeltaTime;
+
+/// How raw mouse motion is turned into the look delta seen by systems
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MouseSettings {
+    /// Bypass acceleration and smoothing entirely
+    pub raw_input: bool,
+    /// 0.0 is no smoothing, values approaching 1.0 smooth more heavily
+    pub smoothing: f32,
+    /// Extra gain per 1000 units/second of mouse speed
+    pub acceleration: f32,
+}
+
+impl Default for MouseSettings {
+    fn default() -> Self {
+        Self {
+            raw_input: true,
+            smoothing: 0.5,
+            acceleration: 0.0,
+        }
+    }
+}
+
+/// Something the player can do by pressing a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    MoveForward,
+    MoveBackward,
+    MoveLeft,
+    MoveRight,
+    /// Jump, swim up, or fly up when in noclip
+    Jump,
+    /// Fly down when in noclip, crouch otherwise
+    Descend,
+    /// Move faster while moving forward, also started by double tapping forward
+    Sprint,
+    ToggleNoclip,
+    ToggleAmbientOcclusion,
+    /// Switch between smooth and flat shading of block corners
+    ToggleSmoothLighting,
+    ToggleSelectOutline,
+    ToggleRawMouse,
+    ToggleHeldItemLight,
+    ToggleAmbientParticles,
+    ToggleDiagnostics,
+    ToggleCompass,
+    /// Switch to the next available language
+    CycleLanguage,
+    /// Show chunk borders and the player's position in the world
+    ToggleDebugOverlay,
+    /// Switch between filled, wireframe and unculled rendering
+    CycleRenderMode,
+    /// Narrow the field of view while held
+    Zoom,
+    /// Talk to the NPC being looked at
+    Interact,
+    /// Mark the block being looked at as a corner of the region to export to glTF
+    ExportRegion,
+    /// Detach the camera from the player and fly it around freely
+    ToggleSpectator,
+    /// Switch between a window and fullscreen
+    ToggleFullscreen,
+    /// Undo the last block edit
+    Undo,
+    /// Redo the last undone block edit
+    Redo,
+}
+
+impl Action {
+    pub const ALL: [Action; 26] = [
+        Action::MoveForward,
+        Action::MoveBackward,
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::Jump,
+        Action::Descend,
+        Action::Sprint,
+        Action::ToggleNoclip,
+        Action::ToggleAmbientOcclusion,
+        Action::ToggleSmoothLighting,
+        Action::ToggleSelectOutline,
+        Action::ToggleRawMouse,
+        Action::ToggleHeldItemLight,
+        Action::ToggleAmbientParticles,
+        Action::ToggleDiagnostics,
+        Action::ToggleCompass,
+        Action::CycleLanguage,
+        Action::ToggleDebugOverlay,
+        Action::CycleRenderMode,
+        Action::Zoom,
+        Action::Interact,
+        Action::ExportRegion,
+        Action::ToggleSpectator,
+        Action::ToggleFullscreen,
+        Action::Undo,
+        Action::Redo,
+    ];
+
+    pub fn default_key(self) -> KeyCode {
+        match self {
+            Action::MoveForward => KeyCode::KeyW,
+            Action::MoveBackward => KeyCode::KeyS,
+            Action::MoveLeft => KeyCode::KeyA,
+            Action::MoveRight => KeyCode::KeyD,
+            Action::Jump => KeyCode::Space,
+            Action::Descend => KeyCode::ShiftLeft,
+            Action::Sprint => KeyCode::ControlLeft,
+            Action::ToggleNoclip => KeyCode::KeyC,
+            Action::ToggleAmbientOcclusion => KeyCode::KeyF,
+            Action::ToggleSmoothLighting => KeyCode::KeyO,
+            Action::ToggleSelectOutline => KeyCode::KeyX,
+            Action::ToggleRawMouse => KeyCode::KeyM,
+            Action::ToggleHeldItemLight => KeyCode::KeyL,
+            Action::ToggleAmbientParticles => KeyCode::KeyP,
+            Action::ToggleDiagnostics => KeyCode::KeyI,
+            Action::ToggleCompass => KeyCode::KeyK,
+            Action::CycleLanguage => KeyCode::F2,
+            Action::ToggleDebugOverlay => KeyCode::F3,
+            Action::CycleRenderMode => KeyCode::F4,
+            Action::Zoom => KeyCode::KeyZ,
+            Action::Interact => KeyCode::KeyE,
+            Action::ExportRegion => KeyCode::F6,
+            Action::ToggleSpectator => KeyCode::F5,
+            Action::ToggleFullscreen => KeyCode::F11,
+            Action::Undo => KeyCode::KeyU,
+            Action::Redo => KeyCode::KeyY,
+        }
+    }
+}
+
+/// Which key triggers each action. Actions missing from the map use their default key.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct InputMap {
+    bindings: BTreeMap<Action, KeyCode>,
+}
+
+impl InputMap {
+    pub fn key(&self, action: Action) -> KeyCode {
+        self.bindings
+            .get(&action)
+            .copied()
+            .unwrap_or(action.default_key())
+    }
+
+    /// Every action bound to `key`
+    pub fn actions(&self, key: KeyCode) -> impl Iterator<Item = Action> + '_ {
+        Action::ALL
+            .into_iter()
+            .filter(move |&action| self.key(action) == key)
+    }
+
+    /// Bind `action` to `key`, returning the other actions that are also bound to it
+    pub fn rebind(&mut self, action: Action, key: KeyCode) -> Vec<Action> {
+        self.bindings.insert(action, key);
+        self.actions(key).filter(|&other| other != action).collect()
+    }
+
+    /// Bind `action` back to its default key, returning the other actions that are also bound
+    /// to it
+    pub fn reset(&mut self, action: Action) -> Vec<Action> {
+        self.bindings.remove(&action);
+        let key = action.default_key();
+        self.actions(key).filter(|&other| other != action).collect()
+    }
+}
+
+/// What happened to an action during a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ActionState {
+    Pressed,
+    /// Still held since an earlier frame, for this many seconds in total
+    Held(f32),
+    /// Let go of after being held for this many seconds
+    Released(f32),
+}
+
+/// Sent once per frame for every action that is pressed, held or released, so that systems
+/// don't have to track key state themselves
+#[derive(Debug, Clone, Copy, PartialEq, Event)]
+pub struct ActionEvent {
+    pub action: Action,
+    pub state: ActionState,
+}
+
 #[derive(Resource)]
 pub struct Input {
     pressed_keys: FastHashSet<KeyCode>,
     pressed_mouse_buttons: FastHashSet<MouseButton>,
     just_pressed_keys: FastHashSet<KeyCode>,
     just_pressed_mouse_buttons: FastHashSet<MouseButton>,
+    raw_mouse_delta: Vector2<f32>,
+    smoothed_mouse_velocity: Vector2<f32>,
     mouse_delta: Vector2<f32>,
+    pub mouse_settings: MouseSettings,
+    pub input_map: InputMap,
+    /// How long each held action has been held
+    held_actions: BTreeMap<Action, f32>,
 }
 
 impl Input {
@@ -22,8 +220,37 @@ impl Input {
             pressed_mouse_buttons: FastHashSet::default(),
             just_pressed_keys: FastHashSet::default(),
             just_pressed_mouse_buttons: FastHashSet::default(),
+            raw_mouse_delta: Vector2::zero(),
+            smoothed_mouse_velocity: Vector2::zero(),
             mouse_delta: Vector2::zero(),
+            mouse_settings: MouseSettings::default(),
+            input_map: InputMap::default(),
+            held_actions: BTreeMap::new(),
+        }
+    }
+
+    /// Turns the mouse motion accumulated since the last frame into this frame's look delta.
+    /// Must be called once per frame, before any system reads `mouse_delta`.
+    pub fn begin_frame(&mut self, dt: f32) {
+        let raw = self.raw_mouse_delta;
+        self.raw_mouse_delta = Vector2::zero();
+
+        let settings = self.mouse_settings;
+        if settings.raw_input || dt <= 0.0 {
+            self.smoothed_mouse_velocity = Vector2::zero();
+            self.mouse_delta = raw;
+            return;
         }
+
+        let velocity = raw / dt;
+        let accelerated = velocity * (1.0 + settings.acceleration * velocity.magnitude() / 1000.0);
+
+        // Exponential smoothing of the mouse velocity, normalized to 60 FPS so it feels the same
+        // at any frame rate
+        let smoothing = settings.smoothing.clamp(0.0, 0.99);
+        let t = 1.0 - smoothing.powf(dt * 60.0);
+        self.smoothed_mouse_velocity += (accelerated - self.smoothed_mouse_velocity) * t;
+        self.mouse_delta = self.smoothed_mouse_velocity * dt;
     }
 
     pub fn process_key_event(&mut self, event: KeyEvent) {
@@ -46,7 +273,7 @@ impl Input {
     }
 
     pub fn process_mouse_move(&mut self, delta: Vector2<f32>) {
-        self.mouse_delta += delta;
+        self.raw_mouse_delta += delta;
     }
 
     pub fn process_mouse_input(&mut self, button: MouseButton, state: ElementState) {
@@ -74,6 +301,42 @@ impl Input {
         self.just_pressed_keys.contains(&key)
     }
 
+    pub fn is_action_pressed(&self, action: Action) -> bool {
+        self.is_pressed(self.input_map.key(action))
+    }
+
+    /// This frame's action events, based on the keys pressed since the last frame.
+    /// Must be called once per frame, before `end_frame`.
+    pub fn action_events(&mut self, dt: f32) -> Vec<ActionEvent> {
+        let mut events = vec![];
+        let mut send = |action, state| events.push(ActionEvent { action, state });
+        for action in Action::ALL {
+            let key = self.input_map.key(action);
+            let pressed = self.is_pressed(key);
+            if self.is_just_pressed(key) {
+                // Pressed again after being released since the last frame
+                if let Some(held) = self.held_actions.remove(&action) {
+                    send(action, ActionState::Released(held));
+                }
+                send(action, ActionState::Pressed);
+                if pressed {
+                    self.held_actions.insert(action, 0.0);
+                } else {
+                    // Pressed and released within a single frame
+                    send(action, ActionState::Released(0.0));
+                }
+            } else if pressed {
+                if let Some(held) = self.held_actions.get_mut(&action) {
+                    *held += dt;
+                    send(action, ActionState::Held(*held));
+                }
+            } else if let Some(held) = self.held_actions.remove(&action) {
+                send(action, ActionState::Released(held));
+            }
+        }
+        events
+    }
+
     pub fn is_released(&self, key: KeyCode) -> bool {
         !self.pressed_keys.contains(&key)
     }
@@ -92,3 +355,12 @@ impl Input {
         self.just_pressed_mouse_buttons.clear();
     }
 }
+
+/// Sends this frame's [`ActionEvent`]s
+pub fn action_event_system(
+    mut input: ResMut<Input>,
+    dt: Res<DeltaTime>,
+    mut events: EventWriter<ActionEvent>,
+) {
+    events.send_batch(input.action_events(dt.0));
+}
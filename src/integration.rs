@@ -0,0 +1,342 @@
+//! End-to-end test of the client and server talking over a real connection, without a window.
+//!
+//! It starts a server on a free port with a world in a scratch directory, connects a
+//! [`HeadlessClient`] to it, loads a chunk, mines a block and places it elsewhere, checks that
+//! loading the chunk again only sends the blocks changed since, and after the server has shut
+//! down checks that the placed block was saved.
+
+use std::{
+    net::{Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Context;
+use async_std::channel::Receiver;
+use cgmath::{EuclideanSpace, Point3, Vector3};
+use uuid::Uuid;
+
+use crate::{
+    game::{
+        block::{Block, BlockId, BlockMetadata, BlockRegistry},
+        chunk::{BlockPos, Chunk, ChunkPos, ChunkRevision},
+        dimension::DimensionId,
+    },
+    server::{
+        config::NetworkConfig,
+        connection::{KeepAlive, RemoteTransport, ServerTrust, Transport},
+        message::{CachedChunk, MessageToClient, MessageToServer, PROTOCOL_VERSION},
+        storage::StorageBackend,
+        Server, ServerConfig,
+    },
+};
+
+/// How long to wait for anything the server should do within a few ticks
+const TIMEOUT: Duration = Duration::from_secs(10);
+/// Stack of the thread receiving messages. Decoding them takes more than the runtime's threads
+/// have in debug builds, as messages are as large as a chunk.
+const RECEIVE_STACK_SIZE: usize = 16 * 1024 * 1024;
+
+/// A client with nothing but a connection, which logs in and sends and receives messages like
+/// the game does
+pub struct HeadlessClient {
+    transport: Transport,
+    /// Messages the server sent on its own, rather than in response to a request
+    messages: Receiver<MessageToClient>,
+    pub spawn: Point3<f32>,
+}
+
+impl HeadlessClient {
+    /// Connect to the server at `address` and log in to a new account named `name`
+    pub async fn connect(address: SocketAddr, name: &str) -> anyhow::Result<Self> {
+        let client = quinn::Endpoint::client("[::]:0".parse().unwrap())?;
+        let mut client_config =
+            quinn::ClientConfig::new(Arc::new(ServerTrust::Insecure.client_config()?));
+        client_config.transport_config(Arc::new(KeepAlive::default().transport_config()));
+        let connection = client
+            .connect_with(client_config, address, "localhost")?
+            .await?;
+        let transport = Transport::Remote(RemoteTransport::new(connection));
+
+        let (messages_tx, messages) = async_std::channel::unbounded();
+        let mut tp = transport.clone();
+        std::thread::Builder::new()
+            .stack_size(RECEIVE_STACK_SIZE)
+            .spawn(move || {
+                async_std::task::block_on(async move {
+                    while let Ok((msg, _)) = tp
+                        .accept_transact::<MessageToClient, MessageToServer>()
+                        .await
+                    {
+                        if messages_tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                })
+            })?;
+
+        match transport
+            .transact::<_, MessageToClient>(&MessageToServer::Version(PROTOCOL_VERSION))
+            .await?
+            .single()
+            .await?
+        {
+            MessageToClient::Ok => {}
+            msg => anyhow::bail!(
+                "unexpected response to the protocol version: {}",
+                msg.name()
+            ),
+        }
+
+        let response = transport
+            .transact::<_, MessageToClient>(&MessageToServer::Connect {
+                name: name.to_string(),
+                token: None,
+            })
+            .await?
+            .single()
+            .await?;
+        let MessageToClient::LoggedIn { spawn, .. } = response else {
+            anyhow::bail!("unexpected response to logging in: {}", response.name());
+        };
+
+        Ok(Self {
+            transport,
+            messages,
+            spawn: Point3::from_vec(spawn),
+        })
+    }
+
+    /// Request the chunks at `positions`, without any cached copies
+    pub async fn get_chunks(
+        &self,
+        positions: &[ChunkPos],
+    ) -> anyhow::Result<Vec<(Chunk, ChunkRevision)>> {
+        let mut transaction = self
+            .transport
+            .transact::<_, MessageToClient>(&MessageToServer::GetChunks(
+                positions.iter().map(|&pos| (pos, None)).collect(),
+            ))
+            .await?;
+        let mut chunks = vec![];
+        while chunks.len() < positions.len() {
+            match transaction.single().await? {
+                MessageToClient::Chunks(received) => chunks.extend(received),
+                msg => anyhow::bail!("unexpected response to loading chunks: {}", msg.name()),
+            }
+        }
+        Ok(chunks)
+    }
+
+    /// Request `chunk` again as if it were cached as `revision`, expecting only the blocks
+    /// changed since in return, and check that they add up to the server's copy
+    pub async fn get_chunk_delta(
+        &self,
+        chunk: &Chunk,
+        revision: ChunkRevision,
+    ) -> anyhow::Result<(ChunkRevision, Vec<(BlockPos, Block)>)> {
+        let cached = CachedChunk {
+            hash: chunk.content_hash(),
+            revision,
+        };
+        let mut transaction = self
+            .transport
+            .transact::<_, MessageToClient>(&MessageToServer::GetChunks(vec![(
+                chunk.pos,
+                Some(cached),
+            )]))
+            .await?;
+        match transaction.single().await? {
+            MessageToClient::ChunkDelta {
+                pos,
+                revision,
+                hash,
+                changes,
+            } if pos == chunk.pos => {
+                let mut chunk = chunk.clone();
+                for &(block_pos, block) in &changes {
+                    *chunk.block_mut(block_pos.rel_pos()) = block;
+                }
+                anyhow::ensure!(
+                    chunk.content_hash() == hash,
+                    "the changes don't add up to the server's copy: {changes:?}"
+                );
+                Ok((revision, changes))
+            }
+            msg => anyhow::bail!(
+                "unexpected response to loading a cached chunk: {}",
+                msg.name()
+            ),
+        }
+    }
+
+    pub fn send(&self, msg: &MessageToServer) {
+        self.transport.send(msg);
+    }
+
+    /// Replace the block at `pos`, waiting until the server has applied the edit
+    pub async fn edit(&self, pos: BlockPos, new_block: Block, edit: u32) -> anyhow::Result<()> {
+        self.send(&MessageToServer::ReplaceBlock {
+            pos,
+            new_block,
+            edit,
+        });
+        let changes = self
+            .wait_for(|msg| match msg {
+                MessageToClient::BlocksPlaced {
+                    changes,
+                    acknowledged_edit: Some(acknowledged),
+                } if acknowledged == edit => Some(changes),
+                _ => None,
+            })
+            .await?;
+        anyhow::ensure!(
+            changes.contains(&(pos, new_block)),
+            "the edit was not echoed back: {changes:?}"
+        );
+        Ok(())
+    }
+
+    /// Wait for a message from the server that `f` accepts, skipping the ones before it
+    pub async fn wait_for<T>(
+        &self,
+        mut f: impl FnMut(MessageToClient) -> Option<T>,
+    ) -> anyhow::Result<T> {
+        let wait = async {
+            loop {
+                if let Some(found) = f(self.messages.recv().await?) {
+                    return anyhow::Ok(found);
+                }
+            }
+        };
+        async_std::future::timeout(TIMEOUT, wait).await?
+    }
+
+    pub async fn disconnect(self) -> anyhow::Result<()> {
+        let response = self
+            .transport
+            .transact::<_, MessageToClient>(&MessageToServer::Disconnect)
+            .await?
+            .single()
+            .await?;
+        self.transport.close("disconnected");
+        match response {
+            MessageToClient::Ok => Ok(()),
+            msg => anyhow::bail!("unexpected response to disconnecting: {}", msg.name()),
+        }
+    }
+}
+
+#[async_std::test]
+async fn place_and_persist() -> anyhow::Result<()> {
+    let save_dir = std::env::temp_dir().join(format!("voxels-integration-{}", Uuid::new_v4()));
+    let result = place_and_persist_in(save_dir.clone()).await;
+    if let Err(e) = std::fs::remove_dir_all(&save_dir) {
+        log::warn!("Could not remove {}: {e}", save_dir.display());
+    }
+    result
+}
+
+async fn place_and_persist_in(save_dir: PathBuf) -> anyhow::Result<()> {
+    let config = ServerConfig {
+        network: NetworkConfig {
+            address: Ipv6Addr::LOCALHOST.into(),
+            port: 0,
+            ..Default::default()
+        },
+        save_dir: save_dir.clone(),
+        idle_save_delay: None,
+        autosave_interval: None,
+        metrics_interval: None,
+        ..Default::default()
+    };
+    let storage = config.storage;
+    let (shutdown_tx, shutdown_rx) = async_std::channel::unbounded();
+    let mut server = Server::new(config, shutdown_rx);
+    let address = server.local_addr();
+    let server = async_std::task::spawn(async move { server.run().await });
+
+    let client = HeadlessClient::connect(address, "integration").await?;
+
+    // Right above the player's head, well within reach
+    let pos = BlockPos::from_point(client.spawn + Vector3::new(0.0, 3.0, 0.0));
+    // Placing a block takes its item, so first mine the ground under the player to get one
+    let below = (1..=4)
+        .map(|depth| BlockPos::from_point(client.spawn - Vector3::new(0.0, depth as f32, 0.0)))
+        .collect::<Vec<_>>();
+    let mut chunk_positions = vec![pos.chunk_pos()];
+    for floor in &below {
+        if !chunk_positions.contains(&floor.chunk_pos()) {
+            chunk_positions.push(floor.chunk_pos());
+        }
+    }
+    let chunks = client.get_chunks(&chunk_positions).await?;
+    let block_at = |pos: BlockPos| {
+        chunks
+            .iter()
+            .find(|(chunk, _)| chunk.pos == pos.chunk_pos())
+            .map(|(chunk, _)| *chunk.block(pos.rel_pos()))
+    };
+    let Some((chunk, revision)) = chunks
+        .iter()
+        .find(|(chunk, _)| chunk.pos == pos.chunk_pos())
+    else {
+        anyhow::bail!("chunk {:?} was not sent", pos.chunk_pos());
+    };
+
+    let block_registry = BlockRegistry::with_default_blocks();
+    let (floor, new_block) = below
+        .iter()
+        .find_map(|&floor| {
+            let block = block_at(floor)?;
+            let attributes = block_registry.get(block.id)?;
+            (!attributes.invisible && !attributes.liquid).then_some((floor, block))
+        })
+        .ok_or_else(|| anyhow::anyhow!("there is no ground to mine under {:?}", client.spawn))?;
+    let air = Block {
+        id: BlockId(0),
+        metadata: BlockMetadata(0),
+    };
+    client
+        .edit(floor, air, 1)
+        .await
+        .context("mining the ground")?;
+    client
+        .edit(pos, new_block, 2)
+        .await
+        .context("placing the mined block")?;
+
+    // Loading the chunk again should only send the blocks that changed, which can include some
+    // changed by block updates, like flowing water
+    let (new_revision, changes) = client.get_chunk_delta(chunk, *revision).await?;
+    anyhow::ensure!(
+        new_revision > *revision && changes.contains(&(pos, new_block)),
+        "wrong changes since revision {revision}: {changes:?} at revision {new_revision}"
+    );
+
+    client.disconnect().await?;
+    shutdown_tx.send(()).await?;
+    async_std::future::timeout(TIMEOUT, server).await?;
+
+    check_saved(&save_dir, storage, pos, new_block, &block_registry)
+}
+
+fn check_saved(
+    save_dir: &Path,
+    storage: StorageBackend,
+    pos: BlockPos,
+    expected: Block,
+    block_registry: &BlockRegistry,
+) -> anyhow::Result<()> {
+    let mut storage = storage.open(save_dir, block_registry.names().clone())?;
+    let chunk = storage
+        .load_chunk(DimensionId::OVERWORLD, pos.chunk_pos())?
+        .ok_or_else(|| anyhow::anyhow!("chunk {:?} was not saved", pos.chunk_pos()))?;
+    let block = *chunk.block(pos.rel_pos());
+    anyhow::ensure!(
+        block == expected,
+        "saved block at {pos:?} is {block:?} instead of {expected:?}"
+    );
+    Ok(())
+}
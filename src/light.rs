@@ -0,0 +1,57 @@
+use bevy_ecs::system::Resource;
+use cgmath::{Point3, Vector3};
+
+/// Maximum number of point lights that can affect a frame
+pub const MAX_LIGHTS: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointLight {
+    pub position: Point3<f32>,
+    pub color: Vector3<f32>,
+    /// Distance at which the light has faded out completely
+    pub radius: f32,
+}
+
+/// Dynamic point lights to render this frame.
+/// Systems push lights into this each frame; lights beyond `MAX_LIGHTS` are ignored.
+#[derive(Debug, Default, Resource)]
+pub struct Lights {
+    pub lights: Vec<PointLight>,
+}
+
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct PointLightRaw {
+    position: [f32; 3],
+    radius: f32,
+    color: [f32; 3],
+    _padding: f32,
+}
+
+/// Uniform holding every point light visible in the current frame
+#[repr(C)]
+#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct LightUniform {
+    lights: [PointLightRaw; MAX_LIGHTS],
+    count: u32,
+    _padding: [u32; 3],
+}
+
+impl LightUniform {
+    pub fn new() -> Self {
+        bytemuck::Zeroable::zeroed()
+    }
+
+    pub fn update(&mut self, lights: &Lights) {
+        self.count = 0;
+        for (raw, light) in self.lights.iter_mut().zip(&lights.lights) {
+            *raw = PointLightRaw {
+                position: light.position.into(),
+                radius: light.radius,
+                color: light.color.into(),
+                _padding: 0.0,
+            };
+            self.count += 1;
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::{fmt::Display, path::Path};
+
+use bevy_ecs::system::Resource;
+use wgpu::naga::FastHashMap;
+
+/// Translated UI strings, looked up by key.
+///
+/// Each language is a TOML file in [`Locale::DIR`], whose nested tables are flattened into
+/// dotted keys (`[compass] north = "N"` becomes `compass.north`). A key missing from the
+/// selected language is looked up in its base language (`pt` for `pt-BR`) and then in
+/// [`Locale::FALLBACK`]. If no language has it, the key itself is shown so that it is easy to
+/// spot.
+#[derive(Resource)]
+pub struct Locale {
+    language: String,
+    /// The selected language's strings first, followed by those of its fallbacks
+    tables: Vec<FastHashMap<String, String>>,
+}
+
+impl Locale {
+    pub const DIR: &'static str = "assets/lang";
+    pub const FALLBACK: &'static str = "en";
+
+    pub fn load(language: &str) -> Self {
+        let mut chain = vec![language.to_string()];
+        if let Some((base, _)) = language.split_once(['-', '_']) {
+            chain.push(base.to_string());
+        }
+        chain.push(Self::FALLBACK.to_string());
+        chain.dedup();
+
+        let tables = chain
+            .iter()
+            .filter_map(|language| {
+                let path = Path::new(Self::DIR).join(format!("{language}.toml"));
+                match Self::load_table(&path) {
+                    Ok(table) => Some(table),
+                    Err(e) => {
+                        log::warn!("Could not load language {language}: {e}");
+                        None
+                    }
+                }
+            })
+            .collect();
+
+        Self {
+            language: language.to_string(),
+            tables,
+        }
+    }
+
+    fn load_table(path: &Path) -> anyhow::Result<FastHashMap<String, String>> {
+        fn flatten(prefix: &str, table: toml::Table, out: &mut FastHashMap<String, String>) {
+            for (key, value) in table {
+                let key = if prefix.is_empty() {
+                    key
+                } else {
+                    format!("{prefix}.{key}")
+                };
+                match value {
+                    toml::Value::String(s) => {
+                        out.insert(key, s);
+                    }
+                    toml::Value::Table(table) => flatten(&key, table, out),
+                    _ => log::warn!("Ignoring non-string translation {key}"),
+                }
+            }
+        }
+
+        let table = toml::from_str(&std::fs::read_to_string(path)?)?;
+        let mut strings = FastHashMap::default();
+        flatten("", table, &mut strings);
+        Ok(strings)
+    }
+
+    /// Every language with a file in [`Locale::DIR`], sorted
+    pub fn available() -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(Self::DIR) else {
+            return vec![Self::FALLBACK.to_string()];
+        };
+        let mut languages = entries
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? != "toml" {
+                    return None;
+                }
+                Some(path.file_stem()?.to_str()?.to_string())
+            })
+            .collect::<Vec<_>>();
+        languages.sort();
+        languages
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn get<'a>(&'a self, key: &'a str) -> &'a str {
+        self.tables
+            .iter()
+            .find_map(|table| table.get(key))
+            .map_or(key, String::as_str)
+    }
+
+    /// Like [`Locale::get`], with each `{name}` in the string replaced by the matching argument
+    pub fn format(&self, key: &str, args: &[(&str, &dyn Display)]) -> String {
+        let mut text = self.get(key).to_string();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{name}}}"), &value.to_string());
+        }
+        text
+    }
+
+    /// The translated "on" or "off"
+    pub fn toggle(&self, enabled: bool) -> &str {
+        self.get(if enabled { "common.on" } else { "common.off" })
+    }
+}
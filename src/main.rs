@@ -8,34 +8,76 @@
 #![feature(async_closure)]
 #![feature(iter_array_chunks)]
 
+mod arena;
 mod assets;
 mod camera;
+mod debug_lines;
+mod direction;
+mod display;
 mod ecs_world;
+mod export;
+mod fog;
 mod game;
 mod input;
+#[cfg(test)]
+mod integration;
+mod light;
+mod locale;
+mod menu;
 mod mesh;
 mod meshifier;
 mod object;
+mod occlusion;
+mod render_targets;
 pub mod server;
+mod settings;
+mod text;
 mod texture;
+mod upload;
 
 use std::{
     borrow::BorrowMut,
-    net::{IpAddr, SocketAddr},
+    io::IsTerminal,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     ops::Rem,
-    sync::Arc,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
+use arena::ChunkArena;
 use assets::AssetManager;
 use camera::{Camera, Frustum, Projection};
-use cgmath::{prelude::*, Quaternion, Vector2, Vector3};
+use cgmath::{prelude::*, Point2, Quaternion, Vector2, Vector3};
 use clap::Parser;
-use game::Game;
-use mesh::{DrawModel, Material, Mesh, MeshVertex, Vertex};
+use debug_lines::DebugLines;
+use display::WindowSettings;
+use game::{
+    block::BlockRegistry,
+    diagnostics::DrawStats,
+    worldgen::CaveSettings,
+    worldgen_snapshot::{self, Snapshot},
+    Game,
+};
+use fog::{FogUniform, SKY_COLOR};
+use light::LightUniform;
+use locale::Locale;
+use menu::{MenuChoice, RecentServers};
+use mesh::{DrawModel, Material, Mesh, MeshBuilder, MeshVertex, Vertex};
 use pollster::FutureExt;
-use server::{connection::SkipServerVerification, Server};
+use render_targets::{RenderTargets, RENDER_SCALES};
+use serde::{Deserialize, Serialize};
+use settings::Settings;
+use server::{
+    config::NetworkConfig, connection::SkipServerVerification, movement::MovementRules,
+    storage::StorageBackend, Server, ServerConfig,
+};
+use text::TextRenderer;
 use texture::Texture;
+use upload::Uploader;
 use wgpu::{
     util::DeviceExt, Device, Queue, Surface, SurfaceCapabilities, SurfaceConfiguration,
     SurfaceTargetUnsafe,
@@ -52,6 +94,9 @@ use winit::{
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 struct CameraUniform {
     view_proj: [[f32; 4]; 4],
+    /// Seconds since startup, wrapping around every hour, used to animate textures
+    time: f32,
+    _padding: [f32; 3],
 }
 
 /// Uniform representing the camera projection
@@ -60,6 +105,8 @@ impl CameraUniform {
         use cgmath::SquareMatrix;
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
+            time: 0.0,
+            _padding: [0.0; 3],
         }
     }
 
@@ -121,52 +168,140 @@ impl InstanceRaw {
     }
 }
 
-struct State<'w> {
-    surface: Surface<'w>,
+/// How opaque geometry is drawn, for debugging the meshes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum RenderMode {
+    #[default]
+    Fill,
+    /// Only the edges of each triangle. Needs `POLYGON_MODE_LINE`, drawn filled if unsupported.
+    Wireframe,
+    /// Back faces are drawn too, showing the faces that culling normally hides
+    NoCull,
+}
+
+impl RenderMode {
+    pub fn next(self) -> Self {
+        match self {
+            RenderMode::Fill => RenderMode::Wireframe,
+            RenderMode::Wireframe => RenderMode::NoCull,
+            RenderMode::NoCull => RenderMode::Fill,
+        }
+    }
+}
+
+fn create_render_pipeline(
+    device: &Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    format: wgpu::TextureFormat,
+    label: &str,
+    blend: wgpu::BlendState,
+    depth_write_enabled: bool,
+    cull_mode: Option<wgpu::Face>,
+    polygon_mode: wgpu::PolygonMode,
+    sample_count: u32,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "vs_main",
+            buffers: &[MeshVertex::desc(), InstanceRaw::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(blend),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode,
+            polygon_mode,
+            unclipped_depth: false,
+            conservative: false,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+        multiview: None,
+    })
+}
+
+/// The graphics device and everything created on it that lives as long as the window, which all
+/// has to be recreated when the device is lost
+struct Gpu {
     device: Arc<Device>,
     queue: Arc<Queue>,
     texture_bind_group_layout: Arc<wgpu::BindGroupLayout>,
     config: SurfaceConfiguration,
-    size: PhysicalSize<u32>,
-    window: Window,
     render_pipeline: wgpu::RenderPipeline,
-    projection: Projection,
-    camera_uniform: CameraUniform,
+    translucent_render_pipeline: wgpu::RenderPipeline,
+    /// `None` if the adapter can't draw lines as polygons
+    wireframe_render_pipeline: Option<wgpu::RenderPipeline>,
+    /// Whether all opaque chunks can be drawn with a single indirect draw call
+    multi_draw: bool,
+    no_cull_render_pipeline: wgpu::RenderPipeline,
+    debug_lines: DebugLines,
+    text: TextRenderer,
     camera_buffer: wgpu::Buffer,
     camera_bind_group: wgpu::BindGroup,
-    depth_texture: Texture,
-    asset_manager: AssetManager,
-    game: Game,
-    frustum: Option<Frustum>,
+    light_buffer: wgpu::Buffer,
+    light_bind_group: wgpu::BindGroup,
+    fog_buffer: wgpu::Buffer,
+    fog_bind_group: wgpu::BindGroup,
+    /// Samples per pixel, 1 without multisampling
+    sample_count: u32,
+    /// Size of the rendered image relative to the window
+    render_scale: f32,
+    targets: RenderTargets,
+    blit_pipeline: wgpu::RenderPipeline,
+    /// Set from wgpu's callback once the device is lost, e.g. to a driver reset
+    lost: Arc<AtomicBool>,
 }
 
-impl<'w> State<'w> {
-    async fn new(window: Window) -> Self {
-        let size = window.inner_size();
-
-        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::all(),
-            ..Default::default()
-        });
-
-        let surface = unsafe {
-            instance.create_surface_unsafe(SurfaceTargetUnsafe::from_window(&window).unwrap())
-        }
-        .unwrap();
-
+impl Gpu {
+    /// Request a device able to draw to `surface`, and configure the surface for it
+    async fn new(
+        instance: &wgpu::Instance,
+        surface: &Surface<'_>,
+        size: PhysicalSize<u32>,
+        settings: &Settings,
+    ) -> Self {
         let adapter = instance
             .request_adapter(&wgpu::RequestAdapterOptions {
                 power_preference: wgpu::PowerPreference::default(),
                 force_fallback_adapter: false,
-                compatible_surface: Some(&surface),
+                compatible_surface: Some(surface),
             })
             .await
             .unwrap();
 
+        // Only needed for the wireframe render mode, for drawing chunks in one call and for
+        // sample counts other than 4, so it's fine if they're missing
+        let optional_features = adapter.features()
+            & (wgpu::Features::POLYGON_MODE_LINE
+                | ChunkArena::MULTI_DRAW_FEATURES
+                | wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES);
         let (device, queue) = adapter
             .request_device(
                 &wgpu::DeviceDescriptor {
-                    required_features: wgpu::Features::empty(),
+                    required_features: optional_features,
                     required_limits: wgpu::Limits::default(),
                     label: None,
                 },
@@ -175,6 +310,17 @@ impl<'w> State<'w> {
             .await
             .unwrap();
 
+        let lost = Arc::new(AtomicBool::new(false));
+        let lost_flag = lost.clone();
+        device.set_device_lost_callback(move |reason, message| {
+            // Only we destroy devices, when replacing them
+            if matches!(reason, wgpu::DeviceLostReason::Destroyed) {
+                return;
+            }
+            log::error!("Graphics device lost ({reason:?}): {message}");
+            lost_flag.store(true, Ordering::Relaxed);
+        });
+
         let surface_caps = surface.get_capabilities(&adapter);
         let surface_format = surface_caps
             .formats
@@ -195,6 +341,12 @@ impl<'w> State<'w> {
         };
         surface.configure(&device, &config);
 
+        let sample_count =
+            supported_sample_count(&adapter, &device, &config, settings.msaa_samples);
+        let render_scale = settings
+            .render_scale
+            .clamp(*RENDER_SCALES.start(), *RENDER_SCALES.end());
+
         let texture_bind_group_layout =
             device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -218,24 +370,14 @@ impl<'w> State<'w> {
                 label: Some("texture_bind_group_layout"),
             });
 
-        let depth_texture =
-            texture::Texture::create_depth_texture(&device, &config, "depth_texture");
-
         let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
             label: Some("Shader"),
             source: wgpu::ShaderSource::Wgsl(include_str!("shader.wgsl").into()),
         });
 
-        let camera = Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-2.0));
-        let projection =
-            Projection::new(config.width, config.height, cgmath::Deg(45.0), 0.1, 10000.0);
-
-        let mut camera_uniform = CameraUniform::new();
-        camera_uniform.update_view_proj(&camera, &projection);
-
         let camera_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some("Camera Buffer"),
-            contents: bytemuck::cast_slice(&[camera_uniform]),
+            contents: bytemuck::cast_slice(&[CameraUniform::new()]),
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
         });
 
@@ -263,100 +405,393 @@ impl<'w> State<'w> {
             label: Some("camera_bind_group"),
         });
 
+        let light_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Light Buffer"),
+            contents: bytemuck::cast_slice(&[LightUniform::new()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let light_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("light_bind_group_layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let light_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &light_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: light_buffer.as_entire_binding(),
+            }],
+            label: Some("light_bind_group"),
+        });
+
+        let fog_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Fog Buffer"),
+            contents: bytemuck::cast_slice(&[FogUniform::new()]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+
+        let fog_bind_group_layout =
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                label: Some("fog_bind_group_layout"),
+                entries: &[wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }],
+            });
+
+        let fog_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &fog_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: fog_buffer.as_entire_binding(),
+            }],
+            label: Some("fog_bind_group"),
+        });
+
         let render_pipeline_layout =
             device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&texture_bind_group_layout, &camera_bind_group_layout],
+                bind_group_layouts: &[
+                    &texture_bind_group_layout,
+                    &camera_bind_group_layout,
+                    &light_bind_group_layout,
+                    &fog_bind_group_layout,
+                ],
                 push_constant_ranges: &[],
             });
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vs_main",
-                buffers: &[MeshVertex::desc(), InstanceRaw::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fs_main",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: config.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
-            multiview: None,
-        });
+        let render_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            &shader,
+            config.format,
+            "Render Pipeline",
+            wgpu::BlendState::REPLACE,
+            true,
+            Some(wgpu::Face::Back),
+            wgpu::PolygonMode::Fill,
+            sample_count,
+        );
+
+        let wireframe_render_pipeline = device
+            .features()
+            .contains(wgpu::Features::POLYGON_MODE_LINE)
+            .then(|| {
+                create_render_pipeline(
+                    &device,
+                    &render_pipeline_layout,
+                    &shader,
+                    config.format,
+                    "Wireframe Render Pipeline",
+                    wgpu::BlendState::REPLACE,
+                    true,
+                    None,
+                    wgpu::PolygonMode::Line,
+                    sample_count,
+                )
+            });
+        if wireframe_render_pipeline.is_none() {
+            log::info!("Adapter can't draw wireframes, the wireframe render mode will be filled");
+        }
+
+        let multi_draw = device.features().contains(ChunkArena::MULTI_DRAW_FEATURES);
+        if !multi_draw {
+            log::info!("Adapter can't multi-draw indirect, chunks will be drawn one at a time");
+        }
+
+        let no_cull_render_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            &shader,
+            config.format,
+            "No Cull Render Pipeline",
+            wgpu::BlendState::REPLACE,
+            true,
+            None,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+        );
+
+        // Translucent geometry is blended over the opaque geometry, and can be seen from both
+        // sides (e.g. a water surface seen from below)
+        let translucent_render_pipeline = create_render_pipeline(
+            &device,
+            &render_pipeline_layout,
+            &shader,
+            config.format,
+            "Translucent Render Pipeline",
+            wgpu::BlendState::ALPHA_BLENDING,
+            false,
+            None,
+            wgpu::PolygonMode::Fill,
+            sample_count,
+        );
+
+        let debug_lines = DebugLines::new(
+            &device,
+            config.format,
+            sample_count,
+            &camera_bind_group_layout,
+        );
+        let text =
+            TextRenderer::new(&device, &queue, config.format, &texture_bind_group_layout).unwrap();
+        let targets = RenderTargets::new(
+            &device,
+            &config,
+            sample_count,
+            render_scale,
+            &texture_bind_group_layout,
+        );
+        let blit_pipeline = render_targets::create_blit_pipeline(
+            &device,
+            config.format,
+            &texture_bind_group_layout,
+        );
 
         let device = Arc::new(device);
         let queue = Arc::new(queue);
         let texture_bind_group_layout = Arc::new(texture_bind_group_layout);
 
+        Gpu {
+            device,
+            queue,
+            texture_bind_group_layout,
+            config,
+            render_pipeline,
+            translucent_render_pipeline,
+            wireframe_render_pipeline,
+            multi_draw,
+            no_cull_render_pipeline,
+            debug_lines,
+            text,
+            camera_buffer,
+            camera_bind_group,
+            light_buffer,
+            light_bind_group,
+            fog_buffer,
+            fog_bind_group,
+            sample_count,
+            render_scale,
+            targets,
+            blit_pipeline,
+            lost,
+        }
+    }
+
+    fn is_lost(&self) -> bool {
+        self.lost.load(Ordering::Relaxed)
+    }
+}
+
+/// The highest sample count up to `requested` that both the color and the depth target support
+fn supported_sample_count(
+    adapter: &wgpu::Adapter,
+    device: &Device,
+    config: &SurfaceConfiguration,
+    requested: u32,
+) -> u32 {
+    let supported = |count: u32| {
+        // Without the adapter's own format features, only what every adapter has can be used
+        if !device
+            .features()
+            .contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES)
+        {
+            return count == 1 || count == 4;
+        }
+        [config.format, Texture::DEPTH_FORMAT]
+            .into_iter()
+            .all(|format| {
+                adapter
+                    .get_texture_format_features(format)
+                    .flags
+                    .sample_count_supported(count)
+            })
+    };
+    let count = [16, 8, 4, 2, 1]
+        .into_iter()
+        .find(|&count| count <= requested && supported(count))
+        .unwrap_or(1);
+    if count != requested {
+        log::warn!("{requested}x multisampling is not supported, using {count}x");
+    }
+    count
+}
+
+struct State<'w> {
+    instance: wgpu::Instance,
+    surface: Surface<'w>,
+    gpu: Gpu,
+    size: PhysicalSize<u32>,
+    scale_factor: f64,
+    window: Window,
+    /// The window settings last applied to the window
+    window_settings: WindowSettings,
+    /// Size of the window the last time it was not fullscreen
+    windowed_size: PhysicalSize<u32>,
+    projection: Projection,
+    camera_uniform: CameraUniform,
+    light_uniform: LightUniform,
+    fog_uniform: FogUniform,
+    uploader: Uploader,
+    asset_manager: AssetManager,
+    game: Game,
+    frustum: Option<Frustum>,
+    diagnostics_timer: Duration,
+    loading_stalled: bool,
+    /// Diagnostics last shown in the title, empty if they are hidden
+    diagnostics_text: String,
+    draw_stats: DrawStats,
+    title: String,
+}
+
+impl<'w> State<'w> {
+    async fn new(window: Window, address: SocketAddr) -> Self {
+        let size = window.inner_size();
+        let scale_factor = window.scale_factor();
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: wgpu::Backends::all(),
+            ..Default::default()
+        });
+
+        let surface = unsafe {
+            instance.create_surface_unsafe(SurfaceTargetUnsafe::from_window(&window).unwrap())
+        }
+        .unwrap();
+
+        // The game loads the settings too, but only once the device is there
+        let settings = Settings::load(Settings::PATH);
+        let gpu = Gpu::new(&instance, &surface, size, &settings).await;
+
+        let camera = Camera::new((0.0, 5.0, 10.0), cgmath::Deg(-90.0), cgmath::Deg(-2.0));
+        let projection = Projection::new(
+            gpu.config.width,
+            gpu.config.height,
+            Camera::DEFAULT_FOV,
+            0.1,
+            10000.0,
+        );
+
+        let mut camera_uniform = CameraUniform::new();
+        camera_uniform.update_view_proj(&camera, &projection);
+
         let mut asset_manager = AssetManager::new(
-            device.clone(),
-            queue.clone(),
-            texture_bind_group_layout.clone(),
+            gpu.device.clone(),
+            gpu.queue.clone(),
+            gpu.texture_bind_group_layout.clone(),
         );
 
-        let game = Game::new(&mut asset_manager, &device).await;
+        let mut game = Game::new(&mut asset_manager, &gpu.device, address).await;
+        game.set_ui_scale(scale_factor as f32);
+
+        let window_settings = game.window_settings().clone();
+        window_settings.apply(&window);
+        let windowed_size = window_settings
+            .windowed_size
+            .map_or(size, |[width, height]| PhysicalSize::new(width, height));
 
         State {
+            instance,
             surface,
-            device,
-            queue,
-            config,
+            gpu,
             size,
+            scale_factor,
             window,
-            render_pipeline,
+            window_settings,
+            windowed_size,
             camera_uniform,
-            camera_buffer,
-            camera_bind_group,
-            depth_texture,
+            light_uniform: LightUniform::new(),
+            fog_uniform: FogUniform::new(),
+            uploader: Uploader::new(),
             projection,
-            texture_bind_group_layout,
             asset_manager,
             game,
             frustum: None,
+            diagnostics_timer: Duration::ZERO,
+            loading_stalled: false,
+            diagnostics_text: String::new(),
+            draw_stats: DrawStats::default(),
+            title: String::new(),
         }
     }
 
+    /// Replace a lost graphics device with a new one, keeping the game going. Nothing drawn so
+    /// far survives, so the game rebuilds its meshes from the chunks it still has.
+    async fn recover_device(&mut self) {
+        log::warn!("Recreating the graphics device");
+        let settings = self.game.settings().clone();
+        self.gpu = Gpu::new(&self.instance, &self.surface, self.size, &settings).await;
+        self.asset_manager = AssetManager::new(
+            self.gpu.device.clone(),
+            self.gpu.queue.clone(),
+            self.gpu.texture_bind_group_layout.clone(),
+        );
+        self.uploader = Uploader::new();
+        self.game
+            .recreate_gpu_resources(&mut self.asset_manager, &self.gpu.device);
+    }
+
     pub fn window(&self) -> &Window {
         &self.window
     }
 
     fn resize(&mut self, new_size: PhysicalSize<u32>) {
+        // A minimized window reports a zero size, which can't be used for a surface or projection
         if new_size.width > 0 && new_size.height > 0 {
             self.size = new_size;
-            self.config.width = new_size.width;
-            self.config.height = new_size.height;
-            self.depth_texture =
-                Texture::create_depth_texture(&self.device, &self.config, "depth_texture");
+            if self.window.fullscreen().is_none() {
+                self.windowed_size = new_size;
+            }
+            self.gpu.config.width = new_size.width;
+            self.gpu.config.height = new_size.height;
+            self.gpu.targets = RenderTargets::new(
+                &self.gpu.device,
+                &self.gpu.config,
+                self.gpu.sample_count,
+                self.gpu.render_scale,
+                &self.gpu.texture_bind_group_layout,
+            );
             self.projection.resize(new_size.width, new_size.height);
-            self.surface.configure(&self.device, &self.config);
+            self.surface.configure(&self.gpu.device, &self.gpu.config);
+        }
+    }
+
+    /// Switch the window in or out of fullscreen when its settings changed
+    fn update_window_mode(&mut self) {
+        let settings = self.game.window_settings();
+        if *settings == self.window_settings {
+            return;
         }
+        self.window_settings = settings.clone();
+        // Leaving fullscreen goes back to the size the window had before this session's switch
+        let mut settings = settings.clone();
+        settings.windowed_size = Some([self.windowed_size.width, self.windowed_size.height]);
+        settings.apply(&self.window);
+    }
+
+    fn set_scale_factor(&mut self, scale_factor: f64) {
+        self.scale_factor = scale_factor;
+        self.game.set_ui_scale(scale_factor as f32);
+        // The new physical size is not always followed by a `Resized` event, so reconfigure the
+        // surface, depth texture and projection against the current size right away
+        self.resize(self.window.inner_size());
     }
 
     fn input(&mut self, event: &WindowEvent) -> bool {
@@ -389,21 +824,136 @@ impl<'w> State<'w> {
     async fn update(&mut self, dt: Duration) {
         // self.camera_controller.update_camera(&mut self.camera, dt);
         self.game.update(dt).await;
+        self.projection.set_fovy(self.game.camera().fovy);
         self.camera_uniform
             .update_view_proj(&self.game.camera(), &self.projection);
-        self.queue.write_buffer(
-            &self.camera_buffer,
+        self.camera_uniform.time = (self.camera_uniform.time + dt.as_secs_f32()) % 3600.0;
+        self.uploader.write_buffer(
+            &self.gpu.camera_buffer,
             0,
             bytemuck::cast_slice(&[self.camera_uniform]),
+            &self.gpu.device,
+        );
+        self.diagnostics_timer += dt;
+        if self.diagnostics_timer >= Duration::from_millis(500) {
+            self.diagnostics_timer = Duration::ZERO;
+            self.update_loading_diagnostics();
+        }
+        self.update_title();
+
+        self.light_uniform.update(self.game.lights());
+        self.uploader.write_buffer(
+            &self.gpu.light_buffer,
+            0,
+            bytemuck::cast_slice(&[self.light_uniform]),
+            &self.gpu.device,
         );
+
+        self.fog_uniform
+            .update(self.game.fog(), self.game.camera().position);
+        self.uploader.write_buffer(
+            &self.gpu.fog_buffer,
+            0,
+            bytemuck::cast_slice(&[self.fog_uniform]),
+            &self.gpu.device,
+        );
+    }
+
+    /// Checks how chunk loading is progressing, warning if it seems stuck
+    fn update_loading_diagnostics(&mut self) {
+        let diagnostics = self.game.loading_diagnostics();
+
+        if diagnostics.is_stalled() && !self.loading_stalled {
+            log::warn!("Chunk requests are taking a long time: {diagnostics}");
+        }
+        self.loading_stalled = diagnostics.is_stalled();
+
+        let render_stats = self.game.render_stats();
+        if !render_stats.is_consistent() {
+            log::warn!("Render caches out of sync with loaded chunks: {render_stats}");
+        }
+
+        self.diagnostics_text = if self.game.show_loading_diagnostics {
+            format!("{diagnostics} | {render_stats}")
+        } else {
+            String::new()
+        };
+    }
+
+    /// Shows the compass, diagnostics and chat input in the window title, since there is no in-game UI yet
+    fn update_title(&mut self) {
+        let mut title = "voxels".to_string();
+        if let Some(progress) = self.game.loading_progress() {
+            let text = self.game.locale().format(
+                "loading.progress",
+                &[
+                    ("requested", &progress.requested),
+                    ("received", &progress.received),
+                    ("ready", &progress.ready),
+                    ("total", &progress.total),
+                ],
+            );
+            title += &format!(" - {text}");
+        }
+        if let Some(compass) = self.game.compass() {
+            title += &format!(" - {}", compass.text(self.game.locale()));
+        }
+        if !self.diagnostics_text.is_empty() {
+            title += &format!(" - {}", self.diagnostics_text);
+        }
+        if let Some(draft) = self.game.chat_draft() {
+            let draft = self.game.locale().format("chat.draft", &[("text", &draft)]);
+            title += &format!(" - {draft}");
+        }
+        // Setting the title can be slow, so only do it when it actually changes
+        if title != self.title {
+            self.window.set_title(&title);
+            self.title = title;
+        }
+    }
+
+    /// Collect the text drawn over the world this frame
+    fn draw_text(&mut self) {
+        let scale = self.scale_factor as f32;
+        if let Some(overlay) = self.game.debug_overlay() {
+            // A section of the overlay on each line, with a shadow to stand out against the sky
+            let text = format!("{overlay} | {}", self.draw_stats).replace(" | ", "\n");
+            let position = Point2::new(8.0, 8.0) * scale;
+            let shadow = position + Vector2::new(1.0, 1.0) * scale;
+            let size = 16.0 * scale;
+            self.gpu
+                .text
+                .draw_text(shadow, size, [0.0, 0.0, 0.0, 0.8], &text);
+            self.gpu
+                .text
+                .draw_text(position, size, [1.0, 1.0, 1.0, 1.0], &text);
+        }
+
+        // The latest chat messages in the bottom left corner, the newest at the bottom
+        let size = 16.0 * scale;
+        let spacing = self.gpu.text.line_spacing(size);
+        let messages: Vec<_> = self.game.visible_chat(Instant::now()).collect();
+        let mut y = self.size.height as f32 - 8.0 * scale - spacing * messages.len() as f32;
+        for (text, opacity) in messages {
+            let position = Point2::new(8.0 * scale, y);
+            let shadow = position + Vector2::new(1.0, 1.0) * scale;
+            self.gpu
+                .text
+                .draw_text(shadow, size, [0.0, 0.0, 0.0, 0.8 * opacity], text);
+            self.gpu
+                .text
+                .draw_text(position, size, [1.0, 1.0, 1.0, opacity], text);
+            y += spacing;
+        }
     }
 
-    fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
+    fn render(&mut self, mode: RenderMode) -> Result<(), wgpu::SurfaceError> {
         let output = self.surface.get_current_texture()?;
         let view = output
             .texture
             .create_view(&wgpu::TextureViewDescriptor::default());
         let mut encoder = self
+            .gpu
             .device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
@@ -411,34 +961,63 @@ impl<'w> State<'w> {
 
         let camera = self.game.camera();
         let frustum = camera.frustum(&self.projection);
+        let camera_position = camera.position;
 
-        let mut meshes_to_render = self
+        self.game.draw_debug_lines(&mut self.gpu.debug_lines);
+        self.gpu
+            .debug_lines
+            .upload(&self.gpu.device, &mut self.uploader);
+
+        let mut objects = self
             .game
-            .get_objects_to_render(&self.device)
-            .collect::<Vec<_>>();
+            .get_objects_to_render(&self.gpu.device, &mut self.uploader);
 
-        for obj in &mut meshes_to_render {
-            obj.update_instance_buffer(&self.queue);
+        for obj in objects.opaque.iter_mut().chain(&mut objects.translucent) {
+            obj.update_instance_buffer(&mut self.uploader, &self.gpu.device);
         }
 
+        let mut draw_stats = DrawStats::default();
+
+        let arena = &mut *objects.chunk_arena;
+        let chunks = objects.chunks.iter().copied().filter(|&pos| {
+            let visible = arena
+                .bounding_sphere(pos)
+                .is_some_and(|sphere| frustum.contains_sphere(sphere));
+            if !visible {
+                draw_stats.culled += 1;
+            }
+            visible
+        });
+        let chunks = chunks.collect::<Vec<_>>();
+        arena.prepare(chunks, &self.gpu.device, &mut self.uploader);
+
+        // Blending only looks right when drawing back to front
+        objects.translucent.retain(|obj| obj.mesh.num_elements > 0);
+        objects.translucent.sort_by(|a, b| {
+            let dista = (a.bounding_sphere().center - camera_position).magnitude2();
+            let distb = (b.bounding_sphere().center - camera_position).magnitude2();
+            distb.partial_cmp(&dista).unwrap()
+        });
+
+        let (color_view, resolve_target) = self.gpu.targets.color_attachment(&view);
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
                 color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
+                    view: color_view,
+                    resolve_target,
                     ops: wgpu::Operations {
                         load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.1,
-                            g: 0.2,
-                            b: 0.3,
+                            r: SKY_COLOR[0] as f64,
+                            g: SKY_COLOR[1] as f64,
+                            b: SKY_COLOR[2] as f64,
                             a: 1.0,
                         }),
                         store: wgpu::StoreOp::Store,
                     },
                 })],
                 depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &self.depth_texture.view,
+                    view: &self.gpu.targets.depth.view,
                     depth_ops: Some(wgpu::Operations {
                         load: wgpu::LoadOp::Clear(1.0),
                         store: wgpu::StoreOp::Store,
@@ -449,38 +1028,89 @@ impl<'w> State<'w> {
                 timestamp_writes: None,
             });
 
-            render_pass.set_pipeline(&self.render_pipeline);
+            let opaque_pipeline = match mode {
+                RenderMode::Fill => &self.gpu.render_pipeline,
+                RenderMode::Wireframe => self
+                    .gpu
+                    .wireframe_render_pipeline
+                    .as_ref()
+                    .unwrap_or(&self.gpu.render_pipeline),
+                RenderMode::NoCull => &self.gpu.no_cull_render_pipeline,
+            };
+            render_pass.set_pipeline(opaque_pipeline);
+            render_pass.set_bind_group(2, &self.gpu.light_bind_group, &[]);
+            render_pass.set_bind_group(3, &self.gpu.fog_bind_group, &[]);
 
-            for obj in &mut meshes_to_render {
+            for obj in &objects.opaque {
                 let sphere = obj.bounding_sphere();
                 if
                 /*let Some(frustum) = self.frustum.as_ref() &&*/
                 !frustum.contains_sphere(sphere) {
+                    draw_stats.culled += 1;
                     continue;
                 }
 
                 render_pass.set_vertex_buffer(1, obj.instance_buffer.slice(..));
                 if obj.mesh.num_elements > 0 {
-                    render_pass.draw_mesh_instanced(&obj.mesh, 0..1, &self.camera_bind_group);
+                    render_pass.draw_mesh_instanced(&obj.mesh, 0..1, &self.gpu.camera_bind_group);
+                    draw_stats.draw_calls += 1;
+                }
+            }
+
+            draw_stats.draw_calls += objects.chunk_arena.draw(
+                &mut render_pass,
+                &self.gpu.camera_bind_group,
+                self.gpu.multi_draw,
+            );
+
+            render_pass.set_pipeline(&self.gpu.translucent_render_pipeline);
+
+            for obj in &objects.translucent {
+                if !frustum.contains_sphere(obj.bounding_sphere()) {
+                    draw_stats.culled += 1;
+                    continue;
                 }
+
+                render_pass.set_vertex_buffer(1, obj.instance_buffer.slice(..));
+                render_pass.draw_mesh_instanced(&obj.mesh, 0..1, &self.gpu.camera_bind_group);
+                draw_stats.draw_calls += 1;
             }
+
+            self.gpu
+                .debug_lines
+                .draw(&mut render_pass, &self.gpu.camera_bind_group);
         }
+        self.gpu
+            .targets
+            .blit(&self.gpu.blit_pipeline, &mut encoder, &view);
+        self.draw_stats = draw_stats;
 
-        self.queue.submit(std::iter::once(encoder.finish()));
+        self.draw_text();
+        self.gpu
+            .text
+            .upload(&self.gpu.device, &mut self.uploader, self.size);
+        self.gpu.text.draw(&mut encoder, &view);
+
+        // Uploads go first, so that this frame is drawn with the new data
+        let uploads = self.uploader.finish();
+        self.gpu
+            .queue
+            .submit(uploads.into_iter().chain([encoder.finish()]));
+        self.uploader.recall();
         output.present();
 
         Ok(())
     }
 }
 
-pub async fn run() {
+pub async fn run(address: SocketAddr) {
     println!("In run");
     env_logger::init();
     let event_loop = EventLoop::new().unwrap();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
 
     println!("Creating state...");
-    let mut state = State::new(window).await;
+    let mut state = State::new(window, address).await;
     println!("State created");
     // return;
     let mut last_render_time = Instant::now();
@@ -519,10 +1149,18 @@ pub async fn run() {
                                     now - last_render_time
                                 };
                                 last_render_time = now;
+                                // Anything submitted to a lost device panics, so the new one has
+                                // to be in place before the frame
+                                if state.gpu.is_lost() {
+                                    pollster::block_on(state.recover_device());
+                                }
                                 pollster::block_on(state.update(dt));
-                                match state.render() {
+                                state.update_window_mode();
+                                match state.render(state.game.render_mode()) {
                                     Ok(_) => {}
-                                    Err(wgpu::SurfaceError::Lost) => state.resize(state.size),
+                                    Err(
+                                        wgpu::SurfaceError::Lost | wgpu::SurfaceError::Outdated,
+                                    ) => state.resize(state.size),
                                     Err(wgpu::SurfaceError::OutOfMemory) => target.exit(),
                                     Err(e) => eprintln!("{:?}", e),
                                 }
@@ -530,11 +1168,9 @@ pub async fn run() {
                             WindowEvent::Resized(physical_size) => {
                                 state.resize(*physical_size);
                             }
-                            WindowEvent::ScaleFactorChanged {
-                                scale_factor,
-                                inner_size_writer,
-                            } => {
-                                // state.resize(inner_size_writer);
+                            WindowEvent::ScaleFactorChanged { scale_factor, .. } => {
+                                // We accept the size suggested by the windowing system
+                                state.set_scale_factor(*scale_factor);
                             }
                             WindowEvent::CloseRequested
                             | WindowEvent::KeyboardInput {
@@ -552,6 +1188,11 @@ pub async fn run() {
                 Event::AboutToWait => {
                     state.window.request_redraw();
                 }
+                Event::LoopExiting => {
+                    state.game.set_windowed_size(state.windowed_size);
+                    state.game.save_settings();
+                    pollster::block_on(state.game.disconnect());
+                }
                 _ => {}
             }
         })
@@ -566,19 +1207,165 @@ struct Args {
     #[arg(short, long)]
     no_server: bool,
 
+    /// Connect to this server right away instead of showing the menu
     #[arg(short, long)]
     ip: Option<SocketAddr>,
+
+    /// File the server reads its address, port, certificate and tick rate from
+    #[arg(long, default_value = NetworkConfig::PATH)]
+    server_config: PathBuf,
+
+    /// Address the server listens on, overriding the server config
+    #[arg(long)]
+    bind_address: Option<IpAddr>,
+
+    /// Port the server listens on, overriding the server config
+    #[arg(long)]
+    port: Option<u16>,
+
+    /// PEM file with the certificate chain the server presents, overriding the server config
+    #[arg(long)]
+    certificate: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate, overriding the server config
+    #[arg(long)]
+    private_key: Option<PathBuf>,
+
+    /// Server ticks per second, overriding the server config
+    #[arg(long)]
+    tick_rate: Option<u32>,
+
+    /// Where the integrated server persists the world
+    #[arg(long, value_enum, default_value_t = StorageBackend::Sqlite)]
+    storage: StorageBackend,
+
+    /// Directory the integrated server saves the world in
+    #[arg(long, default_value = ".")]
+    save_dir: PathBuf,
+
+    /// Radius in chunks around players in which the integrated server ticks the world
+    #[arg(long, default_value_t = 4)]
+    simulation_distance: isize,
+
+    /// Message shown to players when they join the integrated server
+    #[arg(long, default_value = "Welcome!")]
+    motd: String,
+
+    /// Let players on the integrated server move through blocks
+    #[arg(long)]
+    allow_noclip: bool,
+
+    /// Seconds without any players after which the server saves the world, 0 to disable
+    #[arg(long, default_value_t = 60)]
+    idle_save_delay: u64,
+
+    /// Seconds between saves of the chunks changed since they were last saved, 0 to only save
+    /// chunks when they unload
+    #[arg(long, default_value_t = 300)]
+    autosave_interval: u64,
+
+    /// Seconds between logging tick times, message counts and loaded chunks, 0 to disable
+    #[arg(long, default_value_t = 60)]
+    metrics_interval: u64,
+
+    /// Name of a player allowed to run commands that change the world or other players, can be
+    /// given more than once
+    #[arg(long = "operator")]
+    operators: Vec<String>,
+
+    /// How far from their eyes players on the integrated server may edit and use blocks
+    #[arg(long, default_value_t = game::player::DEFAULT_REACH)]
+    reach: f32,
+
+    /// How wide and common caves are in newly generated terrain, 0 for no caves
+    #[arg(long, default_value_t = CaveSettings::default().density)]
+    cave_density: f64,
+
+    /// How many blocks below the surface caves start
+    #[arg(long, default_value_t = CaveSettings::default().min_depth)]
+    cave_min_depth: isize,
+
+    /// Only run the server, without opening a window. Stops cleanly on SIGINT or SIGTERM.
+    #[arg(long, conflicts_with = "no_server")]
+    dedicated: bool,
+
+    /// Replace the world generation snapshot with the chunks generated now, then exit. Only for
+    /// when the terrain of existing worlds is meant to change.
+    #[arg(long)]
+    update_worldgen_snapshot: bool,
 }
 
 pub fn main() {
     let args = Args::parse();
 
-    let ip = args.ip.unwrap_or("[::]:1234".parse().unwrap());
+    if args.update_worldgen_snapshot {
+        let snapshot = Snapshot::take(&BlockRegistry::with_default_blocks());
+        snapshot.save(worldgen_snapshot::PATH).unwrap();
+        println!("Wrote {} chunks to {}", snapshot.chunks.len(), worldgen_snapshot::PATH);
+        return;
+    }
+
+    let mut network = NetworkConfig::load(&args.server_config);
+    network.address = args.bind_address.unwrap_or(network.address);
+    network.port = args.port.unwrap_or(network.port);
+
+    let choice = if args.dedicated {
+        MenuChoice::Singleplayer
+    } else if let Some(ip) = args.ip {
+        MenuChoice::Connect(ip)
+    } else if !std::io::stdin().is_terminal() {
+        // Started from a desktop shortcut or the like, with nobody to answer the menu
+        MenuChoice::Singleplayer
+    } else {
+        let settings = Settings::load(Settings::PATH);
+        let locale = Locale::load(&settings.language);
+        let mut recent = RecentServers::load(RecentServers::PATH);
+        let Some(choice) = menu::choose(&recent, &locale) else {
+            return;
+        };
+        if let MenuChoice::Connect(address) = choice {
+            recent.add(address);
+            if let Err(e) = recent.save(RecentServers::PATH) {
+                log::warn!("Could not save the recent servers: {e:#}");
+            }
+        }
+        choice
+    };
+    let address = match choice {
+        MenuChoice::Singleplayer => SocketAddr::new(Ipv6Addr::LOCALHOST.into(), network.port),
+        MenuChoice::Connect(address) => address,
+    };
 
     let (shutdown_signal_tx, shutdown_signal_rx) = async_std::channel::unbounded();
 
-    let task = if !args.no_server {
-        let mut server = Server::new(shutdown_signal_rx);
+    let task = if !args.no_server && choice == MenuChoice::Singleplayer {
+        network.certificate = args.certificate.or(network.certificate);
+        network.private_key = args.private_key.or(network.private_key);
+        network.tick_rate = args.tick_rate.unwrap_or(network.tick_rate);
+        let config = ServerConfig {
+            network,
+            storage: args.storage,
+            save_dir: args.save_dir,
+            simulation_distance: args.simulation_distance,
+            motd: args.motd,
+            movement_rules: MovementRules {
+                check_collisions: !args.allow_noclip,
+                ..Default::default()
+            },
+            idle_save_delay: (args.idle_save_delay > 0)
+                .then(|| Duration::from_secs(args.idle_save_delay)),
+            autosave_interval: (args.autosave_interval > 0)
+                .then(|| Duration::from_secs(args.autosave_interval)),
+            metrics_interval: (args.metrics_interval > 0)
+                .then(|| Duration::from_secs(args.metrics_interval)),
+            operators: args.operators,
+            reach: args.reach,
+            caves: CaveSettings {
+                density: args.cave_density,
+                min_depth: args.cave_min_depth,
+            },
+        };
+        let mut server = Server::new(config, shutdown_signal_rx);
     
         Some(async_std::task::spawn(async move {
                     server.run().await;
@@ -587,7 +1374,20 @@ pub fn main() {
         None
     };
 
-    pollster::block_on(run());
+    if args.dedicated {
+        // Container and service managers stop processes with SIGTERM, make sure the world is
+        // saved when that happens
+        let shutdown_signal_tx = shutdown_signal_tx.clone();
+        ctrlc::set_handler(move || {
+            println!("Received shutdown signal, stopping server...");
+            let _ = shutdown_signal_tx.send_blocking(());
+        })
+        .unwrap();
+        task.unwrap().block_on();
+        return;
+    }
+
+    pollster::block_on(run(address));
     if let Some(task) = task {
         println!("Shutting down server...");
         shutdown_signal_tx.send_blocking(()).unwrap();
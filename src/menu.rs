@@ -0,0 +1,119 @@
+//! The menu shown on startup, where the player picks what to play before anything connects.
+//!
+//! The menu is a prompt in the terminal, shown before the window opens. Without a terminal to
+//! answer it in, the game starts in singleplayer instead.
+
+use std::{
+    io::{BufRead, Write},
+    net::{SocketAddr, ToSocketAddrs},
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::locale::Locale;
+
+/// What the player picked in the menu
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MenuChoice {
+    /// Start the integrated server and play on it
+    Singleplayer,
+    /// Play on the server at this address
+    Connect(SocketAddr),
+}
+
+/// Servers connected to directly, most recent first, persisted between launches
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecentServers {
+    pub servers: Vec<SocketAddr>,
+}
+
+impl RecentServers {
+    pub const PATH: &'static str = "./recent_servers.toml";
+    /// How many servers are remembered
+    const MAX: usize = 8;
+
+    /// Load the recent servers from `path`, starting over if the file is missing or invalid
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                log::warn!("Could not read {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        toml::from_str(&contents).unwrap_or_else(|e| {
+            log::warn!("Invalid recent servers in {}: {e}", path.display());
+            Self::default()
+        })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Move `address` to the top of the list, forgetting the oldest server if it gets too long
+    pub fn add(&mut self, address: SocketAddr) {
+        self.servers.retain(|&server| server != address);
+        self.servers.insert(0, address);
+        self.servers.truncate(Self::MAX);
+    }
+}
+
+/// Show the menu and ask until the player picks something. `None` if stdin is closed before
+/// they do.
+pub fn choose(recent: &RecentServers, locale: &Locale) -> Option<MenuChoice> {
+    println!("{}", locale.get("menu.title"));
+    println!("  1. {}", locale.get("menu.singleplayer"));
+    println!("  2. {}", locale.get("menu.direct_connect"));
+    for (i, server) in recent.servers.iter().enumerate() {
+        println!("  {}. {server}", i + 3);
+    }
+
+    let mut lines = std::io::stdin().lock().lines();
+    let mut prompt = |text: &str| {
+        print!("{text}");
+        std::io::stdout().flush().ok()?;
+        lines.next()?.ok()
+    };
+    loop {
+        let line = prompt("> ")?;
+        let line = line.trim();
+        let address = match line.parse::<usize>() {
+            Ok(1) => return Some(MenuChoice::Singleplayer),
+            Ok(2) => prompt(locale.get("menu.address"))?.trim().to_string(),
+            Ok(n) if (3..recent.servers.len() + 3).contains(&n) => {
+                return Some(MenuChoice::Connect(recent.servers[n - 3]));
+            }
+            Ok(_) => {
+                println!("{}", locale.get("menu.invalid_choice"));
+                continue;
+            }
+            Err(_) if line.is_empty() => continue,
+            // A server address typed right away, skipping the direct connect entry
+            Err(_) => line.to_string(),
+        };
+        match resolve(&address) {
+            Ok(address) => return Some(MenuChoice::Connect(address)),
+            Err(e) => {
+                let message = locale.format(
+                    "menu.unknown_address",
+                    &[("address", &address), ("error", &e)],
+                );
+                println!("{message}");
+            }
+        }
+    }
+}
+
+/// Look up a `host:port` address
+fn resolve(address: &str) -> anyhow::Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no addresses found"))
+}
@@ -1,11 +1,17 @@
 use std::{
-    ops::{Neg, Range},
+    ops::{AddAssign, Range},
     sync::Arc,
 };
 
-use wgpu::util::DeviceExt;
+use wgpu::{naga::FastHashMap, util::DeviceExt};
 
-use crate::{camera::Sphere, game::chunk::ChunkPos, texture::Texture};
+use crate::{
+    camera::Sphere,
+    direction::Direction,
+    game::chunk::ChunkPos,
+    texture::{Texture, TextureOptions},
+    upload::Uploader,
+};
 
 pub trait Vertex {
     fn desc() -> wgpu::VertexBufferLayout<'static>;
@@ -18,6 +24,9 @@ pub struct MeshVertex {
     pub tex_coords: [f32; 2],
     pub ambient_occlusion: f32,
     pub normal: [f32; 3],
+    /// Frame count, seconds per frame, and texture coordinate offset between frames.
+    /// All zero for faces that aren't animated.
+    pub animation: [f32; 3],
 }
 
 impl Vertex for MeshVertex {
@@ -46,6 +55,11 @@ impl Vertex for MeshVertex {
                     shader_location: 3,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 9]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
@@ -56,9 +70,26 @@ pub struct Mesh {
     index_buffer: wgpu::Buffer,
     pub local_bounding_sphere: Sphere,
     pub num_elements: u32,
+    num_vertices: u32,
     material: Arc<Material>,
 }
 
+/// How much geometry a mesh has, and how much GPU memory it takes up
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MeshSize {
+    pub vertices: u64,
+    pub indices: u64,
+    pub bytes: u64,
+}
+
+impl AddAssign for MeshSize {
+    fn add_assign(&mut self, other: Self) {
+        self.vertices += other.vertices;
+        self.indices += other.indices;
+        self.bytes += other.bytes;
+    }
+}
+
 impl Mesh {
     pub fn new(
         vertices: &[MeshVertex],
@@ -66,40 +97,100 @@ impl Mesh {
         material: Arc<Material>,
         device: &wgpu::Device,
     ) -> Self {
-        let center = vertices
-            .iter()
-            .map(|v| Vector3::from(v.position))
-            .sum::<Vector3<f32>>()
-            / vertices.len() as f32;
-        let radius = vertices
-            .iter()
-            .map(|v| (Vector3::from(v.position) - center).magnitude2())
-            .reduce(f32::max)
-            .unwrap_or(0.0)
-            .sqrt();
-
         let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some(&format!("{:?} Vertex Buffer", "TEMP!! ")),
             contents: bytemuck::cast_slice(&vertices),
-            usage: wgpu::BufferUsages::VERTEX,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
         });
         let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: Some(&format!("{:?} Index Buffer", "TEMP!! ")),
             contents: bytemuck::cast_slice(&indices),
-            usage: wgpu::BufferUsages::INDEX,
+            usage: wgpu::BufferUsages::INDEX | wgpu::BufferUsages::COPY_DST,
         });
 
         Self {
             vertex_buffer,
-            local_bounding_sphere: Sphere {
-                center: Point3::from_vec(center),
-                radius,
-            },
+            local_bounding_sphere: Self::bounding_sphere(vertices),
             index_buffer,
             num_elements: indices.len() as u32,
+            num_vertices: vertices.len() as u32,
             material,
         }
     }
+
+    /// The size of the mesh, counting the whole buffers even where they have room to spare
+    pub fn size(&self) -> MeshSize {
+        MeshSize {
+            vertices: self.num_vertices as u64,
+            indices: self.num_elements as u64,
+            bytes: self.vertex_buffer.size() + self.index_buffer.size(),
+        }
+    }
+
+    /// Replace the contents of this mesh, writing into the existing buffers if they are large
+    /// enough and only allocating new ones when the mesh has outgrown them
+    pub fn update(
+        &mut self,
+        vertices: &[MeshVertex],
+        indices: &[u32],
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) {
+        Self::write_or_grow(
+            &mut self.vertex_buffer,
+            bytemuck::cast_slice(vertices),
+            wgpu::BufferUsages::VERTEX,
+            device,
+            uploader,
+        );
+        Self::write_or_grow(
+            &mut self.index_buffer,
+            bytemuck::cast_slice(indices),
+            wgpu::BufferUsages::INDEX,
+            device,
+            uploader,
+        );
+        self.local_bounding_sphere = Self::bounding_sphere(vertices);
+        self.num_elements = indices.len() as u32;
+        self.num_vertices = vertices.len() as u32;
+    }
+
+    fn write_or_grow(
+        buffer: &mut wgpu::Buffer,
+        contents: &[u8],
+        usage: wgpu::BufferUsages,
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) {
+        if contents.len() as u64 > buffer.size() {
+            // Leave some room to grow, so that a chunk being built on doesn't reallocate every edit
+            *buffer = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&format!("{:?} {:?} Buffer", "TEMP!! ", usage)),
+                size: (contents.len() as u64).next_power_of_two(),
+                usage: usage | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+        }
+        uploader.write_buffer(buffer, 0, contents, device);
+    }
+
+    pub fn bounding_sphere(vertices: &[MeshVertex]) -> Sphere {
+        let center = vertices
+            .iter()
+            .map(|v| Vector3::from(v.position))
+            .sum::<Vector3<f32>>()
+            / vertices.len() as f32;
+        let radius = vertices
+            .iter()
+            .map(|v| (Vector3::from(v.position) - center).magnitude2())
+            .reduce(f32::max)
+            .unwrap_or(0.0)
+            .sqrt();
+        Sphere {
+            center: Point3::from_vec(center),
+            radius,
+        }
+    }
 }
 
 pub struct Material {
@@ -109,13 +200,23 @@ pub struct Material {
 }
 
 impl Material {
-    pub fn new(
-        image: &[u8],
+    pub fn from_image(
+        image: &image::DynamicImage,
+        options: TextureOptions,
         device: &wgpu::Device,
         queue: &wgpu::Queue,
         layout: &wgpu::BindGroupLayout,
     ) -> Self {
-        let diffuse_texture = Texture::from_bytes(device, queue, image, "TEMP!! ").unwrap();
+        let diffuse_texture =
+            Texture::from_image(device, queue, image, Some("TEMP!! "), options).unwrap();
+        Self::from_texture(diffuse_texture, device, layout)
+    }
+
+    fn from_texture(
+        diffuse_texture: Texture,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
         let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
             layout,
             entries: &[
@@ -171,161 +272,13 @@ where
     }
 }
 
-use cgmath::{
-    ElementWise, EuclideanSpace, InnerSpace, One, Point2, Point3, Vector2, Vector3, Zero,
-};
+use cgmath::{ElementWise, EuclideanSpace, InnerSpace, Point2, Point3, Vector2, Vector3, Zero};
 
 pub struct MeshBuilder {
     vertices: Vec<MeshVertex>,
     indices: Vec<u32>,
-}
-
-#[derive(Clone, Copy, Debug)]
-pub enum Direction {
-    /// +Z
-    North,
-    /// -X
-    East,
-    /// -Z
-    South,
-    /// +X
-    West,
-    /// +Y
-    Up,
-    /// -Y
-    Down,
-}
-
-#[derive(Debug, Clone, Copy)]
-pub enum Vector3Accessor {
-    X,
-    Y,
-    Z,
-}
-
-impl Vector3Accessor {
-    pub fn of<T: D3Accessible>(self, vec: T) -> T::Element {
-        vec.get(self)
-    }
-
-    pub fn set<T: D3Accessible>(self, vec: &mut T, value: T::Element) {
-        vec.set(self, value);
-    }
-}
-
-pub trait D3Accessible {
-    type Element;
-
-    fn get(self, accessor: Vector3Accessor) -> Self::Element;
-    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element);
-}
-
-impl<T> D3Accessible for Vector3<T> {
-    type Element = T;
-
-    fn get(self, accessor: Vector3Accessor) -> Self::Element {
-        match accessor {
-            Vector3Accessor::X => self.x,
-            Vector3Accessor::Y => self.y,
-            Vector3Accessor::Z => self.z,
-        }
-    }
-
-    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element) {
-        match accessor {
-            Vector3Accessor::X => self.x = value,
-            Vector3Accessor::Y => self.y = value,
-            Vector3Accessor::Z => self.z = value,
-        }
-    }
-}
-
-impl<T> D3Accessible for Point3<T> {
-    type Element = T;
-
-    fn get(self, accessor: Vector3Accessor) -> Self::Element {
-        match accessor {
-            Vector3Accessor::X => self.x,
-            Vector3Accessor::Y => self.y,
-            Vector3Accessor::Z => self.z,
-        }
-    }
-
-    fn set(&mut self, accessor: Vector3Accessor, value: Self::Element) {
-        match accessor {
-            Vector3Accessor::X => self.x = value,
-            Vector3Accessor::Y => self.y = value,
-            Vector3Accessor::Z => self.z = value,
-        }
-    }
-}
-
-impl Direction {
-    pub const ALL: [Direction; 6] = [
-        Direction::North,
-        Direction::South,
-        Direction::East,
-        Direction::West,
-        Direction::Up,
-        Direction::Down,
-    ];
-
-    pub fn normal<T: Zero<Output = T> + One + Neg<Output = T> + Clone>(&self) -> Vector3<T> {
-        match self {
-            Direction::North => [T::zero(), T::zero(), T::one()],
-            Direction::East => [-T::one(), T::zero(), T::zero()],
-            Direction::South => [T::zero(), T::zero(), -T::one()],
-            Direction::West => [T::one(), T::zero(), T::zero()],
-            Direction::Up => [T::zero(), T::one(), T::zero()],
-            Direction::Down => [T::zero(), -T::one(), T::zero()],
-        }
-        .into()
-    }
-
-    pub fn inverse(self) -> Self {
-        match self {
-            Direction::North => Self::South,
-            Direction::East => Self::West,
-            Direction::South => Self::North,
-            Direction::West => Self::East,
-            Direction::Up => Self::Down,
-            Direction::Down => Self::Up,
-        }
-    }
-
-    pub fn chunk_limit(self) -> usize {
-        match self {
-            Direction::North => 15,
-            Direction::East => 0,
-            Direction::South => 0,
-            Direction::West => 15,
-            Direction::Up => 15,
-            Direction::Down => 0,
-        }
-    }
-
-    pub fn axle(self) -> Vector3Accessor {
-        match self {
-            Direction::North => Vector3Accessor::Z,
-            Direction::East => Vector3Accessor::X,
-            Direction::South => Vector3Accessor::Z,
-            Direction::West => Vector3Accessor::X,
-            Direction::Up => Vector3Accessor::Y,
-            Direction::Down => Vector3Accessor::Y,
-        }
-    }
-
-    pub fn on_plane<T: Zero + Neg<Output = T> + Clone>(self, coords: Point2<T>) -> Point3<T> {
-        match self {
-            Direction::North => [coords.x, coords.y, T::zero()],
-            Direction::East => [T::zero(), coords.y, coords.x],
-            Direction::South => [-coords.x, coords.y, T::zero()],
-            Direction::West => [T::zero(), coords.y, -coords.x],
-            Direction::Up => [-coords.x, T::zero(), coords.y],
-            Direction::Down => [coords.x, T::zero(), coords.y],
-        }
-        .into()
-    }
+    /// Index of every distinct vertex added so far, if identical vertices should be shared
+    welded: Option<FastHashMap<[u32; 12], u32>>,
 }
 
 impl MeshBuilder {
@@ -333,17 +286,71 @@ impl MeshBuilder {
         Self {
             vertices: vec![],
             indices: vec![],
+            welded: None,
+        }
+    }
+
+    /// A builder that reuses existing vertices instead of adding identical copies
+    pub fn new_welded() -> Self {
+        Self {
+            welded: Some(FastHashMap::default()),
+            ..Self::new()
         }
     }
 
     pub fn add_vert_indices(&mut self, vertices: &[MeshVertex], local_indices: &[u32]) {
-        let offset = self.vertices.len();
-        self.vertices.extend_from_slice(vertices);
+        let Some(welded) = &mut self.welded else {
+            let offset = self.vertices.len();
+            self.vertices.extend_from_slice(vertices);
+            self.indices
+                .extend(local_indices.iter().map(|i| i + offset as u32));
+            return;
+        };
+
+        let indices = vertices
+            .iter()
+            .map(|vertex| {
+                // Compare the exact bits, so that only truly identical vertices are merged
+                let key = bytemuck::cast::<MeshVertex, [u32; 12]>(*vertex);
+                *welded.entry(key).or_insert_with(|| {
+                    self.vertices.push(*vertex);
+                    self.vertices.len() as u32 - 1
+                })
+            })
+            .collect::<Vec<_>>();
         self.indices
-            .extend(local_indices.iter().map(|i| i + offset as u32));
+            .extend(local_indices.iter().map(|&i| indices[i as usize]));
+    }
+
+    /// Add a quad from its four corners, given clockwise from the top left as seen from the
+    /// front.
+    ///
+    /// The quad is split into two triangles along the diagonal whose corners are more occluded.
+    /// A single occluded corner then shades both triangles evenly, instead of one of them with
+    /// a crease along the other diagonal, which made corners look different depending on which
+    /// way the quad was turned.
+    pub fn add_quad(&mut self, vertices: [MeshVertex; 4]) {
+        let [ao00, ao01, ao11, ao10] = vertices.map(|vertex| vertex.ambient_occlusion);
+        let indices = if ao00 + ao11 > ao01 + ao10 {
+            [0, 3, 2, 0, 2, 1]
+        } else {
+            [0, 3, 1, 1, 3, 2]
+        };
+        self.add_vert_indices(&vertices, &indices);
     }
 
     pub fn add_face(&mut self, offset: Vector3<f32>, direction: Direction, uv: [Point2<f32>; 4]) {
+        self.add_face_scaled(offset, direction, uv, 1.0);
+    }
+
+    /// Add the face of a cube with sides `scale` long, centered on `offset`
+    pub fn add_face_scaled(
+        &mut self,
+        offset: Vector3<f32>,
+        direction: Direction,
+        uv: [Point2<f32>; 4],
+        scale: f32,
+    ) {
         let pos = match direction {
             Direction::North => [
                 [-0.5, 0.5, 0.5],
@@ -383,8 +390,6 @@ impl MeshBuilder {
             ],
         };
 
-        let indices = [0, 3, 1, 1, 3, 2];
-
         let normals = match direction {
             Direction::North => [[0.0, 0.0, 1.0]; 4],
             Direction::East => [[-1.0, 0.0, 0.0]; 4],
@@ -394,23 +399,128 @@ impl MeshBuilder {
             Direction::Down => [[0.0, -1.0, 0.0]; 4],
         };
 
-        let vertices = pos
-            .into_iter()
-            .zip(uv)
-            .zip(normals)
-            .map(|((pos, uv), norm)| MeshVertex {
-                position: [pos[0] + offset.x, pos[1] + offset.y, pos[2] + offset.z],
-                tex_coords: uv.into(),
-                ambient_occlusion: 0.0,
-                normal: norm,
-            });
+        let vertices = std::array::from_fn(|i| MeshVertex {
+            position: [
+                pos[i][0] * scale + offset.x,
+                pos[i][1] * scale + offset.y,
+                pos[i][2] * scale + offset.z,
+            ],
+            tex_coords: uv[i].into(),
+            ambient_occlusion: 0.0,
+            normal: normals[i],
+            animation: [0.0; 3],
+        });
 
-        let offset = self.vertices.len() as u32;
-        self.vertices.extend(vertices);
-        self.indices.extend_from_slice(&indices.map(|x| x + offset));
+        self.add_quad(vertices);
+    }
+
+    pub fn vertices(&self) -> &[MeshVertex] {
+        &self.vertices
+    }
+
+    pub fn indices(&self) -> &[u32] {
+        &self.indices
     }
 
     pub fn build(self, material: Arc<Material>, device: &wgpu::Device) -> Mesh {
+        #[cfg(debug_assertions)]
+        self.warn_bad_triangles();
         Mesh::new(&self.vertices, &self.indices, material, device)
     }
+
+    /// Build into `old` if nothing else holds on to it, reusing its buffers
+    pub fn rebuild(
+        self,
+        old: Option<Arc<Mesh>>,
+        material: Arc<Material>,
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+    ) -> Mesh {
+        match old.and_then(Arc::into_inner) {
+            Some(mut mesh) => {
+                #[cfg(debug_assertions)]
+                self.warn_bad_triangles();
+                mesh.update(&self.vertices, &self.indices, device, uploader);
+                mesh
+            }
+            None => self.build(material, device),
+        }
+    }
+
+    /// Every triangle added so far that would be drawn wrongly, by the position of its first
+    /// index
+    pub fn audit(&self) -> Vec<(usize, WindingError)> {
+        self.indices
+            .chunks_exact(3)
+            .enumerate()
+            .filter_map(|(i, triangle)| {
+                let [a, b, c] = [0, 1, 2].map(|j| &self.vertices[triangle[j] as usize]);
+                check_triangle(a, b, c).err().map(|error| (i * 3, error))
+            })
+            .collect()
+    }
+
+    #[cfg(debug_assertions)]
+    fn warn_bad_triangles(&self) {
+        let bad = self.audit();
+        if let Some(&(index, error)) = bad.first() {
+            let position = self.vertices[self.indices[index] as usize].position;
+            log::warn!(
+                "Mesh has {} badly wound or degenerate triangles, the first at {position:?} is {error:?}",
+                bad.len()
+            );
+        }
+    }
+
+    /// Build one face in every direction with [`add_face`](Self::add_face) and check their
+    /// winding, returning the directions that came out wrong
+    #[cfg(test)]
+    pub fn audit_faces() -> Vec<(Direction, WindingError)> {
+        Direction::iter()
+            .filter_map(|dir| {
+                let mut builder = Self::new();
+                builder.add_face(Vector3::zero(), dir, [Point2::new(0.0, 0.0); 4]);
+                builder.audit().first().map(|&(_, error)| (dir, error))
+            })
+            .collect()
+    }
+}
+
+/// Why a triangle would not be drawn as intended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindingError {
+    /// The corners are on a line or on top of each other, so the triangle covers no area
+    Degenerate,
+    /// The corners go clockwise when looking against the normals, so back-face culling hides
+    /// the side that should be visible
+    Clockwise,
+}
+
+/// Check that a triangle covers some area and is wound counter-clockwise when seen from the
+/// side its vertex normals point to, the front face of the render pipelines. Triangles without
+/// normals are only checked for area.
+pub fn check_triangle(a: &MeshVertex, b: &MeshVertex, c: &MeshVertex) -> Result<(), WindingError> {
+    let [pa, pb, pc] = [a, b, c].map(|v| Vector3::from(v.position));
+    let face_normal = (pb - pa).cross(pc - pa);
+    if face_normal.magnitude2() < 1e-12 {
+        return Err(WindingError::Degenerate);
+    }
+    let normal = [a, b, c]
+        .map(|v| Vector3::from(v.normal))
+        .into_iter()
+        .sum::<Vector3<f32>>();
+    if !normal.is_zero() && face_normal.dot(normal) <= 0.0 {
+        return Err(WindingError::Clockwise);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn faces_are_wound_counter_clockwise() {
+        assert_eq!(MeshBuilder::audit_faces(), []);
+    }
 }
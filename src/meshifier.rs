@@ -1,21 +1,47 @@
 use std::{cell::RefCell, sync::Arc};
 
-use cgmath::{EuclideanSpace, Point2, Point3, Vector2, Vector3, Zero};
+use cgmath::{ElementWise, EuclideanSpace, InnerSpace, Point2, Point3, Vector2, Vector3, Zero};
 use wgpu::naga::FastHashMap;
 
 use crate::{
+    arena::ChunkArena,
     game::{
         atlas::Atlas,
-        block::{BlockId, BlockRegistry},
-        chunk::{Chunk, ChunkPos, ChunkRelativeBlockPos},
+        block::{Aabb, BlockAttributes, BlockId, BlockRegistry, BlockShape, ConnectedTexture},
+        chunk::{BlockPos, Chunk, ChunkPos, ChunkRelativeBlockPos, LodChunk},
+        fluid,
         world::World,
     },
-    mesh::{Direction, Mesh, MeshBuilder, MeshVertex},
+    direction::Direction,
+    mesh::{Mesh, MeshBuilder, MeshVertex},
+    occlusion::ChunkVisibility,
+    upload::Uploader,
 };
+#[cfg(test)]
+use crate::mesh::WindingError;
+
+/// The meshes making up a single chunk, besides its opaque faces which are kept in the
+/// `ChunkArena`
+#[derive(Clone)]
+pub struct ChunkMeshes {
+    /// Faces of translucent blocks, which must be drawn after all opaque geometry
+    pub translucent: Arc<Mesh>,
+    /// Which faces of the chunk can see each other, for occlusion culling
+    pub visibility: ChunkVisibility,
+}
+
+/// The meshes making up a downsampled chunk
+pub struct LodMeshes {
+    pub opaque: Arc<Mesh>,
+    pub translucent: Arc<Mesh>,
+}
 
 pub struct ChunkMeshifier {
-    cache: FastHashMap<ChunkPos, Arc<Mesh>>,
+    cache: FastHashMap<ChunkPos, ChunkMeshes>,
     pub enable_ao: bool,
+    /// Shade each vertex by the average light of the four blocks around it, instead of by how
+    /// many of them are solid
+    pub smooth_lighting: bool,
 }
 
 impl ChunkMeshifier {
@@ -23,22 +49,110 @@ impl ChunkMeshifier {
         Self {
             cache: FastHashMap::default(),
             enable_ao: true,
+            smooth_lighting: true,
         }
     }
 
+    /// Drop the cached meshes of a chunk that is no longer loaded
+    pub fn remove(&mut self, pos: ChunkPos) {
+        self.cache.remove(&pos);
+    }
+
+    /// Keep only the cached meshes of chunks for which `f` returns true,
+    /// returning how many were dropped
+    pub fn retain(&mut self, mut f: impl FnMut(ChunkPos) -> bool) -> usize {
+        let before = self.cache.len();
+        self.cache.retain(|&pos, _| f(pos));
+        before - self.cache.len()
+    }
+
+    pub fn cached_chunks(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// The visibility of a chunk as of its last meshing
+    pub fn visibility(&self, pos: ChunkPos) -> Option<ChunkVisibility> {
+        self.cache.get(&pos).map(|meshes| meshes.visibility)
+    }
+
+    /// Build one face in every direction the way chunk meshes are built and check their
+    /// winding, returning the directions that came out wrong
+    #[cfg(test)]
+    pub fn audit_faces(
+        &mut self,
+        block_registry: &BlockRegistry,
+    ) -> Vec<(Direction, WindingError)> {
+        let world = World::new();
+        let chunk = Chunk::new(Point3::new(0, 0, 0).into());
+        let position = ChunkRelativeBlockPos::from(Point3::new(8, 8, 8));
+        Direction::iter()
+            .filter_map(|dir| {
+                let mut builder = MeshBuilder::new();
+                self.build_face(
+                    &mut builder,
+                    Vector3::new(8.5, 8.5, 8.5),
+                    &chunk,
+                    &world,
+                    position,
+                    dir,
+                    [Point2::new(0.0, 0.0); 4],
+                    [0.0; 3],
+                    Aabb::FULL,
+                    block_registry,
+                );
+                builder.audit().first().map(|&(_, error)| (dir, error))
+            })
+            .collect()
+    }
+
     pub fn meshify(
         &mut self,
         world: &World,
         chunk: &Chunk,
         atlas: &Atlas,
         block_registry: &BlockRegistry,
+        arena: &mut ChunkArena,
         device: &wgpu::Device,
-    ) -> Arc<Mesh> {
-        if !chunk.get_dirty() && self.cache.contains_key(&chunk.pos) {
+        uploader: &mut Uploader,
+    ) -> ChunkMeshes {
+        if !chunk.get_dirty() && self.cache.contains_key(&chunk.pos) && arena.contains(chunk.pos) {
             return self.cache.get(&chunk.pos).unwrap().clone();
         }
 
-        let mut builder = MeshBuilder::new();
+        let (opaque_builder, translucent_builder) =
+            self.build_builders(world, chunk, atlas, block_registry);
+
+        let position = Point3::from(chunk.pos).cast::<f32>().unwrap() * Chunk::SIZE as f32;
+        arena.insert(chunk.pos, &opaque_builder, position, device, uploader);
+
+        let material = atlas.material.clone();
+
+        // The previous mesh's buffers can be written to directly once no objects use it anymore
+        let old_translucent = self.cache.remove(&chunk.pos).map(|old| old.translucent);
+        let meshes = ChunkMeshes {
+            translucent: Arc::new(translucent_builder.rebuild(
+                old_translucent,
+                material,
+                device,
+                uploader,
+            )),
+            visibility: ChunkVisibility::compute(chunk, block_registry),
+        };
+        self.cache.insert(chunk.pos, meshes);
+        chunk.set_dirty(false);
+        self.cache.get(&chunk.pos).unwrap().clone()
+    }
+
+    /// Build the faces of a chunk without uploading them, as opaque and translucent geometry
+    pub fn build_builders(
+        &mut self,
+        world: &World,
+        chunk: &Chunk,
+        atlas: &Atlas,
+        block_registry: &BlockRegistry,
+    ) -> (MeshBuilder, MeshBuilder) {
+        let mut opaque_builder = MeshBuilder::new_welded();
+        let mut translucent_builder = MeshBuilder::new_welded();
 
         let neighbouring_chunks = Direction::ALL.map(|d| world.chunk(chunk.pos + d.normal()));
 
@@ -54,46 +168,103 @@ impl ChunkMeshifier {
 
                     let offset = Vector3::new(x as _, y as _, z as _) + Vector3::new(0.5, 0.5, 0.5);
 
-                    let is_transparent = |[dx, dy, dz]: [isize; 3]| {
-                        block_registry
-                            .get(
-                                // chunk.blocks[(x as isize + dx) as usize]
-                                //     [(y as isize + dy) as usize]
-                                //     [(z as isize + dz) as usize]
-                                //     .id,
-                                chunk.block(Point3::from([(x as isize + dx) as usize, (y as isize + dy) as usize, (z as isize + dz) as usize]).into()).id
-                            )
-                            .unwrap()
-                            .transparent
+                    let neighbour_in_chunk = |[dx, dy, dz]: [isize; 3]| {
+                        // chunk.blocks[(x as isize + dx) as usize]
+                        //     [(y as isize + dy) as usize]
+                        //     [(z as isize + dz) as usize]
+                        *chunk.block(Point3::from([(x as isize + dx) as usize, (y as isize + dy) as usize, (z as isize + dz) as usize]).into())
+                    };
+
+                    let uv = atlas.uv(atlas.cell(attr.block_texture(**block, chunk.pos + position)));
+                    let animation = attr
+                        .animation
+                        .map_or([0.0; 3], |animation| animation.vertex_data(uv[1].x - uv[0].x));
+
+                    let builder = if attr.translucent {
+                        &mut translucent_builder
+                    } else {
+                        &mut opaque_builder
                     };
 
-                    let uv = atlas.uv(attr.uv_coords);
+                    // The surface of a liquid sinks the further it has flowed, unless more of
+                    // it lies on top
+                    let surface;
+                    let boxes = if attr.liquid {
+                        let above = Point3::from(chunk.pos + position) + Vector3::unit_y();
+                        let height = if world
+                            .block(above.into())
+                            .is_some_and(|above| above.id == block.id)
+                        {
+                            1.0
+                        } else {
+                            fluid::surface_height(**block)
+                        };
+                        surface =
+                            Aabb::new(Point3::new(0.0, 0.0, 0.0), Point3::new(1.0, height, 1.0));
+                        std::slice::from_ref(&surface)
+                    } else {
+                        attr.boxes(**block)
+                    };
 
                     for (dir, neighbour_chunk) in
                         Direction::ALL.into_iter().zip(&neighbouring_chunks)
                     {
-                        let build = if dir.axle().of(position) == dir.chunk_limit() {
-                            if let Some(chunk) = neighbour_chunk {
+                        let neighbour = if dir.axle().of(position) == dir.chunk_limit() {
+                            neighbour_chunk.map(|chunk| {
                                 let mut block_pos = position;
-                                dir.axle().set(&mut block_pos, dir.inverse().chunk_limit());
-                                let block = chunk.block(block_pos);
-                                block_registry.get(block.id).unwrap().transparent
-                            } else {
-                                true
-                            }
+                                dir.axle().set(&mut block_pos, dir.opposite().chunk_limit());
+                                *chunk.block(block_pos)
+                            })
                         } else {
-                            is_transparent(dir.normal().into())
+                            Some(neighbour_in_chunk(dir.normal().into()))
                         };
 
-                        if build {
+                        for shape in boxes {
+                            // Faces against another box of the same block are inside of it
+                            if boxes.iter().any(|other| shape.face_covered_by(dir, other)) {
+                                continue;
+                            }
+
+                            let hidden = neighbour.is_some_and(|neighbour| {
+                                let neighbour_attr = block_registry.get(neighbour.id).unwrap();
+                                let normal = dir.normal();
+                                if neighbour_attr.shape == BlockShape::Full {
+                                    // Liquids and connected blocks only show their surface
+                                    // towards other blocks, not the faces between two blocks of
+                                    // the same type
+                                    let hides = !neighbour_attr.transparent
+                                        || ((attr.liquid || attr.connected_texture)
+                                            && neighbour.id == block.id);
+                                    hides && shape.face_covered_by(dir, &Aabb::FULL.offset(normal))
+                                } else {
+                                    !neighbour_attr.translucent
+                                        && neighbour_attr.boxes(neighbour).iter().any(|other| {
+                                            shape.face_covered_by(dir, &other.offset(normal))
+                                        })
+                                }
+                            });
+                            if hidden {
+                                continue;
+                            }
+
+                            let uv = if attr.connected_texture {
+                                let index =
+                                    Self::connected_texture_index(world, chunk.pos + position, dir);
+                                let cell = atlas.cell(attr.texture);
+                                atlas.uv([cell.x + index, cell.y])
+                            } else {
+                                uv
+                            };
                             self.build_face(
-                                &mut builder,
+                                builder,
                                 offset,
                                 chunk,
                                 world,
                                 position,
                                 dir,
                                 uv,
+                                animation,
+                                *shape,
                                 block_registry,
                             );
                         }
@@ -102,12 +273,103 @@ impl ChunkMeshifier {
             }
         }
 
+        (opaque_builder, translucent_builder)
+    }
+
+    /// Build meshes for a downsampled chunk.
+    /// These are not cached, as LOD chunks are only remeshed when replaced.
+    pub fn meshify_lod(
+        &self,
+        lod: &LodChunk,
+        atlas: &Atlas,
+        block_registry: &BlockRegistry,
+        device: &wgpu::Device,
+    ) -> LodMeshes {
+        let mut opaque_builder = MeshBuilder::new_welded();
+        let mut translucent_builder = MeshBuilder::new_welded();
+
+        let size = lod.size() as isize;
+        let scale = lod.scale() as f32;
+
+        for x in 0..size {
+            for y in 0..size {
+                for z in 0..size {
+                    let position = Point3::new(x, y, z);
+                    let block = lod.block(position.cast().unwrap());
+                    let attr = block_registry.get(block.id).unwrap();
+                    if attr.invisible {
+                        continue;
+                    }
+
+                    let offset = (position.to_vec().cast().unwrap() + Vector3::new(0.5, 0.5, 0.5))
+                        * scale;
+                    let uv = atlas.uv(atlas.cell(attr.texture));
+
+                    let builder = if attr.translucent {
+                        &mut translucent_builder
+                    } else {
+                        &mut opaque_builder
+                    };
+
+                    for dir in Direction::ALL {
+                        let neighbour = position + dir.normal();
+                        // Faces on the chunk border are always built, as neighbouring LOD chunks
+                        // may be at a different level
+                        let build = if neighbour.x < 0
+                            || neighbour.y < 0
+                            || neighbour.z < 0
+                            || neighbour.x >= size
+                            || neighbour.y >= size
+                            || neighbour.z >= size
+                        {
+                            true
+                        } else {
+                            let neighbour = lod.block(neighbour.cast().unwrap());
+                            block_registry.get(neighbour.id).unwrap().transparent
+                                && !(attr.liquid && neighbour.id == block.id)
+                        };
+
+                        if build {
+                            builder.add_face_scaled(offset, dir, uv, scale);
+                        }
+                    }
+                }
+            }
+        }
+
         let material = atlas.material.clone();
 
-        let mesh = builder.build(material, device);
-        self.cache.insert(chunk.pos, Arc::new(mesh));
-        chunk.set_dirty(false);
-        self.cache.get(&chunk.pos).unwrap().clone()
+        LodMeshes {
+            opaque: Arc::new(opaque_builder.build(material.clone(), device)),
+            translucent: Arc::new(translucent_builder.build(material, device)),
+        }
+    }
+
+    /// Which of the 16 connected texture cells to use for a face, based on which of the four
+    /// blocks next to it in the plane of the face are the same block
+    fn connected_texture_index(world: &World, pos: BlockPos, direction: Direction) -> usize {
+        let Some(&block) = world.block(pos) else {
+            return 0;
+        };
+        // Texture space up and right map onto the face plane like the vertices in `build_face`
+        let sides = [
+            ([0, 1], ConnectedTexture::UP),
+            ([1, 0], ConnectedTexture::RIGHT),
+            ([0, -1], ConnectedTexture::DOWN),
+            ([-1, 0], ConnectedTexture::LEFT),
+        ];
+
+        let mut index = 0;
+        for (offset, bit) in sides {
+            let neighbour_pos = Point3::from(pos) + direction.on_plane(offset.into()).to_vec();
+            if world
+                .block(neighbour_pos.into())
+                .is_some_and(|neighbour| neighbour.id == block.id)
+            {
+                index |= bit;
+            }
+        }
+        index
     }
 
     fn build_face(
@@ -119,6 +381,8 @@ impl ChunkMeshifier {
         position: ChunkRelativeBlockPos,
         direction: Direction,
         uv: [Point2<f32>; 4],
+        animation: [f32; 3],
+        shape: Aabb,
         block_registry: &BlockRegistry,
     ) {
         let no = 0.0 / 6.0;
@@ -140,15 +404,17 @@ impl ChunkMeshifier {
         ];
 
         let mut blocking: u8 = 0;
+        // Light of the blocks around the face, in the same order as `blocking`
+        let mut light = [1.0; 8];
 
-        for offset in block_offsets {
+        for (i, offset) in block_offsets.into_iter().enumerate() {
             blocking >>= 1;
             let pos: Point3<isize> =
                 pos + direction.on_plane(offset.into()).to_vec() + direction.normal();
             if pos.x < 0 || pos.y < 0 || pos.z < 0 || pos.x > 15 || pos.y > 15 || pos.z > 15 {
                 let mut chunk_pos = chunk.pos;
                 let mut block_pos = pos;
-                for dir in Direction::ALL.into_iter() {
+                for dir in Direction::iter() {
                     if dir.chunk_limit() == 0 && dir.axle().of(pos.to_vec()) < 0 {
                         chunk_pos += dir.normal();
                         dir.axle().set(&mut block_pos, 15);
@@ -159,25 +425,23 @@ impl ChunkMeshifier {
                 }
 
                 if let Some(chunk) = world.chunk(chunk_pos) {
-                    if !block_registry
+                    let attributes = block_registry
                         .get(chunk.block(block_pos.cast().unwrap().into()).id)
-                        .unwrap()
-                        .transparent
-                    {
+                        .unwrap();
+                    if !attributes.transparent {
                         blocking |= 0x80;
                     }
+                    light[i] = Self::block_light(attributes);
                 }
 
                 continue;
             }
             let pos = pos.cast().unwrap();
-            if !block_registry
-                .get(chunk.block(pos.into()).id)
-                .unwrap()
-                .transparent
-            {
+            let attributes = block_registry.get(chunk.block(pos.into()).id).unwrap();
+            if !attributes.transparent {
                 blocking |= 0x80;
             }
+            light[i] = Self::block_light(attributes);
         }
 
         let get_ao = |blocks: u8| match blocks & 0b111 {
@@ -192,28 +456,76 @@ impl ChunkMeshifier {
             _ => unreachable!(),
         };
 
-        let tl_ao = get_ao(blocking);
-        let tr_ao = get_ao(blocking >> 2);
-        let br_ao = get_ao(blocking >> 4);
-        let bl_ao = get_ao(blocking >> 6 | blocking << 2);
+        // The side, corner and side block of each vertex, starting from the top left one
+        let corners = [[0, 1, 2], [2, 3, 4], [4, 5, 6], [6, 7, 0]];
+        let smooth_ao = |[side, corner, other_side]: [usize; 3]| {
+            // Light can't get through the corner block when both sides are solid
+            let both_sides = blocking & (1 << side) != 0 && blocking & (1 << other_side) != 0;
+            let corner = if both_sides { 0.0 } else { light[corner] };
+            // The block in front of the face is never solid, or the face wouldn't be built.
+            // Once there is block light it has to be averaged in here too.
+            let average = (1.0 + light[side] + corner + light[other_side]) / 4.0;
+            // Three dark blocks are as dark as the darkest flat shading
+            (1.0 - average) * he / 0.75
+        };
 
-        let vertex_positions = [[-0.5, 0.5], [0.5, 0.5], [0.5, -0.5], [-0.5, -0.5]]
-            .into_iter()
-            .map(|p| direction.on_plane(p.into()) + direction.normal() * 0.5)
-            .collect::<Vec<_>>();
-        let vertex_uvs = uv;
+        let [tl_ao, tr_ao, br_ao, bl_ao] = if self.smooth_lighting {
+            corners.map(smooth_ao)
+        } else {
+            [
+                get_ao(blocking),
+                get_ao(blocking >> 2),
+                get_ao(blocking >> 4),
+                get_ao(blocking >> 6 | blocking << 2),
+            ]
+        };
+
+        // Fit the face of a full block onto the side of `shape`, relative to the block's center
+        let half = Vector3::new(0.5, 0.5, 0.5);
+        let size = shape.max - shape.min;
+        let vertex_positions = [[-0.5, 0.5], [0.5, 0.5], [0.5, -0.5], [-0.5, -0.5]].map(|p| {
+            let p: Point3<f32> = direction.on_plane(p.into()) + direction.normal() * 0.5;
+            shape.min + (p.to_vec() + half).mul_element_wise(size) - half
+        });
+        // Crop the texture to the part of the face that is covered, instead of squashing it
+        let right = direction.on_plane(Point2::new(1.0, 0.0)).to_vec();
+        let up = direction.on_plane(Point2::new(0.0, 1.0)).to_vec();
+        let vertex_uvs = vertex_positions.map(|p| {
+            let u = p.to_vec().dot(right) + 0.5;
+            let v = 0.5 - p.to_vec().dot(up);
+            uv[0] + (uv[1] - uv[0]) * u + (uv[3] - uv[0]) * v
+        });
         let vertex_aos = [tl_ao, tr_ao, br_ao, bl_ao];
-        let vertex_indices = [0, 3, 1, 1, 3, 2];
-
-        let vertices = (0..4)
-            .map(|i| MeshVertex {
-                position: (vertex_positions[i] + offset).into(),
-                tex_coords: vertex_uvs[i].into(),
-                ambient_occlusion: if self.enable_ao { vertex_aos[i] } else { 0.0 },
-                normal: direction.normal().into(),
-            })
-            .collect::<Vec<_>>();
 
-        builder.add_vert_indices(&vertices, &vertex_indices);
+        let vertices = std::array::from_fn(|i| MeshVertex {
+            position: (vertex_positions[i] + offset).into(),
+            tex_coords: vertex_uvs[i].into(),
+            ambient_occlusion: if self.enable_ao { vertex_aos[i] } else { 0.0 },
+            normal: direction.normal().into(),
+            animation,
+        });
+
+        builder.add_quad(vertices);
+    }
+
+    /// How much light a block gives the faces around it, 0 for solid blocks and 1 for open air.
+    /// Glowing blocks don't darken their surroundings even though they are solid.
+    fn block_light(attributes: &BlockAttributes) -> f32 {
+        if attributes.transparent || attributes.light_emission > 0.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_faces_are_wound_counter_clockwise() {
+        let block_registry = BlockRegistry::with_default_blocks();
+        assert_eq!(ChunkMeshifier::new().audit_faces(&block_registry), []);
     }
 }
@@ -3,7 +3,7 @@ use std::sync::Arc;
 use cgmath::EuclideanSpace;
 use wgpu::util::DeviceExt;
 
-use crate::{camera::Sphere, mesh::Mesh, Instance};
+use crate::{camera::Sphere, mesh::Mesh, upload::Uploader, Instance};
 
 pub struct Object {
     pub mesh: Arc<Mesh>,
@@ -47,12 +47,13 @@ impl Object {
         }
     }
 
-    pub fn update_instance_buffer(&mut self, queue: &wgpu::Queue) {
+    pub fn update_instance_buffer(&mut self, uploader: &mut Uploader, device: &wgpu::Device) {
         if self.instance_dirty {
-            queue.write_buffer(
+            uploader.write_buffer(
                 &self.instance_buffer,
                 0,
                 bytemuck::bytes_of(&self.instance.to_raw()),
+                device,
             );
             self.instance_dirty = false;
         }
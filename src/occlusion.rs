@@ -0,0 +1,130 @@
+use std::collections::VecDeque;
+
+use cgmath::Point3;
+use wgpu::naga::FastHashSet;
+
+use crate::{
+    direction::Direction,
+    game::{
+        block::BlockRegistry,
+        chunk::{Chunk, ChunkPos},
+    },
+};
+
+/// Which pairs of a chunk's faces can see each other through the chunk, following connected
+/// transparent blocks from one face to the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChunkVisibility(u64);
+
+impl ChunkVisibility {
+    /// Every face sees every other, as for a chunk without opaque blocks
+    pub const ALL: Self = Self((1 << 36) - 1);
+
+    fn bit(a: Direction, b: Direction) -> u64 {
+        1 << (a.index() * 6 + b.index())
+    }
+
+    pub fn connected(self, a: Direction, b: Direction) -> bool {
+        self.0 & Self::bit(a, b) != 0
+    }
+
+    fn connect(&mut self, a: Direction, b: Direction) {
+        self.0 |= Self::bit(a, b) | Self::bit(b, a);
+    }
+
+    /// Flood fill the transparent blocks of a chunk, connecting all faces each region of them
+    /// touches
+    pub fn compute(chunk: &Chunk, block_registry: &BlockRegistry) -> Self {
+        const SIZE: usize = Chunk::SIZE;
+        let index = |p: Point3<usize>| (p.x * SIZE + p.y) * SIZE + p.z;
+
+        let mut transparent = vec![false; SIZE * SIZE * SIZE];
+        for x in 0..SIZE {
+            for y in 0..SIZE {
+                for z in 0..SIZE {
+                    let pos = Point3::new(x, y, z);
+                    let block = chunk.block(pos.into());
+                    transparent[index(pos)] = block_registry.get(block.id).unwrap().transparent;
+                }
+            }
+        }
+        if transparent.iter().all(|&t| t) {
+            return Self::ALL;
+        }
+
+        let mut visibility = Self(0);
+        let mut visited = vec![false; SIZE * SIZE * SIZE];
+        let mut stack = vec![];
+        for start in 0..transparent.len() {
+            if !transparent[start] || visited[start] {
+                continue;
+            }
+            visited[start] = true;
+            stack.push(Point3::new(start / (SIZE * SIZE), start / SIZE % SIZE, start % SIZE));
+
+            let mut faces = FastHashSet::default();
+            while let Some(pos) = stack.pop() {
+                for dir in Direction::ALL {
+                    let neighbour = pos.cast::<isize>().unwrap() + dir.normal();
+                    let Some(neighbour) = neighbour.cast::<usize>().filter(|n| {
+                        n.x < SIZE && n.y < SIZE && n.z < SIZE
+                    }) else {
+                        faces.insert(dir);
+                        continue;
+                    };
+                    let i = index(neighbour);
+                    if transparent[i] && !visited[i] {
+                        visited[i] = true;
+                        stack.push(neighbour);
+                    }
+                }
+            }
+
+            for &a in &faces {
+                for &b in &faces {
+                    visibility.connect(a, b);
+                }
+            }
+        }
+        visibility
+    }
+}
+
+/// The loaded chunks that may be visible from a camera in chunk `start`.
+///
+/// Walks outwards from the camera, only entering a chunk through a face that the face it came in
+/// through can see, and never turning back towards the camera. Chunks hidden behind terrain, or
+/// enclosed in it like most underground chunks, are never reached.
+pub fn visible_chunks(
+    start: ChunkPos,
+    visibility: impl Fn(ChunkPos) -> Option<ChunkVisibility>,
+) -> FastHashSet<ChunkPos> {
+    let mut visible = FastHashSet::default();
+    visible.insert(start);
+
+    // Chunks to leave from, the face they were entered through and every direction taken to
+    // get there
+    let mut queue = VecDeque::new();
+    for dir in Direction::ALL {
+        let next = start + dir.normal();
+        if visibility(next).is_some() && visible.insert(next) {
+            queue.push_back((next, dir.opposite(), 1u8 << dir.index()));
+        }
+    }
+
+    while let Some((pos, entered, taken)) = queue.pop_front() {
+        let Some(chunk) = visibility(pos) else {
+            continue;
+        };
+        for dir in Direction::ALL {
+            if taken & (1 << dir.opposite().index()) != 0 || !chunk.connected(entered, dir) {
+                continue;
+            }
+            let next = pos + dir.normal();
+            if visibility(next).is_some() && visible.insert(next) {
+                queue.push_back((next, dir.opposite(), taken | 1 << dir.index()));
+            }
+        }
+    }
+    visible
+}
@@ -0,0 +1,152 @@
+//! The textures the scene is drawn into on its way to the window.
+//!
+//! With multisampling the scene is drawn into a multisampled texture that is resolved at the end
+//! of the pass. With a render scale other than 1 it ends up in a texture of the scaled size,
+//! which is then stretched over the window by [`RenderTargets::blit`].
+
+use crate::texture::Texture;
+
+/// Lowest and highest render scale, beyond which it is only a waste of memory
+pub const RENDER_SCALES: std::ops::RangeInclusive<f32> = 0.25..=2.0;
+
+pub struct RenderTargets {
+    pub depth: Texture,
+    /// Multisampled color target, `None` without multisampling
+    multisampled: Option<Texture>,
+    /// Color target at the scaled size, `None` when rendering at the window's size
+    scaled: Option<(Texture, wgpu::BindGroup)>,
+}
+
+impl RenderTargets {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        sample_count: u32,
+        render_scale: f32,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        let max = device.limits().max_texture_dimension_2d;
+        let scale = |length: u32| ((length as f32 * render_scale).round() as u32).clamp(1, max);
+        let size = (scale(config.width), scale(config.height));
+
+        let depth = Texture::create_depth_texture(device, size, sample_count, "depth_texture");
+        let multisampled = (sample_count > 1).then(|| {
+            Texture::create_color_target(
+                device,
+                config.format,
+                size,
+                sample_count,
+                "Multisampled Target",
+            )
+        });
+        let scaled = (size != (config.width, config.height)).then(|| {
+            let texture =
+                Texture::create_color_target(device, config.format, size, 1, "Scaled Target");
+            let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                layout: texture_bind_group_layout,
+                entries: &[
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: wgpu::BindingResource::TextureView(&texture.view),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                    },
+                ],
+                label: Some("scaled_target_bind_group"),
+            });
+            (texture, bind_group)
+        });
+
+        Self {
+            depth,
+            multisampled,
+            scaled,
+        }
+    }
+
+    /// The view to draw the scene into and the one to resolve it to, given the window's
+    /// texture `output`
+    pub fn color_attachment<'a>(
+        &'a self,
+        output: &'a wgpu::TextureView,
+    ) -> (&'a wgpu::TextureView, Option<&'a wgpu::TextureView>) {
+        let target = self
+            .scaled
+            .as_ref()
+            .map_or(output, |(texture, _)| &texture.view);
+        match &self.multisampled {
+            Some(multisampled) => (&multisampled.view, Some(target)),
+            None => (target, None),
+        }
+    }
+
+    /// Stretch the scene over `output` if it was drawn at another size
+    pub fn blit(
+        &self,
+        pipeline: &wgpu::RenderPipeline,
+        encoder: &mut wgpu::CommandEncoder,
+        output: &wgpu::TextureView,
+    ) {
+        let Some((_, bind_group)) = &self.scaled else {
+            return;
+        };
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Blit Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(pipeline);
+        render_pass.set_bind_group(0, bind_group, &[]);
+        render_pass.draw(0..3, 0..1);
+    }
+}
+
+/// The pipeline drawing a texture bound like the block textures over the whole target
+pub fn create_blit_pipeline(
+    device: &wgpu::Device,
+    format: wgpu::TextureFormat,
+    texture_bind_group_layout: &wgpu::BindGroupLayout,
+) -> wgpu::RenderPipeline {
+    let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Blit Shader"),
+        source: wgpu::ShaderSource::Wgsl(include_str!("blit.wgsl").into()),
+    });
+    let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+        label: Some("Blit Pipeline Layout"),
+        bind_group_layouts: &[texture_bind_group_layout],
+        push_constant_ranges: &[],
+    });
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some("Blit Pipeline"),
+        layout: Some(&layout),
+        vertex: wgpu::VertexState {
+            module: &shader,
+            entry_point: "vs_main",
+            buffers: &[],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: &shader,
+            entry_point: "fs_main",
+            targets: &[Some(wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrites::ALL,
+            })],
+        }),
+        primitive: wgpu::PrimitiveState::default(),
+        depth_stencil: None,
+        multisample: wgpu::MultisampleState::default(),
+        multiview: None,
+    })
+}
@@ -0,0 +1,95 @@
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use super::{
+    connection::{Respond, Transport},
+    message::{MessageToClient, MessageToServer, PROTOCOL_VERSION},
+    storage::WorldStorage,
+};
+
+/// A player's identity, which stays the same across connections
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    /// The id the player is known by in the world and to other players
+    pub id: Uuid,
+    pub name: String,
+    /// Secret the client stores and sends when connecting, to log in as this account again
+    pub token: Uuid,
+}
+
+/// A connection that has sent its login, waiting for the server to accept or reject it
+pub struct Login {
+    pub transport: Transport,
+    pub name: String,
+    pub token: Option<Uuid>,
+    pub respond: Respond<MessageToClient>,
+}
+
+impl Login {
+    /// Wait for a new connection to tell its protocol version and then log in, turning it away
+    /// if the version doesn't match
+    pub async fn receive(mut transport: Transport) -> anyhow::Result<Self> {
+        let (msg, mut respond) = transport
+            .accept_transact::<MessageToServer, MessageToClient>()
+            .await?;
+        let MessageToServer::Version(version) = msg else {
+            anyhow::bail!("expected a protocol version, got {}", msg.name());
+        };
+        if version != PROTOCOL_VERSION {
+            respond
+                .respond(&MessageToClient::IncompatibleVersion {
+                    server: PROTOCOL_VERSION,
+                })
+                .await?;
+            // Closing the connection right away could drop the response
+            respond.finish().await?;
+            anyhow::bail!("client speaks protocol version {version}, not {PROTOCOL_VERSION}");
+        }
+        respond.respond(&MessageToClient::Ok).await?;
+
+        let (msg, respond) = transport
+            .accept_transact::<MessageToServer, MessageToClient>()
+            .await?;
+        let MessageToServer::Connect { name, token } = msg else {
+            anyhow::bail!("expected a login, got {}", msg.name());
+        };
+        Ok(Self {
+            transport,
+            name,
+            token,
+            respond,
+        })
+    }
+}
+
+/// Find the account a player logs in to, creating one if they have none yet.
+///
+/// Players with a token log in to the account it belongs to. Players without one get a new
+/// account, unless their name is already taken, so nobody can pose as someone else by name.
+/// Returns the reason the login is rejected on failure.
+pub fn authenticate(
+    storage: &mut dyn WorldStorage,
+    name: &str,
+    token: Option<Uuid>,
+) -> anyhow::Result<Result<Account, String>> {
+    if let Some(token) = token {
+        return Ok(storage
+            .load_account(token)?
+            .ok_or_else(|| "Unknown identity token".to_string()));
+    }
+
+    let name = name.trim();
+    if name.is_empty() {
+        return Ok(Err("Empty player name".to_string()));
+    }
+    if storage.account_by_name(name)?.is_some() {
+        return Ok(Err(format!("The name {name} is taken")));
+    }
+    let account = Account {
+        id: Uuid::new_v4(),
+        name: name.to_string(),
+        token: Uuid::new_v4(),
+    };
+    storage.save_account(&account)?;
+    Ok(Ok(account))
+}
@@ -0,0 +1,72 @@
+use std::collections::BTreeMap;
+
+use bevy_ecs::system::{Res, ResMut, Resource};
+use wgpu::naga::FastHashSet;
+
+use crate::game::{block::Block, chunk::BlockPos, fluid, signal, world::World};
+
+use super::simulation::SimulationArea;
+
+/// Blocks waiting to be updated a number of ticks from now, e.g. to let a signal travel one
+/// block per tick or water flow one block every few
+#[derive(Debug, Default, Resource)]
+pub struct BlockUpdates {
+    tick: u64,
+    /// Positions to update, by the tick they are due
+    scheduled: BTreeMap<u64, FastHashSet<BlockPos>>,
+    /// Blocks changed by updates since this was last drained, to be sent to players
+    pub changes: Vec<(BlockPos, Block)>,
+}
+
+impl BlockUpdates {
+    /// Update the block at `pos` in `delay` ticks. A block is only updated once per tick, no
+    /// matter how many times it was scheduled for it.
+    pub fn schedule(&mut self, pos: BlockPos, delay: u64) {
+        self.scheduled
+            .entry(self.tick + delay.max(1))
+            .or_default()
+            .insert(pos);
+    }
+
+    /// Update `pos` and the blocks around it, after a change at `pos`
+    pub fn schedule_around(&mut self, pos: BlockPos, delay: u64) {
+        self.schedule(pos, delay);
+        for neighbour in signal::neighbours(pos) {
+            self.schedule(neighbour, delay);
+        }
+    }
+
+    fn take_due(&mut self) -> Vec<BlockPos> {
+        let later = self.scheduled.split_off(&(self.tick + 1));
+        let due = std::mem::replace(&mut self.scheduled, later);
+        due.into_values().flatten().collect()
+    }
+}
+
+/// Run the block updates due this tick inside the simulation area. Updates in loaded chunks
+/// outside of it wait until the area covers them again.
+pub fn run_block_updates(
+    mut updates: ResMut<BlockUpdates>,
+    mut world: ResMut<World>,
+    area: Res<SimulationArea>,
+) {
+    for pos in updates.take_due() {
+        let chunk_pos = pos.chunk_pos();
+        if !area.contains(chunk_pos) {
+            if world.chunk(chunk_pos).is_some() {
+                updates.schedule(pos, 1);
+            }
+            continue;
+        }
+
+        let update = signal::update(&world, pos)
+            .map(|block| (block, 1))
+            .or_else(|| fluid::update(&world, pos).map(|block| (block, fluid::FLOW_DELAY)));
+        if let Some((block, delay)) = update {
+            *world.chunk_mut(chunk_pos).unwrap().block_mut(pos.rel_pos()) = block;
+            updates.changes.push((pos, block));
+            updates.schedule_around(pos, delay);
+        }
+    }
+    updates.tick += 1;
+}
@@ -0,0 +1,294 @@
+//! Commands typed into the server console or sent by players as chat messages starting with `/`.
+//!
+//! Both go through the same parser and are run by the server the same way, only who may run
+//! what differs, see [`Permission`]. Commands acting on a player act on the one who sent them
+//! when no player is named, which only works from the chat.
+
+use std::str::FromStr;
+
+use cgmath::{Point3, Vector3};
+use uuid::Uuid;
+
+use crate::game::{
+    chunk::{BlockPos, ChunkPos},
+    dimension::DimensionId,
+};
+
+/// Ticks in a day, the time of day is the world time modulo this
+pub const DAY_LENGTH: u64 = 24000;
+
+/// Who a command came from, and where its output goes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandSource {
+    /// Printed to stdout
+    Console,
+    /// Sent back as chat messages
+    Player(Uuid),
+}
+
+/// What a command source may do, each level allowing everything the ones below it do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// Anyone connected, for commands that only look things up or affect the player themselves
+    Player,
+    /// Players listed as operators, for commands that change the world or other players
+    Operator,
+    /// Only the server console
+    Console,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    /// Write every loaded chunk to storage
+    SaveAll,
+    /// Print the connected players
+    List,
+    /// Save and shut down the server
+    Stop,
+    /// Print the world generation seed of a dimension, the loaded one by default
+    Seed { dimension: Option<DimensionId> },
+    /// Print every dimension of the save
+    Dimensions,
+    /// Teleport a player, identified by their name or a prefix of their id
+    Tp {
+        player: Option<String>,
+        position: Vector3<f32>,
+    },
+    /// Give a player a number of items
+    Give {
+        player: Option<String>,
+        item: String,
+        count: u32,
+    },
+    /// Print the time of day, or set it
+    Time { set: Option<u64> },
+    /// Send a player back to their spawn point
+    Spawn { player: Option<String> },
+    /// Set the spawn point of a player, or the world spawn without a player
+    SetSpawn {
+        player: Option<String>,
+        position: Vector3<f32>,
+    },
+    /// Print the reference count of every loaded chunk and the chunks each player has loaded,
+    /// only those within `radius` chunks of the player if given
+    Chunks { radius: Option<isize> },
+    /// Turn checking the loaded chunk reference counts against the players' chunks every tick
+    /// on or off
+    CheckChunks { enabled: bool },
+    /// Keep the chunks in the box between two corners loaded, given in chunk coordinates, or
+    /// print the pinned chunks without a region
+    Pin {
+        region: Option<(ChunkPos, ChunkPos)>,
+    },
+    /// Stop keeping the chunks in the box between two corners loaded
+    Unpin { region: (ChunkPos, ChunkPos) },
+    /// Set every block in the box between two corners to a block
+    Fill {
+        min: BlockPos,
+        max: BlockPos,
+        block: String,
+    },
+}
+
+impl Command {
+    pub const USAGE: &'static str =
+        "Commands: save-all, list, stop, seed [dimension], dimensions, tp [player] <x> <y> <z>, \
+        give [player] <item> [count], time [set <day|noon|night|midnight|ticks>], \
+        spawn [player], setspawn [player] <x> <y> <z>, chunks [radius], check-chunks <on|off>, \
+        pin [<x> <y> <z> [<x> <y> <z>]], unpin <x> <y> <z> [<x> <y> <z>], \
+        fill <x> <y> <z> <x> <y> <z> <block>";
+
+    /// What it takes to run this command
+    pub fn permission(&self) -> Permission {
+        match self {
+            Command::List
+            | Command::Seed { .. }
+            | Command::Time { set: None }
+            | Command::Spawn { player: None }
+            | Command::Pin { region: None } => Permission::Player,
+            Command::Stop => Permission::Console,
+            _ => Permission::Operator,
+        }
+    }
+}
+
+/// Parse the three coordinates at the end of `args`, and the player before them if there is one
+fn player_and_position(
+    args: &[&str],
+    usage: &str,
+) -> Result<(Option<String>, Vector3<f32>), String> {
+    let (player, coords) = match args {
+        [player, coords @ ..] if coords.len() == 3 => (Some(player.to_string()), coords),
+        coords if coords.len() == 3 => (None, coords),
+        _ => return Err(format!("Usage: {usage}")),
+    };
+    let coord = |s: &str| -> Result<f32, String> {
+        s.parse().map_err(|e| format!("Invalid coordinate: {e}"))
+    };
+    let position = Vector3::new(coord(coords[0])?, coord(coords[1])?, coord(coords[2])?);
+    Ok((player, position))
+}
+
+/// Parse the region of chunks between two corners, a single chunk if only one is given
+fn chunk_region(args: &[&str], usage: &str) -> Result<(ChunkPos, ChunkPos), String> {
+    let coord = |s: &str| -> Result<isize, String> {
+        s.parse()
+            .map_err(|e| format!("Invalid chunk coordinate: {e}"))
+    };
+    let corner = |coords: &[&str]| -> Result<ChunkPos, String> {
+        Ok(ChunkPos::from(Point3::new(
+            coord(coords[0])?,
+            coord(coords[1])?,
+            coord(coords[2])?,
+        )))
+    };
+    match args.len() {
+        3 => Ok((corner(args)?, corner(args)?)),
+        6 => Ok((corner(&args[..3])?, corner(&args[3..])?)),
+        _ => Err(format!("Usage: {usage}")),
+    }
+}
+
+/// The namespaced name of a block, which is in the `voxels` namespace if it has none
+fn block_name(name: &str) -> String {
+    if name.contains(':') {
+        name.to_string()
+    } else {
+        format!("voxels:{name}")
+    }
+}
+
+/// Parse a time of day, by name or in ticks
+fn time_of_day(s: &str) -> Result<u64, String> {
+    Ok(match s {
+        "day" => 1000,
+        "noon" => 6000,
+        "night" => 13000,
+        "midnight" => 18000,
+        ticks => {
+            let ticks = ticks
+                .parse::<u64>()
+                .map_err(|e| format!("Invalid time: {e}"))?;
+            ticks % DAY_LENGTH
+        }
+    })
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    /// Parse a command, with or without the leading `/` used in the chat
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('/').unwrap_or(s);
+        let mut words = s.split_whitespace();
+        let Some(command) = words.next() else {
+            return Err(Command::USAGE.to_string());
+        };
+        let args = words.collect::<Vec<_>>();
+        let no_args = |command: Command| {
+            if args.is_empty() {
+                Ok(command)
+            } else {
+                Err(format!("Too many arguments. {}", Command::USAGE))
+            }
+        };
+
+        match command {
+            "save-all" => no_args(Command::SaveAll),
+            "list" => no_args(Command::List),
+            "stop" => no_args(Command::Stop),
+            "dimensions" => no_args(Command::Dimensions),
+            "seed" => match args.as_slice() {
+                [] => Ok(Command::Seed { dimension: None }),
+                [dimension] => Ok(Command::Seed {
+                    dimension: Some(dimension.parse()?),
+                }),
+                _ => Err("Usage: seed [dimension]".to_string()),
+            },
+            "tp" => {
+                let (player, position) = player_and_position(&args, "tp [player] <x> <y> <z>")?;
+                Ok(Command::Tp { player, position })
+            }
+            "give" => {
+                let usage = || "Usage: give [player] <item> [count]".to_string();
+                // The count is the only argument that is a number
+                let (rest, count) = match args.split_last() {
+                    Some((count, rest)) if count.parse::<u32>().is_ok() => {
+                        (rest, count.parse().unwrap())
+                    }
+                    _ => (args.as_slice(), 1),
+                };
+                let (player, item) = match rest {
+                    [item] => (None, item),
+                    [player, item] => (Some(player.to_string()), item),
+                    _ => return Err(usage()),
+                };
+                // Items are blocks for now, which all live in the `voxels` namespace
+                let item = block_name(item);
+                Ok(Command::Give {
+                    player,
+                    item,
+                    count,
+                })
+            }
+            "time" => match args.as_slice() {
+                [] => Ok(Command::Time { set: None }),
+                ["set", time] => Ok(Command::Time {
+                    set: Some(time_of_day(time)?),
+                }),
+                _ => Err("Usage: time [set <day|noon|night|midnight|ticks>]".to_string()),
+            },
+            "spawn" => match args.as_slice() {
+                [] => Ok(Command::Spawn { player: None }),
+                [player] => Ok(Command::Spawn {
+                    player: Some(player.to_string()),
+                }),
+                _ => Err("Usage: spawn [player]".to_string()),
+            },
+            "setspawn" => {
+                let (player, position) =
+                    player_and_position(&args, "setspawn [player] <x> <y> <z>")?;
+                Ok(Command::SetSpawn { player, position })
+            }
+            "chunks" => match args.as_slice() {
+                [] => Ok(Command::Chunks { radius: None }),
+                [radius] => Ok(Command::Chunks {
+                    radius: Some(radius.parse().map_err(|e| format!("Invalid radius: {e}"))?),
+                }),
+                _ => Err("Usage: chunks [radius]".to_string()),
+            },
+            "check-chunks" => match args.as_slice() {
+                ["on"] => Ok(Command::CheckChunks { enabled: true }),
+                ["off"] => Ok(Command::CheckChunks { enabled: false }),
+                _ => Err("Usage: check-chunks <on|off>".to_string()),
+            },
+            "pin" if args.is_empty() => Ok(Command::Pin { region: None }),
+            "pin" => Ok(Command::Pin {
+                region: Some(chunk_region(&args, "pin [<x> <y> <z> [<x> <y> <z>]]")?),
+            }),
+            "unpin" => Ok(Command::Unpin {
+                region: chunk_region(&args, "unpin <x> <y> <z> [<x> <y> <z>]")?,
+            }),
+            "fill" => {
+                let usage = "Usage: fill <x> <y> <z> <x> <y> <z> <block>";
+                let [coords @ .., block] = args.as_slice() else {
+                    return Err(usage.to_string());
+                };
+                let [x1, y1, z1, x2, y2, z2] = coords
+                    .iter()
+                    .map(|s| s.parse::<isize>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|e| format!("Invalid coordinate: {e}"))?[..]
+                else {
+                    return Err(usage.to_string());
+                };
+                Ok(Command::Fill {
+                    min: BlockPos::from(Point3::new(x1, y1, z1)),
+                    max: BlockPos::from(Point3::new(x2, y2, z2)),
+                    block: block_name(block),
+                })
+            }
+            _ => Err(format!("Unknown command `{command}`. {}", Command::USAGE)),
+        }
+    }
+}
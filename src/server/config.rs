@@ -0,0 +1,106 @@
+use std::{
+    io::BufReader,
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use rustls_pemfile::Item;
+use serde::{Deserialize, Serialize};
+
+use super::connection::KeepAlive;
+
+/// Where and how the server listens, read from a config file so a dedicated server can be set up
+/// once instead of on every start. Command line flags override what the file says.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub address: IpAddr,
+    pub port: u16,
+    /// PEM file with the certificate chain to present to clients. Without one, the server makes
+    /// up a self-signed certificate every time it starts.
+    pub certificate: Option<PathBuf>,
+    /// PEM file with the private key of `certificate`
+    pub private_key: Option<PathBuf>,
+    /// Ticks per second
+    pub tick_rate: u32,
+    pub keep_alive: KeepAlive,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            address: Ipv6Addr::UNSPECIFIED.into(),
+            port: 1234,
+            certificate: None,
+            private_key: None,
+            tick_rate: 20,
+            keep_alive: KeepAlive::default(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    pub const PATH: &'static str = "./server.toml";
+
+    /// Load the config from `path`, falling back to the defaults if the file is missing or
+    /// invalid
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                log::warn!("Could not read {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        match toml::from_str(&contents) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!("Invalid server config in {}, using defaults: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn bind_address(&self) -> SocketAddr {
+        (self.address, self.port).into()
+    }
+
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs_f32(1.0 / self.tick_rate.max(1) as f32)
+    }
+
+    /// The configured certificate chain and its key, or `None` if the server should sign its own
+    pub fn load_certificate(
+        &self,
+    ) -> anyhow::Result<Option<(Vec<rustls::Certificate>, rustls::PrivateKey)>> {
+        let (certificate, private_key) = match (&self.certificate, &self.private_key) {
+            (Some(certificate), Some(private_key)) => (certificate, private_key),
+            (None, None) => return Ok(None),
+            _ => anyhow::bail!("a certificate needs both the certificate and private key file"),
+        };
+
+        let open = |path: &Path| -> anyhow::Result<_> {
+            std::fs::File::open(path)
+                .map(BufReader::new)
+                .map_err(|e| anyhow::anyhow!("could not open {}: {e}", path.display()))
+        };
+        let chain = rustls_pemfile::certs(&mut open(certificate)?)?
+            .into_iter()
+            .map(rustls::Certificate)
+            .collect::<Vec<_>>();
+        if chain.is_empty() {
+            anyhow::bail!("no certificates in {}", certificate.display());
+        }
+        let key = rustls_pemfile::read_all(&mut open(private_key)?)?
+            .into_iter()
+            .find_map(|item| match item {
+                Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(key),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("no private key in {}", private_key.display()))?;
+        Ok(Some((chain, rustls::PrivateKey(key))))
+    }
+}
@@ -1,10 +1,10 @@
-use std::{fmt::Debug, marker::PhantomData, sync::Arc};
+use std::{fmt::Debug, marker::PhantomData, sync::Arc, time::Duration};
 
 use async_std::prelude::FutureExt;
 use bevy_ecs::system::Resource;
 use cgmath::num_traits::ToBytes;
 use futures::{Future, Stream};
-use quinn::{Endpoint, ReadExactError, RecvStream, SendStream};
+use quinn::{Endpoint, ReadExactError, RecvStream, SendStream, VarInt};
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
@@ -33,6 +33,289 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
+/// Trusts only the one certificate with the given fingerprint, whoever signed it
+#[derive(Debug)]
+struct PinnedCertificate {
+    fingerprint: String,
+}
+
+impl rustls::client::ServerCertVerifier for PinnedCertificate {
+    fn verify_server_cert(
+        &self,
+        end_entity: &rustls::Certificate,
+        _intermediates: &[rustls::Certificate],
+        _server_name: &rustls::ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: std::time::SystemTime,
+    ) -> Result<rustls::client::ServerCertVerified, rustls::Error> {
+        if fingerprint(end_entity) == self.fingerprint {
+            Ok(rustls::client::ServerCertVerified::assertion())
+        } else {
+            Err(rustls::Error::General(
+                "the certificate doesn't match the pinned fingerprint".to_string(),
+            ))
+        }
+    }
+}
+
+/// The SHA-256 hash of a certificate, as colon separated hex bytes
+pub fn fingerprint(certificate: &rustls::Certificate) -> String {
+    ring::digest::digest(&ring::digest::SHA256, &certificate.0)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// How the client decides whether to trust the certificate a server presents
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ServerTrust {
+    /// Accept any certificate, which is enough for a server on the same machine, like the
+    /// integrated one. Anyone between the client and a remote server could listen in.
+    #[default]
+    Insecure,
+    /// Accept certificates issued for the server name by an authority the system trusts
+    System,
+    /// Accept only the certificate with this fingerprint, as printed by the server when it
+    /// starts. Colons and case don't matter.
+    Pinned { fingerprint: String },
+}
+
+impl ServerTrust {
+    pub fn client_config(&self) -> anyhow::Result<rustls::ClientConfig> {
+        let builder = rustls::ClientConfig::builder().with_safe_defaults();
+        let config = match self {
+            ServerTrust::Insecure => builder
+                .with_custom_certificate_verifier(SkipServerVerification::new())
+                .with_no_client_auth(),
+            ServerTrust::System => {
+                let mut roots = rustls::RootCertStore::empty();
+                for certificate in rustls_native_certs::load_native_certs()? {
+                    if let Err(e) = roots.add(&rustls::Certificate(certificate.0)) {
+                        log::warn!("Skipping invalid system certificate: {e}");
+                    }
+                }
+                if roots.is_empty() {
+                    anyhow::bail!("no trusted certificates found on this system");
+                }
+                builder
+                    .with_root_certificates(roots)
+                    .with_no_client_auth()
+            }
+            ServerTrust::Pinned { fingerprint } => {
+                let fingerprint = fingerprint
+                    .chars()
+                    .filter(|c| c.is_ascii_hexdigit())
+                    .map(|c| c.to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+                    .chunks(2)
+                    .map(|byte| byte.iter().collect::<String>())
+                    .collect::<Vec<_>>()
+                    .join(":");
+                builder
+                    .with_custom_certificate_verifier(Arc::new(PinnedCertificate { fingerprint }))
+                    .with_no_client_auth()
+            }
+        };
+        Ok(config)
+    }
+}
+
+/// How long a peer gets to take a message, or to send one it has opened a stream for, before the
+/// stream is given up on. Without this, a peer that stops reading keeps streams open forever,
+/// until none are left.
+pub const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How a connection is kept alive while there is nothing to send, set up the same way on
+/// clients and servers
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KeepAlive {
+    /// Seconds between keep-alives sent while the connection is otherwise quiet, 0 to send none
+    pub interval: f32,
+    /// Seconds a connection can go without hearing anything from the peer before it is closed,
+    /// so players whose client crashed are cleaned up. The shorter of the two peers' timeouts
+    /// applies.
+    pub idle_timeout: f32,
+}
+
+impl Default for KeepAlive {
+    fn default() -> Self {
+        Self {
+            interval: 5.0,
+            idle_timeout: 15.0,
+        }
+    }
+}
+
+impl KeepAlive {
+    pub fn transport_config(&self) -> quinn::TransportConfig {
+        let idle_timeout = Duration::from_secs_f32(self.idle_timeout.max(1.0));
+        let mut interval = Duration::from_secs_f32(self.interval.max(0.0));
+        // Keep-alives only keep anything alive if they are sent before the timeout
+        if interval >= idle_timeout {
+            log::warn!(
+                "Keep-alive interval of {interval:?} is not below the idle timeout of \
+                 {idle_timeout:?}, sending them twice as often as the timeout instead"
+            );
+            interval = idle_timeout / 2;
+        }
+
+        let mut config = quinn::TransportConfig::default();
+        config.keep_alive_interval((!interval.is_zero()).then_some(interval));
+        config.max_idle_timeout(Some(idle_timeout.try_into().unwrap()));
+        config
+    }
+}
+
+/// Why a connection ended, for telling the player or logging it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Disconnect {
+    /// Nothing was heard from the peer for longer than the idle timeout
+    TimedOut,
+    /// The peer closed the connection, saying why
+    Closed(String),
+    /// This side closed the connection
+    ClosedLocally,
+    /// Anything else, like the peer breaking the protocol
+    Failed(String),
+}
+
+impl From<&quinn::ConnectionError> for Disconnect {
+    fn from(e: &quinn::ConnectionError) -> Self {
+        match e {
+            quinn::ConnectionError::TimedOut => Disconnect::TimedOut,
+            quinn::ConnectionError::ApplicationClosed(close) => {
+                Disconnect::Closed(String::from_utf8_lossy(&close.reason).into_owned())
+            }
+            quinn::ConnectionError::LocallyClosed => Disconnect::ClosedLocally,
+            e => Disconnect::Failed(e.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for Disconnect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Disconnect::TimedOut => write!(f, "timed out"),
+            Disconnect::Closed(reason) => write!(f, "closed by the peer: {reason}"),
+            Disconnect::ClosedLocally => write!(f, "closed"),
+            Disconnect::Failed(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// Error code for streams given up on after `TRANSACTION_TIMEOUT`
+const STREAM_TIMED_OUT: VarInt = VarInt::from_u32(1);
+/// Error code for connections closed on purpose, the reason given with it tells why
+const CONNECTION_CLOSED: VarInt = VarInt::from_u32(1);
+
+/// Largest message the client reads. Responses with all the chunks around a player are the
+/// largest messages, at a few megabytes.
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// A message that can be read off a stream
+pub trait Message: for<'de> Deserialize<'de> {
+    /// Largest message that is read, larger ones are treated as garbage
+    const MAX_SIZE: usize;
+}
+
+impl Message for MessageToClient {
+    const MAX_SIZE: usize = MAX_MESSAGE_SIZE;
+}
+
+impl Message for MessageToServer {
+    /// Clients only send small messages, see [`MessageToServer::MAX_CHUNKS`]
+    const MAX_SIZE: usize = 16 * 1024;
+}
+
+/// Most messages waiting to be sent on a connection. A peer that falls this far behind isn't
+/// keeping up, and its connection is closed rather than letting the queue grow.
+const OUTGOING_QUEUE_SIZE: usize = 1024;
+
+/// Everything that can go wrong talking to a peer
+#[derive(Debug)]
+pub enum NetworkError {
+    /// The connection is gone, or no stream could be opened on it
+    Connection(quinn::ConnectionError),
+    Write(quinn::WriteError),
+    Read(ReadExactError),
+    /// The peer didn't do its part in time
+    TimedOut(&'static str),
+    /// A message could not be serialized
+    Encode(postcard::Error),
+    /// The peer sent something that isn't a valid message
+    Decode(postcard::Error),
+    /// The peer announced a message larger than the limit for its type
+    TooLarge {
+        len: usize,
+        limit: usize,
+    },
+}
+
+impl NetworkError {
+    /// Whether the peer broke the protocol, rather than the connection failing. Such a peer
+    /// can't be trusted to send anything sensible anymore.
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(
+            self,
+            NetworkError::Decode(_) | NetworkError::TooLarge { .. }
+        )
+    }
+}
+
+impl std::fmt::Display for NetworkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            NetworkError::Connection(e) => write!(f, "connection failed: {e}"),
+            NetworkError::Write(e) => write!(f, "could not send: {e}"),
+            NetworkError::Read(e) => write!(f, "could not receive: {e}"),
+            NetworkError::TimedOut(what) => {
+                write!(f, "timed out {what} after {TRANSACTION_TIMEOUT:?}")
+            }
+            NetworkError::Encode(e) => write!(f, "could not serialize message: {e}"),
+            NetworkError::Decode(e) => write!(f, "received invalid message: {e}"),
+            NetworkError::TooLarge { len, limit } => write!(
+                f,
+                "received message of {len} bytes, more than the limit of {limit}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NetworkError {}
+
+impl From<quinn::ConnectionError> for NetworkError {
+    fn from(e: quinn::ConnectionError) -> Self {
+        NetworkError::Connection(e)
+    }
+}
+
+impl From<quinn::WriteError> for NetworkError {
+    fn from(e: quinn::WriteError) -> Self {
+        NetworkError::Write(e)
+    }
+}
+
+impl From<ReadExactError> for NetworkError {
+    fn from(e: ReadExactError) -> Self {
+        NetworkError::Read(e)
+    }
+}
+
+async fn with_timeout<T>(
+    what: &'static str,
+    future: impl Future<Output = Result<T, NetworkError>>,
+) -> Result<T, NetworkError> {
+    match future.timeout(TRANSACTION_TIMEOUT).await {
+        Ok(result) => result,
+        Err(_) => Err(NetworkError::TimedOut(what)),
+    }
+}
+
 pub struct Connection {
     pub player_id: Uuid,
     pub transport: Transport,
@@ -50,27 +333,46 @@ pub enum Transport {
 // pub type Responder<R> = impl Future<Output = Result<(), anyhow::Error>>;
 // pub type ResponderFunc<S: for <'de> Deserialize<'de> + Debug, R: Serialize + 'static> = impl FnOnce(R) -> Responder<R>;
 
-pub async fn write<T: Serialize>(tx: &mut SendStream, msg: T) -> anyhow::Result<()> {
+pub async fn write<T: Serialize>(tx: &mut SendStream, msg: T) -> Result<(), NetworkError> {
     // let bytes = serde_json::to_string_pretty(&msg)?;
-    let bytes = postcard::to_allocvec(&msg).unwrap();
+    let bytes = postcard::to_allocvec(&msg).map_err(NetworkError::Encode)?;
+    write_bytes(tx, &bytes).await
+}
+
+/// Write a message that is already encoded
+async fn write_bytes(tx: &mut SendStream, bytes: &[u8]) -> Result<(), NetworkError> {
     tx.write_all(&(bytes.len() as u32).to_be_bytes()).await?;
-    tx.write_all(&bytes).await?;
+    tx.write_all(bytes).await?;
     Ok(())
 }
 
-pub async fn read<T: for <'de> Deserialize<'de>>(rx: &mut RecvStream) -> anyhow::Result<T> {
+pub async fn read<T: Message>(rx: &mut RecvStream) -> Result<T, NetworkError> {
     // println!("Reading length");
     let mut len = [0; 4];
     rx.read_exact(&mut len).await?;
     let len = u32::from_be_bytes(len) as usize;
+    if len > T::MAX_SIZE {
+        return Err(NetworkError::TooLarge {
+            len,
+            limit: T::MAX_SIZE,
+        });
+    }
     // println!("Length was {len}");
-    let mut buffer = vec![0; len];
-    // println!("Reading data");
-    rx.read_exact(&mut buffer).await?;
+    // Grown as the data arrives, so a peer only claiming a large message can't make us
+    // allocate it
+    let mut buffer = Vec::new();
+    while buffer.len() < len {
+        let chunk = rx
+            .read_chunk(len - buffer.len(), true)
+            .await
+            .map_err(ReadExactError::ReadError)?
+            .ok_or(ReadExactError::FinishedEarly)?;
+        buffer.extend_from_slice(&chunk.bytes);
+    }
     // println!("Data read");
     // println!("{}", std::str::from_utf8(&buffer).unwrap());
     // Ok(serde_json::from_slice(&buffer)?)
-    Ok(postcard::from_bytes(&buffer)?)
+    postcard::from_bytes(&buffer).map_err(NetworkError::Decode)
 }
 
 pub struct Transaction<R> {
@@ -78,30 +380,46 @@ pub struct Transaction<R> {
     _r: PhantomData<R>,
 }
 
-impl<R: for<'de> Deserialize<'de>> Transaction<R> {
-    pub async fn single(&mut self) -> anyhow::Result<R> {
-        read(&mut self.rx).await
+impl<R: Message> Transaction<R> {
+    pub async fn single(&mut self) -> Result<R, NetworkError> {
+        Self::receive(&mut self.rx).await
     }
 
-    pub fn stream(&mut self) -> impl Stream<Item = anyhow::Result<R>> + '_ {
+    pub fn stream(&mut self) -> impl Stream<Item = Result<R, NetworkError>> + '_ {
         futures::stream::unfold(&mut self.rx, |rx| async {
-            let msg = read(rx).await;
+            let msg = Self::receive(rx).await;
             Some((msg, rx))
         })
     }
+
+    /// Read the next response, stopping the stream if it doesn't come in time
+    async fn receive(rx: &mut RecvStream) -> Result<R, NetworkError> {
+        let msg = with_timeout("waiting for a response", read(rx)).await;
+        if msg.is_err() {
+            let _ = rx.stop(STREAM_TIMED_OUT);
+        }
+        msg
+    }
 }
 
 impl Transport {
-    pub async fn transact<S: Serialize + 'static, R: for<'de> Deserialize<'de>>(
+    pub async fn transact<S: Serialize + 'static, R: Message>(
         &self,
         msg: &S,
-    ) -> anyhow::Result<Transaction<R>> {
+    ) -> Result<Transaction<R>, NetworkError> {
         match self {
             Transport::Remote(remote) => {
                 let remote = remote.clone();
-                let (mut tx, rx) = remote.connection.open_bi().await?;
+                // Waits for the peer to allow more streams if all are in use
+                let (mut tx, rx) = with_timeout("opening a stream", async {
+                    Ok(remote.connection.open_bi().await?)
+                })
+                .await?;
 
-                write(&mut tx, msg).await?;
+                if let Err(e) = with_timeout("sending a message", write(&mut tx, msg)).await {
+                    let _ = tx.reset(STREAM_TIMED_OUT);
+                    return Err(e);
+                }
                 // tx.finish().await?;
 
                 Ok(Transaction {
@@ -112,21 +430,75 @@ impl Transport {
         }
     }
 
-    pub async fn accept_transact<S: for<'de> Deserialize<'de> + Debug, R: Serialize + 'static>(
+    pub async fn accept_transact<S: Message + Debug, R: Serialize + 'static>(
         &mut self,
-    ) -> anyhow::Result<(S, Respond<R>)> {
+    ) -> Result<(S, Respond<R>), NetworkError> {
         match self {
-            Transport::Remote(remote) => {
-                let (tx, mut rx) = remote.connection.accept_bi().await?;
+            Transport::Remote(remote) => loop {
+                let (mut tx, mut rx) = remote.connection.accept_bi().await?;
                 println!("Server accepted stream");
-                let msg = read(&mut rx).await?;
-                
+                // A stream that never gets its message is dropped, but the connection is fine.
+                // A peer sending garbage is another matter.
+                let msg = match with_timeout("waiting for a message", read(&mut rx)).await {
+                    Ok(msg) => msg,
+                    Err(e) if e.is_protocol_violation() => return Err(e),
+                    Err(e) => {
+                        log::warn!("Dropping incoming stream: {e}");
+                        let _ = rx.stop(STREAM_TIMED_OUT);
+                        let _ = tx.reset(STREAM_TIMED_OUT);
+                        continue;
+                    }
+                };
+
                 println!("Server received message");
 
-                Ok((msg, Respond { tx, _r: PhantomData }))
+                return Ok((msg, Respond { tx, _r: PhantomData }));
+            },
+        }
+    }
+
+    /// Queue a message that gets no response, without waiting for the peer. If the peer doesn't
+    /// take it in time or falls too far behind, the connection is closed, which ends the peer's
+    /// session the same way as a disconnect.
+    pub fn send<S: Serialize + 'static>(&self, msg: &S) {
+        match self {
+            Transport::Remote(remote) => {
+                let bytes = match postcard::to_allocvec(msg) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        log::warn!("Could not encode a message: {e}");
+                        return;
+                    }
+                };
+                if remote.outgoing.try_send(bytes).is_err() && self.disconnect().is_none() {
+                    log::warn!("Closing connection after {OUTGOING_QUEUE_SIZE} unsent messages");
+                    self.close("stopped responding");
+                }
             }
         }
     }
+
+    /// Why the connection ended, `None` while it is still open
+    pub fn disconnect(&self) -> Option<Disconnect> {
+        match self {
+            Transport::Remote(remote) => remote.connection.close_reason().as_ref().map(Into::into),
+        }
+    }
+
+    /// The current estimate of the time a message takes to get to the peer and back
+    pub fn rtt(&self) -> Duration {
+        match self {
+            Transport::Remote(remote) => remote.connection.rtt(),
+        }
+    }
+
+    pub fn close(&self, reason: &str) {
+        match self {
+            Transport::Remote(remote) => remote
+                .connection
+                .close(CONNECTION_CLOSED, reason.as_bytes()),
+        }
+    }
 }
 
 pub struct Respond<R> {
@@ -135,9 +507,18 @@ pub struct Respond<R> {
 }
 
 impl<R: Serialize> Respond<R> {
-    pub async fn respond(&mut self, msg: &R) -> anyhow::Result<()> {
-        write(&mut self.tx, msg).await?;
-        Ok(())
+    /// Send a response, resetting the stream if the peer doesn't take it in time
+    pub async fn respond(&mut self, msg: &R) -> Result<(), NetworkError> {
+        let result = with_timeout("sending a response", write(&mut self.tx, msg)).await;
+        if result.is_err() {
+            let _ = self.tx.reset(STREAM_TIMED_OUT);
+        }
+        result
+    }
+
+    /// Wait until the peer has received everything sent on the stream
+    pub async fn finish(&mut self) -> Result<(), NetworkError> {
+        with_timeout("finishing a response", async { Ok(self.tx.finish().await?) }).await
     }
 }
 
@@ -149,4 +530,44 @@ pub struct LocalTransport {
 #[derive(Clone)]
 pub struct RemoteTransport {
     pub connection: quinn::Connection,
+    /// Messages queued by [`Transport::send`], sent in order by a task of their own
+    outgoing: async_std::channel::Sender<Vec<u8>>,
+}
+
+impl RemoteTransport {
+    pub fn new(connection: quinn::Connection) -> Self {
+        let (outgoing, queue) = async_std::channel::bounded(OUTGOING_QUEUE_SIZE);
+        async_std::task::spawn(Self::send_queued(connection.clone(), queue));
+        Self {
+            connection,
+            outgoing,
+        }
+    }
+
+    /// Send every queued message on a stream of its own, until the connection is closed or
+    /// every transport for it is dropped
+    async fn send_queued(
+        connection: quinn::Connection,
+        queue: async_std::channel::Receiver<Vec<u8>>,
+    ) {
+        while let Ok(bytes) = queue.recv().await {
+            // Waits for the peer to allow more streams if all are in use
+            let result = with_timeout("sending a message", async {
+                let (mut tx, _rx) = connection.open_bi().await?;
+                let result = write_bytes(&mut tx, &bytes).await;
+                if result.is_err() {
+                    let _ = tx.reset(STREAM_TIMED_OUT);
+                }
+                result
+            })
+            .await;
+            if let Err(e) = result {
+                if connection.close_reason().is_none() {
+                    log::warn!("Closing connection after failing to send a message: {e}");
+                    connection.close(CONNECTION_CLOSED, b"stopped responding");
+                }
+                break;
+            }
+        }
+    }
 }
@@ -0,0 +1,23 @@
+use async_std::{
+    channel::Sender,
+    io::{prelude::BufReadExt, stdin, BufReader},
+};
+use futures::StreamExt;
+
+use super::command::Command;
+
+/// Read commands from stdin line by line, sending them through the channel
+pub async fn read_commands(tx: Sender<Command>) -> anyhow::Result<()> {
+    let mut lines = BufReader::new(stdin()).lines();
+    while let Some(line) = lines.next().await {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match line.parse() {
+            Ok(command) => tx.send(command).await?,
+            Err(e) => println!("{e}"),
+        }
+    }
+    Ok(())
+}
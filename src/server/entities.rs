@@ -0,0 +1,77 @@
+//! Saving entities along with the chunk they are in.
+//!
+//! Entities marked [`Persistent`] are saved with the chunk their position is in, and despawned
+//! when that chunk unloads, to be spawned again when it loads. Only the components listed in
+//! [`SavedEntity`] survive this, anything else has to be derived from them again.
+
+use bevy_ecs::{component::Component, entity::Entity, world::World};
+use cgmath::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wgpu::naga::FastHashMap;
+
+use crate::game::{
+    chunk::{BlockPos, ChunkPos},
+    inventory::Inventory,
+    mob::Mob,
+    Position, Velocity,
+};
+
+use super::mobs;
+
+/// Marks an entity to be saved with its chunk, under an id that stays the same across restarts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Component)]
+pub struct Persistent(pub Uuid);
+
+/// The saved components of a persistent entity
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedEntity {
+    pub id: Uuid,
+    pub position: Point3<f32>,
+    pub velocity: Option<Vector3<f32>>,
+    pub inventory: Option<Inventory>,
+    pub mob: Option<Mob>,
+}
+
+impl SavedEntity {
+    pub fn spawn(self, world: &mut World) -> Entity {
+        let mut entity = world.spawn((Persistent(self.id), Position(self.position)));
+        if let Some(velocity) = self.velocity {
+            entity.insert(Velocity(velocity));
+        }
+        if let Some(inventory) = self.inventory {
+            entity.insert(inventory);
+        }
+        if self.mob.is_some() {
+            entity.insert(mobs::bundle());
+        }
+        entity.id()
+    }
+}
+
+/// Every persistent entity in the world, by the chunk it is in
+pub fn by_chunk(world: &mut World) -> FastHashMap<ChunkPos, Vec<(Entity, SavedEntity)>> {
+    let mut chunks = FastHashMap::<_, Vec<_>>::default();
+    let mut query = world.query::<(
+        Entity,
+        &Persistent,
+        &Position,
+        Option<&Velocity>,
+        Option<&Inventory>,
+        Option<&Mob>,
+    )>();
+    for (entity, persistent, position, velocity, inventory, mob) in query.iter(world) {
+        let saved = SavedEntity {
+            id: persistent.0,
+            position: position.0,
+            velocity: velocity.map(|velocity| velocity.0),
+            inventory: inventory.cloned(),
+            mob: mob.copied(),
+        };
+        chunks
+            .entry(BlockPos::from_point(position.0).chunk_pos())
+            .or_default()
+            .push((entity, saved));
+    }
+    chunks
+}
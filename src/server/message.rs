@@ -1,31 +1,131 @@
 use cgmath::Vector3;
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-use crate::game::{block::Block, chunk::{BlockPos, Chunk, ChunkPos}};
+use super::metrics::ServerMetrics;
+use crate::game::{
+    block::Block,
+    chunk::{BlockPos, Chunk, ChunkHash, ChunkPos, ChunkRevision, LodChunk},
+    inventory::ItemStack,
+    npc::{NpcId, Trade},
+};
 
+/// Version of the messages below, bump it whenever they change. Clients and servers only talk
+/// to each other when their versions match.
+pub const PROTOCOL_VERSION: u32 = 12;
+
+/// Which way a player is looking, in radians
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct Orientation {
+    pub yaw: f32,
+    pub pitch: f32,
+}
+
+/// The copy of a chunk a client has cached, sent along when asking for the chunk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedChunk {
+    pub hash: ChunkHash,
+    /// The revision the chunk was at when the copy was sent
+    pub revision: ChunkRevision,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageToServer {
-    Connect,
+    /// The first message on a connection, answered with [`MessageToClient::Ok`] or
+    /// [`MessageToClient::IncompatibleVersion`]. Must stay the first variant, so that it can be
+    /// read whatever version the peer has.
+    Version(u32),
+    /// Sent after the version, answered with [`MessageToClient::LoggedIn`] or
+    /// [`MessageToClient::LoginRejected`]
+    Connect {
+        /// The name for a new account, ignored when logging in with a token
+        name: String,
+        /// The token of the account to log in to, from an earlier [`MessageToClient::LoggedIn`]
+        token: Option<Uuid>,
+    },
+    /// Where the player is and which way they are looking, sent a few times a second while
+    /// they move
     UpdatePlayerPosition {
         new_position: Vector3<f32>,
+        orientation: Orientation,
+    },
+    /// Load chunks, each with the copy the client has cached if it has one. Answered with
+    /// [`MessageToClient::Chunks`], [`MessageToClient::ChunksUnchanged`] for the cached copies
+    /// that are still up to date, and a [`MessageToClient::ChunkDelta`] for each cached copy
+    /// that only a few blocks changed in since.
+    GetChunks(Vec<(ChunkPos, Option<CachedChunk>)>),
+    /// Request downsampled versions of chunks, without keeping them loaded
+    GetChunksLod {
+        chunks: Vec<ChunkPos>,
+        level: u8,
     },
-    GetChunks(Vec<ChunkPos>),
     UnloadChunks(Vec<ChunkPos>),
+    /// Have the server send the chunks within `distance` chunks of the player as they move,
+    /// instead of requesting them. Chunks arrive as [`MessageToClient::Chunks`] and leave with
+    /// [`MessageToClient::UnloadChunks`].
+    StreamChunks {
+        distance: u32,
+    },
     ReplaceBlock {
         pos: BlockPos,
-        new_block: Block
+        new_block: Block,
+        /// Client-side sequence number, acknowledged in [`MessageToClient::BlocksPlaced`]
+        edit: u32,
+    },
+    /// Set every block in the box between two corners, both included, to `block`. Only
+    /// operators may fill regions, and only up to a limited number of blocks at once.
+    FillRegion {
+        min: BlockPos,
+        max: BlockPos,
+        block: Block,
+    },
+    /// Ask an NPC for its trades, answered with [`MessageToClient::TradeOffers`]
+    OpenTrade {
+        npc: NpcId,
+    },
+    /// Make one of an NPC's trades, if the player has the items for it
+    Trade {
+        npc: NpcId,
+        offer: usize,
     },
+    /// Flip the lever at `pos`
+    UseBlock {
+        pos: BlockPos,
+    },
+    /// The player is leaving, answered with [`MessageToClient::Ok`] once they have been saved
+    Disconnect,
+    /// Ask how the server is doing, answered with [`MessageToClient::Metrics`]
+    GetMetrics,
+    /// The player died, answered with [`MessageToClient::Respawn`]
+    Respawn,
+    /// A line typed into the chat, run as a command if it starts with `/` and shown to everyone
+    /// otherwise
+    Chat(String),
 }
 
 impl MessageToServer {
+    /// Most chunks asked for or unloaded in a single message, which keeps it well under the
+    /// size limit for messages to the server
+    pub const MAX_CHUNKS: usize = 256;
+
     pub fn name(&self) -> &'static str {
         match self {
-            MessageToServer::Connect => "MessageToServer::Connect",
+            MessageToServer::Version(_) => "MessageToServer::Version",
+            MessageToServer::Connect { .. } => "MessageToServer::Connect",
             MessageToServer::UpdatePlayerPosition { .. } => "MessageToServer::UpdatePlayerPosition",
             MessageToServer::GetChunks(_) => "MessageToServer::GetChunks",
+            MessageToServer::GetChunksLod { .. } => "MessageToServer::GetChunksLod",
             MessageToServer::UnloadChunks(_) => "MessageToServer::UnloadChunks",
-            MessageToServer::ReplaceBlock { pos, new_block } => "MessageToServer::ReplaceBlock",
+            MessageToServer::StreamChunks { .. } => "MessageToServer::StreamChunks",
+            MessageToServer::ReplaceBlock { .. } => "MessageToServer::ReplaceBlock",
+            MessageToServer::FillRegion { .. } => "MessageToServer::FillRegion",
+            MessageToServer::OpenTrade { .. } => "MessageToServer::OpenTrade",
+            MessageToServer::Trade { .. } => "MessageToServer::Trade",
+            MessageToServer::UseBlock { .. } => "MessageToServer::UseBlock",
+            MessageToServer::Disconnect => "MessageToServer::Disconnect",
+            MessageToServer::GetMetrics => "MessageToServer::GetMetrics",
+            MessageToServer::Respawn => "MessageToServer::Respawn",
+            MessageToServer::Chat(_) => "MessageToServer::Chat",
         }
     }
 }
@@ -33,26 +133,112 @@ impl MessageToServer {
 #[derive(Debug, Serialize, Deserialize)]
 pub enum MessageToClient {
     Ok,
+    /// The server speaks another protocol version, and is about to close the connection. Must
+    /// stay the second variant, like [`MessageToServer::Version`] the first.
+    IncompatibleVersion {
+        server: u32,
+    },
     EntitiesPositionUpdate {
         entity: usize,
         new_position: Vector3<f32>,
     },
     Chunk(Chunk),
-    Chunks(Vec<Chunk>),
-    BlockPlaced {
-        pos: BlockPos,
-        new_block: Block,
-    }
+    Chunks(Vec<(Chunk, ChunkRevision)>),
+    LodChunks(Vec<LodChunk>),
+    /// Chunks asked for whose copy in the client's cache is up to date, to be loaded from there
+    ChunksUnchanged(Vec<ChunkPos>),
+    /// The blocks changed in a chunk since the revision of the client's cached copy, to be
+    /// applied to that copy
+    ChunkDelta {
+        pos: ChunkPos,
+        revision: ChunkRevision,
+        /// Content hash of the chunk once the changes are applied
+        hash: ChunkHash,
+        changes: Vec<(BlockPos, Block)>,
+    },
+    /// Chunks that are no longer streamed to the player, see [`MessageToServer::StreamChunks`]
+    UnloadChunks(Vec<ChunkPos>),
+    /// Blocks changed in chunks the client has loaded, including by the client itself
+    BlocksPlaced {
+        changes: Vec<(BlockPos, Block)>,
+        /// The last edit from this client that has been applied, if any were this tick
+        acknowledged_edit: Option<u32>,
+    },
+    /// Move the player to a new position, e.g. by a server command
+    Teleport {
+        position: Vector3<f32>,
+    },
+    /// Put the player back at their spawn point, standing still and looking ahead
+    Respawn {
+        position: Vector3<f32>,
+    },
+    /// A line to show in the chat log
+    Chat(String),
+    /// Another player has moved
+    PlayerMoved {
+        player: Uuid,
+        position: Vector3<f32>,
+        orientation: Orientation,
+    },
+    /// An NPC the player can interact with, sent when connecting
+    NpcSpawned {
+        npc: NpcId,
+        name: String,
+        position: Vector3<f32>,
+    },
+    /// Mobs in chunks the player has loaded that moved or came into view, and mobs that left
+    /// those chunks or despawned
+    Mobs {
+        moved: Vec<(Uuid, Vector3<f32>)>,
+        removed: Vec<Uuid>,
+    },
+    TradeOffers {
+        npc: NpcId,
+        offers: Vec<Trade>,
+    },
+    /// The full contents of the player's inventory, sent whenever it changes
+    Inventory(Vec<ItemStack>),
+    /// The seed the world is generated with, so the client can tell which biome it is in
+    WorldSeed(u32),
+    /// The account the player logged in to, with the token to log in to it next time
+    LoggedIn {
+        player: Uuid,
+        name: String,
+        token: Uuid,
+        /// Where the player is put when joining, on top of the terrain unless they have a
+        /// spawn point of their own
+        spawn: Vector3<f32>,
+    },
+    /// The login was turned down, and the connection is about to be closed
+    LoginRejected(String),
+    Metrics(ServerMetrics),
 }
 
 impl MessageToClient {
     pub fn name(&self) -> &'static str {
         match self {
             MessageToClient::Ok => "MessageToClient::Ok",
+            MessageToClient::IncompatibleVersion { .. } => "MessageToClient::IncompatibleVersion",
             MessageToClient::EntitiesPositionUpdate { .. } => "MessageToClient::EntitiesPositionUpdate",
             MessageToClient::Chunk(_) => "MessageToClient::Chunk",
             MessageToClient::Chunks(_) => "MessageToClient::Chunks",
-            MessageToClient::BlockPlaced { .. } => "MessageToClient::BlockPlaced",
+            MessageToClient::LodChunks(_) => "MessageToClient::LodChunks",
+            MessageToClient::ChunksUnchanged(_) => "MessageToClient::ChunksUnchanged",
+            MessageToClient::ChunkDelta { .. } => "MessageToClient::ChunkDelta",
+            MessageToClient::UnloadChunks(_) => "MessageToClient::UnloadChunks",
+            MessageToClient::BlocksPlaced { .. } => "MessageToClient::BlocksPlaced",
+            MessageToClient::Teleport { .. } => "MessageToClient::Teleport",
+            MessageToClient::Respawn { .. } => "MessageToClient::Respawn",
+            MessageToClient::Chat(_) => "MessageToClient::Chat",
+            MessageToClient::PlayerMoved { .. } => "MessageToClient::PlayerMoved",
+            MessageToClient::NpcSpawned { .. } => "MessageToClient::NpcSpawned",
+            MessageToClient::Mobs { .. } => "MessageToClient::Mobs",
+            MessageToClient::TradeOffers { .. } => "MessageToClient::TradeOffers",
+            MessageToClient::Inventory(_) => "MessageToClient::Inventory",
+            MessageToClient::WorldSeed(_) => "MessageToClient::WorldSeed",
+            MessageToClient::LoggedIn { .. } => "MessageToClient::LoggedIn",
+            MessageToClient::LoginRejected(_) => "MessageToClient::LoginRejected",
+            MessageToClient::Metrics(_) => "MessageToClient::Metrics",
         }
     }
 }
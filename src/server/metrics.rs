@@ -0,0 +1,91 @@
+use std::{collections::VecDeque, fmt::Display, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use wgpu::naga::FastHashMap;
+
+/// Collects statistics about how the server is doing while it runs
+#[derive(Debug, Default)]
+pub struct MetricsCollector {
+    /// Durations of the most recent ticks, oldest first
+    tick_times: VecDeque<Duration>,
+    /// Messages received since the server started, by type
+    messages: FastHashMap<&'static str, u64>,
+}
+
+impl MetricsCollector {
+    /// How many ticks the tick times are taken over, 5 seconds worth at the default tick rate
+    const TICK_WINDOW: usize = 100;
+
+    pub fn record_tick(&mut self, duration: Duration) {
+        if self.tick_times.len() == Self::TICK_WINDOW {
+            self.tick_times.pop_front();
+        }
+        self.tick_times.push_back(duration);
+    }
+
+    /// Count a received message, by its [`MessageToServer::name`](super::message::MessageToServer::name)
+    pub fn record_message(&mut self, name: &'static str) {
+        *self.messages.entry(name).or_default() += 1;
+    }
+
+    pub fn snapshot(&self, loaded_chunks: usize, players: usize) -> ServerMetrics {
+        let total = self.tick_times.iter().sum::<Duration>();
+        let average = total
+            .checked_div(self.tick_times.len() as u32)
+            .unwrap_or_default();
+        let max = self.tick_times.iter().max().copied().unwrap_or_default();
+
+        let mut messages = self
+            .messages
+            .iter()
+            .map(|(&name, &count)| (name.to_string(), count))
+            .collect::<Vec<_>>();
+        messages.sort();
+
+        ServerMetrics {
+            average_tick_ms: average.as_secs_f32() * 1000.0,
+            max_tick_ms: max.as_secs_f32() * 1000.0,
+            messages,
+            loaded_chunks,
+            players,
+        }
+    }
+}
+
+/// How the server is doing, logged periodically and sent to clients that ask for it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerMetrics {
+    /// Average tick duration over the last few seconds, in milliseconds. Ticks are 50 ms apart
+    /// at the default tick rate.
+    pub average_tick_ms: f32,
+    /// Longest tick over the last few seconds, in milliseconds
+    pub max_tick_ms: f32,
+    /// Messages received since the server started, by type, sorted by name
+    pub messages: Vec<(String, u64)>,
+    pub loaded_chunks: usize,
+    pub players: usize,
+}
+
+impl ServerMetrics {
+    /// The message counts as one line
+    pub fn message_counts(&self) -> String {
+        if self.messages.is_empty() {
+            return "none".to_string();
+        }
+        self.messages
+            .iter()
+            .map(|(name, count)| format!("{name}: {count}"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+impl Display for ServerMetrics {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "tick: {:.1} ms avg, {:.1} ms max, loaded chunks: {}, players: {}",
+            self.average_tick_ms, self.max_tick_ms, self.loaded_chunks, self.players
+        )
+    }
+}
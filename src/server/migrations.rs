@@ -0,0 +1,174 @@
+//! Upgrades of the SQLite save to the current schema.
+//!
+//! The save records how many of [`MIGRATIONS`] have been applied to it in `schema_version`, and
+//! the rest are applied in order when it is opened, each in its own transaction. To change the
+//! schema, append a migration, never edit one that has been released, as saves may already have
+//! it applied.
+//!
+//! Saves from before the version was tracked count as version 0. The first migrations only
+//! create what does not exist yet, so they apply to those saves as well as to new ones.
+
+use rusqlite::{Connection, OptionalExtension, Transaction};
+
+pub struct Migration {
+    pub description: &'static str,
+    pub apply: fn(&Transaction) -> anyhow::Result<()>,
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        description: "create the dimension and overworld chunk tables",
+        apply: create_dimensions,
+    },
+    Migration {
+        description: "create the player table",
+        apply: create_players,
+    },
+    Migration {
+        description: "create the entity table",
+        apply: create_entities,
+    },
+    Migration {
+        description: "create the spawn point table",
+        apply: create_spawn_points,
+    },
+    Migration {
+        description: "create the pinned chunk table",
+        apply: create_pinned_chunks,
+    },
+    Migration {
+        description: "create the pending structure block table",
+        apply: create_pending_blocks,
+    },
+];
+
+/// The schema version this server writes, the number of migrations
+pub fn current_version() -> u32 {
+    MIGRATIONS.len() as u32
+}
+
+/// Bring the save up to the current schema. Fails on saves from a newer server, which this
+/// one can't know how to read.
+pub fn migrate(db: &mut Connection) -> anyhow::Result<()> {
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS schema_version (
+            version INTEGER NOT NULL
+        );
+        ",
+        [],
+    )?;
+    let version = db
+        .query_row("SELECT version FROM schema_version", [], |row| {
+            row.get::<_, u32>(0)
+        })
+        .optional()?
+        .unwrap_or(0);
+    if version > current_version() {
+        anyhow::bail!(
+            "the save has schema version {version}, newer than the {} this server supports",
+            current_version()
+        );
+    }
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let version = index as u32 + 1;
+        println!(
+            "Upgrading save to schema version {version}: {}",
+            migration.description
+        );
+        let transaction = db.transaction()?;
+        (migration.apply)(&transaction)?;
+        transaction.execute("DELETE FROM schema_version", [])?;
+        transaction.execute(
+            "INSERT INTO schema_version (version) VALUES (?1)",
+            (version,),
+        )?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
+
+fn create_dimensions(db: &Transaction) -> anyhow::Result<()> {
+    db.execute_batch(
+        "
+        CREATE TABLE IF NOT EXISTS dimensions (
+            id INTEGER NOT NULL PRIMARY KEY,
+            info BLOB NOT NULL
+        );
+        CREATE TABLE IF NOT EXISTS chunks (
+            pos BLOB NOT NULL PRIMARY KEY,
+            blocks BLOB NOT NULL
+        );
+        ",
+    )?;
+    Ok(())
+}
+
+fn create_players(db: &Transaction) -> anyhow::Result<()> {
+    db.execute(
+        "
+        CREATE TABLE IF NOT EXISTS players (
+            id BLOB NOT NULL PRIMARY KEY,
+            name TEXT NOT NULL UNIQUE,
+            token BLOB NOT NULL UNIQUE
+        );
+        ",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_entities(db: &Transaction) -> anyhow::Result<()> {
+    db.execute_batch(
+        "
+        CREATE TABLE entities (
+            id BLOB NOT NULL PRIMARY KEY,
+            dimension INTEGER NOT NULL,
+            chunk BLOB NOT NULL,
+            data BLOB NOT NULL
+        );
+        CREATE INDEX entities_by_chunk ON entities (dimension, chunk);
+        ",
+    )?;
+    Ok(())
+}
+
+fn create_spawn_points(db: &Transaction) -> anyhow::Result<()> {
+    db.execute(
+        "
+        CREATE TABLE spawn_points (
+            dimension INTEGER NOT NULL PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+        ",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_pinned_chunks(db: &Transaction) -> anyhow::Result<()> {
+    db.execute(
+        "
+        CREATE TABLE pinned_chunks (
+            dimension INTEGER NOT NULL PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+        ",
+        [],
+    )?;
+    Ok(())
+}
+
+fn create_pending_blocks(db: &Transaction) -> anyhow::Result<()> {
+    db.execute(
+        "
+        CREATE TABLE pending_blocks (
+            dimension INTEGER NOT NULL PRIMARY KEY,
+            data BLOB NOT NULL
+        );
+        ",
+        [],
+    )?;
+    Ok(())
+}
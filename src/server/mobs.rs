@@ -0,0 +1,188 @@
+//! Mobs spawning on the surface and wandering around aimlessly.
+//!
+//! Mobs only spawn on the top of a column of loaded blocks, out in the open, and away from
+//! light-emitting blocks, so lamps keep an area clear of them. They are ticked inside the
+//! simulation area and frozen in place outside of it.
+
+use bevy_ecs::{
+    bundle::Bundle,
+    component::Component,
+    query::With,
+    system::{Commands, Query, Res},
+};
+use cgmath::{InnerSpace, Point3, Vector2, Vector3, Zero};
+use rand::{seq::IteratorRandom, Rng};
+use uuid::Uuid;
+
+use crate::game::{
+    block::BlockRegistry,
+    chunk::{BlockPos, Chunk},
+    mob::Mob,
+    physics::Collider,
+    world::World,
+    DeltaTime, Position, Velocity,
+};
+
+use super::{entities::Persistent, simulation::SimulationArea};
+
+/// Most mobs there can be at once
+pub const MAX_MOBS: usize = 16;
+/// Chance each tick to try spawning a mob
+const SPAWN_CHANCE: f64 = 0.05;
+/// How close to a light-emitting block mobs can't spawn
+const LIGHT_RADIUS: isize = 4;
+const WALK_SPEED: f32 = 1.5;
+const JUMP_SPEED: f32 = 8.0;
+
+/// Where a mob is walking and for how much longer. Not saved, mobs just pick a new direction
+/// after loading.
+#[derive(Debug, Clone, Copy, Component)]
+pub struct Wander {
+    /// Horizontal direction to walk in, zero while standing still
+    direction: Vector2<f32>,
+    /// Seconds until a new direction is picked
+    time_left: f32,
+    /// Where the mob was on the last tick, to notice when it walks into a wall
+    last_position: Option<Point3<f32>>,
+}
+
+impl Default for Wander {
+    fn default() -> Self {
+        Self {
+            direction: Vector2::zero(),
+            time_left: 0.0,
+            last_position: None,
+        }
+    }
+}
+
+/// The components that make an entity a mob, besides its position and velocity
+pub fn bundle() -> impl Bundle {
+    (Mob, Mob::collider(), Wander::default())
+}
+
+/// Try to spawn a mob at a random spot in the simulation area, every now and then
+pub fn spawn_system(
+    mut commands: Commands,
+    mobs: Query<(), With<Mob>>,
+    area: Res<SimulationArea>,
+    world: Res<World>,
+    block_registry: Res<BlockRegistry>,
+) {
+    let mut rng = rand::thread_rng();
+    if mobs.iter().count() >= MAX_MOBS || !rng.gen_bool(SPAWN_CHANCE) {
+        return;
+    }
+    let Some(chunk) = area.chunks().choose(&mut rng) else {
+        return;
+    };
+    let column = Point3::from(chunk) * Chunk::SIZE as isize
+        + Vector3::new(
+            rng.gen_range(0..Chunk::SIZE as isize),
+            0,
+            rng.gen_range(0..Chunk::SIZE as isize),
+        );
+    let Some(position) = spawn_position(&world, &block_registry, column.x, column.z) else {
+        return;
+    };
+
+    commands.spawn((
+        Persistent(Uuid::new_v4()),
+        Position(position),
+        Velocity(Vector3::zero()),
+        bundle(),
+    ));
+}
+
+/// Where a mob may spawn in the column at `x`, `z`: on top of its highest loaded block, if that
+/// is solid ground and no light-emitting block is nearby
+fn spawn_position(
+    world: &World,
+    block_registry: &BlockRegistry,
+    x: isize,
+    z: isize,
+) -> Option<Point3<f32>> {
+    // Start at the top of the highest loaded chunk in the column, so the spot is as open to the
+    // sky as the server can tell
+    let size = Chunk::SIZE as isize;
+    let column = (x.div_euclid(size), z.div_euclid(size));
+    let top = world
+        .chunks
+        .keys()
+        .map(|&pos| Point3::from(pos))
+        .filter(|pos| (pos.x, pos.z) == column)
+        .map(|pos| pos.y)
+        .max()?;
+    let mut y = (top + 1) * size - 1;
+
+    let ground = loop {
+        let block = *world.block(Point3::new(x, y, z).into())?;
+        let attributes = block_registry.get(block.id).unwrap();
+        if attributes.liquid {
+            return None;
+        }
+        if !attributes.invisible {
+            break Point3::new(x, y, z);
+        }
+        y -= 1;
+    };
+
+    for dx in -LIGHT_RADIUS..=LIGHT_RADIUS {
+        for dy in -LIGHT_RADIUS..=LIGHT_RADIUS {
+            for dz in -LIGHT_RADIUS..=LIGHT_RADIUS {
+                let pos = ground + Vector3::new(dx, dy, dz);
+                let Some(block) = world.block(pos.into()) else {
+                    continue;
+                };
+                if block_registry.get(block.id).unwrap().light_emission > 0.0 {
+                    return None;
+                }
+            }
+        }
+    }
+
+    Some(ground.cast().unwrap() + Vector3::new(0.5, 1.0, 0.5))
+}
+
+/// Walk mobs in the simulation area around, and freeze the ones outside of it. Runs before the
+/// physics, which moves them.
+pub fn wander_system(
+    mut mobs: Query<(&Position, &mut Velocity, &mut Collider, &mut Wander), With<Mob>>,
+    area: Res<SimulationArea>,
+    dt: Res<DeltaTime>,
+) {
+    let mut rng = rand::thread_rng();
+    for (pos, mut vel, mut collider, mut wander) in &mut mobs {
+        if !area.contains(BlockPos::from_point(pos.0).chunk_pos()) {
+            vel.0 = Vector3::zero();
+            collider.gravity = false;
+            wander.last_position = None;
+            continue;
+        }
+        collider.gravity = true;
+
+        wander.time_left -= dt.0;
+        if wander.time_left <= 0.0 {
+            wander.time_left = rng.gen_range(2.0..5.0);
+            wander.direction = if rng.gen_bool(0.5) {
+                Vector2::zero()
+            } else {
+                let angle = rng.gen_range(0.0..std::f32::consts::TAU);
+                Vector2::new(angle.cos(), angle.sin())
+            };
+        }
+
+        vel.0.x = wander.direction.x * WALK_SPEED;
+        vel.0.z = wander.direction.y * WALK_SPEED;
+
+        // Jump up blocks in the way, noticed by having barely moved since the last tick
+        let stuck = wander.last_position.is_some_and(|last| {
+            let moved = Vector2::new(pos.0.x - last.x, pos.0.z - last.z);
+            moved.magnitude() < WALK_SPEED * dt.0 * 0.5
+        });
+        if !wander.direction.is_zero() && collider.on_ground && stuck {
+            vel.0.y = JUMP_SPEED;
+        }
+        wander.last_position = Some(pos.0);
+    }
+}
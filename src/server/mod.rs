@@ -1,36 +1,144 @@
 use std::{
-    net::IpAddr,
+    net::SocketAddr,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
 
 use async_std::channel::{Receiver, Sender, TryRecvError};
-use bevy_ecs::schedule::{Schedule, ScheduleLabel};
-use cgmath::Point3;
+use bevy_ecs::{
+    query::With,
+    schedule::{
+        ExecutorKind, IntoSystemConfigs, IntoSystemSetConfigs, Schedule, ScheduleLabel, SystemSet,
+    },
+};
+use cgmath::{EuclideanSpace, MetricSpace, Point3, Vector3};
 use futures::{FutureExt, StreamExt};
 use itertools::Itertools;
-use quinn::{Endpoint, RecvStream, ServerConfig};
-use rusqlite::OptionalExtension;
+use quinn::{Endpoint, RecvStream};
 use uuid::Uuid;
 use wgpu::naga::{FastHashMap, FastHashSet};
 
 use self::{
     super::game::{
-        chunk::{Chunk, ChunkPos},
+        block::{Block, BlockId, BlockMetadata, BlockRegistry},
+        chunk::{BlockPos, Chunk, ChunkPos, LodChunk},
+        dimension::{DimensionId, DimensionInfo},
+        inventory::{Inventory, ItemStack},
+        mob::Mob,
+        npc::{Npc, NpcDefinition, NpcId, Trade, Trades},
+        physics, player, signal,
         world::World,
+        worldgen::{CaveSettings, Worldgen},
+        DeltaTime, Position,
     },
+    accounts::Login,
+    block_updates::BlockUpdates,
+    config::NetworkConfig,
     connection::{Connection, RemoteTransport, Respond, Transport},
+    command::{Command, CommandSource, Permission},
+    entities::Persistent,
     message::{MessageToClient, MessageToServer},
+    metrics::{MetricsCollector, ServerMetrics},
+    movement::{MovementRules, PlayerPosition, PlayerPositions},
+    prefetch::{ChunkPrefetcher, PrefetchedChunk},
+    revisions::ChunkRevisions,
+    simulation::{SimulationArea, SimulationDistance},
+    spawn::SpawnPoints,
+    storage::{StorageBackend, WorldStorage},
 };
 
+pub mod accounts;
+pub mod block_updates;
+pub mod command;
+pub mod config;
 pub mod connection;
+pub mod console;
+pub mod entities;
 pub mod message;
+pub mod metrics;
+pub mod migrations;
+pub mod mobs;
+pub mod movement;
+pub mod pinning;
+pub mod prefetch;
+pub mod revisions;
+pub mod simulation;
+pub mod spawn;
+pub mod storage;
+pub mod streaming;
+
+pub struct ServerConfig {
+    pub network: NetworkConfig,
+    pub storage: StorageBackend,
+    /// Directory the world is saved in
+    pub save_dir: PathBuf,
+    /// Radius in chunks around each player in which entities and blocks are ticked
+    pub simulation_distance: isize,
+    /// Message of the day, sent to players when they connect
+    pub motd: String,
+    pub movement_rules: MovementRules,
+    /// Save the world once nobody has been connected for this long, `None` to never do so
+    pub idle_save_delay: Option<Duration>,
+    /// How often to save the chunks changed since they were last saved, `None` to only save
+    /// them when they unload
+    pub autosave_interval: Option<Duration>,
+    pub caves: CaveSettings,
+    /// How often to log the server metrics, `None` to never do so
+    pub metrics_interval: Option<Duration>,
+    /// Names of the players allowed to run commands that change the world or other players
+    pub operators: Vec<String>,
+    /// How far from their eyes players may edit and use blocks
+    pub reach: f32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            network: NetworkConfig::default(),
+            storage: StorageBackend::Sqlite,
+            save_dir: PathBuf::from("."),
+            simulation_distance: 4,
+            motd: "Welcome!".to_string(),
+            movement_rules: MovementRules::default(),
+            idle_save_delay: Some(Duration::from_secs(60)),
+            autosave_interval: Some(Duration::from_secs(300)),
+            caves: CaveSettings::default(),
+            metrics_interval: Some(Duration::from_secs(60)),
+            operators: vec![],
+            reach: player::DEFAULT_REACH,
+        }
+    }
+}
+
+/// Height of a standing player's eyes above their feet, matching the client
+const EYE_HEIGHT: f32 = 1.6;
+/// Distance allowed on top of the reach, since the client measures it to where it hit the block
+/// rather than to its center, and may have moved since the position it last sent
+const REACH_TOLERANCE: f32 = 1.5;
+
+/// Most blocks a single fill may change, as they are all changed within one tick
+const MAX_FILL_BLOCKS: usize = 32 * 32 * 32;
+
+/// How much of the time between ticks, counted from the start of a tick, may be spent
+/// prefetching chunks
+const PREFETCH_SHARE: f32 = 0.4;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ScheduleLabel)]
 pub enum ScheduleStage {
     Tick,
 }
 
+/// The stages of a tick, run one after the other. Systems within a set run in parallel where
+/// the data they access allows it.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemSet)]
+pub enum TickSet {
+    /// Bring derived state, like the simulation area, up to date with this tick's messages
+    Prepare,
+    /// Tick blocks and entities inside the simulation area
+    Simulate,
+}
+
 pub struct Server {
     ecs_world: bevy_ecs::world::World,
     endpoint: Endpoint,
@@ -43,75 +151,402 @@ pub struct Server {
     >,
     loaded_chunks: FastHashMap<ChunkPos, usize>,
     player_loaded_chunks: FastHashMap<Uuid, FastHashSet<ChunkPos>>,
-    db: rusqlite::Connection,
+    /// Players the chunks around them are streamed to, and how far out, see [`streaming`]
+    chunk_streams: FastHashMap<Uuid, u32>,
+    player_names: FastHashMap<Uuid, String>,
+    /// Block changes made this tick, and the player that made them, or `None` for changes made
+    /// by the server itself
+    pending_block_changes: Vec<(Option<Uuid>, BlockPos, Block)>,
+    /// The last edit applied this tick for each player
+    acknowledged_edits: FastHashMap<Uuid, u32>,
+    /// Which blocks changed in which revision of each chunk, to send clients only those
+    revisions: ChunkRevisions,
+    inventories: FastHashMap<Uuid, Inventory>,
+    /// The mobs each player has been told about, and where they were last said to be
+    known_mobs: FastHashMap<Uuid, FastHashMap<Uuid, Point3<f32>>>,
+    motd: String,
+    movement_rules: MovementRules,
+    idle_save_delay: Option<Duration>,
+    /// When the last player left, or `None` if someone is connected or the world has been saved
+    /// since
+    idle_since: Option<Instant>,
+    autosave_interval: Option<Duration>,
+    last_autosave: Instant,
+    /// Chunks changed since they were last saved. Chunks that have been unloaded since may
+    /// linger here, they were saved when they unloaded.
+    dirty_chunks: FastHashSet<ChunkPos>,
+    spawn_points: SpawnPoints,
+    /// Chunks operators pinned, the ones around the world spawn are pinned as well, see
+    /// [`pinning`]
+    pinned: FastHashSet<ChunkPos>,
+    operators: FastHashSet<String>,
+    reach: f32,
+    /// Ticks since the server started, see [`command::DAY_LENGTH`]
+    time: u64,
+    storage: Box<dyn WorldStorage>,
+    prefetcher: ChunkPrefetcher,
+    /// Check the loaded chunk reference counts every tick, see `check_loaded_chunks`
+    check_chunk_counts: bool,
+    metrics: MetricsCollector,
+    metrics_interval: Option<Duration>,
+    /// When the metrics were last logged
+    metrics_logged: Instant,
+    tick_interval: Duration,
     shutdown_signal: Receiver<()>,
 }
 
 impl Server {
-    pub fn new(shutdown_signal: Receiver<()>) -> Self {
+    pub fn new(config: ServerConfig, shutdown_signal: Receiver<()>) -> Self {
         let server_config = rustls::ServerConfig::builder();
-        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
-        let key = rustls::PrivateKey(cert.serialize_private_key_der().into());
-        let cert = rustls::Certificate(cert.serialize_der().unwrap());
-        let config = server_config
+        let (chain, key) = match config.network.load_certificate().unwrap() {
+            Some(certificate) => certificate,
+            None => {
+                let cert =
+                    rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+                let key = rustls::PrivateKey(cert.serialize_private_key_der().into());
+                let cert = rustls::Certificate(cert.serialize_der().unwrap());
+                (vec![cert], key)
+            }
+        };
+        // Players pin this to trust a server whose certificate no authority signed
+        println!(
+            "Certificate fingerprint: {}",
+            connection::fingerprint(&chain[0])
+        );
+        let crypto_config = server_config
             .with_safe_defaults()
             .with_no_client_auth()
-            .with_single_cert(vec![cert], key)
+            .with_single_cert(chain, key)
             .unwrap();
-        let endpoint = quinn::Endpoint::server(
-            ServerConfig::with_crypto(Arc::new(config)),
-            ("::".parse::<IpAddr>().unwrap(), 1234).into(),
-        )
-        .unwrap();
-
-        let db = rusqlite::Connection::open("./savegame.db3").unwrap();
-        db.execute(
-            "
-        CREATE TABLE IF NOT EXISTS chunks (
-            pos BLOB NOT NULL PRIMARY KEY,
-            blocks BLOB NOT NULL
-        );
-        ",
-            [],
-        )
-        .unwrap();
+        let transport_config = config.network.keep_alive.transport_config();
+        let mut quinn_config = quinn::ServerConfig::with_crypto(Arc::new(crypto_config));
+        quinn_config.transport_config(Arc::new(transport_config));
+        let endpoint =
+            quinn::Endpoint::server(quinn_config, config.network.bind_address()).unwrap();
 
-        let world = World::new();
+        let block_registry = BlockRegistry::with_default_blocks();
+        let mut storage = config
+            .storage
+            .open(&config.save_dir, block_registry.names().clone())
+            .unwrap();
+
+        let dimension = DimensionId::OVERWORLD;
+        let info = match storage.load_dimension(dimension).unwrap() {
+            Some(info) => info,
+            None => {
+                let info = DimensionInfo {
+                    seed: Worldgen::new().seed(),
+                };
+                storage.save_dimension(dimension, &info).unwrap();
+                info
+            }
+        };
+        let mut world = World::with_worldgen(
+            dimension,
+            Worldgen::with_seed(info.seed)
+                .with_caves(config.caves)
+                .with_ores(&block_registry),
+        );
+        world.add_pending_blocks(storage.load_pending_blocks(dimension).unwrap());
+        let mut spawn_points = storage
+            .load_spawn_points(dimension)
+            .unwrap()
+            .unwrap_or_else(|| SpawnPoints::new(&world.worldgen));
+        // Only players' spawn points were ever set in these, the world spawn was never chosen
+        if spawn_points.world == spawn::DEFAULT_SPAWN {
+            spawn_points.world = SpawnPoints::new(&world.worldgen).world;
+        }
+        let pinned = storage.load_pinned_chunks(dimension).unwrap();
 
         let mut ecs_world = bevy_ecs::world::World::new();
 
         ecs_world.insert_resource(world);
+        ecs_world.insert_resource(block_registry);
+        ecs_world.insert_resource(SimulationArea::default());
+        ecs_world.insert_resource(SimulationDistance(config.simulation_distance));
+        ecs_world.insert_resource(PlayerPositions::default());
+        ecs_world.insert_resource(BlockUpdates::default());
+        ecs_world.insert_resource(DeltaTime(config.network.tick_interval().as_secs_f32()));
 
-        Self {
+        for (i, npc) in NpcDefinition::load_all(NpcDefinition::PATH)
+            .into_iter()
+            .enumerate()
+        {
+            ecs_world.spawn((
+                Npc {
+                    id: NpcId(i as u32),
+                    name: npc.name,
+                },
+                Position(npc.position.into()),
+                Trades(npc.trades),
+            ));
+        }
+
+        // Networking stays outside the schedule as it needs to await, and so do the chunk loading
+        // and saving it drives. Block updates write `World`, so they run on their own, while mob
+        // spawning, which only reads it, can run alongside mob movement.
+        let mut tick = Schedule::new(ScheduleStage::Tick);
+        tick.set_executor_kind(ExecutorKind::MultiThreaded);
+        tick.configure_sets((TickSet::Prepare, TickSet::Simulate).chain());
+        tick.add_systems(simulation::update_simulation_area.in_set(TickSet::Prepare));
+        tick.add_systems(block_updates::run_block_updates.in_set(TickSet::Simulate));
+        tick.add_systems(
+            (
+                mobs::spawn_system,
+                mobs::wander_system.before(physics::physics_system),
+                physics::physics_system,
+            )
+                .in_set(TickSet::Simulate),
+        );
+        ecs_world.add_schedule(tick);
+
+        let mut server = Self {
             ecs_world,
             endpoint,
             connections: FastHashMap::default(),
             loaded_chunks: FastHashMap::default(),
-            player_loaded_chunks: FastHashMap::default(),
-            db,
+            player_loaded_chunks: [(pinning::LOADER, FastHashSet::default())]
+                .into_iter()
+                .collect(),
+            chunk_streams: FastHashMap::default(),
+            player_names: FastHashMap::default(),
+            pending_block_changes: vec![],
+            acknowledged_edits: FastHashMap::default(),
+            revisions: ChunkRevisions::new(),
+            inventories: FastHashMap::default(),
+            known_mobs: FastHashMap::default(),
+            motd: config.motd,
+            movement_rules: config.movement_rules,
+            idle_save_delay: config.idle_save_delay,
+            idle_since: Some(Instant::now()),
+            autosave_interval: config.autosave_interval,
+            last_autosave: Instant::now(),
+            dirty_chunks: FastHashSet::default(),
+            spawn_points,
+            pinned: pinned.into_iter().collect(),
+            operators: config.operators.into_iter().collect(),
+            reach: config.reach,
+            time: 0,
+            storage,
+            prefetcher: ChunkPrefetcher::default(),
+            check_chunk_counts: false,
+            metrics: MetricsCollector::default(),
+            metrics_interval: config.metrics_interval,
+            metrics_logged: Instant::now(),
+            tick_interval: config.network.tick_interval(),
             shutdown_signal
+        };
+        server.update_pins();
+        server
+    }
+
+    /// The address the server listens on, with the actual port if it was asked to pick one
+    pub fn local_addr(&self) -> SocketAddr {
+        self.endpoint.local_addr().unwrap()
+    }
+
+    /// Load the pinned chunks that aren't loaded by [`pinning::LOADER`] yet, and unload the
+    /// ones that aren't pinned anymore
+    fn update_pins(&mut self) {
+        let spawn_chunks = pinning::spawn_chunks(self.spawn_points.world);
+        let loaded = &self.player_loaded_chunks[&pinning::LOADER];
+        let unpinned = loaded
+            .iter()
+            .filter(|pos| !self.pinned.contains(pos) && !spawn_chunks.contains(pos))
+            .copied()
+            .collect::<Vec<_>>();
+        let pinned = self
+            .pinned
+            .iter()
+            .chain(&spawn_chunks)
+            .filter(|pos| !loaded.contains(pos))
+            .copied()
+            .collect::<FastHashSet<_>>();
+        for pos in unpinned {
+            self.unload_chunk(pinning::LOADER, pos);
+        }
+        for pos in pinned {
+            if let Err(e) = self.load_chunk(pinning::LOADER, pos) {
+                eprintln!("Could not load pinned chunk {pos:?}: {e:#}");
+            }
+        }
+    }
+
+    fn save_pins(&mut self) -> Result<(), String> {
+        let dimension = self.ecs_world.resource::<World>().dimension;
+        let pinned = self.pinned.iter().copied().collect::<Vec<_>>();
+        self.storage
+            .save_pinned_chunks(dimension, &pinned)
+            .map_err(|e| format!("Could not save the pinned chunks: {e:#}"))
+    }
+
+    /// Write every loaded chunk and its entities to storage, keeping them loaded
+    fn save_all(&mut self) -> anyhow::Result<()> {
+        let world = self.ecs_world.resource::<World>();
+        for pos in self.loaded_chunks.keys() {
+            self.storage
+                .save_chunk(world.dimension, &world.chunks[pos])?;
+        }
+        let loaded = self.loaded_chunks.keys().copied().collect::<Vec<_>>();
+        self.save_entities(loaded)?;
+        self.save_pending_blocks()?;
+        self.storage.flush()?;
+        self.dirty_chunks.clear();
+        Ok(())
+    }
+
+    /// Save the structure blocks waiting for chunks that aren't loaded, which are placed when
+    /// those chunks load
+    fn save_pending_blocks(&mut self) -> anyhow::Result<()> {
+        let world = self.ecs_world.resource::<World>();
+        self.storage
+            .save_pending_blocks(world.dimension, &world.pending_blocks())
+    }
+
+    /// Save the loaded chunks that changed since they were last saved, once per autosave
+    /// interval, so that a crash loses at most that much
+    fn autosave_if_due(&mut self) {
+        let Some(interval) = self.autosave_interval else {
+            return;
+        };
+        if self.last_autosave.elapsed() < interval {
+            return;
+        }
+        self.last_autosave = Instant::now();
+
+        let world = self.ecs_world.resource::<World>();
+        let mut saved = 0;
+        for pos in self.dirty_chunks.drain() {
+            if let Some(chunk) = world.chunks.get(&pos) {
+                match self.storage.save_chunk(world.dimension, chunk) {
+                    Ok(()) => saved += 1,
+                    Err(e) => eprintln!("Could not save chunk {pos:?}: {e:#}"),
+                }
+            }
+        }
+        // Entities move without changing any blocks, so theirs are always saved
+        let loaded = self.loaded_chunks.keys().copied().collect::<Vec<_>>();
+        if let Err(e) = self.save_entities(loaded) {
+            eprintln!("Could not save entities: {e:#}");
+        }
+        if let Err(e) = self.save_pending_blocks() {
+            eprintln!("Could not save pending structure blocks: {e:#}");
+        }
+        if let Err(e) = self.storage.flush() {
+            eprintln!("Could not save the world: {e:#}");
+        }
+        if saved > 0 {
+            println!("Autosaved {saved} changed chunks");
+        }
+    }
+
+    /// Spawn the entities saved with a chunk that is being loaded
+    fn load_entities(&mut self, pos: ChunkPos) -> anyhow::Result<()> {
+        let dimension = self.ecs_world.resource::<World>().dimension;
+        for entity in self.storage.load_entities(dimension, pos)? {
+            entity.spawn(&mut self.ecs_world);
+        }
+        Ok(())
+    }
+
+    /// Save the persistent entities in `chunks`, despawning the ones in chunks that aren't
+    /// loaded anymore. Entities that wandered out of the loaded chunks are added to the entities
+    /// saved with the chunk they are in and despawned as well, or they would never be saved.
+    fn save_entities(&mut self, chunks: impl IntoIterator<Item = ChunkPos>) -> anyhow::Result<()> {
+        let dimension = self.ecs_world.resource::<World>().dimension;
+        let mut by_chunk = entities::by_chunk(&mut self.ecs_world);
+        let chunks = chunks.into_iter().collect::<FastHashSet<_>>();
+        let strays = by_chunk
+            .keys()
+            .filter(|pos| !chunks.contains(pos) && !self.loaded_chunks.contains_key(pos))
+            .copied()
+            .collect::<Vec<_>>();
+
+        for pos in chunks.into_iter().chain(strays) {
+            let entities = by_chunk.remove(&pos).unwrap_or_default();
+            let mut saved = entities
+                .iter()
+                .map(|(_, entity)| entity.clone())
+                .collect::<Vec<_>>();
+            if !self.loaded_chunks.contains_key(&pos) {
+                for &(entity, _) in &entities {
+                    self.ecs_world.despawn(entity);
+                }
+            }
+            if !self.loaded_chunks.contains_key(&pos) && !entities.is_empty() {
+                // Keep what is already saved with an unloaded chunk strays wandered into
+                let mut existing = self.storage.load_entities(dimension, pos)?;
+                existing.retain(|old| saved.iter().all(|new| new.id != old.id));
+                saved.extend(existing);
+            }
+            self.storage.save_entities(dimension, pos, &saved)?;
+        }
+        Ok(())
+    }
+
+    /// Save the world if nobody has been connected for a while, once per idle period
+    fn save_if_idle(&mut self) {
+        let Some(delay) = self.idle_save_delay else {
+            return;
+        };
+        if let Some(idle_since) = self.idle_since
+            && idle_since.elapsed() >= delay
+        {
+            println!("Server is idle, saving the world");
+            if let Err(e) = self.save_all() {
+                eprintln!("Could not save the world: {e:#}");
+            }
+            self.idle_since = None;
         }
     }
 
     fn shutdown(&mut self) {
+        let loaded = self.loaded_chunks.keys().copied().collect::<Vec<_>>();
+        if let Err(e) = self.save_entities(loaded) {
+            eprintln!("Could not save entities: {e:#}");
+        }
         for &pos in self.loaded_chunks.keys() {
             let mut world = self.ecs_world.resource_mut::<World>();
             let chunk = world.chunks.remove(&pos).unwrap();
-            self.db
-                .execute(
-                    "INSERT OR REPLACE INTO chunks (pos, blocks) VALUES(?2, ?1);",
-                    (chunk.serialize(), pos),
-                )
-                .unwrap();
+            if let Err(e) = self.storage.save_chunk(world.dimension, &chunk) {
+                eprintln!("Could not save chunk {pos:?}: {e:#}");
+            }
+        }
+        if let Err(e) = self.save_pending_blocks() {
+            eprintln!("Could not save pending structure blocks: {e:#}");
+        }
+        if let Err(e) = self.storage.flush() {
+            eprintln!("Could not save the world: {e:#}");
         }
 
+        self.dirty_chunks.clear();
         self.loaded_chunks.clear();
         self.player_loaded_chunks.clear();
         self.connections.clear();
     }
 
-    fn clean_up_disconnected_player(&mut self, player: Uuid) {
+    /// Close the connection of a player the server failed to serve, e.g. because their chunks
+    /// could not be loaded. They are cleaned up once the disconnect is noticed, like any other.
+    fn drop_player(&self, player: Uuid, error: &anyhow::Error) {
+        eprintln!("Dropping {player}: {error:#}");
+        if let Some((conn, _)) = self.connections.get(&player) {
+            conn.transport.close(&format!("{error:#}"));
+        }
+    }
+
+    /// Send a message to every connected player except `except`
+    async fn broadcast(&mut self, msg: &MessageToClient, except: Option<Uuid>) {
+        for (player, (conn, _)) in &self.connections {
+            if Some(*player) == except {
+                continue;
+            }
+            conn.transport.send(msg);
+        }
+    }
+
+    async fn clean_up_disconnected_player(&mut self, player: Uuid) {
         if let Some(loaded_chunks) = self.player_loaded_chunks.remove(&player) {
+            let mut unloaded = vec![];
             for pos in loaded_chunks {
                 let count = self.loaded_chunks.get_mut(&pos).unwrap();
                 *count -= 1;
@@ -119,17 +554,37 @@ impl Server {
                     self.loaded_chunks.remove(&pos);
                     let mut world = self.ecs_world.resource_mut::<World>();
                     let chunk = world.chunks.remove(&pos).unwrap();
-                    self.db
-                        .execute(
-                            "INSERT OR REPLACE INTO chunks (pos, blocks) VALUES(?2, ?1);",
-                            (chunk.serialize(), pos),
-                        )
-                        .unwrap();
+                    if let Err(e) = self.storage.save_chunk(world.dimension, &chunk) {
+                        eprintln!("Could not save chunk {pos:?}: {e:#}");
+                    }
+                    self.revisions.forget(pos);
+                    unloaded.push(pos);
                 }
             }
+            if let Err(e) = self.save_entities(unloaded) {
+                eprintln!("Could not save entities: {e:#}");
+            }
         }
 
+        self.ecs_world
+            .resource_mut::<PlayerPositions>()
+            .0
+            .remove(&player);
+        self.inventories.remove(&player);
+        self.known_mobs.remove(&player);
+        self.chunk_streams.remove(&player);
         self.connections.remove(&player);
+        if let Err(e) = self.storage.flush() {
+            eprintln!("Could not save the world: {e:#}");
+        }
+        if self.connections.is_empty() {
+            self.idle_since = Some(Instant::now());
+        }
+
+        if let Some(name) = self.player_names.remove(&player) {
+            self.broadcast(&MessageToClient::Chat(format!("{name} left the game")), None)
+                .await;
+        }
     }
 
     pub async fn run(&mut self) {
@@ -140,8 +595,15 @@ impl Server {
 
         async_std::task::spawn(accept(endpoint, tx));
 
-        let mut tick_interval =
-            async_std::stream::interval(Duration::from_secs_f32(1.0 / 20.0)).fuse();
+        let (console_tx, console_rx) = async_std::channel::unbounded();
+        let mut console_rx = console_rx.fuse();
+        async_std::task::spawn(async move {
+            if let Err(e) = console::read_commands(console_tx).await {
+                eprintln!("Stopped reading console commands: {e}");
+            }
+        });
+
+        let mut tick_interval = async_std::stream::interval(self.tick_interval).fuse();
 
         let receiver = &self.shutdown_signal.clone();
         let mut shutdown = receiver.recv().fuse();
@@ -152,23 +614,12 @@ impl Server {
         let mut runs = 0;
         // let (send_to_server, recv_to_server) = async_std::channel::unbounded();
 
-        println!("Starting listening serverside...");
+        println!("Listening on {}...", self.local_addr());
         loop {
             futures::select! {
                 x = rx.next() => match x {
-                    Some(conn) => {
-                        println!("Connection received from {}", conn.player_id);
-                        let transport = conn.transport.clone();
-
-                        // Incoming messages are sent over this channel
-                        let (send_to_server, recv_to_server) = async_std::channel::unbounded();
-                        // Spawn task that constantly reads messages from the player
-                        async_std::task::spawn(async move {
-                            read_messages(transport, conn.player_id, send_to_server).await.unwrap();
-                        });
-
-                        self.player_loaded_chunks.insert(conn.player_id, FastHashSet::default());
-                        self.connections.insert(conn.player_id, (conn, recv_to_server));
+                    Some(login) => {
+                        self.log_in(login).await;
                     },
                     None => {
                         break;
@@ -186,6 +637,16 @@ impl Server {
 
                     self.tick().await;
                 }
+                command = console_rx.next() => {
+                    if let Some(command) = command {
+                        if command == Command::Stop {
+                            println!("Stopping server...");
+                            self.shutdown();
+                            break;
+                        }
+                        self.run_command(CommandSource::Console, command).await;
+                    }
+                }
                 _ = shutdown => {
                     self.shutdown();
                     break;
@@ -194,7 +655,154 @@ impl Server {
         }
     }
 
+    /// Accept or reject a new connection's login. Accepted players join the game under their
+    /// account's id, so they are the same player every time they connect.
+    async fn log_in(&mut self, login: Login) {
+        let Login {
+            transport,
+            name,
+            token,
+            mut respond,
+        } = login;
+        let account = match accounts::authenticate(&mut *self.storage, &name, token) {
+            Ok(Ok(account)) if self.connections.contains_key(&account.id) => {
+                Err(format!("{} is already logged in", account.name))
+            }
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("Could not load the account of {name}: {e:#}");
+                Err("Could not load your account, try again later".to_string())
+            }
+        };
+
+        let account = match account {
+            Ok(account) => account,
+            Err(reason) => {
+                println!("Rejected login of {name}: {reason}");
+                // Closing the connection right away could drop the response, so wait for the
+                // client to get it, without holding up the tick loop
+                async_std::task::spawn(async move {
+                    let sent = respond
+                        .respond(&MessageToClient::LoginRejected(reason))
+                        .await;
+                    if let Err(e) = sent {
+                        log::warn!("Could not send login rejection: {e:#}");
+                    } else if let Err(e) = respond.finish().await {
+                        log::warn!("Could not send login rejection: {e:#}");
+                    }
+                    transport.close("login rejected");
+                });
+                return;
+            }
+        };
+
+        let response = MessageToClient::LoggedIn {
+            player: account.id,
+            name: account.name.clone(),
+            token: account.token,
+            spawn: self.spawn_points.of(account.id).to_vec(),
+        };
+        if let Err(e) = respond.respond(&response).await {
+            log::warn!("Could not accept login of {}: {e:#}", account.name);
+            return;
+        }
+
+        let player_id = account.id;
+        let conn = Connection {
+            player_id,
+            transport,
+        };
+        println!("Connection received from {player_id}");
+        let transport = conn.transport.clone();
+
+        // Incoming messages are sent over this channel
+        let (send_to_server, recv_to_server) = async_std::channel::unbounded();
+        // Spawn task that constantly reads messages from the player. When anything goes wrong,
+        // only this player's connection is closed, which closes the channel and gets the player
+        // cleaned up.
+        async_std::task::spawn(async move {
+            if let Err(e) = read_messages(transport.clone(), player_id, send_to_server).await {
+                println!("Dropping connection of {player_id}: {e:#}");
+                transport.close(&e.to_string());
+            }
+        });
+
+        self.player_loaded_chunks.insert(player_id, FastHashSet::default());
+        self.idle_since = None;
+        self.connections.insert(player_id, (conn, recv_to_server));
+        self.join(player_id, account.name).await;
+    }
+
+    /// Announce a player that just logged in, and send them what they need to start playing
+    async fn join(&mut self, player_id: Uuid, name: String) {
+        println!("{player_id} joined as {name}");
+        self.broadcast(
+            &MessageToClient::Chat(format!("{name} joined the game")),
+            Some(player_id),
+        )
+        .await;
+        self.player_names.insert(player_id, name);
+
+        let (conn, _) = &self.connections[&player_id];
+        conn.transport
+            .send(&MessageToClient::Chat(self.motd.clone()));
+        let seed = self.ecs_world.resource::<World>().worldgen.seed();
+        conn.transport.send(&MessageToClient::WorldSeed(seed));
+
+        let npcs = self
+            .ecs_world
+            .query::<(&Npc, &Position)>()
+            .iter(&self.ecs_world)
+            .map(|(npc, pos)| MessageToClient::NpcSpawned {
+                npc: npc.id,
+                name: npc.name.clone(),
+                position: pos.0.to_vec(),
+            })
+            .collect::<Vec<_>>();
+        for msg in &npcs {
+            conn.transport.send(msg);
+        }
+        self.inventories.insert(player_id, Inventory::default());
+        self.send_inventory(player_id).await;
+        self.respawn(player_id).await;
+    }
+
+    /// Send a player back to their spawn point
+    async fn respawn(&mut self, player_id: Uuid) {
+        let position = self.spawn_points.of(player_id);
+        self.ecs_world
+            .resource_mut::<PlayerPositions>()
+            .0
+            .insert(player_id, PlayerPosition::new(position));
+        let (conn, _) = &self.connections[&player_id];
+        conn.transport.send(&MessageToClient::Respawn {
+            position: position.to_vec(),
+        });
+    }
+
+    /// The connected player called `player`, or else the one whose id starts with it
+    fn find_player(&self, player: &str) -> Result<Uuid, String> {
+        let by_name = self
+            .player_names
+            .iter()
+            .find(|&(id, name)| name == player && self.connections.contains_key(id));
+        if let Some((&player_id, _)) = by_name {
+            return Ok(player_id);
+        }
+        let matching = self
+            .connections
+            .keys()
+            .filter(|id| id.to_string().starts_with(player))
+            .collect::<Vec<_>>();
+        let &[&player_id] = matching.as_slice() else {
+            return Err(format!("`{player}` matches {} players, expected one", matching.len()));
+        };
+        Ok(player_id)
+    }
+
     pub async fn tick(&mut self) {
+        let tick_start = Instant::now();
+
         // We collect all incoming messages into a vec, so that we can avoid having `self` borrowed while
         // we do stuff with them
         let mut msgs = vec![];
@@ -207,26 +815,122 @@ impl Server {
                     Ok(x) => msgs.push((conn.player_id, x)),
                     Err(TryRecvError::Empty) => break,
                     Err(TryRecvError::Closed) => {
-                        disconnected_players.push(conn.player_id);
+                        disconnected_players.push((conn.player_id, conn.transport.disconnect()));
                         break;
                     },
                 }
             }
         }
         
-        for player in disconnected_players {
-            println!("Player disconnected! Cleaning up");
-            self.clean_up_disconnected_player(player);
+        for (player, disconnect) in disconnected_players {
+            match disconnect {
+                Some(disconnect) => println!("{player} disconnected: {disconnect}"),
+                None => println!("{player} disconnected"),
+            }
+            self.clean_up_disconnected_player(player).await;
         }
 
         for (player_id, (msg, mut respond)) in msgs {
+            // Messages sent right before disconnecting are dropped along with the player
+            if !self.connections.contains_key(&player_id) {
+                continue;
+            }
+            self.metrics.record_message(msg.name());
             match msg {
-                MessageToServer::Connect => {}
-                MessageToServer::UpdatePlayerPosition { new_position } => {}
+                MessageToServer::Version(_) | MessageToServer::Connect { .. } => {
+                    log::warn!("{player_id} logged in again, ignoring it");
+                }
+                MessageToServer::UpdatePlayerPosition {
+                    new_position,
+                    orientation,
+                } => {
+                    let now = Instant::now();
+                    let world = self.ecs_world.resource::<World>();
+                    let block_registry = self.ecs_world.resource::<BlockRegistry>();
+                    let player_positions = self.ecs_world.resource::<PlayerPositions>();
+                    let result = match player_positions.0.get(&player_id) {
+                        Some(previous) => self.movement_rules.validate(
+                            previous,
+                            Point3::from_vec(new_position),
+                            now,
+                            world,
+                            block_registry,
+                        ),
+                        // The first reported position is where the player spawned
+                        None => Ok(()),
+                    };
+
+                    match result {
+                        Ok(()) => {
+                            let position = Point3::from_vec(new_position);
+                            let mut player_positions =
+                                self.ecs_world.resource_mut::<PlayerPositions>();
+                            let updated = match player_positions.0.get(&player_id) {
+                                Some(previous) => previous.moved_to(position, now),
+                                None => PlayerPosition::new(position),
+                            };
+                            player_positions.0.insert(player_id, updated);
+                            self.broadcast(
+                                &MessageToClient::PlayerMoved {
+                                    player: player_id,
+                                    position: new_position,
+                                    orientation,
+                                },
+                                Some(player_id),
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            println!("Rejected movement of {player_id}: {e}");
+                            // Send the player back to where the server thinks they are
+                            let position = self.ecs_world.resource::<PlayerPositions>().0
+                                [&player_id]
+                                .position;
+                            let (conn, _) = &self.connections[&player_id];
+                            conn.transport.send(&MessageToClient::Teleport {
+                                position: position.to_vec(),
+                            });
+                        }
+                    }
+                }
                 MessageToServer::GetChunks(chunks) => {
-                    let mut chunks = chunks
-                        .into_iter()
-                        .map(|pos| self.load_chunk(player_id, pos));
+                    let mut unchanged = vec![];
+                    let mut deltas = vec![];
+                    let mut failed = None;
+                    let mut chunks = chunks.into_iter().filter_map(|(pos, cached)| {
+                        let chunk = match self.load_chunk(player_id, pos) {
+                            Ok(chunk) => chunk,
+                            Err(e) => {
+                                failed.get_or_insert(e.context(format!("loading chunk {pos:?}")));
+                                return None;
+                            }
+                        };
+                        let revision = self.revisions.revision(pos);
+                        let Some(cached) = cached else {
+                            return Some((chunk, revision));
+                        };
+                        let hash = chunk.content_hash();
+                        if cached.hash == hash {
+                            unchanged.push(pos);
+                            return None;
+                        }
+                        match self.revisions.changed_since(pos, cached.revision) {
+                            Some(changed) => {
+                                let changes = changed
+                                    .into_iter()
+                                    .map(|block_pos| (block_pos, *chunk.block(block_pos.rel_pos())))
+                                    .collect();
+                                deltas.push(MessageToClient::ChunkDelta {
+                                    pos,
+                                    revision,
+                                    hash,
+                                    changes,
+                                });
+                                None
+                            }
+                            None => Some((chunk, revision)),
+                        }
+                    });
 
                     loop {
                         // Batch chunks so that the client can start rendering before all are sent over the network
@@ -236,10 +940,37 @@ impl Server {
                             break;
                         }
 
-                        respond
-                            .respond(&MessageToClient::Chunks(chunks))
-                            .await
-                            .unwrap();
+                        if let Err(e) = respond.respond(&MessageToClient::Chunks(chunks)).await {
+                            log::warn!("Could not send chunks to {player_id}: {e:#}");
+                            break;
+                        }
+                    }
+
+                    if !unchanged.is_empty() {
+                        let msg = MessageToClient::ChunksUnchanged(unchanged);
+                        if let Err(e) = respond.respond(&msg).await {
+                            log::warn!("Could not send unchanged chunks to {player_id}: {e:#}");
+                        }
+                    }
+                    for delta in deltas {
+                        if let Err(e) = respond.respond(&delta).await {
+                            log::warn!("Could not send chunk changes to {player_id}: {e:#}");
+                            break;
+                        }
+                    }
+                    // The client would wait for the missing chunks forever
+                    if let Some(e) = failed {
+                        self.drop_player(player_id, &e);
+                    }
+                }
+                MessageToServer::GetChunksLod { chunks, level } => {
+                    let chunks = chunks
+                        .into_iter()
+                        .map(|pos| self.lod_chunk(pos, level))
+                        .collect();
+
+                    if let Err(e) = respond.respond(&MessageToClient::LodChunks(chunks)).await {
+                        log::warn!("Could not send LOD chunks to {player_id}: {e:#}");
                     }
                 }
                 MessageToServer::UnloadChunks(chunks) => {
@@ -247,42 +978,927 @@ impl Server {
                         self.unload_chunk(player_id, pos);
                     }
                 }
-                MessageToServer::ReplaceBlock { pos, new_block } => {
+                MessageToServer::StreamChunks { distance } => {
+                    self.chunk_streams
+                        .insert(player_id, distance.min(streaming::MAX_DISTANCE));
+                }
+                MessageToServer::ReplaceBlock { pos, new_block, edit } => {
+                    let allowed = self.check_reach(player_id, pos).and_then(|()| {
+                        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+                        if block_registry.get(new_block.id).is_none() {
+                            anyhow::bail!("there is no block with id {}", new_block.id.0);
+                        }
+                        self.take_placed_item(player_id, new_block)
+                    });
+                    let placed_item = match allowed {
+                        Ok(placed_item) => placed_item,
+                        Err(e) => {
+                            println!("Rejected edit of {pos:?} by {player_id}: {e}");
+                            // The player already made the edit locally, so send the block back
+                            if let Some(&block) = self.ecs_world.resource::<World>().block(pos) {
+                                self.pending_block_changes.push((Some(player_id), pos, block));
+                            }
+                            self.acknowledged_edits.insert(player_id, edit);
+                            continue;
+                        }
+                    };
                     let chunk_pos = pos.chunk_pos();
                     let rel_pos = pos.rel_pos();
                     let mut world = self.ecs_world.resource_mut::<World>();
                     // If the chunk is loaded, directly modify it
-                    if let Some(chunk) = world.chunk_mut(chunk_pos) {
-                        *chunk.block_mut(rel_pos) = new_block;
+                    let old_block = if let Some(chunk) = world.chunk_mut(chunk_pos) {
+                        std::mem::replace(chunk.block_mut(rel_pos), new_block)
                     } else {
                         // Else, load it, modify it, and then unload it
-                        self.load_chunk(player_id, chunk_pos);
+                        if let Err(e) = self.load_chunk(player_id, chunk_pos) {
+                            println!("Could not apply edit of {pos:?} by {player_id}: {e:#}");
+                            if let Some(item) = placed_item {
+                                self.inventories.entry(player_id).or_default().add(&item);
+                            }
+                            self.acknowledged_edits.insert(player_id, edit);
+                            continue;
+                        }
                         let mut world = self.ecs_world.resource_mut::<World>();
                         let chunk = world.chunk_mut(chunk_pos).unwrap();
-                        *chunk.block_mut(rel_pos) = new_block;
+                        let old_block = std::mem::replace(chunk.block_mut(rel_pos), new_block);
                         self.unload_chunk(player_id, chunk_pos);
+                        old_block
                     };
 
-                    // Propagate block placements to all connected players
-                    for (player, (conn, _)) in &self.connections {
-                        if *player == player_id {
-                            println!("Skipping sending to {player}");
-                            continue;
-                        }
-                        println!("Sending to {player}");
+                    self.pending_block_changes
+                        .push((Some(player_id), pos, new_block));
+                    self.acknowledged_edits.insert(player_id, edit);
+                    self.ecs_world
+                        .resource_mut::<BlockUpdates>()
+                        .schedule_around(pos, 1);
 
+                    if placed_item.is_some() {
+                        self.send_inventory(player_id).await;
+                    }
+                    // Mining gives the block's item
+                    if let Some(item) = self.mined_item(old_block)
+                        && new_block.id == BlockId(0)
+                    {
+                        self.inventories.entry(player_id).or_default().add(&item);
+                        self.send_inventory(player_id).await;
+                    }
+                }
+                MessageToServer::FillRegion { min, max, block } => {
+                    if self.permission(CommandSource::Player(player_id)) < Permission::Operator {
+                        println!("Rejected fill by {player_id}: not an operator");
+                        continue;
+                    }
+                    match self.fill_region(Some(player_id), min, max, block) {
+                        Ok(count) => println!("{player_id} filled {count} blocks"),
+                        Err(e) => println!("Rejected fill by {player_id}: {e}"),
+                    }
+                }
+                MessageToServer::OpenTrade { npc } => {
+                    if let Some(offers) = self.npc_trades(player_id, npc) {
+                        let (conn, _) = &self.connections[&player_id];
                         conn.transport
-                            .transact::<_, ()>(&MessageToClient::BlockPlaced { pos, new_block })
-                            .await
-                            .unwrap();
+                            .send(&MessageToClient::TradeOffers { npc, offers });
+                    }
+                }
+                MessageToServer::Trade { npc, offer } => {
+                    let result = self.trade(player_id, npc, offer);
+                    let text = match result {
+                        Ok(trade) => format!("Traded {} for {}", trade.cost, trade.reward),
+                        Err(e) => format!("Trade failed: {e}"),
+                    };
+                    let (conn, _) = &self.connections[&player_id];
+                    conn.transport.send(&MessageToClient::Chat(text));
+                    self.send_inventory(player_id).await;
+                }
+                MessageToServer::UseBlock { pos } => {
+                    if let Err(e) = self.use_block(player_id, pos) {
+                        println!("Rejected use of {pos:?} by {player_id}: {e}");
+                    }
+                }
+                MessageToServer::GetMetrics => {
+                    let metrics = self.metrics();
+                    let (conn, _) = &self.connections[&player_id];
+                    conn.transport.send(&MessageToClient::Metrics(metrics));
+                }
+                MessageToServer::Respawn => {
+                    println!("{player_id} died");
+                    self.respawn(player_id).await;
+                }
+                MessageToServer::Chat(text) => {
+                    let text = text.trim();
+                    if text.is_empty() {
+                        continue;
+                    }
+                    if text.starts_with('/') {
+                        let source = CommandSource::Player(player_id);
+                        match text.parse() {
+                            Ok(command) => self.run_command(source, command).await,
+                            Err(e) => {
+                                let (conn, _) = &self.connections[&player_id];
+                                conn.transport.send(&MessageToClient::Chat(e));
+                            }
+                        }
+                        continue;
+                    }
+                    let name = self.player_names.get(&player_id).map_or("?", String::as_str);
+                    let line = format!("<{name}> {text}");
+                    println!("{line}");
+                    self.broadcast(&MessageToClient::Chat(line), None).await;
+                }
+                MessageToServer::Disconnect => {
+                    println!("{player_id} disconnected");
+                    self.clean_up_disconnected_player(player_id).await;
+                    // The client closes the connection once it has this
+                    if let Err(e) = respond.respond(&MessageToClient::Ok).await {
+                        log::warn!("Could not confirm disconnect of {player_id}: {e:#}");
+                    }
+                }
+            }
+        }
+
+        self.ecs_world.run_schedule(ScheduleStage::Tick);
+        self.time += 1;
+
+        let changes = std::mem::take(&mut self.ecs_world.resource_mut::<BlockUpdates>().changes);
+        self.pending_block_changes
+            .extend(changes.into_iter().map(|(pos, block)| (None, pos, block)));
+        self.send_block_changes().await;
+        self.send_mob_updates().await;
+        self.stream_chunks().await;
+
+        self.save_if_idle();
+        self.autosave_if_due();
+        if self.check_chunk_counts {
+            let problems = self.check_loaded_chunks();
+            assert!(
+                problems.is_empty(),
+                "Loaded chunks are out of sync:\n{}",
+                problems.join("\n")
+            );
+        }
+        // Prefetching fills up whatever is left of its budget, so it isn't counted
+        self.metrics.record_tick(tick_start.elapsed());
+        self.log_metrics_if_due();
+        self.prefetch_chunks(tick_start + self.tick_interval.mul_f32(PREFETCH_SHARE));
+    }
+
+    fn metrics(&self) -> ServerMetrics {
+        self.metrics
+            .snapshot(self.loaded_chunks.len(), self.connections.len())
+    }
+
+    fn log_metrics_if_due(&mut self) {
+        if let Some(interval) = self.metrics_interval
+            && self.metrics_logged.elapsed() >= interval
+        {
+            let metrics = self.metrics();
+            println!("Metrics: {metrics}");
+            println!("Messages received: {}", metrics.message_counts());
+            self.metrics_logged = Instant::now();
+        }
+    }
+
+    /// Every way the loaded chunk reference counts disagree with the chunks players have
+    /// loaded, or with the chunks in the world
+    fn check_loaded_chunks(&self) -> Vec<String> {
+        let mut expected = FastHashMap::<ChunkPos, usize>::default();
+        for loaded in self.player_loaded_chunks.values() {
+            for &pos in loaded {
+                *expected.entry(pos).or_default() += 1;
+            }
+        }
+
+        let mut problems = vec![];
+        for (pos, &count) in &self.loaded_chunks {
+            let players = expected.get(pos).copied().unwrap_or(0);
+            if count != players {
+                problems.push(format!(
+                    "{pos:?} has a count of {count}, but {players} players have it loaded"
+                ));
+            }
+        }
+        for pos in expected.keys() {
+            if !self.loaded_chunks.contains_key(pos) {
+                problems.push(format!("{pos:?} is loaded by players but has no count"));
+            }
+        }
+
+        let world = self.ecs_world.resource::<World>();
+        for pos in self.loaded_chunks.keys() {
+            if !world.chunks.contains_key(pos) {
+                problems.push(format!("{pos:?} is counted but not in the world"));
+            }
+        }
+        for pos in world.chunks.keys() {
+            if !self.loaded_chunks.contains_key(pos) {
+                problems.push(format!("{pos:?} is in the world but not counted"));
+            }
+        }
+        problems
+    }
+
+    /// Read or generate the chunks players are heading towards until `deadline`
+    fn prefetch_chunks(&mut self, deadline: Instant) {
+        let player_positions = self.ecs_world.resource::<PlayerPositions>();
+        let mut players = vec![];
+        let mut candidates = vec![];
+        for (player, position) in &player_positions.0 {
+            let Some(loaded) = self.player_loaded_chunks.get(player) else {
+                continue;
+            };
+            // Clients load every chunk within their loading distance, so the furthest chunk
+            // they have tells how far out they will ask for more
+            let center = BlockPos::from_point(position.position).chunk_pos();
+            let Some(radius) = loaded
+                .iter()
+                .map(|&pos| prefetch::chebyshev_distance(pos, center))
+                .max()
+            else {
+                continue;
+            };
+            players.push((center, radius + 1));
+            candidates.extend(ChunkPrefetcher::candidates(position, radius, |pos| {
+                self.loaded_chunks.contains_key(&pos)
+            }));
+        }
+
+        self.prefetcher.retain_near(&players);
+        for pos in candidates {
+            if Instant::now() >= deadline || self.prefetcher.is_full() {
+                break;
+            }
+            if self.prefetcher.contains(pos) {
+                continue;
+            }
+            let world = self.ecs_world.resource::<World>();
+            // Loading the chunk for real reports the error, if it happens again then
+            let chunk = match self.storage.load_chunk(world.dimension, pos) {
+                Ok(Some(chunk)) => PrefetchedChunk::Stored(chunk),
+                Ok(None) => {
+                    let (chunk, overhang) = world.worldgen.generate(pos);
+                    PrefetchedChunk::Generated(chunk, overhang)
+                }
+                Err(_) => continue,
+            };
+            self.prefetcher.insert(pos, chunk);
+        }
+    }
+
+    /// The trades of `npc`, if `player` is close enough to trade with it
+    fn npc_trades(&mut self, player: Uuid, npc: NpcId) -> Option<Vec<Trade>> {
+        let player_pos = self
+            .ecs_world
+            .resource::<PlayerPositions>()
+            .0
+            .get(&player)?
+            .position;
+        self.ecs_world
+            .query::<(&Npc, &Position, &Trades)>()
+            .iter(&self.ecs_world)
+            .find(|(candidate, _, _)| candidate.id == npc)
+            .filter(|(_, pos, _)| pos.0.distance(player_pos) <= Npc::REACH)
+            .map(|(_, _, trades)| trades.0.clone())
+    }
+
+    /// Make one of an NPC's trades for `player`, checking that they are in reach and have the
+    /// items to pay with
+    fn trade(&mut self, player: Uuid, npc: NpcId, offer: usize) -> anyhow::Result<Trade> {
+        let trades = self
+            .npc_trades(player, npc)
+            .ok_or_else(|| anyhow::anyhow!("too far away"))?;
+        let trade = trades
+            .into_iter()
+            .nth(offer)
+            .ok_or_else(|| anyhow::anyhow!("no such offer"))?;
+        let inventory = self.inventories.entry(player).or_default();
+        if !inventory.remove(&trade.cost) {
+            anyhow::bail!("not enough {}", trade.cost.item);
+        }
+        inventory.add(&trade.reward);
+        Ok(trade)
+    }
+
+    /// Fail unless the block at `pos` is within the reach of `player`
+    fn check_reach(&self, player: Uuid, pos: BlockPos) -> anyhow::Result<()> {
+        let player_pos = self
+            .ecs_world
+            .resource::<PlayerPositions>()
+            .0
+            .get(&player)
+            .ok_or_else(|| anyhow::anyhow!("position unknown"))?
+            .position;
+        let eyes = player_pos + Vector3::unit_y() * EYE_HEIGHT;
+        let center = Point3::from(pos).cast::<f32>().unwrap() + Vector3::new(0.5, 0.5, 0.5);
+        if center.distance(eyes) > self.reach + REACH_TOLERANCE {
+            anyhow::bail!("too far away");
+        }
+        Ok(())
+    }
+
+    /// Set every block in the box between `a` and `b` to `block`, a chunk at a time, returning
+    /// how many blocks changed. The changes go out to players with the next block changes.
+    fn fill_region(
+        &mut self,
+        editor: Option<Uuid>,
+        a: BlockPos,
+        b: BlockPos,
+        block: Block,
+    ) -> Result<usize, String> {
+        let (a, b) = (Point3::from(a), Point3::from(b));
+        let min = Point3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z));
+        let max = Point3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z));
+        let size = (max - min).map(|length| length.unsigned_abs().saturating_add(1));
+        let count = size.x.saturating_mul(size.y).saturating_mul(size.z);
+        if count > MAX_FILL_BLOCKS {
+            return Err(format!(
+                "The region has {count} blocks, at most {MAX_FILL_BLOCKS} can be filled at once"
+            ));
+        }
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        if block_registry.get(block.id).is_none() {
+            return Err(format!("There is no block with id {}", block.id.0));
+        }
+
+        let (min_chunk, max_chunk) = (
+            Point3::from(BlockPos::from(min).chunk_pos()),
+            Point3::from(BlockPos::from(max).chunk_pos()),
+        );
+        let size = Chunk::SIZE as isize;
+        let mut changed = 0;
+        for cx in min_chunk.x..=max_chunk.x {
+            for cy in min_chunk.y..=max_chunk.y {
+                for cz in min_chunk.z..=max_chunk.z {
+                    let chunk_pos = ChunkPos::from(Point3::new(cx, cy, cz));
+                    // Nobody has the chunk loaded, so the pinning loader can have it just
+                    // while it is filled
+                    let was_loaded = self.loaded_chunks.contains_key(&chunk_pos);
+                    if !was_loaded {
+                        self.load_chunk(pinning::LOADER, chunk_pos)
+                            .map_err(|e| format!("Could not load chunk {chunk_pos:?}: {e:#}"))?;
+                    }
+
+                    let origin = Point3::new(cx, cy, cz) * size;
+                    let mut world = self.ecs_world.resource_mut::<World>();
+                    let chunk = world.chunk_mut(chunk_pos).unwrap();
+                    let mut changes = vec![];
+                    for x in min.x.max(origin.x)..=max.x.min(origin.x + size - 1) {
+                        for y in min.y.max(origin.y)..=max.y.min(origin.y + size - 1) {
+                            for z in min.z.max(origin.z)..=max.z.min(origin.z + size - 1) {
+                                let pos = BlockPos::from(Point3::new(x, y, z));
+                                let old_block =
+                                    std::mem::replace(chunk.block_mut(pos.rel_pos()), block);
+                                if old_block != block {
+                                    changes.push(pos);
+                                }
+                            }
+                        }
+                    }
+
+                    let mut block_updates = self.ecs_world.resource_mut::<BlockUpdates>();
+                    for &pos in &changes {
+                        block_updates.schedule_around(pos, 1);
+                    }
+                    changed += changes.len();
+                    self.pending_block_changes
+                        .extend(changes.into_iter().map(|pos| (editor, pos, block)));
+                    if !was_loaded {
+                        self.unload_chunk(pinning::LOADER, chunk_pos);
                     }
                 }
             }
         }
+        Ok(changed)
+    }
+
+    /// Flip the lever at `pos` for `player`, who has to be within reach of it
+    fn use_block(&mut self, player: Uuid, pos: BlockPos) -> anyhow::Result<()> {
+        self.check_reach(player, pos)?;
+
+        let mut world = self.ecs_world.resource_mut::<World>();
+        let Some(chunk) = world.chunk_mut(pos.chunk_pos()) else {
+            anyhow::bail!("chunk not loaded");
+        };
+        let block = chunk.block_mut(pos.rel_pos());
+        if block.id != signal::LEVER {
+            anyhow::bail!("not a lever");
+        }
+        *block = signal::toggle_lever(*block);
+        let block = *block;
+
+        self.pending_block_changes.push((Some(player), pos, block));
+        self.ecs_world
+            .resource_mut::<BlockUpdates>()
+            .schedule_around(pos, 1);
+        Ok(())
+    }
+
+    /// The item a player gets for mining `block`
+    fn mined_item(&self, block: Block) -> Option<ItemStack> {
+        let block_registry = self.ecs_world.resource::<BlockRegistry>();
+        let attributes = block_registry.get(block.id)?;
+        if attributes.invisible || attributes.liquid {
+            return None;
+        }
+        Some(ItemStack {
+            item: attributes.name.to_string(),
+            count: 1,
+        })
+    }
+
+    /// Take the item needed to place `block` from the inventory of `player`, returning it so it
+    /// can be given back if the block can't be placed after all. Placing air takes nothing.
+    fn take_placed_item(
+        &mut self,
+        player: Uuid,
+        block: Block,
+    ) -> anyhow::Result<Option<ItemStack>> {
+        if block.id == BlockId(0) {
+            return Ok(None);
+        }
+        let Some(item) = self.mined_item(block) else {
+            anyhow::bail!("block {} can't be placed", block.id.0);
+        };
+        if !self.inventories.entry(player).or_default().remove(&item) {
+            anyhow::bail!("they have no {}", item.item);
+        }
+        Ok(Some(item))
+    }
+
+    async fn send_inventory(&mut self, player: Uuid) {
+        let Some((conn, _)) = self.connections.get(&player) else {
+            return;
+        };
+        let stacks = self
+            .inventories
+            .get(&player)
+            .map(Inventory::stacks)
+            .unwrap_or_default();
+        conn.transport.send(&MessageToClient::Inventory(stacks));
     }
 
-    /// Loads a chunk, or generates it if no such chunk exists
-    pub fn load_chunk(&mut self, loader: Uuid, pos: ChunkPos) -> Chunk {
+    /// Send this tick's block changes to the players that have the affected chunks loaded,
+    /// batched into one message per player. Players get their own changes back too, along with
+    /// the last edit of theirs that was applied, so they can reconcile them with their local
+    /// world.
+    async fn send_block_changes(&mut self) {
+        if self.pending_block_changes.is_empty() {
+            return;
+        }
+        let changes = std::mem::take(&mut self.pending_block_changes);
+        self.dirty_chunks
+            .extend(changes.iter().map(|(_, pos, _)| pos.chunk_pos()));
+        for &(_, pos, _) in &changes {
+            self.revisions.record(pos);
+        }
+        let mut acknowledged_edits = std::mem::take(&mut self.acknowledged_edits);
+
+        for (player, (conn, _)) in &self.connections {
+            let Some(loaded) = self.player_loaded_chunks.get(player) else {
+                continue;
+            };
+            let relevant = changes
+                .iter()
+                .filter(|(_, pos, _)| loaded.contains(&pos.chunk_pos()))
+                .map(|&(_, pos, block)| (pos, block))
+                .collect::<Vec<_>>();
+            let acknowledged_edit = acknowledged_edits.remove(player);
+            if relevant.is_empty() && acknowledged_edit.is_none() {
+                continue;
+            }
+
+            conn.transport.send(&MessageToClient::BlocksPlaced {
+                changes: relevant,
+                acknowledged_edit,
+            });
+        }
+    }
+
+    /// Tell every player about the mobs in the chunks they have loaded that moved, and the ones
+    /// that are gone from there
+    async fn send_mob_updates(&mut self) {
+        let mobs = self
+            .ecs_world
+            .query_filtered::<(&Persistent, &Position), With<Mob>>()
+            .iter(&self.ecs_world)
+            .map(|(persistent, pos)| (persistent.0, pos.0))
+            .collect::<Vec<_>>();
+
+        for (player, (conn, _)) in &self.connections {
+            let Some(loaded) = self.player_loaded_chunks.get(player) else {
+                continue;
+            };
+            let known = self.known_mobs.entry(*player).or_default();
+            let visible = mobs
+                .iter()
+                .filter(|(_, pos)| loaded.contains(&BlockPos::from_point(*pos).chunk_pos()))
+                .copied()
+                .collect::<FastHashMap<_, _>>();
+
+            let moved = visible
+                .iter()
+                .filter(|&(id, pos)| known.get(id) != Some(pos))
+                .map(|(&id, pos)| (id, pos.to_vec()))
+                .collect::<Vec<_>>();
+            let removed = known
+                .keys()
+                .filter(|id| !visible.contains_key(id))
+                .copied()
+                .collect::<Vec<_>>();
+            *known = visible;
+            if moved.is_empty() && removed.is_empty() {
+                continue;
+            }
+
+            conn.transport
+                .send(&MessageToClient::Mobs { moved, removed });
+        }
+    }
+
+    /// What `source` may do
+    fn permission(&self, source: CommandSource) -> Permission {
+        match source {
+            CommandSource::Console => Permission::Console,
+            CommandSource::Player(player) => {
+                let name = self.player_names.get(&player);
+                if name.is_some_and(|name| self.operators.contains(name)) {
+                    Permission::Operator
+                } else {
+                    Permission::Player
+                }
+            }
+        }
+    }
+
+    /// Run a command if `source` is allowed to, and send its output back to them
+    async fn run_command(&mut self, source: CommandSource, command: Command) {
+        let output = if self.permission(source) < command.permission() {
+            Err("You are not allowed to use this command".to_string())
+        } else {
+            self.execute(source, command).await
+        };
+        let output = output.unwrap_or_else(|e| e);
+        match source {
+            CommandSource::Console => println!("{output}"),
+            CommandSource::Player(player) => {
+                let Some((conn, _)) = self.connections.get(&player) else {
+                    return;
+                };
+                for line in output.lines() {
+                    conn.transport
+                        .send(&MessageToClient::Chat(line.to_string()));
+                }
+            }
+        }
+    }
+
+    /// The player a command acts on, the one named or else whoever sent it
+    fn command_target(
+        &self,
+        source: CommandSource,
+        player: Option<String>,
+    ) -> Result<Uuid, String> {
+        match (player, source) {
+            (Some(player), _) => self.find_player(&player),
+            (None, CommandSource::Player(player)) => Ok(player),
+            (None, CommandSource::Console) => {
+                Err("Name a player to use this command from the console".to_string())
+            }
+        }
+    }
+
+    /// Run a command, returning its output
+    async fn execute(&mut self, source: CommandSource, command: Command) -> Result<String, String> {
+        match command {
+            Command::SaveAll => {
+                self.save_all()
+                    .map_err(|e| format!("Could not save the world: {e:#}"))?;
+                Ok(format!("Saved {} chunks", self.loaded_chunks.len()))
+            }
+            Command::List => {
+                let mut lines = vec![format!("{} player(s) connected", self.connections.len())];
+                for player in self.connections.keys() {
+                    let name = self.player_names.get(player).map_or("?", String::as_str);
+                    lines.push(format!("  {player} ({name})"));
+                }
+                Ok(lines.join("\n"))
+            }
+            Command::Stop => unreachable!("handled by the server loop"),
+            Command::Seed { dimension } => {
+                let world = self.ecs_world.resource::<World>();
+                let dimension = dimension.unwrap_or(world.dimension);
+                if dimension == world.dimension {
+                    Ok(format!("Seed of {dimension}: {}", world.worldgen.seed()))
+                } else {
+                    match self.storage.load_dimension(dimension) {
+                        Ok(Some(info)) => Ok(format!("Seed of {dimension}: {}", info.seed)),
+                        Ok(None) => Err(format!("Dimension {dimension} has not been generated")),
+                        Err(e) => Err(format!("Could not load dimension {dimension}: {e:#}")),
+                    }
+                }
+            }
+            Command::Dimensions => {
+                let loaded = self.ecs_world.resource::<World>().dimension;
+                let lines = self
+                    .storage
+                    .dimensions()
+                    .map_err(|e| format!("Could not list the dimensions: {e:#}"))?
+                    .into_iter()
+                    .map(|dimension| {
+                        let suffix = if dimension == loaded { " (loaded)" } else { "" };
+                        format!("  {dimension}{suffix}")
+                    })
+                    .collect::<Vec<_>>();
+                Ok(lines.join("\n"))
+            }
+            Command::Tp { player, position } => {
+                let player_id = self.command_target(source, player)?;
+                let (conn, _) = &self.connections[&player_id];
+                conn.transport.send(&MessageToClient::Teleport { position });
+                self.ecs_world
+                    .resource_mut::<PlayerPositions>()
+                    .0
+                    .insert(player_id, PlayerPosition::new(Point3::from_vec(position)));
+                Ok(format!("Teleported {player_id} to {position:?}"))
+            }
+            Command::Give {
+                player,
+                item,
+                count,
+            } => {
+                let player_id = self.command_target(source, player)?;
+                let block_registry = self.ecs_world.resource::<BlockRegistry>();
+                if block_registry.names().id(&item).is_none() {
+                    return Err(format!("There is no item called {item}"));
+                }
+                let stack = ItemStack { item, count };
+                self.inventories.entry(player_id).or_default().add(&stack);
+                self.send_inventory(player_id).await;
+                Ok(format!("Gave {stack} to {player_id}"))
+            }
+            Command::Time { set: None } => Ok(format!(
+                "The time is {} on day {}",
+                self.time % command::DAY_LENGTH,
+                self.time / command::DAY_LENGTH
+            )),
+            Command::Time { set: Some(time) } => {
+                // Setting the time never goes back to an earlier day
+                let day = self.time / command::DAY_LENGTH;
+                self.time = day * command::DAY_LENGTH + time;
+                Ok(format!("Set the time to {time}"))
+            }
+            Command::Spawn { player } => {
+                let player_id = self.command_target(source, player)?;
+                self.respawn(player_id).await;
+                Ok(format!("Sent {player_id} back to their spawn point"))
+            }
+            Command::SetSpawn { player, position } => {
+                let position = Point3::from_vec(position);
+                let output = match player {
+                    Some(player) => {
+                        let player_id = self.find_player(&player)?;
+                        self.spawn_points.players.insert(player_id, position);
+                        format!("Set the spawn point of {player_id} to {position:?}")
+                    }
+                    None => {
+                        self.spawn_points.world = position;
+                        format!("Set the world spawn to {position:?}")
+                    }
+                };
+                let dimension = self.ecs_world.resource::<World>().dimension;
+                let saved = self.storage.save_spawn_points(dimension, &self.spawn_points);
+                if let Err(e) = saved {
+                    return Err(format!("Could not save the spawn points: {e:#}"));
+                }
+                // The chunks around the new world spawn are pinned instead
+                self.update_pins();
+                Ok(output)
+            }
+            Command::Chunks { radius } => {
+                let player_positions = self.ecs_world.resource::<PlayerPositions>();
+                // Within `radius` of any player
+                let is_shown = |pos: ChunkPos| {
+                    radius.is_none_or(|radius| {
+                        player_positions.0.values().any(|position| {
+                            let center = BlockPos::from_point(position.position).chunk_pos();
+                            prefetch::chebyshev_distance(pos, center) <= radius
+                        })
+                    })
+                };
+
+                let mut counts = self
+                    .loaded_chunks
+                    .iter()
+                    .filter(|(&pos, _)| is_shown(pos))
+                    .map(|(&pos, &count)| (Point3::from(pos), count))
+                    .collect::<Vec<_>>();
+                counts.sort_by_key(|&(pos, _)| (pos.x, pos.y, pos.z));
+                let mut lines = vec![format!(
+                    "{} loaded chunk(s), {} shown",
+                    self.loaded_chunks.len(),
+                    counts.len()
+                )];
+                for (pos, count) in counts {
+                    lines.push(format!("  ({}, {}, {}): {count}", pos.x, pos.y, pos.z));
+                }
+
+                for (player, loaded) in &self.player_loaded_chunks {
+                    let name = if *player == pinning::LOADER {
+                        "pinned"
+                    } else {
+                        self.player_names.get(player).map_or("?", String::as_str)
+                    };
+                    let center = player_positions
+                        .0
+                        .get(player)
+                        .map(|position| BlockPos::from_point(position.position).chunk_pos());
+                    let mut chunks = loaded
+                        .iter()
+                        .filter(|&&pos| match (radius, center) {
+                            (Some(radius), Some(center)) => {
+                                prefetch::chebyshev_distance(pos, center) <= radius
+                            }
+                            _ => true,
+                        })
+                        .map(|&pos| Point3::from(pos))
+                        .collect::<Vec<_>>();
+                    chunks.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+                    lines.push(format!(
+                        "{player} ({name}) has {} chunk(s) loaded:",
+                        loaded.len()
+                    ));
+                    let chunks = chunks
+                        .iter()
+                        .map(|pos| format!("({}, {}, {})", pos.x, pos.y, pos.z))
+                        .collect::<Vec<_>>();
+                    lines.push(format!("  {}", chunks.join(" ")));
+                }
+                Ok(lines.join("\n"))
+            }
+            Command::CheckChunks { enabled } => {
+                if !enabled {
+                    self.check_chunk_counts = false;
+                    return Ok("Stopped checking loaded chunks".to_string());
+                }
+                // Checking would panic on the next tick, report what's wrong instead
+                let problems = self.check_loaded_chunks();
+                if problems.is_empty() {
+                    self.check_chunk_counts = true;
+                    Ok("Checking loaded chunks every tick".to_string())
+                } else {
+                    let mut lines =
+                        vec!["Loaded chunks are already out of sync, not checking:".to_string()];
+                    for problem in problems {
+                        lines.push(format!("  {problem}"));
+                    }
+                    Err(lines.join("\n"))
+                }
+            }
+            Command::Pin { region: None } => {
+                let mut chunks = self
+                    .pinned
+                    .iter()
+                    .map(|&pos| Point3::from(pos))
+                    .collect::<Vec<_>>();
+                chunks.sort_by_key(|pos| (pos.x, pos.y, pos.z));
+                let chunks = chunks
+                    .iter()
+                    .map(|pos| format!("({}, {}, {})", pos.x, pos.y, pos.z))
+                    .collect::<Vec<_>>();
+                let spawn_chunks = pinning::spawn_chunks(self.spawn_points.world).len();
+                Ok(format!(
+                    "{} chunk(s) pinned, besides the {spawn_chunks} around the world spawn:\n  {}",
+                    chunks.len(),
+                    chunks.join(" ")
+                ))
+            }
+            Command::Pin {
+                region: Some((a, b)),
+            } => {
+                let chunks = pinning::region(a, b)?;
+                let count = chunks
+                    .into_iter()
+                    .filter(|&pos| self.pinned.insert(pos))
+                    .count();
+                self.save_pins()?;
+                self.update_pins();
+                Ok(format!("Pinned {count} chunk(s)"))
+            }
+            Command::Fill { min, max, block } => {
+                let block_registry = self.ecs_world.resource::<BlockRegistry>();
+                let Some(id) = block_registry.names().id(&block) else {
+                    return Err(format!("There is no block called {block}"));
+                };
+                let block = Block {
+                    id,
+                    metadata: BlockMetadata(0),
+                };
+                let count = self.fill_region(None, min, max, block)?;
+                Ok(format!("Filled {count} block(s)"))
+            }
+            Command::Unpin { region: (a, b) } => {
+                let chunks = pinning::region(a, b)?;
+                let count = chunks
+                    .into_iter()
+                    .filter(|pos| self.pinned.remove(pos))
+                    .count();
+                self.save_pins()?;
+                self.update_pins();
+                Ok(format!("Unpinned {count} chunk(s)"))
+            }
+        }
+    }
+
+    /// Send the players streaming chunks the ones that came into their distance, and tell them
+    /// which ones to drop
+    async fn stream_chunks(&mut self) {
+        let streams = self
+            .chunk_streams
+            .iter()
+            .map(|(&player_id, &distance)| (player_id, distance))
+            .collect::<Vec<_>>();
+        for (player_id, distance) in streams {
+            let player_positions = self.ecs_world.resource::<PlayerPositions>();
+            let Some(position) = player_positions.0.get(&player_id).map(|p| p.position) else {
+                continue;
+            };
+            let update =
+                streaming::update(position, distance, &self.player_loaded_chunks[&player_id]);
+            for &pos in &update.unload {
+                self.unload_chunk(player_id, pos);
+            }
+            let mut chunks = vec![];
+            for pos in update.load {
+                match self.load_chunk(player_id, pos) {
+                    Ok(chunk) => chunks.push((chunk, self.revisions.revision(pos))),
+                    Err(e) => {
+                        self.drop_player(player_id, &e.context(format!("loading chunk {pos:?}")));
+                        break;
+                    }
+                }
+            }
+
+            let (conn, _) = &self.connections[&player_id];
+            if !update.unload.is_empty() {
+                conn.transport
+                    .send(&MessageToClient::UnloadChunks(update.unload));
+            }
+            if !chunks.is_empty() {
+                conn.transport.send(&MessageToClient::Chunks(chunks));
+            }
+        }
+    }
+
+    /// Loads a chunk, or generates it if no such chunk exists. If the chunk can't be read from
+    /// storage, it isn't loaded for `loader` either.
+    pub fn load_chunk(&mut self, loader: Uuid, pos: ChunkPos) -> anyhow::Result<Chunk> {
+        let world = self.ecs_world.resource::<World>();
+
+        let chunk = if let Some(chunk) = world.chunk(pos) {
+            println!("Loading chunk {pos:?} from already loaded");
+            chunk.clone()
+        } else if let Some(prefetched) = self.prefetcher.take(pos) {
+            println!("Loading chunk {pos:?} from prefetched");
+            self.load_entities(pos)?;
+            let mut world = self.ecs_world.resource_mut::<World>();
+            match prefetched {
+                PrefetchedChunk::Stored(chunk) => {
+                    for block_pos in world.insert_chunk(chunk) {
+                        self.revisions.record(block_pos);
+                        self.dirty_chunks.insert(pos);
+                    }
+                }
+                PrefetchedChunk::Generated(chunk, overhang) => {
+                    let changes = world.insert_generated_chunk(chunk, overhang);
+                    self.pending_block_changes
+                        .extend(changes.into_iter().map(|(pos, block)| (None, pos, block)));
+                }
+            }
+            world.chunks[&pos].clone()
+        } else if let Some(chunk) = self.storage.load_chunk(world.dimension, pos)? {
+            println!("Loading chunk {pos:?} from storage");
+            self.load_entities(pos)?;
+            let mut world = self.ecs_world.resource_mut::<World>();
+            // Structure blocks that were waiting for the chunk change it since it was saved, so
+            // clients with a copy from back then need to be sent them
+            for block_pos in world.insert_chunk(chunk) {
+                self.revisions.record(block_pos);
+                self.dirty_chunks.insert(pos);
+            }
+            world.chunks[&pos].clone()
+        } else {
+            println!("Loading chunk {pos:?} from newly generated");
+            // Entities that wandered out of the loaded chunks can end up saved with chunks
+            // that were never generated
+            self.load_entities(pos)?;
+            let mut world = self.ecs_world.resource_mut::<World>();
+            // Structures reaching into chunks players already have are sent as block changes
+            let changes = world.generate_chunk(pos);
+            let chunk = world.chunks[&pos].clone();
+            self.pending_block_changes
+                .extend(changes.into_iter().map(|(pos, block)| (None, pos, block)));
+            chunk
+        };
+
         // Add this chunk to the list of chunks that `loader` has loaded
         let player_loaded = self.player_loaded_chunks.get_mut(&loader).unwrap();
         let just_loaded = player_loaded.insert(pos);
@@ -291,30 +1907,25 @@ impl Server {
             // Increment the "reference counter" for the chunk
             *self.loaded_chunks.entry(pos).or_default() += 1;
         }
+        Ok(chunk)
+    }
 
+    /// Creates a downsampled copy of a chunk, without loading it
+    pub fn lod_chunk(&mut self, pos: ChunkPos, level: u8) -> LodChunk {
         let world = self.ecs_world.resource::<World>();
 
-        if let Some(chunk) = world.chunk(pos) {
-            println!("Loading chunk {pos:?} from already loaded");
-            chunk.clone()
+        if let Some(chunk) = world.chunk(pos).or_else(|| self.prefetcher.get(pos)) {
+            LodChunk::from_chunk(chunk, level)
         } else if let Some(chunk) = self
-            .db
-            .query_row("SELECT blocks FROM chunks WHERE pos = ?1", (pos,), |row| {
-                Ok(Chunk::deserialize(row.get_ref(0)?.as_blob()?))
-            })
-            .optional()
-            .unwrap()
+            .storage
+            .load_chunk(world.dimension, pos)
+            .map_err(|e| log::warn!("Could not load chunk {pos:?} for its LOD: {e:#}"))
+            .ok()
+            .flatten()
         {
-            println!("Loading chunk {pos:?} from database");
-            let mut world = self.ecs_world.resource_mut::<World>();
-            world.chunks.insert(pos, chunk.clone());
-            chunk
+            LodChunk::from_chunk(&chunk, level)
         } else {
-            println!("Loading chunk {pos:?} from newly generated");
-            let mut world = self.ecs_world.resource_mut::<World>();
-            let chunk = world.worldgen.generate_chunk(pos);
-            world.chunks.insert(pos, chunk.clone());
-            chunk
+            LodChunk::from_chunk(&world.worldgen.generate(pos).0, level)
         }
     }
 
@@ -331,31 +1942,69 @@ impl Server {
                 self.loaded_chunks.remove(&pos);
                 let mut world = self.ecs_world.resource_mut::<World>();
                 let chunk = world.chunks.remove(&pos).unwrap();
-                self.db
-                    .execute(
-                        "INSERT OR REPLACE INTO chunks (pos, blocks) VALUES(?2, ?1);",
-                        (chunk.serialize(), pos),
-                    )
-                    .unwrap();
+                if let Err(e) = self.storage.save_chunk(world.dimension, &chunk) {
+                    eprintln!("Could not save chunk {pos:?}: {e:#}");
+                }
+                self.revisions.forget(pos);
+                if let Err(e) = self.save_entities([pos]) {
+                    eprintln!("Could not save entities in chunk {pos:?}: {e:#}");
+                }
             }
         }
     }
 }
 
-/// Wait for incoming connections, sending them through the channel
-async fn accept(endpoint: Endpoint, tx: async_std::channel::Sender<Connection>) {
-    loop {
-        if let Some(connecting) = endpoint.accept().await {
-            let Ok(x) = connecting.await else { continue };
-
-            let player_id = Uuid::new_v4();
+impl Drop for Server {
+    /// Save whatever is still loaded when the server stops without shutting down, above all
+    /// when a panic unwinds through it. Errors are only reported, panicking again here would
+    /// abort before anything else is saved.
+    fn drop(&mut self) {
+        if self.loaded_chunks.is_empty() {
+            return;
+        }
+        if std::thread::panicking() {
+            eprintln!("Server panicked, saving the world before exiting");
+        } else {
+            eprintln!("Server stopped without shutting down, saving the world");
+        }
 
-            let conn = Connection {
-                player_id,
-                transport: Transport::Remote(RemoteTransport { connection: x }),
+        let world = self.ecs_world.resource::<World>();
+        for pos in self.loaded_chunks.keys() {
+            let Some(chunk) = world.chunks.get(pos) else {
+                continue;
             };
+            if let Err(e) = self.storage.save_chunk(world.dimension, chunk) {
+                eprintln!("Could not save chunk {pos:?}: {e:#}");
+            }
+        }
+        let loaded = self.loaded_chunks.keys().copied().collect::<Vec<_>>();
+        if let Err(e) = self.save_entities(loaded) {
+            eprintln!("Could not save entities: {e:#}");
+        }
+        if let Err(e) = self.storage.flush() {
+            eprintln!("Could not save the world: {e:#}");
+        }
+    }
+}
 
-            tx.send(conn).await.unwrap();
+/// Wait for incoming connections, sending their logins through the channel
+async fn accept(endpoint: Endpoint, tx: async_std::channel::Sender<Login>) {
+    loop {
+        if let Some(connecting) = endpoint.accept().await {
+            let tx = tx.clone();
+            // Waiting for the login happens on its own, so one slow client doesn't hold up the
+            // rest
+            async_std::task::spawn(async move {
+                let Ok(x) = connecting.await else { return };
+                let transport = Transport::Remote(RemoteTransport::new(x));
+                match Login::receive(transport.clone()).await {
+                    Ok(login) => tx.send(login).await.unwrap(),
+                    Err(e) => {
+                        log::warn!("Dropping connection that didn't log in: {e:#}");
+                        transport.close("no login");
+                    }
+                }
+            });
         }
     }
 }
@@ -0,0 +1,138 @@
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+
+use bevy_ecs::system::Resource;
+use cgmath::{InnerSpace, Point3, Vector2, Vector3, Zero};
+use uuid::Uuid;
+use wgpu::naga::FastHashMap;
+
+use crate::game::{block::BlockRegistry, physics, world::World};
+
+/// Limits on how players may move, checked against every position update
+#[derive(Debug, Clone, Copy)]
+pub struct MovementRules {
+    pub max_horizontal_speed: f32,
+    /// Falling is not limited, as gravity can accelerate players indefinitely
+    pub max_upward_speed: f32,
+    /// Distance allowed on top of the speed limits, to absorb network jitter
+    pub tolerance: f32,
+    /// Reject positions where the player would be inside a solid block
+    pub check_collisions: bool,
+}
+
+impl Default for MovementRules {
+    fn default() -> Self {
+        Self {
+            max_horizontal_speed: 6.0,
+            max_upward_speed: 10.0,
+            tolerance: 1.0,
+            check_collisions: true,
+        }
+    }
+}
+
+/// Size of the player's collision box, matching the client
+const PLAYER_EXTENTS: Vector3<f32> = Vector3::new(0.5, 1.8, 0.5);
+/// Long gaps between updates don't allow arbitrarily large moves
+const MAX_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy)]
+pub enum MovementError {
+    TooFast { distance: f32, allowed: f32 },
+    InsideBlock,
+}
+
+impl Display for MovementError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MovementError::TooFast { distance, allowed } => {
+                write!(f, "moved {distance:.2} blocks, only {allowed:.2} allowed")
+            }
+            MovementError::InsideBlock => write!(f, "moved inside a block"),
+        }
+    }
+}
+
+/// Where every connected player is, as far as the server knows
+#[derive(Debug, Default, Resource)]
+pub struct PlayerPositions(pub FastHashMap<Uuid, PlayerPosition>);
+
+/// The server's authoritative view of where a player is
+#[derive(Debug, Clone, Copy)]
+pub struct PlayerPosition {
+    pub position: Point3<f32>,
+    pub updated: Instant,
+    /// Estimated from the last two updates, for predicting where the player is heading
+    pub velocity: Vector3<f32>,
+}
+
+impl PlayerPosition {
+    pub fn new(position: Point3<f32>) -> Self {
+        Self {
+            position,
+            updated: Instant::now(),
+            velocity: Vector3::zero(),
+        }
+    }
+
+    /// The position after moving to `position` at `now`
+    pub fn moved_to(&self, position: Point3<f32>, now: Instant) -> Self {
+        let elapsed = (now - self.updated).as_secs_f32();
+        let velocity = if elapsed > 0.0 {
+            (position - self.position) / elapsed
+        } else {
+            self.velocity
+        };
+        Self {
+            position,
+            updated: now,
+            velocity,
+        }
+    }
+}
+
+impl MovementRules {
+    /// Check whether a player at `from` could have legitimately moved to `to` by `now`
+    pub fn validate(
+        &self,
+        from: &PlayerPosition,
+        to: Point3<f32>,
+        now: Instant,
+        world: &World,
+        block_registry: &BlockRegistry,
+    ) -> Result<(), MovementError> {
+        let elapsed = (now - from.updated).min(MAX_UPDATE_INTERVAL).as_secs_f32();
+        let delta = to - from.position;
+
+        let horizontal = Vector2::new(delta.x, delta.z).magnitude();
+        let allowed = self.max_horizontal_speed * elapsed + self.tolerance;
+        if horizontal > allowed {
+            return Err(MovementError::TooFast {
+                distance: horizontal,
+                allowed,
+            });
+        }
+
+        let allowed = self.max_upward_speed * elapsed + self.tolerance;
+        if delta.y > allowed {
+            return Err(MovementError::TooFast {
+                distance: delta.y,
+                allowed,
+            });
+        }
+
+        if self.check_collisions {
+            // Shrink the box slightly so standing flush against a block is fine
+            let epsilon = Vector3::new(0.01, 0.01, 0.01);
+            let min = to - Vector3::new(PLAYER_EXTENTS.x / 2.0, 0.0, PLAYER_EXTENTS.z / 2.0);
+            let max = min + PLAYER_EXTENTS;
+            if physics::overlaps_solid(min + epsilon, max - epsilon, world, block_registry) {
+                return Err(MovementError::InsideBlock);
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+//! Chunks kept loaded no matter where players are.
+//!
+//! The chunks around the world spawn are always pinned, so players joining or respawning
+//! there find them ready, and operators can pin regions of their own with the `pin` command.
+//! Pinned chunks are loaded by [`LOADER`] as if it were a player, so they are reference
+//! counted and unloaded just like the chunks players load.
+
+use cgmath::{Point3, Vector3};
+use uuid::Uuid;
+use wgpu::naga::FastHashSet;
+
+use crate::game::chunk::{BlockPos, ChunkPos};
+
+/// The loader id pinned chunks are loaded under, which no player can have
+pub const LOADER: Uuid = Uuid::nil();
+/// Radius in chunks of the cube of chunks pinned around the world spawn
+pub const SPAWN_RADIUS: isize = 1;
+/// Most chunks a single command may pin or unpin, since each one is loaded right away
+pub const MAX_REGION: usize = 512;
+
+/// The chunks pinned around the world spawn at `spawn`
+pub fn spawn_chunks(spawn: Point3<f32>) -> FastHashSet<ChunkPos> {
+    let center = BlockPos::from_point(spawn).chunk_pos();
+    let mut chunks = FastHashSet::default();
+    for x in -SPAWN_RADIUS..=SPAWN_RADIUS {
+        for y in -SPAWN_RADIUS..=SPAWN_RADIUS {
+            for z in -SPAWN_RADIUS..=SPAWN_RADIUS {
+                chunks.insert(center + Vector3::new(x, y, z));
+            }
+        }
+    }
+    chunks
+}
+
+/// Every chunk in the box between two corners, in either order
+pub fn region(a: ChunkPos, b: ChunkPos) -> Result<Vec<ChunkPos>, String> {
+    let (a, b) = (Point3::from(a), Point3::from(b));
+    let (min, max) = (
+        Point3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+        Point3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)),
+    );
+    let size = (max - min).map(|length| length.unsigned_abs().saturating_add(1));
+    let count = size.x.saturating_mul(size.y).saturating_mul(size.z);
+    if count > MAX_REGION {
+        return Err(format!(
+            "The region has {count} chunks, at most {MAX_REGION} can be pinned at once"
+        ));
+    }
+    let mut chunks = Vec::with_capacity(count);
+    for x in min.x..=max.x {
+        for y in min.y..=max.y {
+            for z in min.z..=max.z {
+                chunks.push(ChunkPos::from(Point3::new(x, y, z)));
+            }
+        }
+    }
+    Ok(chunks)
+}
@@ -0,0 +1,105 @@
+use cgmath::{InnerSpace, Point3, Vector3};
+use wgpu::naga::FastHashMap;
+
+use crate::game::{
+    block::Block,
+    chunk::{BlockPos, Chunk, ChunkPos},
+};
+
+use super::movement::PlayerPosition;
+
+/// A chunk fetched before any player asked for it
+pub enum PrefetchedChunk {
+    Stored(Chunk),
+    /// Newly generated, along with the blocks of its structures that are in other chunks
+    Generated(Chunk, Vec<(BlockPos, Block)>),
+}
+
+impl PrefetchedChunk {
+    pub fn chunk(&self) -> &Chunk {
+        match self {
+            PrefetchedChunk::Stored(chunk) | PrefetchedChunk::Generated(chunk, _) => chunk,
+        }
+    }
+}
+
+/// Chunks read from storage or generated during idle tick time, in the direction players are
+/// heading, so that loading them later doesn't have to wait for worldgen.
+///
+/// Prefetched chunks aren't loaded, nothing can change them until they are taken out of here.
+#[derive(Default)]
+pub struct ChunkPrefetcher {
+    chunks: FastHashMap<ChunkPos, PrefetchedChunk>,
+}
+
+impl ChunkPrefetcher {
+    /// How many seconds ahead player movement is extrapolated
+    pub const LOOKAHEAD: f32 = 2.0;
+    /// Most chunks kept at once
+    pub const MAX_CHUNKS: usize = 1024;
+
+    pub fn is_full(&self) -> bool {
+        self.chunks.len() >= Self::MAX_CHUNKS
+    }
+
+    pub fn contains(&self, pos: ChunkPos) -> bool {
+        self.chunks.contains_key(&pos)
+    }
+
+    pub fn get(&self, pos: ChunkPos) -> Option<&Chunk> {
+        self.chunks.get(&pos).map(PrefetchedChunk::chunk)
+    }
+
+    /// Take a chunk out to load it
+    pub fn take(&mut self, pos: ChunkPos) -> Option<PrefetchedChunk> {
+        self.chunks.remove(&pos)
+    }
+
+    pub fn insert(&mut self, pos: ChunkPos, chunk: PrefetchedChunk) {
+        self.chunks.insert(pos, chunk);
+    }
+
+    /// Drop the chunks further than `radius` chunks from every player, as they moved elsewhere
+    pub fn retain_near(&mut self, players: &[(ChunkPos, isize)]) {
+        self.chunks.retain(|&pos, _| {
+            players
+                .iter()
+                .any(|&(center, radius)| chebyshev_distance(pos, center) <= radius)
+        });
+    }
+
+    /// The chunks a player will want within `radius` chunks of where they are heading, that
+    /// aren't loaded yet, nearest to the player first
+    pub fn candidates(
+        player: &PlayerPosition,
+        radius: isize,
+        is_loaded: impl Fn(ChunkPos) -> bool,
+    ) -> Vec<ChunkPos> {
+        let heading = player.position + player.velocity * Self::LOOKAHEAD;
+        let center = BlockPos::from_point(heading).chunk_pos();
+        let max_distance = (radius * Chunk::SIZE as isize) as f32;
+
+        let mut candidates = vec![];
+        for x in -radius..=radius {
+            for y in -radius..=radius {
+                for z in -radius..=radius {
+                    let pos = center + Vector3::new(x, y, z);
+                    if (pos.center() - heading).magnitude() <= max_distance && !is_loaded(pos) {
+                        candidates.push(pos);
+                    }
+                }
+            }
+        }
+        let distance = |pos: &ChunkPos| (pos.center() - player.position).magnitude2();
+        candidates.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+        candidates
+    }
+}
+
+pub fn chebyshev_distance(a: ChunkPos, b: ChunkPos) -> isize {
+    let (a, b) = (Point3::from(a), Point3::from(b));
+    (a.x - b.x)
+        .abs()
+        .max((a.y - b.y).abs())
+        .max((a.z - b.z).abs())
+}
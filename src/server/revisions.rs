@@ -0,0 +1,192 @@
+//! Revisions of chunks and the blocks changed in each, so that a client with an outdated copy
+//! of a chunk can be sent only the blocks that changed since, instead of the whole chunk.
+//!
+//! Revisions are only kept in memory, and only for loaded chunks. They start from the time the
+//! server started, so every revision handed out by an earlier run is older than anything
+//! recorded in this one, and clients with copies from back then get the whole chunk. The same
+//! goes for copies of chunks whose history was forgotten when they unloaded.
+
+use std::{
+    collections::VecDeque,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use wgpu::naga::{FastHashMap, FastHashSet};
+
+use crate::game::chunk::{BlockPos, ChunkPos, ChunkRevision};
+
+/// Most changes remembered per chunk. Past that many, the blocks changed since an old revision
+/// take about as much space as the chunk, so older changes are forgotten.
+const MAX_CHANGES: usize = 256;
+
+#[derive(Debug)]
+pub struct ChunkRevisions {
+    /// Revision of every chunk without a history, either because it hasn't changed since the
+    /// server started or because its history was forgotten. Always at least the last revision
+    /// of every forgotten history, so copies from before it are too old to tell.
+    base: ChunkRevision,
+    latest: ChunkRevision,
+    chunks: FastHashMap<ChunkPos, History>,
+}
+
+#[derive(Debug)]
+struct History {
+    /// Every change after this revision is in `changes`
+    since: ChunkRevision,
+    /// Revision and position of every change after `since`, oldest first
+    changes: VecDeque<(ChunkRevision, BlockPos)>,
+}
+
+impl Default for ChunkRevisions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkRevisions {
+    pub fn new() -> Self {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_micros() as ChunkRevision);
+        Self {
+            base: start,
+            latest: start,
+            chunks: FastHashMap::default(),
+        }
+    }
+
+    /// The current revision of the chunk at `pos`
+    pub fn revision(&self, pos: ChunkPos) -> ChunkRevision {
+        self.chunks
+            .get(&pos)
+            .and_then(|history| history.changes.back())
+            .map_or(self.base, |&(revision, _)| revision)
+    }
+
+    /// Give the chunk of `pos` a new revision, in which the block at `pos` changed
+    pub fn record(&mut self, pos: BlockPos) {
+        self.latest += 1;
+        let history = self
+            .chunks
+            .entry(pos.chunk_pos())
+            .or_insert_with(|| History {
+                since: self.base,
+                changes: VecDeque::new(),
+            });
+        history.changes.push_back((self.latest, pos));
+        if history.changes.len() > MAX_CHANGES {
+            let (forgotten, _) = history.changes.pop_front().unwrap();
+            history.since = forgotten;
+        }
+    }
+
+    /// The blocks of the chunk at `pos` changed after `revision`, or `None` if it is too old to
+    /// tell
+    pub fn changed_since(&self, pos: ChunkPos, revision: ChunkRevision) -> Option<Vec<BlockPos>> {
+        let Some(history) = self.chunks.get(&pos) else {
+            return (revision >= self.base && revision <= self.latest).then(Vec::new);
+        };
+        if revision < history.since || revision > self.latest {
+            return None;
+        }
+        let mut seen = FastHashSet::default();
+        Some(
+            history
+                .changes
+                .iter()
+                .filter(|&&(changed, pos)| changed > revision && seen.insert(pos))
+                .map(|&(_, pos)| pos)
+                .collect(),
+        )
+    }
+
+    /// Forget the history of the chunk at `pos`, when it unloads. Clients with copies from
+    /// before are sent the whole chunk instead.
+    pub fn forget(&mut self, pos: ChunkPos) {
+        if let Some(history) = self.chunks.remove(&pos) {
+            let last = history
+                .changes
+                .back()
+                .map_or(history.since, |&(revision, _)| revision);
+            self.base = self.base.max(last);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cgmath::Point3;
+
+    use super::*;
+
+    fn block(x: isize) -> BlockPos {
+        BlockPos::from(Point3::new(x, 0, 0))
+    }
+
+    #[test]
+    fn changed_since_bounds() {
+        let mut revisions = ChunkRevisions::new();
+        let pos = block(0).chunk_pos();
+        let start = revisions.revision(pos);
+        assert_eq!(revisions.changed_since(pos, start), Some(vec![]));
+        assert_eq!(revisions.changed_since(pos, start - 1), None);
+
+        revisions.record(block(1));
+        let first = revisions.revision(pos);
+        revisions.record(block(2));
+        let latest = revisions.revision(pos);
+        assert_eq!(
+            revisions.changed_since(pos, start),
+            Some(vec![block(1), block(2)])
+        );
+        assert_eq!(revisions.changed_since(pos, first), Some(vec![block(2)]));
+        assert_eq!(revisions.changed_since(pos, latest), Some(vec![]));
+        assert_eq!(revisions.changed_since(pos, latest + 1), None);
+        assert_eq!(revisions.changed_since(pos, start - 1), None);
+    }
+
+    #[test]
+    fn changed_since_deduplicates() {
+        let mut revisions = ChunkRevisions::new();
+        let pos = block(0).chunk_pos();
+        let start = revisions.revision(pos);
+        for x in [1, 2, 1, 1] {
+            revisions.record(block(x));
+        }
+        assert_eq!(
+            revisions.changed_since(pos, start),
+            Some(vec![block(1), block(2)])
+        );
+    }
+
+    #[test]
+    fn changed_since_forgets_old_changes() {
+        let mut revisions = ChunkRevisions::new();
+        let pos = block(0).chunk_pos();
+        let start = revisions.revision(pos);
+        for _ in 0..=MAX_CHANGES {
+            revisions.record(block(1));
+        }
+        assert_eq!(revisions.changed_since(pos, start), None);
+        assert_eq!(
+            revisions.changed_since(pos, start + 1),
+            Some(vec![block(1)])
+        );
+    }
+
+    #[test]
+    fn forgotten_chunks_are_sent_whole() {
+        let mut revisions = ChunkRevisions::new();
+        let pos = block(0).chunk_pos();
+        let start = revisions.revision(pos);
+        revisions.record(block(1));
+        let changed = revisions.revision(pos);
+        revisions.forget(pos);
+
+        assert!(revisions.revision(pos) >= changed);
+        assert_eq!(revisions.changed_since(pos, start), None);
+        revisions.record(block(2));
+        assert_eq!(revisions.changed_since(pos, start), None);
+        assert_eq!(revisions.changed_since(pos, changed), Some(vec![block(2)]));
+    }
+}
@@ -0,0 +1,74 @@
+use bevy_ecs::system::{Res, ResMut, Resource};
+use cgmath::{InnerSpace, Point3, Vector3};
+use wgpu::naga::FastHashSet;
+
+use crate::game::{
+    chunk::{BlockPos, Chunk, ChunkPos},
+    world::World,
+};
+
+use super::movement::PlayerPositions;
+
+/// Radius in chunks around each player in which entities and blocks are ticked
+#[derive(Debug, Clone, Copy, Resource)]
+pub struct SimulationDistance(pub isize);
+
+/// The loaded chunks that entities and blocks are ticked in.
+///
+/// Players may have chunks loaded much further away than this, those chunks are only kept
+/// around to be sent to clients and are otherwise frozen.
+#[derive(Debug, Default, Resource)]
+pub struct SimulationArea {
+    chunks: FastHashSet<ChunkPos>,
+}
+
+impl SimulationArea {
+    pub fn contains(&self, pos: ChunkPos) -> bool {
+        self.chunks.contains(&pos)
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = ChunkPos> + '_ {
+        self.chunks.iter().copied()
+    }
+
+    /// Recompute the area as every loaded chunk within `distance` chunks of a player
+    pub fn update(
+        &mut self,
+        player_positions: impl IntoIterator<Item = Point3<f32>>,
+        distance: isize,
+        is_loaded: impl Fn(ChunkPos) -> bool,
+    ) {
+        self.chunks.clear();
+        let max_distance = (distance * Chunk::SIZE as isize) as f32;
+
+        for player_pos in player_positions {
+            let center = BlockPos::from_point(player_pos).chunk_pos();
+            for x in -distance..=distance {
+                for y in -distance..=distance {
+                    for z in -distance..=distance {
+                        let pos = center + Vector3::new(x, y, z);
+                        if (pos.center() - player_pos).magnitude() > max_distance {
+                            continue;
+                        }
+                        if is_loaded(pos) {
+                            self.chunks.insert(pos);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn update_simulation_area(
+    mut area: ResMut<SimulationArea>,
+    players: Res<PlayerPositions>,
+    distance: Res<SimulationDistance>,
+    world: Res<World>,
+) {
+    area.update(
+        players.0.values().map(|p| p.position),
+        distance.0,
+        |pos| world.chunks.contains_key(&pos),
+    );
+}
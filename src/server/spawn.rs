@@ -0,0 +1,65 @@
+//! Where players appear when they join for the first time and when they respawn.
+//!
+//! Every dimension has a world spawn, and players can have a spawn point of their own that
+//! takes precedence over it. Both are saved with the dimension. A dimension without a saved
+//! world spawn gets one on top of the generated terrain at [`SPAWN_COLUMN`].
+
+use cgmath::{Point3, Vector3};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wgpu::naga::FastHashMap;
+
+use crate::game::{
+    chunk::{BlockPos, ChunkPos},
+    worldgen::Worldgen,
+};
+
+/// The world spawn of saves from before it was computed from the terrain
+pub const DEFAULT_SPAWN: Point3<f32> = Point3::new(0.0, 20.0, 0.0);
+/// The column the world spawn is put on top of
+pub const SPAWN_COLUMN: (isize, isize) = (0, 0);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpawnPoints {
+    pub world: Point3<f32>,
+    pub players: FastHashMap<Uuid, Point3<f32>>,
+}
+
+impl SpawnPoints {
+    /// Spawn points of a new dimension, with the world spawn on its surface
+    pub fn new(worldgen: &Worldgen) -> Self {
+        Self {
+            world: surface_spawn(worldgen, SPAWN_COLUMN.0, SPAWN_COLUMN.1),
+            players: FastHashMap::default(),
+        }
+    }
+
+    /// Where `player` respawns, their own spawn point if they have one
+    pub fn of(&self, player: Uuid) -> Point3<f32> {
+        self.players.get(&player).copied().unwrap_or(self.world)
+    }
+}
+
+/// Where a player standing on top of the column at `x`, `z` would be, as generated. Structures
+/// are taken into account so players don't spawn inside of trees.
+pub fn surface_spawn(worldgen: &Worldgen, x: isize, z: isize) -> Point3<f32> {
+    let ground = worldgen.ground_height(x, z);
+    // Structures are based on the ground of their own column, and only reach a chunk or so
+    // away from it
+    let center = BlockPos::from(Point3::new(x, ground + 1, z)).chunk_pos();
+    let mut top = ground;
+    for dx in -1..=1 {
+        for dy in -1..=1 {
+            for dz in -1..=1 {
+                let pos = ChunkPos::from(Point3::from(center) + Vector3::new(dx, dy, dz));
+                for (block_pos, _) in worldgen.structure_blocks(pos) {
+                    let block_pos = Point3::from(block_pos);
+                    if (block_pos.x, block_pos.z) == (x, z) {
+                        top = top.max(block_pos.y);
+                    }
+                }
+            }
+        }
+    }
+    Point3::new(x as f32 + 0.5, (top + 1) as f32, z as f32 + 0.5)
+}
@@ -0,0 +1,770 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use cgmath::Point3;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use wgpu::naga::{FastHashMap, FastHashSet};
+
+use super::{accounts::Account, entities::SavedEntity, migrations, spawn::SpawnPoints};
+use crate::game::{
+    block::{Block, BlockId, BlockNames},
+    chunk::{BlockPos, Chunk, ChunkPos},
+    dimension::{DimensionId, DimensionInfo},
+};
+
+/// Persistent storage of chunk data, kept separately for each dimension
+pub trait WorldStorage: Send {
+    /// Load a chunk, returning `None` if it has never been saved
+    fn load_chunk(&mut self, dimension: DimensionId, pos: ChunkPos)
+        -> anyhow::Result<Option<Chunk>>;
+    /// Save a chunk, replacing any previous data for the same position
+    fn save_chunk(&mut self, dimension: DimensionId, chunk: &Chunk) -> anyhow::Result<()>;
+    /// Load the metadata of a dimension, returning `None` if it has never been saved
+    fn load_dimension(&mut self, dimension: DimensionId) -> anyhow::Result<Option<DimensionInfo>>;
+    /// Save the metadata of a dimension, replacing any previous metadata
+    fn save_dimension(&mut self, dimension: DimensionId, info: &DimensionInfo)
+        -> anyhow::Result<()>;
+    /// Every dimension that has saved metadata
+    fn dimensions(&mut self) -> anyhow::Result<Vec<DimensionId>>;
+    /// Load the account with an identity token, returning `None` if there is none
+    fn load_account(&mut self, token: Uuid) -> anyhow::Result<Option<Account>>;
+    /// Load the account with a name, returning `None` if there is none
+    fn account_by_name(&mut self, name: &str) -> anyhow::Result<Option<Account>>;
+    /// Save an account, replacing any previous account with the same id
+    fn save_account(&mut self, account: &Account) -> anyhow::Result<()>;
+    /// Load the entities saved with a chunk
+    fn load_entities(&mut self, dimension: DimensionId, pos: ChunkPos)
+        -> anyhow::Result<Vec<SavedEntity>>;
+    /// Save the entities in a chunk, replacing the ones previously saved with it
+    fn save_entities(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+        entities: &[SavedEntity],
+    ) -> anyhow::Result<()>;
+    /// Load the spawn points of a dimension, returning `None` if they have never been saved
+    fn load_spawn_points(&mut self, dimension: DimensionId) -> anyhow::Result<Option<SpawnPoints>>;
+    /// Save the spawn points of a dimension, replacing the previous ones
+    fn save_spawn_points(
+        &mut self,
+        dimension: DimensionId,
+        spawn_points: &SpawnPoints,
+    ) -> anyhow::Result<()>;
+    /// Load the chunks operators pinned in a dimension
+    fn load_pinned_chunks(&mut self, dimension: DimensionId) -> anyhow::Result<Vec<ChunkPos>>;
+    /// Save the chunks operators pinned in a dimension, replacing the previous ones
+    fn save_pinned_chunks(&mut self, dimension: DimensionId, chunks: &[ChunkPos])
+        -> anyhow::Result<()>;
+    /// Load the structure blocks of a dimension waiting for their chunks to be generated or
+    /// loaded
+    fn load_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+    ) -> anyhow::Result<Vec<(BlockPos, Block)>>;
+    /// Save the structure blocks of a dimension waiting for their chunks, replacing the previous
+    /// ones
+    fn save_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+        blocks: &[(BlockPos, Block)],
+    ) -> anyhow::Result<()>;
+    /// Make sure everything saved so far has reached the disk
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Structure blocks waiting for their chunks, saved with a palette of block names like chunks
+#[derive(Serialize, Deserialize)]
+struct SavedPendingBlocks {
+    palette: Vec<String>,
+    /// With indices into `palette` as ids
+    blocks: Vec<(BlockPos, Block)>,
+}
+
+impl SavedPendingBlocks {
+    fn serialize(blocks: &[(BlockPos, Block)], names: &BlockNames) -> anyhow::Result<Vec<u8>> {
+        let mut palette = vec![];
+        let mut palette_indices = FastHashMap::default();
+        let blocks = blocks
+            .iter()
+            .map(|&(pos, block)| {
+                let index = *palette_indices.entry(block.id).or_insert_with(|| {
+                    let name = names
+                        .name(block.id)
+                        .unwrap_or_else(|| panic!("Block id {:?} has no name", block.id));
+                    palette.push(name.to_string());
+                    BlockId(palette.len() as u16 - 1)
+                });
+                (pos, Block { id: index, ..block })
+            })
+            .collect();
+        let saved = SavedPendingBlocks { palette, blocks };
+        Ok(postcard::to_allocvec(&saved)?)
+    }
+
+    /// Remap the saved block names to the current ids, dropping blocks that no longer exist
+    fn deserialize(data: &[u8], names: &BlockNames) -> anyhow::Result<Vec<(BlockPos, Block)>> {
+        let saved: SavedPendingBlocks = postcard::from_bytes(data)?;
+        let ids = saved
+            .palette
+            .iter()
+            .map(|name| {
+                let id = names.id(name);
+                if id.is_none() {
+                    log::warn!("Unknown block {name} in pending structure blocks, dropping it");
+                }
+                id
+            })
+            .collect::<Vec<_>>();
+        Ok(saved
+            .blocks
+            .into_iter()
+            .filter_map(|(pos, block)| {
+                let id = ids.get(block.id.0 as usize).copied().flatten()?;
+                Some((pos, Block { id, ..block }))
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum StorageBackend {
+    /// One row per chunk in `savegame.db3`, with a table per dimension
+    Sqlite,
+    /// One file per 32³ chunk region in `savegame_regions/`, with a subdirectory per dimension
+    /// other than the overworld
+    Region,
+}
+
+impl StorageBackend {
+    /// Open the save in `dir`, using `names` to translate between block ids and the saved block
+    /// names
+    pub fn open(self, dir: &Path, names: BlockNames) -> anyhow::Result<Box<dyn WorldStorage>> {
+        std::fs::create_dir_all(dir)?;
+        Ok(match self {
+            StorageBackend::Sqlite => {
+                Box::new(SqliteStorage::open(dir.join("savegame.db3"), names)?)
+            }
+            StorageBackend::Region => {
+                Box::new(RegionStorage::open(dir.join("savegame_regions"), names)?)
+            }
+        })
+    }
+}
+
+pub struct SqliteStorage {
+    db: rusqlite::Connection,
+    names: BlockNames,
+    /// Dimensions whose chunk table is known to exist
+    chunk_tables: FastHashSet<DimensionId>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>, names: BlockNames) -> anyhow::Result<Self> {
+        let mut db = rusqlite::Connection::open(path)?;
+        migrations::migrate(&mut db)?;
+        Ok(Self {
+            db,
+            names,
+            chunk_tables: FastHashSet::default(),
+        })
+    }
+
+    /// The name of the chunk table of a dimension, creating the table if needed.
+    /// The overworld uses `chunks`, so saves from before dimensions existed still load. Migrations
+    /// that change the chunk tables need to handle every `chunks_*` table there is.
+    fn chunk_table(&mut self, dimension: DimensionId) -> anyhow::Result<String> {
+        let table = if dimension == DimensionId::OVERWORLD {
+            "chunks".to_string()
+        } else {
+            format!("chunks_{dimension}")
+        };
+        if self.chunk_tables.insert(dimension) {
+            self.db.execute(
+                &format!(
+                    "
+        CREATE TABLE IF NOT EXISTS {table} (
+            pos BLOB NOT NULL PRIMARY KEY,
+            blocks BLOB NOT NULL
+        );
+        "
+                ),
+                [],
+            )?;
+        }
+        Ok(table)
+    }
+
+    fn query_account(
+        &mut self,
+        column: &str,
+        value: impl rusqlite::ToSql,
+    ) -> anyhow::Result<Option<Account>> {
+        let row = self
+            .db
+            .query_row(
+                &format!("SELECT id, name, token FROM players WHERE {column} = ?1"),
+                (value,),
+                |row| {
+                    Ok((
+                        row.get::<_, Vec<u8>>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, Vec<u8>>(2)?,
+                    ))
+                },
+            )
+            .optional()?;
+        let Some((id, name, token)) = row else {
+            return Ok(None);
+        };
+        Ok(Some(Account {
+            id: Uuid::from_slice(&id)?,
+            name,
+            token: Uuid::from_slice(&token)?,
+        }))
+    }
+}
+
+impl WorldStorage for SqliteStorage {
+    fn load_chunk(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+    ) -> anyhow::Result<Option<Chunk>> {
+        let table = self.chunk_table(dimension)?;
+        Ok(self
+            .db
+            .query_row(
+                &format!("SELECT blocks FROM {table} WHERE pos = ?1"),
+                (pos,),
+                |row| Ok(Chunk::deserialize(row.get_ref(0)?.as_blob()?, &self.names)),
+            )
+            .optional()?)
+    }
+
+    fn save_chunk(&mut self, dimension: DimensionId, chunk: &Chunk) -> anyhow::Result<()> {
+        let table = self.chunk_table(dimension)?;
+        self.db.execute(
+            &format!("INSERT OR REPLACE INTO {table} (pos, blocks) VALUES(?2, ?1);"),
+            (chunk.serialize(&self.names), chunk.pos),
+        )?;
+        Ok(())
+    }
+
+    fn load_dimension(&mut self, dimension: DimensionId) -> anyhow::Result<Option<DimensionInfo>> {
+        let info = self
+            .db
+            .query_row(
+                "SELECT info FROM dimensions WHERE id = ?1",
+                (dimension.0,),
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        Ok(info.map(|info| postcard::from_bytes(&info)).transpose()?)
+    }
+
+    fn save_dimension(
+        &mut self,
+        dimension: DimensionId,
+        info: &DimensionInfo,
+    ) -> anyhow::Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO dimensions (id, info) VALUES(?1, ?2);",
+            (dimension.0, postcard::to_allocvec(info)?),
+        )?;
+        Ok(())
+    }
+
+    fn dimensions(&mut self) -> anyhow::Result<Vec<DimensionId>> {
+        let mut statement = self.db.prepare("SELECT id FROM dimensions ORDER BY id")?;
+        let ids = statement
+            .query_map([], |row| row.get(0))?
+            .map(|id| Ok(DimensionId(id?)))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(ids)
+    }
+
+    fn load_account(&mut self, token: Uuid) -> anyhow::Result<Option<Account>> {
+        self.query_account("token", token.as_bytes().as_slice())
+    }
+
+    fn account_by_name(&mut self, name: &str) -> anyhow::Result<Option<Account>> {
+        self.query_account("name", name)
+    }
+
+    fn save_account(&mut self, account: &Account) -> anyhow::Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO players (id, name, token) VALUES(?1, ?2, ?3);",
+            (
+                account.id.as_bytes().as_slice(),
+                &account.name,
+                account.token.as_bytes().as_slice(),
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn load_entities(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+    ) -> anyhow::Result<Vec<SavedEntity>> {
+        let mut statement = self
+            .db
+            .prepare("SELECT data FROM entities WHERE dimension = ?1 AND chunk = ?2")?;
+        let entities = statement
+            .query_map((dimension.0, pos), |row| row.get::<_, Vec<u8>>(0))?
+            .map(|data| Ok(postcard::from_bytes(&data?)?))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(entities)
+    }
+
+    fn save_entities(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+        entities: &[SavedEntity],
+    ) -> anyhow::Result<()> {
+        let transaction = self.db.transaction()?;
+        transaction.execute(
+            "DELETE FROM entities WHERE dimension = ?1 AND chunk = ?2",
+            (dimension.0, pos),
+        )?;
+        for entity in entities {
+            // An entity that moved here from another chunk replaces its row there
+            transaction.execute(
+                "INSERT OR REPLACE INTO entities (id, dimension, chunk, data) VALUES(?1, ?2, ?3, ?4)",
+                (
+                    entity.id.as_bytes().as_slice(),
+                    dimension.0,
+                    pos,
+                    postcard::to_allocvec(entity)?,
+                ),
+            )?;
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+
+    fn load_spawn_points(
+        &mut self,
+        dimension: DimensionId,
+    ) -> anyhow::Result<Option<SpawnPoints>> {
+        let data = self
+            .db
+            .query_row(
+                "SELECT data FROM spawn_points WHERE dimension = ?1",
+                (dimension.0,),
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        Ok(data.map(|data| postcard::from_bytes(&data)).transpose()?)
+    }
+
+    fn save_spawn_points(
+        &mut self,
+        dimension: DimensionId,
+        spawn_points: &SpawnPoints,
+    ) -> anyhow::Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO spawn_points (dimension, data) VALUES(?1, ?2);",
+            (dimension.0, postcard::to_allocvec(spawn_points)?),
+        )?;
+        Ok(())
+    }
+
+    fn load_pinned_chunks(&mut self, dimension: DimensionId) -> anyhow::Result<Vec<ChunkPos>> {
+        let data = self
+            .db
+            .query_row(
+                "SELECT data FROM pinned_chunks WHERE dimension = ?1",
+                (dimension.0,),
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        Ok(data
+            .map(|data| postcard::from_bytes(&data))
+            .transpose()?
+            .unwrap_or_default())
+    }
+
+    fn save_pinned_chunks(
+        &mut self,
+        dimension: DimensionId,
+        chunks: &[ChunkPos],
+    ) -> anyhow::Result<()> {
+        self.db.execute(
+            "INSERT OR REPLACE INTO pinned_chunks (dimension, data) VALUES(?1, ?2);",
+            (dimension.0, postcard::to_allocvec(chunks)?),
+        )?;
+        Ok(())
+    }
+
+    fn load_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+    ) -> anyhow::Result<Vec<(BlockPos, Block)>> {
+        let data = self
+            .db
+            .query_row(
+                "SELECT data FROM pending_blocks WHERE dimension = ?1",
+                (dimension.0,),
+                |row| row.get::<_, Vec<u8>>(0),
+            )
+            .optional()?;
+        match data {
+            Some(data) => SavedPendingBlocks::deserialize(&data, &self.names),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn save_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+        blocks: &[(BlockPos, Block)],
+    ) -> anyhow::Result<()> {
+        let data = SavedPendingBlocks::serialize(blocks, &self.names)?;
+        self.db.execute(
+            "INSERT OR REPLACE INTO pending_blocks (dimension, data) VALUES(?1, ?2);",
+            (dimension.0, data),
+        )?;
+        Ok(())
+    }
+}
+
+/// Stores chunks in region files, each holding a 32³ cube of chunks.
+///
+/// A region file starts with an index table with one entry per chunk slot, followed by the
+/// chunk data itself. Chunk data is stored in whole sectors so that a chunk can usually be
+/// rewritten in place; if it outgrows its sectors it is moved to the end of the file.
+///
+/// The overworld's regions are stored directly in the save directory, other dimensions get a
+/// subdirectory each. Every dimension directory also holds the dimension's metadata, its spawn
+/// points, its pinned chunks, its pending structure blocks and an `entities` directory with a
+/// file per chunk that has entities, and the save directory holds the player accounts.
+pub struct RegionStorage {
+    dir: PathBuf,
+    regions: FastHashMap<(DimensionId, Point3<isize>), File>,
+    names: BlockNames,
+}
+
+impl RegionStorage {
+    pub const REGION_SIZE: isize = 32;
+    const SLOTS: usize = (Self::REGION_SIZE * Self::REGION_SIZE * Self::REGION_SIZE) as usize;
+    /// offset: u64, length: u32, capacity: u32
+    const ENTRY_SIZE: u64 = 16;
+    const HEADER_SIZE: u64 = Self::SLOTS as u64 * Self::ENTRY_SIZE;
+    const SECTOR_SIZE: u64 = 4096;
+    const DIMENSION_FILE: &'static str = "dimension.dat";
+    const SPAWN_FILE: &'static str = "spawn.dat";
+    const PINNED_FILE: &'static str = "pinned.dat";
+    const PENDING_BLOCKS_FILE: &'static str = "pending_blocks.dat";
+    const ACCOUNTS_FILE: &'static str = "players.dat";
+
+    pub fn open(dir: impl Into<PathBuf>, names: BlockNames) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self {
+            dir,
+            regions: FastHashMap::default(),
+            names,
+        })
+    }
+
+    fn region_pos(pos: ChunkPos) -> Point3<isize> {
+        Point3::from(pos).map(|e| e.div_euclid(Self::REGION_SIZE))
+    }
+
+    fn slot(pos: ChunkPos) -> u64 {
+        let p = Point3::from(pos).map(|e| e.rem_euclid(Self::REGION_SIZE) as u64);
+        let size = Self::REGION_SIZE as u64;
+        (p.x * size + p.y) * size + p.z
+    }
+
+    fn dimension_dir(&self, dimension: DimensionId) -> PathBuf {
+        if dimension == DimensionId::OVERWORLD {
+            self.dir.clone()
+        } else {
+            self.dir.join(dimension.to_string())
+        }
+    }
+
+    fn region_file(&mut self, dimension: DimensionId, pos: ChunkPos) -> anyhow::Result<&mut File> {
+        let region = (dimension, Self::region_pos(pos));
+        if !self.regions.contains_key(&region) {
+            let (_, r) = region;
+            let dir = self.dimension_dir(dimension);
+            std::fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("r.{}.{}.{}.region", r.x, r.y, r.z));
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            if file.metadata()?.len() < Self::HEADER_SIZE {
+                file.set_len(Self::HEADER_SIZE)?;
+            }
+            self.regions.insert(region, file);
+        }
+        Ok(self.regions.get_mut(&region).unwrap())
+    }
+
+    fn entities_file(&self, dimension: DimensionId, pos: ChunkPos) -> PathBuf {
+        let p = Point3::from(pos);
+        self.dimension_dir(dimension)
+            .join("entities")
+            .join(format!("{}.{}.{}.dat", p.x, p.y, p.z))
+    }
+
+    fn load_accounts(&self) -> anyhow::Result<Vec<Account>> {
+        let path = self.dir.join(Self::ACCOUNTS_FILE);
+        if !path.exists() {
+            return Ok(vec![]);
+        }
+        Ok(postcard::from_bytes(&std::fs::read(path)?)?)
+    }
+
+    fn read_entry(file: &mut File, slot: u64) -> anyhow::Result<(u64, u32, u32)> {
+        let mut entry = [0; Self::ENTRY_SIZE as usize];
+        file.seek(SeekFrom::Start(slot * Self::ENTRY_SIZE))?;
+        file.read_exact(&mut entry)?;
+        let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+        let length = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let capacity = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        Ok((offset, length, capacity))
+    }
+
+    fn write_entry(
+        file: &mut File,
+        slot: u64,
+        offset: u64,
+        length: u32,
+        capacity: u32,
+    ) -> anyhow::Result<()> {
+        let mut entry = [0; Self::ENTRY_SIZE as usize];
+        entry[0..8].copy_from_slice(&offset.to_le_bytes());
+        entry[8..12].copy_from_slice(&length.to_le_bytes());
+        entry[12..16].copy_from_slice(&capacity.to_le_bytes());
+        file.seek(SeekFrom::Start(slot * Self::ENTRY_SIZE))?;
+        file.write_all(&entry)?;
+        Ok(())
+    }
+}
+
+impl WorldStorage for RegionStorage {
+    fn load_chunk(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+    ) -> anyhow::Result<Option<Chunk>> {
+        let slot = Self::slot(pos);
+        let file = self.region_file(dimension, pos)?;
+        let (offset, length, _) = Self::read_entry(file, slot)?;
+        if length == 0 {
+            return Ok(None);
+        }
+
+        let mut data = vec![0; length as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+        Ok(Some(Chunk::deserialize(&data, &self.names)))
+    }
+
+    fn save_chunk(&mut self, dimension: DimensionId, chunk: &Chunk) -> anyhow::Result<()> {
+        let data = chunk.serialize(&self.names);
+        let slot = Self::slot(chunk.pos);
+        let file = self.region_file(dimension, chunk.pos)?;
+        let (mut offset, _, mut capacity) = Self::read_entry(file, slot)?;
+
+        if (capacity as usize) < data.len() {
+            // Doesn't fit in the old location, move it to the end of the file
+            offset = file
+                .metadata()?
+                .len()
+                .next_multiple_of(Self::SECTOR_SIZE);
+            capacity = (data.len() as u64).next_multiple_of(Self::SECTOR_SIZE) as u32;
+        }
+
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(&data)?;
+        Self::write_entry(file, slot, offset, data.len() as u32, capacity)?;
+        Ok(())
+    }
+
+    fn load_dimension(&mut self, dimension: DimensionId) -> anyhow::Result<Option<DimensionInfo>> {
+        let path = self.dimension_dir(dimension).join(Self::DIMENSION_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(postcard::from_bytes(&std::fs::read(path)?)?))
+    }
+
+    fn save_dimension(
+        &mut self,
+        dimension: DimensionId,
+        info: &DimensionInfo,
+    ) -> anyhow::Result<()> {
+        let dir = self.dimension_dir(dimension);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(Self::DIMENSION_FILE), postcard::to_allocvec(info)?)?;
+        Ok(())
+    }
+
+    fn dimensions(&mut self) -> anyhow::Result<Vec<DimensionId>> {
+        let has_info = |dir: &Path| dir.join(Self::DIMENSION_FILE).exists();
+        let mut dimensions = vec![];
+        if has_info(&self.dir) {
+            dimensions.push(DimensionId::OVERWORLD);
+        }
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if let Some(name) = entry.file_name().to_str()
+                && name.starts_with("dim")
+                && let Ok(dimension) = name.parse()
+                && has_info(&entry.path())
+            {
+                dimensions.push(dimension);
+            }
+        }
+        dimensions.sort();
+        Ok(dimensions)
+    }
+
+    fn load_account(&mut self, token: Uuid) -> anyhow::Result<Option<Account>> {
+        Ok(self
+            .load_accounts()?
+            .into_iter()
+            .find(|account| account.token == token))
+    }
+
+    fn account_by_name(&mut self, name: &str) -> anyhow::Result<Option<Account>> {
+        Ok(self
+            .load_accounts()?
+            .into_iter()
+            .find(|account| account.name == name))
+    }
+
+    fn save_account(&mut self, account: &Account) -> anyhow::Result<()> {
+        let mut accounts = self.load_accounts()?;
+        accounts.retain(|other| other.id != account.id);
+        accounts.push(account.clone());
+        std::fs::write(
+            self.dir.join(Self::ACCOUNTS_FILE),
+            postcard::to_allocvec(&accounts)?,
+        )?;
+        Ok(())
+    }
+
+    fn load_entities(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+    ) -> anyhow::Result<Vec<SavedEntity>> {
+        match std::fs::read(self.entities_file(dimension, pos)) {
+            Ok(data) => Ok(postcard::from_bytes(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_entities(
+        &mut self,
+        dimension: DimensionId,
+        pos: ChunkPos,
+        entities: &[SavedEntity],
+    ) -> anyhow::Result<()> {
+        let path = self.entities_file(dimension, pos);
+        if entities.is_empty() {
+            return match std::fs::remove_file(path) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+                _ => Ok(()),
+            };
+        }
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, postcard::to_allocvec(entities)?)?;
+        Ok(())
+    }
+
+    fn load_spawn_points(
+        &mut self,
+        dimension: DimensionId,
+    ) -> anyhow::Result<Option<SpawnPoints>> {
+        match std::fs::read(self.dimension_dir(dimension).join(Self::SPAWN_FILE)) {
+            Ok(data) => Ok(Some(postcard::from_bytes(&data)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_spawn_points(
+        &mut self,
+        dimension: DimensionId,
+        spawn_points: &SpawnPoints,
+    ) -> anyhow::Result<()> {
+        let dir = self.dimension_dir(dimension);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(Self::SPAWN_FILE), postcard::to_allocvec(spawn_points)?)?;
+        Ok(())
+    }
+
+    fn load_pinned_chunks(&mut self, dimension: DimensionId) -> anyhow::Result<Vec<ChunkPos>> {
+        match std::fs::read(self.dimension_dir(dimension).join(Self::PINNED_FILE)) {
+            Ok(data) => Ok(postcard::from_bytes(&data)?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_pinned_chunks(
+        &mut self,
+        dimension: DimensionId,
+        chunks: &[ChunkPos],
+    ) -> anyhow::Result<()> {
+        let dir = self.dimension_dir(dimension);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(Self::PINNED_FILE), postcard::to_allocvec(chunks)?)?;
+        Ok(())
+    }
+
+    fn load_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+    ) -> anyhow::Result<Vec<(BlockPos, Block)>> {
+        let path = self
+            .dimension_dir(dimension)
+            .join(Self::PENDING_BLOCKS_FILE);
+        match std::fs::read(path) {
+            Ok(data) => SavedPendingBlocks::deserialize(&data, &self.names),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(vec![]),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn save_pending_blocks(
+        &mut self,
+        dimension: DimensionId,
+        blocks: &[(BlockPos, Block)],
+    ) -> anyhow::Result<()> {
+        let dir = self.dimension_dir(dimension);
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(
+            dir.join(Self::PENDING_BLOCKS_FILE),
+            SavedPendingBlocks::serialize(blocks, &self.names)?,
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        for file in self.regions.values_mut() {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,63 @@
+//! Server-driven chunk loading, for clients that would rather be sent the chunks around them than
+//! ask for them.
+//!
+//! The server follows the position each streaming player reports, sending the chunks that come
+//! within their distance nearest first and telling them to drop the ones that fall out of it.
+//! Clients that don't ask for streaming keep requesting chunks themselves.
+
+use cgmath::{InnerSpace, Point3, Vector3};
+use wgpu::naga::FastHashSet;
+
+use crate::game::chunk::{BlockPos, Chunk, ChunkPos};
+
+/// Furthest distance in chunks chunks are streamed to, whatever a client asks for
+pub const MAX_DISTANCE: u32 = 16;
+/// Most chunks sent to a player in a tick, so that running into new terrain spreads loading and
+/// worldgen over several ticks
+const CHUNKS_PER_TICK: usize = 32;
+
+/// The chunks a streaming player should be sent and the ones they should drop
+#[derive(Debug, Default)]
+pub struct StreamUpdate {
+    /// Nearest to the player first
+    pub load: Vec<ChunkPos>,
+    pub unload: Vec<ChunkPos>,
+}
+
+/// What changes for a player at `position` streaming chunks within `distance` chunks, who has
+/// the chunks in `loaded`
+pub fn update(
+    position: Point3<f32>,
+    distance: u32,
+    loaded: &FastHashSet<ChunkPos>,
+) -> StreamUpdate {
+    let distance = distance.min(MAX_DISTANCE) as isize;
+    let max_distance = (distance * Chunk::SIZE as isize) as f32;
+    // Chunks are kept a little further out than they are loaded, so moving back and forth over
+    // a chunk border doesn't unload and load the same chunks over and over
+    let keep_distance = max_distance + Chunk::SIZE as f32;
+
+    let unload = loaded
+        .iter()
+        .copied()
+        .filter(|pos| (pos.center() - position).magnitude() > keep_distance)
+        .collect();
+
+    let center = BlockPos::from_point(position).chunk_pos();
+    let mut load = vec![];
+    for x in -distance..=distance {
+        for y in -distance..=distance {
+            for z in -distance..=distance {
+                let pos = center + Vector3::new(x, y, z);
+                if (pos.center() - position).magnitude() <= max_distance && !loaded.contains(&pos) {
+                    load.push(pos);
+                }
+            }
+        }
+    }
+    let distance = |pos: &ChunkPos| (pos.center() - position).magnitude2();
+    load.sort_by(|a, b| distance(a).total_cmp(&distance(b)));
+    load.truncate(CHUNKS_PER_TICK);
+
+    StreamUpdate { load, unload }
+}
@@ -0,0 +1,144 @@
+use std::path::Path;
+
+use bevy_ecs::system::Resource;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    display::WindowSettings,
+    input::{InputMap, MouseSettings},
+    locale::Locale,
+    server::connection::{KeepAlive, ServerTrust},
+    texture::TextureFiltering,
+    RenderMode,
+};
+
+/// Client settings that systems can read at runtime, persisted between launches
+#[derive(Debug, Clone, Resource, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Name shown to other players
+    pub player_name: String,
+    /// Radius in chunks around the player that is loaded at full detail
+    pub chunk_loading_distance: isize,
+    /// Whether the server sends the chunks around the player as they move, instead of the client
+    /// asking for them. Streamed chunks follow the player, not the spectator camera.
+    pub chunk_streaming: bool,
+    /// Whether chunks are kept on disk between sessions, so that rejoining a server only
+    /// downloads the ones that changed
+    pub chunk_cache: bool,
+    pub ambient_occlusion: bool,
+    /// Whether block corners are shaded by the average light around them instead of in steps
+    pub smooth_lighting: bool,
+    pub mouse_sensitivity: f32,
+    /// Vertical field of view in degrees, before zooming or sprinting changes it
+    pub fov: f32,
+    pub mouse: MouseSettings,
+    pub keys: InputMap,
+    /// Whether holding an emissive block lights up the area around the player
+    pub held_item_light: bool,
+    /// Whether dust and leaf particles are spawned around the camera
+    pub ambient_particles: bool,
+    /// Whether the compass and coordinates are shown
+    pub show_compass: bool,
+    /// Filtering of block textures, applied on the next launch
+    pub texture_filtering: TextureFiltering,
+    /// Samples per pixel for antialiasing, 1 to turn it off. Lowered to what the graphics card
+    /// supports, applied on the next launch.
+    pub msaa_samples: u32,
+    /// Size the world is rendered at relative to the window, then stretched to fit it. Applied
+    /// on the next launch.
+    pub render_scale: f32,
+    /// Language of the UI, the name of a file in `assets/lang` without its extension
+    pub language: String,
+    /// How opaque geometry is drawn, for debugging
+    pub render_mode: RenderMode,
+    /// Fullscreen or windowed, and at which size
+    pub window: WindowSettings,
+    /// Whether the camera bobs up and down while walking
+    pub view_bobbing: bool,
+    /// Whether the camera dips and springs back when landing, instead of stopping dead
+    pub camera_smoothing: bool,
+    /// Whether chunks hidden behind terrain are skipped when rendering
+    pub occlusion_culling: bool,
+    /// Whether distant geometry fades into the sky instead of ending abruptly
+    pub fog: bool,
+    /// Whether fog also gathers in valleys below sea level
+    pub height_fog: bool,
+    /// Most chunk meshes built in a frame, fewer while frames are slower than the target
+    pub meshes_per_frame: usize,
+    /// Frame time in milliseconds above which fewer chunk meshes are built per frame
+    pub target_frame_ms: f32,
+    /// How many of the player's own block edits can be undone
+    pub edit_history_size: usize,
+    /// Which server certificates to trust
+    pub server_trust: ServerTrust,
+    /// Name the server's certificate has to be issued for, when trusting system authorities
+    pub server_name: String,
+    pub keep_alive: KeepAlive,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            player_name: "Player".to_string(),
+            chunk_loading_distance: 5,
+            chunk_streaming: false,
+            chunk_cache: true,
+            ambient_occlusion: true,
+            smooth_lighting: true,
+            mouse_sensitivity: 1.0,
+            fov: 45.0,
+            mouse: MouseSettings::default(),
+            keys: InputMap::default(),
+            held_item_light: true,
+            ambient_particles: true,
+            show_compass: true,
+            texture_filtering: TextureFiltering::Nearest,
+            msaa_samples: 1,
+            render_scale: 1.0,
+            language: Locale::FALLBACK.to_string(),
+            render_mode: RenderMode::Fill,
+            window: WindowSettings::default(),
+            view_bobbing: true,
+            camera_smoothing: true,
+            occlusion_culling: true,
+            fog: true,
+            height_fog: false,
+            meshes_per_frame: 8,
+            target_frame_ms: 1000.0 / 60.0,
+            edit_history_size: 100,
+            server_trust: ServerTrust::default(),
+            server_name: "localhost".to_string(),
+            keep_alive: KeepAlive::default(),
+        }
+    }
+}
+
+impl Settings {
+    pub const PATH: &'static str = "./settings.toml";
+
+    /// Load settings from `path`, falling back to the defaults if the file is missing or invalid
+    pub fn load(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref();
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                log::warn!("Could not read {}: {e}", path.display());
+                return Self::default();
+            }
+        };
+        match toml::from_str(&contents) {
+            Ok(settings) => settings,
+            Err(e) => {
+                log::warn!("Invalid settings in {}, using defaults: {e}", path.display());
+                Self::default()
+            }
+        }
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        std::fs::write(path, toml::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
@@ -8,21 +8,50 @@ struct InstanceInput {
 
 struct CameraUniform {
     view_proj: mat4x4<f32>,
+    time: f32,
 };
 
 @group(1) @binding(0) var<uniform> camera: CameraUniform;
 
+struct PointLight {
+    position: vec3<f32>,
+    radius: f32,
+    color: vec3<f32>,
+};
+
+struct LightUniform {
+    lights: array<PointLight, 8>,
+    count: u32,
+};
+
+@group(2) @binding(0) var<uniform> lights: LightUniform;
+
+struct FogUniform {
+    color: vec3<f32>,
+    start: f32,
+    camera_position: vec3<f32>,
+    end: f32,
+    height_density: f32,
+    height_base: f32,
+};
+
+@group(3) @binding(0) var<uniform> fog: FogUniform;
+
 struct VertexInput {
     @location(0) position: vec3<f32>,
     @location(1) tex_coords: vec2<f32>,
     @location(2) ambient_occlusion: f32,
     @location(3) normal: vec3<f32>,
+    // Frame count, seconds per frame, and texture coordinate offset between frames
+    @location(4) animation: vec3<f32>,
 };
 
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) tex_coords: vec2<f32>,
     @location(1) ambient_occlusion: f32,
+    @location(2) world_position: vec3<f32>,
+    @location(3) normal: vec3<f32>,
 };
 
 @vertex
@@ -35,8 +64,15 @@ fn vs_main(model: VertexInput, instance: InstanceInput) -> VertexOutput {
     );
     var out: VertexOutput;
     out.tex_coords = model.tex_coords;
-    out.clip_position = camera.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    if model.animation.x > 1.0 {
+        let frame = floor(camera.time / model.animation.y) % model.animation.x;
+        out.tex_coords.x += frame * model.animation.z;
+    }
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.clip_position = camera.view_proj * world_position;
     out.ambient_occlusion = model.ambient_occlusion;
+    out.world_position = world_position.xyz;
+    out.normal = (model_matrix * vec4<f32>(model.normal, 0.0)).xyz;
     return out;
 }
 
@@ -47,5 +83,25 @@ var s_diffuse: sampler;
 
 @fragment
 fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
-    return textureSample(t_diffuse, s_diffuse, in.tex_coords) * (1.0 - in.ambient_occlusion);
+    var light = vec3<f32>(0.0);
+    for (var i = 0u; i < lights.count; i++) {
+        let l = lights.lights[i];
+        let to_light = l.position - in.world_position;
+        let falloff = clamp(1.0 - length(to_light) / l.radius, 0.0, 1.0);
+        let facing = max(dot(normalize(in.normal), normalize(to_light)), 0.0);
+        light += l.color * falloff * falloff * facing;
+    }
+
+    let color = textureSample(t_diffuse, s_diffuse, in.tex_coords) * (1.0 - in.ambient_occlusion);
+    return vec4<f32>(apply_fog(color.rgb * (1.0 + light), in.world_position), color.a);
+}
+
+fn apply_fog(color: vec3<f32>, world_position: vec3<f32>) -> vec3<f32> {
+    let distance = length(world_position - fog.camera_position);
+    var amount = smoothstep(fog.start, fog.end, distance);
+    // Thicker the further below the base height, but only a haze close to the camera
+    let depth = max(fog.height_base - world_position.y, 0.0);
+    let height = (1.0 - exp(-depth * fog.height_density)) * min(distance / fog.start, 1.0);
+    amount = max(amount, height);
+    return mix(color, fog.color, amount);
 }
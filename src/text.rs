@@ -0,0 +1,355 @@
+//! Text drawn over the screen, using a glyph atlas baked from the bundled font at startup.
+//!
+//! Like the debug lines, text is collected from scratch every frame with
+//! [`TextRenderer::draw_text`] and then drawn in a single batch.
+
+use ab_glyph::{Font, FontVec, PxScale, ScaleFont};
+use cgmath::{Point2, Vector2};
+use wgpu::naga::FastHashMap;
+use winit::dpi::PhysicalSize;
+
+use crate::{mesh::Vertex, upload::Uploader};
+
+pub const FONT_PATH: &str = "./assets/fonts/DejaVuSansMono.ttf";
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TextVertex {
+    /// In pixels from the top left corner until uploaded, in clip space after
+    pub position: [f32; 2],
+    pub tex_coords: [f32; 2],
+    pub color: [f32; 4],
+}
+
+impl Vertex for TextVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<TextVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::VertexStepMode::Vertex,
+            attributes: &[
+                wgpu::VertexAttribute {
+                    offset: 0,
+                    shader_location: 0,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 2]>() as wgpu::BufferAddress,
+                    shader_location: 1,
+                    format: wgpu::VertexFormat::Float32x2,
+                },
+                wgpu::VertexAttribute {
+                    offset: std::mem::size_of::<[f32; 4]>() as wgpu::BufferAddress,
+                    shader_location: 2,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
+            ],
+        }
+    }
+}
+
+/// Where a glyph is in the atlas and how it is placed, in pixels at the baked size
+#[derive(Debug, Clone, Copy)]
+struct Glyph {
+    /// Offset of the top left corner from the pen position on the baseline
+    offset: Vector2<f32>,
+    size: Vector2<f32>,
+    uv_min: [f32; 2],
+    uv_max: [f32; 2],
+    advance: f32,
+}
+
+pub struct TextRenderer {
+    pipeline: wgpu::RenderPipeline,
+    atlas_bind_group: wgpu::BindGroup,
+    glyphs: FastHashMap<char, Glyph>,
+    /// Distance from the top of a line to its baseline, at the baked size
+    ascent: f32,
+    /// Distance between the baselines of two lines, at the baked size
+    line_height: f32,
+    vertices: Vec<TextVertex>,
+    buffer: wgpu::Buffer,
+    /// Vertices written to `buffer` by the last `upload`
+    uploaded: u32,
+}
+
+impl TextRenderer {
+    /// Height in pixels the glyphs are baked at, larger text gets blurry
+    const BAKE_SIZE: f32 = 32.0;
+    const ATLAS_WIDTH: u32 = 512;
+    /// Empty pixels between glyphs, so filtering doesn't bleed into neighbours
+    const PADDING: u32 = 1;
+    const INITIAL_CAPACITY: u64 = 6 * 256;
+
+    pub fn new(
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        format: wgpu::TextureFormat,
+        texture_bind_group_layout: &wgpu::BindGroupLayout,
+    ) -> anyhow::Result<Self> {
+        let font = FontVec::try_from_vec(std::fs::read(FONT_PATH)?)?;
+        let font = font.as_scaled(PxScale::from(Self::BAKE_SIZE));
+
+        // Printable ASCII and Latin-1, which covers the languages the UI is translated to
+        let chars = (' '..='~').chain('\u{a1}'..='\u{ff}');
+
+        // Lay the glyphs out in rows, then draw them once the size of the atlas is known
+        let mut glyphs = FastHashMap::default();
+        let mut placed = vec![];
+        let (mut x, mut y, mut row_height) = (0, 0, 0);
+        for c in chars {
+            let glyph = font.scaled_glyph(c);
+            let advance = font.h_advance(glyph.id);
+            let Some(outline) = font.outline_glyph(glyph) else {
+                glyphs.insert(
+                    c,
+                    Glyph {
+                        offset: Vector2::new(0.0, 0.0),
+                        size: Vector2::new(0.0, 0.0),
+                        uv_min: [0.0; 2],
+                        uv_max: [0.0; 2],
+                        advance,
+                    },
+                );
+                continue;
+            };
+            let bounds = outline.px_bounds();
+            let (width, height) = (bounds.width() as u32, bounds.height() as u32);
+            if x + width > Self::ATLAS_WIDTH {
+                x = 0;
+                y += row_height + Self::PADDING;
+                row_height = 0;
+            }
+            placed.push((c, outline, advance, x, y));
+            x += width + Self::PADDING;
+            row_height = row_height.max(height);
+        }
+        let atlas_height = y + row_height;
+
+        let mut pixels = vec![0u8; (Self::ATLAS_WIDTH * atlas_height) as usize];
+        let atlas_size = Vector2::new(Self::ATLAS_WIDTH as f32, atlas_height as f32);
+        for (c, outline, advance, x, y) in placed {
+            outline.draw(|gx, gy, coverage| {
+                let index = (y + gy) * Self::ATLAS_WIDTH + x + gx;
+                pixels[index as usize] = (coverage.clamp(0.0, 1.0) * 255.0) as u8;
+            });
+            let bounds = outline.px_bounds();
+            let size = Vector2::new(bounds.width(), bounds.height());
+            let uv_min = Vector2::new(x as f32, y as f32);
+            let uv_max = uv_min + size;
+            glyphs.insert(
+                c,
+                Glyph {
+                    offset: Vector2::new(bounds.min.x, bounds.min.y),
+                    size,
+                    uv_min: [uv_min.x / atlas_size.x, uv_min.y / atlas_size.y],
+                    uv_max: [uv_max.x / atlas_size.x, uv_max.y / atlas_size.y],
+                    advance,
+                },
+            );
+        }
+
+        let size = wgpu::Extent3d {
+            width: Self::ATLAS_WIDTH,
+            height: atlas_height.max(1),
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("Glyph Atlas"),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            view_formats: &[],
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &pixels,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(Self::ATLAS_WIDTH),
+                rows_per_image: Some(atlas_height),
+            },
+            size,
+        );
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let atlas_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: texture_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+            ],
+            label: Some("glyph_atlas_bind_group"),
+        });
+
+        let shader = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Text Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("text.wgsl").into()),
+        });
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Text Pipeline Layout"),
+            bind_group_layouts: &[texture_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: Some("Text Pipeline"),
+            layout: Some(&layout),
+            vertex: wgpu::VertexState {
+                module: &shader,
+                entry_point: "vs_main",
+                buffers: &[TextVertex::desc()],
+            },
+            fragment: Some(wgpu::FragmentState {
+                module: &shader,
+                entry_point: "fs_main",
+                targets: &[Some(wgpu::ColorTargetState {
+                    format,
+                    blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+                    write_mask: wgpu::ColorWrites::ALL,
+                })],
+            }),
+            primitive: wgpu::PrimitiveState::default(),
+            depth_stencil: None,
+            multisample: wgpu::MultisampleState::default(),
+            multiview: None,
+        });
+
+        Ok(Self {
+            pipeline,
+            atlas_bind_group,
+            glyphs,
+            ascent: font.ascent(),
+            line_height: font.height() + font.line_gap(),
+            vertices: vec![],
+            buffer: Self::create_buffer(device, Self::INITIAL_CAPACITY),
+            uploaded: 0,
+        })
+    }
+
+    fn create_buffer(device: &wgpu::Device, capacity: u64) -> wgpu::Buffer {
+        device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Text Buffer"),
+            size: capacity * std::mem::size_of::<TextVertex>() as u64,
+            usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        })
+    }
+
+    /// Draw `text` with its top left corner at `position`, in pixels from the top left corner
+    /// of the window, with lines `size` pixels high. Characters the font has no glyph for are
+    /// drawn as `?`.
+    pub fn draw_text(&mut self, position: Point2<f32>, size: f32, color: [f32; 4], text: &str) {
+        let scale = size / Self::BAKE_SIZE;
+        let mut pen = Point2::new(position.x, position.y + self.ascent * scale);
+        for c in text.chars() {
+            if c == '\n' {
+                pen.x = position.x;
+                pen.y += self.line_height * scale;
+                continue;
+            }
+            let Some(glyph) = self.glyphs.get(&c).or_else(|| self.glyphs.get(&'?')) else {
+                continue;
+            };
+            if glyph.size.x > 0.0 {
+                let min = pen + glyph.offset * scale;
+                let max = min + glyph.size * scale;
+                let vertex = |x: f32, y: f32, u: f32, v: f32| TextVertex {
+                    position: [x, y],
+                    tex_coords: [u, v],
+                    color,
+                };
+                let [u0, v0] = glyph.uv_min;
+                let [u1, v1] = glyph.uv_max;
+                self.vertices.extend([
+                    vertex(min.x, min.y, u0, v0),
+                    vertex(min.x, max.y, u0, v1),
+                    vertex(max.x, max.y, u1, v1),
+                    vertex(min.x, min.y, u0, v0),
+                    vertex(max.x, max.y, u1, v1),
+                    vertex(max.x, min.y, u1, v0),
+                ]);
+            }
+            pen.x += glyph.advance * scale;
+        }
+    }
+
+    /// Distance between two lines of text `size` pixels high
+    pub fn line_spacing(&self, size: f32) -> f32 {
+        self.line_height * size / Self::BAKE_SIZE
+    }
+
+    /// Write this frame's text to the GPU for a window of `size`, and start collecting the next
+    /// frame's
+    pub fn upload(
+        &mut self,
+        device: &wgpu::Device,
+        uploader: &mut Uploader,
+        size: PhysicalSize<u32>,
+    ) {
+        for vertex in &mut self.vertices {
+            let [x, y] = vertex.position;
+            vertex.position = [
+                x / size.width as f32 * 2.0 - 1.0,
+                1.0 - y / size.height as f32 * 2.0,
+            ];
+        }
+        let capacity = self.buffer.size() / std::mem::size_of::<TextVertex>() as u64;
+        if self.vertices.len() as u64 > capacity {
+            self.buffer =
+                Self::create_buffer(device, (self.vertices.len() as u64).next_power_of_two());
+        }
+        uploader.write_buffer(
+            &self.buffer,
+            0,
+            bytemuck::cast_slice(&self.vertices),
+            device,
+        );
+        self.uploaded = self.vertices.len() as u32;
+        self.vertices.clear();
+    }
+
+    /// Draw the uploaded text over what is already in `output`
+    pub fn draw(&self, encoder: &mut wgpu::CommandEncoder, output: &wgpu::TextureView) {
+        if self.uploaded == 0 {
+            return;
+        }
+        let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+            label: Some("Text Pass"),
+            color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                view: output,
+                resolve_target: None,
+                ops: wgpu::Operations {
+                    load: wgpu::LoadOp::Load,
+                    store: wgpu::StoreOp::Store,
+                },
+            })],
+            depth_stencil_attachment: None,
+            occlusion_query_set: None,
+            timestamp_writes: None,
+        });
+        render_pass.set_pipeline(&self.pipeline);
+        render_pass.set_bind_group(0, &self.atlas_bind_group, &[]);
+        render_pass.set_vertex_buffer(0, self.buffer.slice(..));
+        render_pass.draw(0..self.uploaded, 0..1);
+    }
+}
@@ -1,5 +1,32 @@
 use anyhow::*;
-use image::{imageops::FilterType::Nearest, GenericImageView};
+use image::{imageops::FilterType::Nearest, GenericImageView, RgbaImage};
+use serde::{Deserialize, Serialize};
+
+/// How a texture is sampled when drawn smaller than its actual size
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TextureFiltering {
+    /// Use the nearest pixel of the nearest mipmap level
+    Nearest,
+    /// Blend between pixels and between mipmap levels. Smoother in the distance, but blurs
+    /// pixel art.
+    Trilinear,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TextureOptions {
+    pub filtering: TextureFiltering,
+    /// Number of mipmap levels, including the full size image. 1 disables mipmapping.
+    pub mip_levels: u32,
+}
+
+impl Default for TextureOptions {
+    fn default() -> Self {
+        Self {
+            filtering: TextureFiltering::Nearest,
+            mip_levels: 1,
+        }
+    }
+}
 
 pub struct Texture {
     pub texture: wgpu::Texture,
@@ -12,19 +39,20 @@ impl Texture {
 
     pub fn create_depth_texture(
         device: &wgpu::Device,
-        config: &wgpu::SurfaceConfiguration,
+        (width, height): (u32, u32),
+        sample_count: u32,
         label: &str,
     ) -> Self {
         let size = wgpu::Extent3d {
-            width: config.width,
-            height: config.height,
+            width,
+            height,
             depth_or_array_layers: 1,
         };
         let desc = wgpu::TextureDescriptor {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
@@ -53,14 +81,48 @@ impl Texture {
         }
     }
 
-    pub fn from_bytes(
+    /// A texture to draw into instead of the window. Single-sampled ones can be sampled
+    /// afterwards, with linear filtering.
+    pub fn create_color_target(
         device: &wgpu::Device,
-        queue: &wgpu::Queue,
-        bytes: &[u8],
+        format: wgpu::TextureFormat,
+        (width, height): (u32, u32),
+        sample_count: u32,
         label: &str,
-    ) -> Result<Self> {
-        let img = image::load_from_memory(bytes)?;
-        Self::from_image(device, queue, &img, Some(label))
+    ) -> Self {
+        let mut usage = wgpu::TextureUsages::RENDER_ATTACHMENT;
+        if sample_count == 1 {
+            usage |= wgpu::TextureUsages::TEXTURE_BINDING;
+        }
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count,
+            dimension: wgpu::TextureDimension::D2,
+            format,
+            usage,
+            view_formats: &[],
+        });
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self {
+            texture,
+            view,
+            sampler,
+        }
     }
 
     pub fn from_image(
@@ -68,9 +130,13 @@ impl Texture {
         queue: &wgpu::Queue,
         img: &image::DynamicImage,
         label: Option<&str>,
+        options: TextureOptions,
     ) -> Result<Self> {
         let rgba = img.to_rgba8();
         let dimensions = img.dimensions();
+        let mip_levels = options
+            .mip_levels
+            .clamp(1, dimensions.0.max(dimensions.1).ilog2() + 1);
 
         let size = wgpu::Extent3d {
             width: dimensions.0,
@@ -80,7 +146,7 @@ impl Texture {
         let texture = device.create_texture(&wgpu::TextureDescriptor {
             label,
             size,
-            mip_level_count: 1,
+            mip_level_count: mip_levels,
             sample_count: 1,
             dimension: wgpu::TextureDimension::D2,
             format: wgpu::TextureFormat::Rgba8UnormSrgb,
@@ -88,30 +154,45 @@ impl Texture {
             view_formats: &[],
         });
 
-        queue.write_texture(
-            wgpu::ImageCopyTexture {
-                aspect: wgpu::TextureAspect::All,
-                texture: &texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-            },
-            &rgba,
-            wgpu::ImageDataLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * dimensions.0),
-                rows_per_image: Some(dimensions.1),
-            },
-            size,
-        );
+        let mut level = rgba;
+        for mip_level in 0..mip_levels {
+            if mip_level > 0 {
+                level = Self::downsample(&level);
+            }
+            queue.write_texture(
+                wgpu::ImageCopyTexture {
+                    aspect: wgpu::TextureAspect::All,
+                    texture: &texture,
+                    mip_level,
+                    origin: wgpu::Origin3d::ZERO,
+                },
+                &level,
+                wgpu::ImageDataLayout {
+                    offset: 0,
+                    bytes_per_row: Some(4 * level.width()),
+                    rows_per_image: Some(level.height()),
+                },
+                wgpu::Extent3d {
+                    width: level.width(),
+                    height: level.height(),
+                    depth_or_array_layers: 1,
+                },
+            );
+        }
 
+        let (min_filter, mipmap_filter) = match options.filtering {
+            TextureFiltering::Nearest => (wgpu::FilterMode::Nearest, wgpu::FilterMode::Nearest),
+            TextureFiltering::Trilinear => (wgpu::FilterMode::Linear, wgpu::FilterMode::Linear),
+        };
         let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
         let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
             address_mode_u: wgpu::AddressMode::ClampToEdge,
             address_mode_v: wgpu::AddressMode::ClampToEdge,
             address_mode_w: wgpu::AddressMode::ClampToEdge,
+            // Up close, blocks always keep their crisp pixels
             mag_filter: wgpu::FilterMode::Nearest,
-            min_filter: wgpu::FilterMode::Nearest,
-            mipmap_filter: wgpu::FilterMode::Nearest,
+            min_filter,
+            mipmap_filter,
             ..Default::default()
         });
 
@@ -121,4 +202,23 @@ impl Texture {
             sampler,
         })
     }
+
+    /// Halve the size of an image by averaging each 2x2 block of pixels.
+    /// Unlike a general resize, this never mixes pixels from different atlas cells as long as
+    /// the cells are at least 2 pixels wide.
+    fn downsample(image: &RgbaImage) -> RgbaImage {
+        let width = (image.width() / 2).max(1);
+        let height = (image.height() / 2).max(1);
+        RgbaImage::from_fn(width, height, |x, y| {
+            let mut sum = [0u32; 4];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let px = (x * 2 + dx).min(image.width() - 1);
+                let py = (y * 2 + dy).min(image.height() - 1);
+                for (total, channel) in sum.iter_mut().zip(image.get_pixel(px, py).0) {
+                    *total += channel as u32;
+                }
+            }
+            image::Rgba(sum.map(|total| (total / 4) as u8))
+        })
+    }
 }
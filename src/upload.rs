@@ -0,0 +1,75 @@
+use std::num::NonZeroU64;
+
+use wgpu::util::StagingBelt;
+
+/// Batches a frame's buffer uploads through reusable staging buffers.
+///
+/// The copies are recorded into a single command buffer, which is submitted together with the
+/// frame's rendering instead of every write going to the queue on its own.
+pub struct Uploader {
+    belt: StagingBelt,
+    encoder: Option<wgpu::CommandEncoder>,
+}
+
+impl Uploader {
+    /// Size of each staging buffer, uploads larger than this get a buffer of their own
+    const CHUNK_SIZE: u64 = 1 << 20;
+
+    pub fn new() -> Self {
+        Self {
+            belt: StagingBelt::new(Self::CHUNK_SIZE),
+            encoder: None,
+        }
+    }
+
+    /// Schedule `data` to be written to `buffer` at `offset`. The buffer needs `COPY_DST` usage,
+    /// and `offset` and the length of `data` must be multiples of 4.
+    pub fn write_buffer(
+        &mut self,
+        buffer: &wgpu::Buffer,
+        offset: wgpu::BufferAddress,
+        data: &[u8],
+        device: &wgpu::Device,
+    ) {
+        let Some(size) = NonZeroU64::new(data.len() as u64) else {
+            return;
+        };
+        let encoder = self.encoder.get_or_insert_with(|| {
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Upload Encoder"),
+            })
+        });
+        self.belt
+            .write_buffer(encoder, buffer, offset, size, device)
+            .copy_from_slice(data);
+    }
+
+    /// Schedule copying the first `size` bytes of `source` into `destination`, in order with the
+    /// writes around it. `source` needs `COPY_SRC` usage.
+    pub fn copy_buffer(
+        &mut self,
+        source: &wgpu::Buffer,
+        destination: &wgpu::Buffer,
+        size: wgpu::BufferAddress,
+        device: &wgpu::Device,
+    ) {
+        let encoder = self.encoder.get_or_insert_with(|| {
+            device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+                label: Some("Upload Encoder"),
+            })
+        });
+        encoder.copy_buffer_to_buffer(source, 0, destination, 0, size);
+    }
+
+    /// The commands performing every upload scheduled since the last call, if any.
+    /// These must be submitted before `recall` is called.
+    pub fn finish(&mut self) -> Option<wgpu::CommandBuffer> {
+        self.belt.finish();
+        self.encoder.take().map(|encoder| encoder.finish())
+    }
+
+    /// Make the staging buffers of submitted uploads available for reuse
+    pub fn recall(&mut self) {
+        self.belt.recall();
+    }
+}